@@ -0,0 +1,96 @@
+//! Filtering of the command APDUs relayed to the card
+//!
+//! Blocked APDUs are answered with a configured status word by the CCID handler, without
+//! reaching the card.
+use std::fmt::Debug;
+
+/// Status word of blocked APDUs unless configured otherwise, security status not satisfied
+pub const DEFAULT_BLOCKED_SW: u16 = 0x6982;
+
+/// Decides which command APDUs of `PC_to_RDR_XfrBlock` reach the card
+pub trait ApduFilter: Debug + Send + Sync {
+    fn allow(&self, apdu: &[u8]) -> bool;
+}
+
+/// Relays every APDU, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl ApduFilter for AllowAll {
+    fn allow(&self, _apdu: &[u8]) -> bool {
+        true
+    }
+}
+
+/// Command an [`Allowlist`] lets through, any class if `cla` is `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedCommand {
+    pub cla: Option<u8>,
+    pub ins: u8,
+}
+
+/// Relays only APDUs whose CLA and INS are listed, blocking the rest
+#[derive(Debug, Clone, Default)]
+pub struct Allowlist {
+    pub commands: Vec<AllowedCommand>,
+}
+
+impl ApduFilter for Allowlist {
+    fn allow(&self, apdu: &[u8]) -> bool {
+        // Too short to have an INS byte
+        let [cla, ins, ..] = *apdu else {
+            return false;
+        };
+        self.commands
+            .iter()
+            .any(|command| command.ins == ins && command.cla.is_none_or(|allowed| allowed == cla))
+    }
+}
+
+/// Parse `CLA:INS` in hex, `*` as CLA allowing any class, e.g. `00:A4` or `*:2A`
+pub fn parse_allowed_command(value: &str) -> Result<AllowedCommand, String> {
+    let (cla, ins) = value
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not of the form CLA:INS", value))?;
+    let byte = |digits: &str| {
+        u8::from_str_radix(digits, 16)
+            .map_err(|e| format!("'{}' is not a hexadecimal byte: {}", digits, e))
+    };
+    Ok(AllowedCommand {
+        cla: match cla {
+            "*" => None,
+            cla => Some(byte(cla)?),
+        },
+        ins: byte(ins)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let filter = Allowlist {
+            commands: ["00:A4", "*:2A"]
+                .into_iter()
+                .map(|command| parse_allowed_command(command).unwrap())
+                .collect(),
+        };
+        // SELECT and PSO in any class
+        assert!(filter.allow(&[
+            0x00, 0xA4, 0x04, 0x00, 0x06, 0xD2, 0x76, 0x00, 0x01, 0x24, 0x01
+        ]));
+        assert!(filter.allow(&[0x00, 0x2A, 0x9E, 0x9A, 0x00]));
+        assert!(filter.allow(&[0x10, 0x2A, 0x9E, 0x9A, 0x00]));
+        // SELECT in another class, CHANGE REFERENCE DATA, too short
+        assert!(!filter.allow(&[0x80, 0xA4, 0x04, 0x00]));
+        assert!(!filter.allow(&[0x00, 0x24, 0x00, 0x81, 0x00]));
+        assert!(!filter.allow(&[0x00]));
+        assert!(AllowAll.allow(&[0x00, 0x24, 0x00, 0x81, 0x00]));
+
+        assert!(parse_allowed_command("00A4").is_err());
+        assert!(parse_allowed_command("00:XY").is_err());
+        assert!(parse_allowed_command("100:A4").is_err());
+    }
+}
@@ -1,25 +1,333 @@
+use crate::apdu_filter::{AllowAll, ApduFilter, DEFAULT_BLOCKED_SW};
+use crate::ccid_backend::{CCIDBackend, Canceller};
+use crate::ccid_descriptor::{self, CcidFunctionalDescriptor};
 use crate::ccid_proto::{
-    CCIDError, Decode, Encode, ICCClockStatus, ICCProtocol, Response, ResponseMessageHeader,
-    SlotErrorRegister, SlotStatusRegister,
+    CCIDError, CommonMessageHeader, Decode, Encode, ICCClockCommand, ICCClockStatus, ICCProtocol,
+    ICCVoltage, Response, ResponseMessageHeader, SlotErrorRegister, SlotStatusRegister,
+    T0Parameters, T1Parameters,
 };
-use crate::{ccid_const, ccid_proto};
-use log::{debug, error};
-use pcsc::{Attribute, Disposition, Protocols, Scope, ShareMode};
+use crate::hexdump::hexdump;
+use crate::status_word;
+use crate::transfer::TransferConfig;
+use crate::usb_backend::{UsbBackend, parse_configuration};
+use crate::{ccid_const, ccid_proto, device, trace};
+use log::{debug, error, info, warn};
+use pcsc::{Disposition, Protocol, Protocols, ShareMode};
 use std::any::Any;
-use std::cell::{Cell, SyncUnsafeCell};
 use std::collections::VecDeque;
 use std::ffi::{CStr, CString};
 use std::fmt::{Debug, Formatter};
 use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use usbip::StandardRequest::GetStatus;
 use usbip::{EndpointAttributes, SetupPacket, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
 
+/// `SCARD_CTL_CODE(1)` of pcsc-lite, which libccid maps to `PC_to_RDR_Escape`
+pub const DEFAULT_ESCAPE_CONTROL_CODE: u32 = 0x42000000 | 1;
+
+/// Large enough for extended APDU responses without chaining
+pub const DEFAULT_MAX_MESSAGE_LENGTH: u32 = 0x10000;
+
+/// Large enough that responses are only chained because of `dwMaxCCIDMessageLength`
+pub const DEFAULT_MAX_IFSD: u32 = 0xFFF6;
+
+/// Clock of ISO/IEC 7816-3 in kHz, relayed when the descriptor of the device is not usable
+const DEFAULT_CLOCK: u32 = 4000;
+
+/// Data rate at `DEFAULT_CLOCK` with the default Fi/Di of ISO/IEC 7816-3, in bps
+const DEFAULT_DATA_RATE: u32 = 10752;
+
+/// Size of the header common to all CCID messages
+const MESSAGE_HEADER_LENGTH: u32 = 10;
+
+/// Longest response APDU, 65536 bytes of data and the status word
+const MAX_RESPONSE_APDU_LENGTH: usize = 65538;
+
+/// `wLevelParameter` of an empty `PC_to_RDR_XfrBlock` asking for the next block of a chained
+/// response
+const LEVEL_GET_NEXT_BLOCK: u16 = 0x0010;
+
+/// APDUs exchanged with the card since the handler was created
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ApduCounters {
+    pub apdus: u64,
+    /// Exchanges failed by the reader, status words of the card are not errors
+    pub errors: u64,
+    /// Bytes of response APDUs, sent to the host
+    pub bytes_in: u64,
+    /// Bytes of command APDUs, received from the host
+    pub bytes_out: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CCIDConfig {
+    /// Control code passed to `SCardControl` when relaying `PC_to_RDR_Escape`
+    pub escape_control_code: u32,
+    /// `dwMaxCCIDMessageLength`, longer response APDUs are chained
+    pub max_message_length: u32,
+    /// Longest command or response APDU relayed, longer ones fail with `XFR_OVERRUN`. Also
+    /// caps `dwMaxCCIDMessageLength`.
+    pub max_apdu_len: Option<u32>,
+    /// CCID class descriptor announced as is instead of the one built from the device
+    pub raw_descriptor: Option<Vec<u8>>,
+    /// Fail creating the handler if connecting the card takes longer, never when `None`
+    pub connect_timeout: Option<Duration>,
+    /// Power the card down after this long without CCID commands, never when `None`
+    pub idle_timeout: Option<Duration>,
+    /// Fail APDU exchanges with `CMD_ABORTED` without passing them to the card
+    pub read_only: bool,
+    /// Fail APDU exchanges with `ICC_MUTE` as if the card did not answer, while it is reported
+    /// present and active, for testing how hosts handle a dead card
+    pub simulate_mute: bool,
+    /// Reset the card before the host selects an applet other than the selected one, as
+    /// WebUSB requests do when that interface is relayed
+    pub reset_on_applet_switch: bool,
+    /// Connect the card shared if another process keeps it from being connected exclusively,
+    /// each APDU exchange then runs in its own transaction
+    pub shared_fallback: bool,
+    /// `bMaxCCIDBusySlots`, commands while as many slots are busy fail with `CMD_SLOT_BUSY`
+    pub max_busy_slots: u8,
+    /// `dwMaxIFSD`, response APDUs longer than this are chained
+    pub max_ifsd: u32,
+    /// Returned by GET_CLOCK_FREQUENCIES in kHz, `bNumClockSupported` is their number and 0
+    /// (card managed) if empty
+    pub clock_frequencies: Vec<u32>,
+    /// Returned by GET_DATA_RATES in bps, `bNumDataRatesSupported` is their number and 0 (card
+    /// managed) if empty
+    pub data_rates: Vec<u32>,
+    /// How commands the reader does not support are answered
+    pub unsupported_response: UnsupportedResponse,
+    /// Command APDUs it does not allow are answered with `blocked_sw` instead of reaching the
+    /// card
+    pub apdu_filter: Arc<dyn ApduFilter>,
+    pub blocked_sw: u16,
+    pub transfer: TransferConfig,
+}
+
+impl Default for CCIDConfig {
+    fn default() -> Self {
+        Self {
+            escape_control_code: DEFAULT_ESCAPE_CONTROL_CODE,
+            max_message_length: DEFAULT_MAX_MESSAGE_LENGTH,
+            max_apdu_len: None,
+            raw_descriptor: None,
+            connect_timeout: None,
+            idle_timeout: None,
+            read_only: false,
+            simulate_mute: false,
+            reset_on_applet_switch: false,
+            shared_fallback: false,
+            max_busy_slots: 1,
+            max_ifsd: DEFAULT_MAX_IFSD,
+            clock_frequencies: vec![],
+            data_rates: vec![],
+            unsupported_response: UnsupportedResponse::default(),
+            apdu_filter: Arc::new(AllowAll),
+            blocked_sw: DEFAULT_BLOCKED_SW,
+            transfer: TransferConfig::default(),
+        }
+    }
+}
+
+/// Response to commands the reader does not support, including unknown message types
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum UnsupportedResponse {
+    /// Header echoing the message type of the command, with a failed status and `bError` 0
+    /// (command not supported)
+    #[default]
+    Dedicated,
+    /// `RDR_to_PC_SlotStatus` with a failed status and this `bError`, for hosts expecting a
+    /// regular response
+    SlotStatus(SlotErrorRegister),
+}
+
 pub struct CCIDInterfaceHandler {
-    context: pcsc::Context,
-    card: Cell<Option<pcsc::Card>>,
-    ccid_descriptor: Vec<u8>,
+    backend: Box<dyn CCIDBackend>,
+    config: CCIDConfig,
+    ccid_descriptor: CcidFunctionalDescriptor,
+    response_buffer: Vec<u8>,
+    // Rest of a chained response APDU, sent on `LEVEL_GET_NEXT_BLOCK`
+    chained_response: Option<Vec<u8>>,
     outQueue: VecDeque<Vec<u8>>,
+    parameter: Option<T1Parameters>,
+    // Changed by PC_to_RDR_SetParameters, T=1 again after the card is powered on
+    protocol: ICCProtocol,
+    // Voltage the card was powered on with, `AUTO` resolved
+    voltage: ICCVoltage,
+    // Of the last connect, reused by reconnects
+    share_mode: ShareMode,
+    atr: Option<Vec<u8>>,
+    // AID of the last SELECT since the card was powered on
+    selected_aid: Option<Vec<u8>>,
+    abort: Option<AbortState>,
+    card_present: bool,
+    clock: ICCClockStatus,
+    last_activity: Instant,
+    // Powered down by `check_idle` behind the back of the host
+    idle_dropped: bool,
+    counters: ApduCounters,
+    // End of the last APDU exchange with the card, for `command_delay`
+    last_exchange: Option<Instant>,
+    in_flight: Option<CommandInFlight>,
+    status: SlotStatus,
+    // APDU exchange running on a worker thread, of the command in flight unless abandoned
+    exchange: Option<Exchange>,
+    // Notified when the exchange ended or timed out, see `urb_ready`
+    ready: Arc<Notify>,
+}
+
+/// Command received on the bulk OUT endpoint whose response is not queued yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandInFlight {
+    pub slot: u8,
+    pub seq: u8,
+    pub message_type: u8,
+    pub started_at: Instant,
+}
+
+/// State of the slot of a CCID interface as of the start or end of the last command
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SlotSnapshot {
+    /// Whether the card is connected through the backend
+    pub powered: bool,
+    /// Whether a card is in the reader, powered or not
+    pub card_present: bool,
+    /// Returned by the last successful power on, `None` while powered off
+    pub atr: Option<Vec<u8>>,
+    /// Voltage the card was powered on with, 5V for `AUTO` and at startup as PCSC does not tell
+    pub voltage: Option<ICCVoltage>,
+    pub counters: ApduCounters,
+    /// The command being processed, `None` between commands
+    pub in_flight: Option<CommandInFlight>,
+}
+
+/// Handle to the [`SlotSnapshot`] a CCID interface handler publishes
+///
+/// Readable from other threads without locking the handler, which stays locked for as long as
+/// a command runs.
+#[derive(Debug, Clone)]
+pub struct SlotStatus {
+    slot_count: u8,
+    snapshot: Arc<Mutex<SlotSnapshot>>,
+}
+
+impl SlotStatus {
+    pub fn snapshot(&self) -> SlotSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+
+    /// Number of slots announced in the CCID class descriptor
+    pub fn slot_count(&self) -> u8 {
+        self.slot_count
+    }
+
+    /// Whether the card in `slot` is powered on, i.e. connected through the backend
+    pub fn is_powered(&self, slot: u8) -> bool {
+        slot < self.slot_count && self.snapshot.lock().unwrap().powered
+    }
+
+    /// ATR returned by the last successful power on of `slot`
+    pub fn current_atr(&self, slot: u8) -> Option<Vec<u8>> {
+        match slot < self.slot_count {
+            true => self.snapshot.lock().unwrap().atr.clone(),
+            false => None,
+        }
+    }
+
+    /// Voltage `slot` was powered on with
+    pub fn voltage(&self, slot: u8) -> Option<ICCVoltage> {
+        match slot < self.slot_count {
+            true => self.snapshot.lock().unwrap().voltage,
+            false => None,
+        }
+    }
+}
+
+/// Half of the two-phase abort received so far
+///
+/// An abort completes once both the ep0 ABORT request and `PC_to_RDR_Abort` of the same
+/// slot and sequence number are received, in either order.
+#[derive(Debug, Clone, Copy)]
+enum AbortState {
+    Control { slot: u8, seq: u8 },
+    Bulk(CommonMessageHeader),
+}
+
+/// PC_to_RDR_XfrBlock or PC_to_RDR_Escape whose data is exchanged with the card on a worker
+/// thread
+///
+/// The connection keeps being served meanwhile, so the ABORT request of the host gets through.
+/// It is answered right away, as is a command still running at `deadline`. The exchange is then
+/// abandoned and cancelled with `canceller` where the backend can.
+struct Exchange {
+    header: CommonMessageHeader,
+    apdu: Vec<u8>,
+    canceller: Option<Canceller>,
+    done: mpsc::Receiver<(Transmitter, Result<usize, pcsc::Error>)>,
+    after: AfterExchange,
+    deadline: Option<Instant>,
+}
+
+/// What becomes of the result of an [`Exchange`] once its worker is done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AfterExchange {
+    /// The result answers the command
+    Answer,
+    /// The command was answered when the exchange was abandoned, the result is thrown away
+    Discard,
+    /// Like `Discard`, the card is powered off once it is handed back
+    PowerOff,
+}
+
+/// What an APDU exchange with the card takes, lent to the worker thread of an [`Exchange`]
+struct Transmitter {
+    backend: Box<dyn CCIDBackend>,
+    response_buffer: Vec<u8>,
+    protocol: ICCProtocol,
+    max_apdu_len: Option<u32>,
+    command_delay: Duration,
+    // End of the last APDU exchange with the card, for `command_delay`
+    last_exchange: Option<Instant>,
+}
+
+/// Stands in for the backend while it is lent to an [`Exchange`], the card being connected
+/// meanwhile
+struct LentBackend {
     reader_name: CString,
-    parameter: Option<Vec<u8>>, // ProtocolData
+}
+
+impl CCIDBackend for LentBackend {
+    fn reader_name(&self) -> &CStr {
+        &self.reader_name
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn connect(&mut self, _: ShareMode, _: Protocols) -> Result<(), pcsc::Error> {
+        Err(pcsc::Error::SharingViolation)
+    }
+
+    fn disconnect(&mut self, _: Disposition) -> Result<(), pcsc::Error> {
+        Err(pcsc::Error::SharingViolation)
+    }
+
+    fn atr(&mut self) -> Result<Vec<u8>, pcsc::Error> {
+        Err(pcsc::Error::SharingViolation)
+    }
+
+    fn transmit<'b>(&mut self, _: &[u8], _: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error> {
+        Err(pcsc::Error::SharingViolation)
+    }
+
+    fn control<'b>(&mut self, _: u32, _: &[u8], _: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error> {
+        Err(pcsc::Error::SharingViolation)
+    }
 }
 
 impl Debug for CCIDInterfaceHandler {
@@ -47,84 +355,274 @@ impl Debug for CCIDInterfaceHandler {
 // }
 
 impl CCIDInterfaceHandler {
-    pub fn new(
-        reader_name: &CStr,
-        device: &nusb::Device,
+    pub fn with_config(
+        device: &dyn UsbBackend,
+        backend: Box<dyn CCIDBackend>,
+        config: CCIDConfig,
     ) -> Result<CCIDInterfaceHandler, io::Error> {
-        let mut ccid_descriptor = vec![
-            0x36, // bLength
-            0x21, // bDescriptorType ( 21h => CCID )
-            0x10, 0x01, // bcdCCID ( v1.10 )
-            0x00, // bMaxSlotIndex ( 0 since we are redirect single card ),
-            0x07, // bVoltageSupport ( Not apply )
-            0x02, 0x00, 0x00, 0x00, // dwProtocols ( Force T=1 )
-            0x00, 0x00, 0x00, 0x00, // dwDefaultClock ( Not apply )
-            0x00, 0x00, 0x00, 0x00, // dwMaximumClock ( Not apply )
-            0x00, // bNumClockSupported ( Not apply )
-            0x00, 0x00, 0x00, 0x00, // dwDataRate ( 4MHz )
-            0x00, 0x00, 0x00, 0x00, // dwMaxDataRate ( 4MHz )
-            0x00, // bNumDataRatesSupported ( Card managed )
-            0xF6, 0xFF, 0x00, 0x00, // dwMaxIFSD (65526 bytes (Prevent chaining))
-            0x00, 0x00, 0x00, 0x00, // dwSynchProtocols
-            0x00, 0x00, 0x00, 0x00, // dwMechanical
-            0xFE, 0x00, 0x04,
-            0x00, // dwFeatures ( All byte 1 characteristics and Short and Extended APDU level exchange with CCID)
-            0x00, 0x00, 0x01, 0x00, // dwMaxCCIDMessageLength (65536 byte (Prevent chaining))
-            0xFF, // bClassGetResponse (  CCID echoes the class of the APDU )
-            0xFF, // bClassEnvelope (  CCID echoes the class of the APDU )
-            0x00, 0x00, // wLcdLayout ( No LCD display ),
-            0x00, // bPINSupport ( No CCID PIN support )
-            0x01, // bMaxCCIDBusySlots ( 1 since we are redirect single card )
-        ];
-        let desc = device
+        let desc = Self::device_ccid_descriptor(device)?;
+        Self::from_parts(&desc, backend, config)
+    }
+
+    /// Re-read the CCID class descriptor from the active configuration of `device`
+    ///
+    /// The previous descriptor is kept if the device no longer exposes a CCID interface.
+    pub fn refresh(&mut self, device: &dyn UsbBackend) -> io::Result<()> {
+        let desc = Self::device_ccid_descriptor(device)?;
+        self.ccid_descriptor = Self::build_descriptor(&desc, &self.config)?;
+        Ok(())
+    }
+
+    /// The CCID class descriptor of `device`, with the clock and data rate of ISO/IEC 7816-3
+    /// if it does not look like a standard one
+    fn device_ccid_descriptor(device: &dyn UsbBackend) -> io::Result<CcidFunctionalDescriptor> {
+        let configuration = device
             .active_configuration()
-            .map_err(|e| io::Error::other(format!("Failed to get active configuration: {}", e)))?
-            .descriptors()
-            .find(|d| {
-                d.descriptor_type() == 0x21 && d.descriptor_len() == 0x36 // CCID
-            })
+            .map_err(|e| io::Error::other(format!("Failed to get active configuration: {}", e)))?;
+        let configuration = parse_configuration(&configuration)?;
+        let desc = configuration
+            .interface_alt_settings()
+            .filter(|setting| setting.class() == 0x0B)
+            .flat_map(|setting| setting.descriptors())
+            .find(|d| d.descriptor_type() == ccid_descriptor::DESCRIPTOR_TYPE)
             .ok_or(io::Error::new(
                 io::ErrorKind::NotFound,
                 "Specified USB device does not have CCID class descriptor",
             ))?;
-        // dwDefaultClock & dwMaximumClock
-        ccid_descriptor[10..10 + 8].copy_from_slice(&desc[10..10 + 8]);
-        // dwDataRate & dwMaxDataRate
-        ccid_descriptor[19..19 + 8].copy_from_slice(&desc[19..19 + 8]);
-        debug!("CCID descriptors: {:02X?}", ccid_descriptor);
-        let context = pcsc::Context::establish(Scope::User).map_err(|e| {
+        let result = CcidFunctionalDescriptor::decode(&desc).and_then(|decoded| {
+            decoded.validate()?;
+            Ok(decoded)
+        });
+        Ok(result.unwrap_or_else(|e| {
+            warn!("{}, using default clock and data rate instead", e);
+            CcidFunctionalDescriptor {
+                default_clock: DEFAULT_CLOCK,
+                maximum_clock: DEFAULT_CLOCK,
+                data_rate: DEFAULT_DATA_RATE,
+                max_data_rate: DEFAULT_DATA_RATE,
+                ..Default::default()
+            }
+        }))
+    }
+
+    fn build_descriptor(
+        device: &CcidFunctionalDescriptor,
+        config: &CCIDConfig,
+    ) -> io::Result<CcidFunctionalDescriptor> {
+        if let Some(raw) = &config.raw_descriptor {
+            debug!("CCID descriptors (raw): {}", hexdump(raw));
+            return CcidFunctionalDescriptor::decode(raw).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid raw CCID class descriptor {}", hexdump(raw)),
+                )
+            });
+        }
+        let max_message_length = match config.max_apdu_len {
+            Some(max_apdu_len) => config
+                .max_message_length
+                .min(max_apdu_len + MESSAGE_HEADER_LENGTH),
+            None => config.max_message_length,
+        };
+        let ccid_descriptor = CcidFunctionalDescriptor {
+            bcd_ccid: 0x0110,
+            // Single card redirected
+            max_slot_index: 0x00,
+            // 5V, 3V and 1.8V, not applied
+            voltage_support: 0x07,
+            // T=0 and T=1, PC_to_RDR_SetParameters reconnects the card with the other one
+            protocols: 0x03,
+            // Clock and data rate of the device
+            default_clock: device.default_clock,
+            maximum_clock: device.maximum_clock,
+            // Checked to fit in `from_parts`
+            num_clock_supported: config.clock_frequencies.len() as u8,
+            data_rate: device.data_rate,
+            max_data_rate: device.max_data_rate,
+            num_data_rates_supported: config.data_rates.len() as u8,
+            max_ifsd: config.max_ifsd,
+            synch_protocols: 0,
+            mechanical: 0,
+            // All byte 1 characteristics and Short and Extended APDU level exchange
+            features: 0x000400FE,
+            max_ccid_message_length: max_message_length,
+            // CCID echoes the class of the APDU
+            class_get_response: 0xFF,
+            class_envelope: 0xFF,
+            // No LCD, no PIN support
+            lcd_layout: 0x0000,
+            pin_support: 0x00,
+            max_ccid_busy_slots: config.max_busy_slots,
+        };
+        debug!("CCID descriptors: {}", hexdump(&ccid_descriptor.encode()));
+        Ok(ccid_descriptor)
+    }
+
+    fn from_parts(
+        desc: &CcidFunctionalDescriptor,
+        backend: Box<dyn CCIDBackend>,
+        config: CCIDConfig,
+    ) -> Result<CCIDInterfaceHandler, io::Error> {
+        let reader_name = backend.reader_name().to_owned();
+        // Between a short APDU and an extended APDU with header, as per CCID 6.0
+        if !(261 + MESSAGE_HEADER_LENGTH..=65544 + MESSAGE_HEADER_LENGTH)
+            .contains(&config.max_message_length)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid maximum CCID message length {}",
+                    config.max_message_length
+                ),
+            ));
+        }
+        if config.max_ifsd == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid maximum IFSD 0, responses could not be sent",
+            ));
+        }
+        for (what, values) in [
+            ("clock frequencies", &config.clock_frequencies),
+            ("data rates", &config.data_rates),
+        ] {
+            if values.len() > u8::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Too many {} {}, at most 255 can be announced",
+                        what,
+                        values.len()
+                    ),
+                ));
+            }
+        }
+        if let Some(max_apdu_len) = config.max_apdu_len
+            && max_apdu_len < 261
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid maximum APDU length {}, short APDUs take up to 261 bytes",
+                    max_apdu_len
+                ),
+            ));
+        }
+        let ccid_descriptor = Self::build_descriptor(desc, &config)?;
+        let slot_count = ccid_descriptor.max_slot_index + 1;
+        if !(1..=slot_count).contains(&config.max_busy_slots) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid maximum busy slots {}, between 1 and the {} relayed slots are usable",
+                    config.max_busy_slots, slot_count
+                ),
+            ));
+        }
+        // Grown by `transmit` for longer responses
+        let response_buffer =
+            vec![0u8; (config.max_message_length - MESSAGE_HEADER_LENGTH) as usize];
+        Self::check_reader_present(backend.as_ref())?;
+        let shared_fallback = config.shared_fallback;
+        let connect = move |mut backend: Box<dyn CCIDBackend>| {
+            let result = Self::connect(backend.as_mut(), shared_fallback, Protocols::T1);
+            (backend, result)
+        };
+        // A wedged reader would block connecting forever
+        let (mut backend, result) = match config.connect_timeout {
+            Some(timeout) => device::probe(
+                &format!("Connecting reader '{}'", reader_name.to_string_lossy()),
+                timeout,
+                move || Ok(connect(backend)),
+            )?,
+            None => connect(backend),
+        };
+        let share_mode = result.map_err(|e| {
             io::Error::other(format!(
-                "Failed to create PCSC context, status = '0x{:08X}'",
+                "Reader '{}' is present but connecting to it failed, status = '0x{:08X}'",
+                reader_name.to_string_lossy(),
                 e as u32
             ))
         })?;
-        let card = context
-            .connect(reader_name, ShareMode::Exclusive, Protocols::T1)
-            .map_err(|e| {
-                io::Error::other(format!(
-                    "Failed to connect to reader '{}', status = '0x{:08X}'",
-                    reader_name.to_string_lossy(),
-                    e as u32
-                ))
-            })?;
         debug!("Created reader '{}'", reader_name.to_string_lossy());
-        let atr = card
-            .get_attribute_owned(Attribute::AtrString)
-            .map_err(|e| {
-                io::Error::other(format!(
-                    "Failed to get ATR from reader '{}', status = {:08X}",
-                    reader_name.to_string_lossy(),
-                    e as u32
-                ))
-            })?;
-        if atr.len() < 2 {
-            return Err(io::Error::other(format!(
+        let atr = backend.atr().map_err(|e| {
+            io::Error::other(format!(
+                "Failed to get ATR from reader '{}', status = {:08X}",
+                reader_name.to_string_lossy(),
+                e as u32
+            ))
+        })?;
+        let parameter = Self::parse_parameters(&reader_name, &atr);
+        let atr = if atr.len() < 2 {
+            // IccPowerOn from the host reads the ATR again
+            error!(
                 "ATR read from reader '{}' is too short, expects at least 2 bytes, got {} bytes",
                 reader_name.to_string_lossy(),
                 atr.len()
-            )));
+            );
+            None
+        } else {
+            Some(atr)
+        };
+
+        let status = SlotStatus {
+            slot_count: ccid_descriptor.max_slot_index + 1,
+            snapshot: Arc::default(),
+        };
+        let handler = Self {
+            backend,
+            config,
+            ccid_descriptor,
+            response_buffer,
+            chained_response: None,
+            outQueue: VecDeque::new(),
+            parameter,
+            protocol: ICCProtocol::T1,
+            voltage: ICCVoltage::V_5_0,
+            share_mode,
+            atr,
+            abort: None,
+            card_present: true,
+            clock: ICCClockStatus::Running,
+            last_activity: Instant::now(),
+            idle_dropped: false,
+            selected_aid: None,
+            counters: ApduCounters::default(),
+            last_exchange: None,
+            in_flight: None,
+            status,
+            exchange: None,
+            ready: Arc::new(Notify::new()),
+        };
+        handler.publish_status();
+        Ok(handler)
+    }
+
+    /// Connect the card exclusively, or shared if `shared_fallback` and another process holds
+    /// it, returning the share mode connected with
+    fn connect(
+        backend: &mut dyn CCIDBackend,
+        shared_fallback: bool,
+        protocols: Protocols,
+    ) -> Result<ShareMode, pcsc::Error> {
+        match backend.connect(ShareMode::Exclusive, protocols) {
+            Err(pcsc::Error::SharingViolation) if shared_fallback => {
+                warn!(
+                    "Reader '{}' is in use by another process, connecting it shared",
+                    backend.reader_name().to_string_lossy()
+                );
+                backend.connect(ShareMode::Shared, protocols)?;
+                Ok(ShareMode::Shared)
+            }
+            result => result.map(|_| ShareMode::Exclusive),
         }
+    }
 
+    /// CCID T=1 parameters (abProtocolDataStructure) derived from the interface bytes of `atr`
+    fn parse_parameters(reader_name: &CStr, atr: &[u8]) -> Option<T1Parameters> {
+        if atr.len() < 2 {
+            return None;
+        }
         let parameter = (|| {
             let direct_convention = match atr[0] {
                 0x3B => true,
@@ -300,15 +798,15 @@ impl CCIDInterfaceHandler {
             let ta3 = atr[td2_offset + 1];
             let tb3 = atr[td2_offset + 2];
 
-            Some(vec![
-                ta1,
-                tcckst1,
-                extra_guard_time,
-                tb3,
-                0x00, //  Stopping the Clock is not allowed
-                ta3,
-                0x0, // NAD value
-            ])
+            Some(T1Parameters {
+                bmFindex: ta1,
+                bmTCCKST1: tcckst1,
+                bGuardTime: extra_guard_time,
+                bwi_cwi: tb3,
+                clock_stop: 0x00, // Stopping the Clock is not allowed
+                ifsc: ta3,
+                nad: 0x00,
+            })
         })();
 
         if parameter.is_none() {
@@ -316,29 +814,281 @@ impl CCIDInterfaceHandler {
                 "Failed to generate CCID parameters, will fail GetParameter request with unsupported command error"
             );
         }
+        parameter
+    }
 
-        Ok(Self {
-            context,
-            card: Cell::new(Some(card)),
-            ccid_descriptor,
-            outQueue: VecDeque::new(),
-            reader_name: reader_name.to_owned(),
-            parameter,
-        })
+    /// Fail with a clear error if PCSC has no readers at all or not the one of `backend`,
+    /// instead of the status code of the failed connect
+    fn check_reader_present(backend: &dyn CCIDBackend) -> io::Result<()> {
+        let reader_name = backend.reader_name();
+        let readers = match backend.list_readers() {
+            Ok(readers) => readers,
+            Err(pcsc::Error::NoReadersAvailable) => vec![],
+            Err(e) => {
+                // Connecting reports the problem, if any
+                debug!("Failed to list PCSC readers: {}", e);
+                return Ok(());
+            }
+        };
+        if readers.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No PCSC readers found, is pcscd running and the device plugged in?",
+            ));
+        }
+        if !readers
+            .iter()
+            .any(|reader| reader.as_c_str() == reader_name)
+        {
+            let names: Vec<_> = readers
+                .iter()
+                .map(|reader| format!("'{}'", reader.to_string_lossy()))
+                .collect();
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "PCSC reader '{}' not found, available readers: {}",
+                    reader_name.to_string_lossy(),
+                    names.join(", ")
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// CCID T=0 parameters derived from the interface bytes of `atr`, defaults of ISO/IEC 7816-3
+    /// for the bytes it lacks
+    fn parse_t0_parameters(atr: &[u8]) -> T0Parameters {
+        let mut parameters = T0Parameters {
+            bmFindex: 0x11,
+            bmTCCKST0: if atr.first() == Some(&0x3F) {
+                0x02
+            } else {
+                0x00
+            },
+            bGuardTime: 0x00,
+            bWaitingInteger: 0x0A,
+            clock_stop: 0x00,
+        };
+        // Y1 of T0, then the Y of each TD byte
+        let mut indicator = atr.get(1).copied();
+        let mut offset = 2usize;
+        let mut group = 1;
+        while let Some(y) = indicator {
+            indicator = None;
+            for bit in 4..8 {
+                if y & (1 << bit) == 0 {
+                    continue;
+                }
+                let Some(&byte) = atr.get(offset) else {
+                    return parameters;
+                };
+                offset += 1;
+                match (group, bit) {
+                    (1, 4) => parameters.bmFindex = byte,        // TA1
+                    (1, 6) => parameters.bGuardTime = byte,      // TC1
+                    (2, 6) => parameters.bWaitingInteger = byte, // TC2
+                    (_, 7) => indicator = Some(byte),            // TDi
+                    _ => {}
+                }
+            }
+            group += 1;
+        }
+        parameters
+    }
+
+    /// RDR_to_PC_Parameters with the parameter block of the current protocol
+    ///
+    /// The protocol the reader reports active wins over the one assumed here, which is taken
+    /// over for later exchanges.
+    fn parameters_response(&mut self, header: CommonMessageHeader) -> Response {
+        let live = match self.backend.protocol() {
+            Ok(Some(Protocol::T0)) => Some(ICCProtocol::T0),
+            Ok(Some(Protocol::T1)) => Some(ICCProtocol::T1),
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Failed to get protocol of card: {:?}", e);
+                None
+            }
+        };
+        if let Some(live) = live
+            && live != self.protocol
+        {
+            warn!(
+                "Card of reader '{}' runs {:?}, not {:?} as derived from its ATR",
+                self.backend.reader_name().to_string_lossy(),
+                live,
+                self.protocol
+            );
+            self.protocol = live;
+        }
+        let mut block = Vec::new();
+        match (self.protocol, &self.parameter, &self.atr) {
+            (ICCProtocol::T1, Some(parameter), _) => parameter.encode(&mut block).unwrap(),
+            (ICCProtocol::T0, _, Some(atr)) => {
+                Self::parse_t0_parameters(atr).encode(&mut block).unwrap()
+            }
+            _ => {
+                return ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                    header,
+                    SlotStatusRegister::ICCActiveFailure,
+                    SlotErrorRegister::UnsupportedCommand,
+                ));
+            }
+        }
+        let mut resp = ccid_proto::Response::new(header);
+        match &mut resp {
+            Response::RDR_to_PC_Parameters { bProtocolNum, .. } => {
+                *bProtocolNum = self.protocol;
+            }
+            other => panic!("Unexpected response type: {:?}", other),
+        }
+        resp.append(&block).unwrap();
+        resp
+    }
+
+    /// Reset the card with `protocol` as its transmission protocol
+    ///
+    /// PCSC negotiates the parameters of the protocol itself, the parameter block the host sent
+    /// along is not applied.
+    fn switch_protocol(&mut self, header: CommonMessageHeader, protocol: ICCProtocol) -> Response {
+        let protocols = match protocol {
+            ICCProtocol::T0 => Protocols::T0,
+            ICCProtocol::T1 => Protocols::T1,
+        };
+        let result = self
+            .backend
+            .reconnect(self.share_mode, protocols)
+            .and_then(|_| self.backend.atr());
+        match result {
+            Ok(atr) => {
+                debug!("Switched card from {:?} to {:?}", self.protocol, protocol);
+                self.parameter = Self::parse_parameters(self.backend.reader_name(), &atr);
+                self.protocol = protocol;
+                self.clock = ICCClockStatus::Running;
+                self.atr = Some(atr);
+                self.parameters_response(header)
+            }
+            Err(e) => {
+                debug!("Failed to switch card to {:?}: {:?}", protocol, e);
+                let status = if self.backend.is_connected() {
+                    SlotStatusRegister::ICCActiveFailure
+                } else {
+                    self.atr = None;
+                    SlotStatusRegister::ICCInactiveFailure
+                };
+                ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                    header,
+                    status,
+                    SlotErrorRegister::UnsupportedICCProtocol,
+                ))
+            }
+        }
+    }
+
+    /// Reset the card if `apdu` selects an applet other than the selected one, with
+    /// `reset_on_applet_switch`
+    ///
+    /// Without the WebUSB interface nothing else resets the state left by the previous applet.
+    fn check_applet_switch(&mut self, apdu: &[u8]) {
+        if !self.config.reset_on_applet_switch {
+            return;
+        }
+        // SELECT by DF name, the AID follows Lc
+        let [cla, 0xA4, 0x04, _, lc, data @ ..] = apdu else {
+            return;
+        };
+        if cla & 0x80 != 0 {
+            return;
+        }
+        let aid = &data[..(*lc as usize).min(data.len())];
+        if self
+            .selected_aid
+            .as_deref()
+            .is_some_and(|selected| selected != aid)
+        {
+            let protocols = match self.protocol {
+                ICCProtocol::T0 => Protocols::T0,
+                ICCProtocol::T1 => Protocols::T1,
+            };
+            match self.backend.reconnect(self.share_mode, protocols) {
+                Ok(()) => debug!(
+                    "Reset card of reader '{}' switching applet to {}",
+                    self.backend.reader_name().to_string_lossy(),
+                    hexdump(aid)
+                ),
+                Err(e) => error!("Failed to reset card switching applet: {:?}", e),
+            }
+        }
+        self.selected_aid = Some(aid.to_vec());
+    }
+
+    /// Longest data block of a response, as announced by `dwMaxCCIDMessageLength` and
+    /// `dwMaxIFSD`
+    fn max_block_len(&self) -> usize {
+        let max_ifsd = self.ccid_descriptor.max_ifsd;
+        let max_message_length = self.ccid_descriptor.max_ccid_message_length;
+        (max_message_length.saturating_sub(MESSAGE_HEADER_LENGTH) as usize)
+            .min(max_ifsd as usize)
+            .max(1)
+    }
+
+    /// Whether a command to `slot` must wait, a slot being busy while a command to it is in
+    /// flight and until the host read the response to its last command
+    fn slot_busy(&self, slot: u8) -> bool {
+        let mut busy: Vec<u8> = self
+            .outQueue
+            .iter()
+            .map(|response| response[5])
+            .chain(self.in_flight.map(|command| command.slot))
+            // The card is still busy with an abandoned exchange
+            .chain(self.exchange.as_ref().map(|exchange| exchange.header.bSlot))
+            .collect();
+        busy.sort_unstable();
+        busy.dedup();
+        // As announced by bMaxCCIDBusySlots
+        let max_busy_slots = (self.ccid_descriptor.max_ccid_busy_slots as usize).max(1);
+        busy.contains(&slot) || busy.len() >= max_busy_slots
+    }
+
+    /// Put the next block of the response APDU `data` into `resp`, keeping the rest until the
+    /// host asks for it
+    fn chain_response(&mut self, resp: &mut ccid_proto::Response, mut data: Vec<u8>, first: bool) {
+        let max_len = self.max_block_len();
+        let rest = (data.len() > max_len).then(|| data.split_off(max_len));
+        resp.append(&data).unwrap();
+        if let ccid_proto::Response::RDR_to_PC_DataBlock {
+            bChainParameter, ..
+        } = resp
+        {
+            // Begins, begins and continues, ends, continues as per CCID 6.2.1
+            *bChainParameter = match (first, rest.is_some()) {
+                (true, false) => 0x00,
+                (true, true) => 0x01,
+                (false, false) => 0x02,
+                (false, true) => 0x03,
+            };
+        }
+        self.chained_response = rest;
     }
 
     pub fn endpoints() -> Vec<UsbEndpoint> {
+        Self::endpoints_at(0x01)
+    }
+
+    /// Bulk endpoints of a CCID interface using endpoint number `number`
+    pub fn endpoints_at(number: u8) -> Vec<UsbEndpoint> {
         vec![
             // Bulk IN device to host (response)
             UsbEndpoint {
-                address: 0x81,
+                address: 0x80 | number,
                 attributes: EndpointAttributes::Bulk as u8,
                 max_packet_size: 0x200,
                 interval: 0,
             },
             // Bulk OUT host to device (command)
             UsbEndpoint {
-                address: 0x01,
+                address: number,
                 attributes: EndpointAttributes::Bulk as u8,
                 max_packet_size: 0x200,
                 interval: 0,
@@ -347,98 +1097,853 @@ impl CCIDInterfaceHandler {
     }
 }
 
-impl CCIDInterfaceHandler {
-    pub fn drop_card(&mut self) {
-        if self.card.get_mut().is_some() {
-            if let Err(e) = self.card.take().unwrap().disconnect(Disposition::ResetCard) {
-                error!("Failed to disconnect reset card: {:?}", e.1);
+impl Transmitter {
+    /// Transmit `apdu` to the card, growing the response buffer up to the longest response
+    /// allowed and transmitting it again if the response did not fit
+    ///
+    /// The card executes the command twice then, which only happens for responses longer than
+    /// `dwMaxCCIDMessageLength`. Returns the length of the response left in the response buffer.
+    fn transmit(&mut self, apdu: &[u8]) -> Result<usize, pcsc::Error> {
+        match self.protocol {
+            ICCProtocol::T0 => self.transmit_t0(apdu),
+            ICCProtocol::T1 => self.transmit_len(apdu),
+        }
+    }
+
+    /// Time left at `now` until the next APDU exchange may start, keeping the command delay
+    /// after the last one
+    fn command_delay(&self, now: Instant) -> Duration {
+        match self.last_exchange {
+            Some(last) => self
+                .command_delay
+                .saturating_sub(now.saturating_duration_since(last)),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Length of the response to `apdu`, which is left in the response buffer
+    fn transmit_len(&mut self, apdu: &[u8]) -> Result<usize, pcsc::Error> {
+        let delay = self.command_delay(Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+        let result = self.transmit_len_now(apdu);
+        self.last_exchange = Some(Instant::now());
+        result
+    }
+
+    fn transmit_len_now(&mut self, apdu: &[u8]) -> Result<usize, pcsc::Error> {
+        let max_len = self
+            .max_apdu_len
+            .map_or(MAX_RESPONSE_APDU_LENGTH, |max| max as usize);
+        // Lengths rather than slices, the buffer is resized in between
+        let result = self
+            .backend
+            .transmit(apdu, &mut self.response_buffer)
+            .map(<[u8]>::len);
+        let len = match result {
+            Err(pcsc::Error::InsufficientBuffer) if self.response_buffer.len() < max_len => {
+                debug!(
+                    "Response APDU exceeds {} bytes, transmitting again",
+                    self.response_buffer.len()
+                );
+                self.response_buffer.resize(max_len, 0);
+                self.backend
+                    .transmit(apdu, &mut self.response_buffer)?
+                    .len()
             }
-            debug!("PC_to_RDR_IccPowerOff: Disconnected reset card");
+            result => result?,
+        };
+        Ok(len)
+    }
+
+    /// [`Self::transmit_len`] for T=0 cards, answering their procedure bytes the way an APDU
+    /// level reader does: 61xx is followed by GET RESPONSE until the whole response was read and
+    /// 6Cxx by the command again with Le set to xx
+    ///
+    /// Fails with `CommError` if the card answers GET RESPONSE with 61xx alone, it would never
+    /// end.
+    fn transmit_t0(&mut self, apdu: &[u8]) -> Result<usize, pcsc::Error> {
+        let max_len = self
+            .max_apdu_len
+            .map_or(MAX_RESPONSE_APDU_LENGTH, |max| max as usize);
+        let mut len = self.transmit_len(apdu)?;
+        if let [.., 0x6C, le] = self.response_buffer[..len]
+            && let Some(retry) = Self::with_le(apdu, le)
+        {
+            debug!("T=0 card expects Le {}, transmitting again", le);
+            len = self.transmit_len(&retry)?;
+        }
+        let mut data = Vec::new();
+        let mut get_response = false;
+        while let [.., 0x61, available] = self.response_buffer[..len] {
+            if get_response && len == 2 {
+                debug!("T=0 card answered GET RESPONSE without data");
+                return Err(pcsc::Error::CommError);
+            }
+            get_response = true;
+            data.extend_from_slice(&self.response_buffer[..len - 2]);
+            if data.len() + 2 > max_len {
+                return Err(pcsc::Error::InsufficientBuffer);
+            }
+            debug!(
+                "T=0 card has {} more bytes, sending GET RESPONSE",
+                available
+            );
+            len = self.transmit_len(&[apdu[0], 0xC0, 0x00, 0x00, available])?;
+        }
+        if data.is_empty() {
+            return Ok(len);
+        }
+        data.extend_from_slice(&self.response_buffer[..len]);
+        if data.len() > max_len {
+            return Err(pcsc::Error::InsufficientBuffer);
         }
+        if data.len() > self.response_buffer.len() {
+            self.response_buffer.resize(data.len(), 0);
+        }
+        self.response_buffer[..data.len()].copy_from_slice(&data);
+        Ok(data.len())
+    }
+
+    /// Length of the response to the escape `data` sent with `control_code`, at most `max_len`
+    /// bytes left in the response buffer
+    fn control(
+        &mut self,
+        control_code: u32,
+        data: &[u8],
+        max_len: usize,
+    ) -> Result<usize, pcsc::Error> {
+        let max_len = max_len.min(self.response_buffer.len());
+        self.backend
+            .control(control_code, data, &mut self.response_buffer[..max_len])
+            .map(<[u8]>::len)
+    }
+
+    /// Short `apdu` with its Le set to `le`, `None` for extended APDUs and those without Le
+    fn with_le(apdu: &[u8], le: u8) -> Option<Vec<u8>> {
+        let mut apdu = apdu.to_vec();
+        match apdu.len() {
+            // Case 2, P3 is Le
+            5 => apdu[4] = le,
+            // Case 4 with Le after Lc bytes of data
+            len if len > 5 && apdu[4] != 0 && len == 6 + apdu[4] as usize => apdu[len - 1] = le,
+            _ => return None,
+        }
+        Some(apdu)
     }
 }
 
-impl UsbInterfaceHandler for CCIDInterfaceHandler {
-    fn get_class_specific_descriptor(&self) -> Vec<u8> {
-        self.ccid_descriptor.clone()
+impl CCIDInterfaceHandler {
+    fn slot_status(&self, success: bool) -> SlotStatusRegister {
+        if !self.card_present {
+            return match success {
+                true => SlotStatusRegister::ICCAbsentSuccess,
+                false => SlotStatusRegister::ICCAbsentFailure,
+            };
+        }
+        match (self.backend.is_connected(), success) {
+            (true, true) => SlotStatusRegister::ICCActiveSuccess,
+            (true, false) => SlotStatusRegister::ICCActiveFailure,
+            (false, true) => SlotStatusRegister::ICCInactiveSuccess,
+            (false, false) => SlotStatusRegister::ICCInactiveFailure,
+        }
     }
 
-    fn handle_urb(
-        &mut self,
-        _interface: &UsbInterface,
-        ep: UsbEndpoint,
-        _transfer_buffer_length: u32,
-        setup: SetupPacket,
-        req: &[u8],
-    ) -> io::Result<Vec<u8>> {
-        if ep.is_ep0() {
-            match setup.request {
-                // Abort
-                0x01 => {
-                    debug!("CCID Setup ABORT request: {:?}", setup);
-                }
-                // GET_CLOCK_FREQUENCIES
-                0x02 => {
-                    debug!("CCID Setup GET_CLOCK_FREQUENCIES request: {:?}", setup);
-                    //  Unsupported
-                }
-                // GET_DATA_RATES
-                0x03 => {
-                    debug!("CCID Setup GET_DATA_RATES request: {:?}", setup);
-                    // Unsupported
-                }
-                _ => {
-                    debug!("Unknown SETUP request: {:?}", setup);
-                }
+    fn unsupported(&self, header: CommonMessageHeader) -> ccid_proto::Response {
+        match self.config.unsupported_response {
+            UnsupportedResponse::Dedicated => {
+                ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                    header,
+                    SlotStatusRegister::ICCActiveFailure,
+                    SlotErrorRegister::UnsupportedCommand,
+                ))
             }
-            if setup.request != 0x01 {
+            UnsupportedResponse::SlotStatus(error) => {
+                ccid_proto::Response::new_slot_status(header, self.slot_status(false), error)
+            }
+        }
+    }
+
+    fn abort_result(&self, header: CommonMessageHeader, completed: bool) -> ccid_proto::Response {
+        if completed {
+            let mut resp = ccid_proto::Response::new(header);
+            resp.set_status(
+                self.slot_status(true),
+                SlotErrorRegister::UnsupportedCommand,
+            );
+            resp
+        } else {
+            // bSeq does not match the other half of the abort
+            ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                header,
+                self.slot_status(false),
+                SlotErrorRegister::InvalidParameter(0x06),
+            ))
+        }
+    }
+
+    /// Handle the ABORT request for `slot` and `seq` from `wValue`, completing the abort with a
+    /// `PC_to_RDR_Abort` already received or arming it for the next one
+    ///
+    /// Fails, stalling the request, for unknown slots and if it does not match the
+    /// `PC_to_RDR_Abort` waiting for it.
+    fn control_abort(&mut self, slot: u8, seq: u8) -> io::Result<()> {
+        if slot >= self.slot_count() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("ABORT request for non-exists CCID slot {}", slot),
+            ));
+        }
+        // Its response is queued ahead of the one of PC_to_RDR_Abort
+        self.abort_exchange(slot);
+        if let Some(AbortState::Bulk(header)) = self.abort {
+            // The PC_to_RDR_Abort waiting keeps waiting for its own ABORT request
+            if header.bSlot != slot || header.bSeq != seq {
                 return Err(io::Error::new(
                     io::ErrorKind::InvalidInput,
-                    "Invalid setup request",
+                    format!(
+                        "ABORT request for slot {} seq {} does not match the outstanding PC_to_RDR_Abort {:02X?}",
+                        slot, seq, header
+                    ),
                 ));
             }
-            Ok(vec![])
-        } else {
-            match ep.address | (setup.request_type & 0x80) {
-                0x81 => {
-                    debug!("CCID Bulk IN request: {:?}", setup);
-                    match self.outQueue.pop_front() {
-                        None => Ok(vec![]),
-                        Some(v) => Ok(v),
-                    }
+            debug!(
+                "ABORT request for slot {} seq {} paired with {:02X?}",
+                slot, seq, header
+            );
+            self.abort = None;
+            let mut data = io::Cursor::new(Vec::new());
+            self.abort_result(header, true).encode(&mut data).unwrap();
+            self.outQueue.push_back(data.into_inner());
+            return Ok(());
+        }
+        self.abort = Some(AbortState::Control { slot, seq });
+        Ok(())
+    }
+
+    fn bulk_abort(&mut self, header: CommonMessageHeader) -> Option<ccid_proto::Response> {
+        if header.bSlot >= self.slot_count() {
+            return Some(ccid_proto::Response::new_with_error(
+                ResponseMessageHeader::new(
+                    header,
+                    SlotStatusRegister::ICCAbsentFailure,
+                    SlotErrorRegister::InvalidParameter(0x05),
+                ),
+            ));
+        }
+        match self.abort {
+            Some(AbortState::Control { slot, seq }) if slot == header.bSlot => {
+                let completed = seq == header.bSeq;
+                debug!(
+                    "PC_to_RDR_Abort {:02X?} paired with ABORT request for seq {}, completed: {}",
+                    header, seq, completed
+                );
+                if completed {
+                    self.abort = None;
                 }
-                0x01 => {
-                    if req.len() < 10 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            format!(
-                                "Invalid transfer buffer length {}, CCID message must be at least 10 bytes long",
-                                req.len()
-                            ),
-                        ));
-                    }
-                    let mut data = io::Cursor::new(req);
-                    let cmd = match ccid_proto::Command::decode(&mut data) {
-                        Ok(cmd) => cmd,
-                        Err(CCIDError::BadCommand) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidData,
-                                "Command bytes is not valid: CCIDError::BadCommand",
-                            ));
-                        }
-                        Err(CCIDError::CommandError(header)) => {
-                            error!("Failed to decode command: {:?}", header);
-                            let mut data = io::Cursor::new(Vec::new());
-                            ccid_proto::Response::new_with_error(header)
-                                .encode(&mut data)
-                                .unwrap();
+                Some(self.abort_result(header, completed))
+            }
+            _ => {
+                debug!(
+                    "PC_to_RDR_Abort {:02X?} waits for ABORT request on control pipe",
+                    header
+                );
+                self.abort = Some(AbortState::Bulk(header));
+                None
+            }
+        }
+    }
+
+    /// Forget the queued responses, chained response, abort and command in flight a client left
+    /// behind
+    ///
+    /// The card is left as it is, powered or not.
+    pub fn reset(&mut self) {
+        if let Some(slot) = self.exchange.as_ref().map(|e| e.header.bSlot) {
+            self.abort_exchange(slot);
+        }
+        self.outQueue.clear();
+        self.chained_response = None;
+        self.abort = None;
+        self.set_in_flight(None);
+    }
+
+    /// Power the card off, once the card is handed back if an exchange is running
+    pub fn drop_card(&mut self) {
+        if !self.collect_exchange() {
+            if let Some(slot) = self.exchange.as_ref().map(|e| e.header.bSlot) {
+                self.abort_exchange(slot);
+            }
+            if let Some(exchange) = self.exchange.as_mut() {
+                exchange.after = AfterExchange::PowerOff;
+            }
+            self.atr = None;
+            self.selected_aid = None;
+            self.publish_status();
+            return;
+        }
+        if self.backend.is_connected() {
+            if let Err(e) = self.backend.disconnect(Disposition::ResetCard) {
+                error!("Failed to disconnect reset card: {:?}", e);
+            }
+            debug!("PC_to_RDR_IccPowerOff: Disconnected reset card");
+        }
+        self.atr = None;
+        self.selected_aid = None;
+        self.publish_status();
+    }
+
+    /// Power the card down if no command arrived within the idle timeout before `now`
+    ///
+    /// The host is not told, the card is powered on again by its next command. Returns whether
+    /// the card was powered down.
+    pub fn check_idle(&mut self, now: Instant) -> bool {
+        let Some(timeout) = self.config.idle_timeout else {
+            return false;
+        };
+        if !self.collect_exchange()
+            || !self.backend.is_connected()
+            || now.duration_since(self.last_activity) < timeout
+        {
+            return false;
+        }
+        debug!(
+            "No CCID command for {:?}, powering down card of reader '{}'",
+            timeout,
+            self.backend.reader_name().to_string_lossy()
+        );
+        self.drop_card();
+        self.idle_dropped = true;
+        true
+    }
+
+    /// Power the card on again after `check_idle`, before the next command is handled
+    fn resume_from_idle(&mut self) {
+        let result = Self::connect(
+            self.backend.as_mut(),
+            self.config.shared_fallback,
+            Protocols::T1,
+        )
+        .and_then(|share_mode| {
+            self.share_mode = share_mode;
+            self.backend.atr()
+        });
+        match result {
+            Ok(atr) => {
+                debug!("Powered on card again after idle timeout");
+                self.parameter = Self::parse_parameters(self.backend.reader_name(), &atr);
+                self.protocol = ICCProtocol::T1;
+                self.atr = Some(atr);
+            }
+            Err(e) => error!("Failed to power on card after idle timeout: {:?}", e),
+        }
+    }
+
+    /// Clock status after a stop request, as allowed by bClockStop of the parameter block
+    fn stopped_clock(&self) -> Option<ICCClockStatus> {
+        match self.parameter.as_ref()?.clock_stop {
+            0x01 | 0x03 => Some(ICCClockStatus::StoppedInL),
+            0x02 => Some(ICCClockStatus::StoppedInH),
+            _ => None,
+        }
+    }
+
+    /// bClockStatus of the slot, a powered off card gets no clock at all
+    fn clock_status(&self) -> ICCClockStatus {
+        if self.backend.is_connected() {
+            self.clock
+        } else {
+            ICCClockStatus::StoppedUnknown
+        }
+    }
+
+    /// Forget the card after PCSC reported it was pulled out of the reader
+    fn card_removed(&mut self) {
+        debug!("Card removed from reader");
+        if let Err(e) = self.backend.disconnect(Disposition::LeaveCard) {
+            debug!("Failed to disconnect removed card: {:?}", e);
+        }
+        self.atr = None;
+        self.card_present = false;
+    }
+}
+
+impl CCIDInterfaceHandler {
+    /// Number of slots announced in the CCID class descriptor
+    fn slot_count(&self) -> u8 {
+        self.ccid_descriptor.max_slot_index + 1
+    }
+
+    /// Shared handle to the state of the slot, see [`SlotStatus`]
+    pub fn status(&self) -> SlotStatus {
+        self.status.clone()
+    }
+
+    /// Take `in_flight` as the command being processed and publish the state of the slot
+    fn set_in_flight(&mut self, in_flight: Option<CommandInFlight>) {
+        self.in_flight = in_flight;
+        self.publish_status();
+    }
+
+    /// Lend the card to an [`Exchange`], leaving a [`LentBackend`] in its place
+    fn lend_card(&mut self) -> Transmitter {
+        let lent = LentBackend {
+            reader_name: self.backend.reader_name().to_owned(),
+        };
+        Transmitter {
+            backend: std::mem::replace(&mut self.backend, Box::new(lent)),
+            response_buffer: std::mem::take(&mut self.response_buffer),
+            protocol: self.protocol,
+            max_apdu_len: self.config.max_apdu_len,
+            command_delay: self.config.transfer.command_delay,
+            last_exchange: self.last_exchange,
+        }
+    }
+
+    fn return_card(&mut self, transmitter: Transmitter) {
+        self.backend = transmitter.backend;
+        self.response_buffer = transmitter.response_buffer;
+        self.last_exchange = transmitter.last_exchange;
+    }
+
+    /// Exchange `apdu` of the PC_to_RDR_XfrBlock with `header`, or the data of the
+    /// PC_to_RDR_Escape, on a worker thread
+    fn start_exchange(&mut self, header: CommonMessageHeader, apdu: Vec<u8>) {
+        let canceller = self.backend.canceller();
+        let escape = (header.bMessageType == ccid_const::PC_to_RDR_Escape)
+            .then(|| (self.config.escape_control_code, self.max_block_len()));
+        let mut transmitter = self.lend_card();
+        let (done, wait) = mpsc::channel();
+        let command = apdu.clone();
+        let timeout = self.config.transfer.transmit_timeout;
+        let ready = self.ready.clone();
+        // Wakes the connection to answer the command once it timed out, unless dropped before
+        let watchdog = timeout.map(|timeout| {
+            let (finished, wait) = mpsc::channel::<()>();
+            let ready = ready.clone();
+            std::thread::spawn(move || {
+                if wait.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout) {
+                    ready.notify_one();
+                }
+            });
+            finished
+        });
+        std::thread::spawn(move || {
+            // The card is handed back even if the backend panicked
+            let result = panic::catch_unwind(AssertUnwindSafe(|| match escape {
+                Some((control_code, max_len)) => {
+                    transmitter.control(control_code, &command, max_len)
+                }
+                None => transmitter.transmit(&command),
+            }))
+            .unwrap_or(Err(pcsc::Error::InternalError));
+            drop(watchdog);
+            let _ = done.send((transmitter, result));
+            ready.notify_one();
+        });
+        self.exchange = Some(Exchange {
+            header,
+            apdu,
+            canceller,
+            done: wait,
+            after: AfterExchange::Answer,
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        });
+    }
+
+    /// Queue the response of the running exchange if its worker is done, without waiting for it
+    ///
+    /// The card is handed back, also by abandoned exchanges. Returns whether no exchange is
+    /// running anymore.
+    fn collect_exchange(&mut self) -> bool {
+        let Some(exchange) = &self.exchange else {
+            return true;
+        };
+        let (transmitter, result) = match exchange.done.try_recv() {
+            Ok(done) => done,
+            Err(TryRecvError::Empty) => {
+                let slot = exchange.header.bSlot;
+                if exchange
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+                    && exchange.after == AfterExchange::Answer
+                {
+                    debug!(
+                        "No answer from card within {:?}, abandoning exchange",
+                        self.config.transfer.transmit_timeout.unwrap_or_default()
+                    );
+                    self.abort_exchange(slot);
+                }
+                return false;
+            }
+            Err(TryRecvError::Disconnected) => {
+                unreachable!("Exchange worker hands back the card")
+            }
+        };
+        let exchange = self.exchange.take().unwrap();
+        self.return_card(transmitter);
+        match exchange.after {
+            AfterExchange::Answer => {
+                let mut data = io::Cursor::new(Vec::new());
+                self.exchange_answer(exchange.header, &exchange.apdu, result)
+                    .encode(&mut data)
+                    .unwrap();
+                let data = data.into_inner();
+                debug!("CCID response bytes: {}", hexdump(&data));
+                self.outQueue.push_back(data);
+                self.set_in_flight(None);
+            }
+            AfterExchange::Discard => {
+                debug!(
+                    "Discarding result {:?} of abandoned APDU exchange of {:02X?}",
+                    result.map(|len| hexdump(&self.response_buffer[..len])),
+                    exchange.header
+                );
+                self.publish_status();
+            }
+            AfterExchange::PowerOff => {
+                debug!(
+                    "Abandoned APDU exchange of {:02X?} ended, powering off card",
+                    exchange.header
+                );
+                self.drop_card();
+            }
+        }
+        true
+    }
+
+    /// Abandon the exchange running for `slot`, if any, answering its command with
+    /// `CMD_ABORTED` right away
+    ///
+    /// The exchange is cancelled if the backend can, otherwise the card finishes it in the
+    /// background and its result is thrown away.
+    fn abort_exchange(&mut self, slot: u8) {
+        let Some(exchange) = self
+            .exchange
+            .as_mut()
+            .filter(|e| e.header.bSlot == slot && e.after == AfterExchange::Answer)
+        else {
+            return;
+        };
+        debug!("Abandoning APDU exchange of {:02X?}", exchange.header);
+        if let Some(cancel) = &exchange.canceller
+            && let Err(e) = cancel()
+        {
+            error!("Failed to cancel reader call: {}", e);
+        }
+        exchange.after = AfterExchange::Discard;
+        let header = exchange.header;
+        let apdu = std::mem::take(&mut exchange.apdu);
+        let mut data = io::Cursor::new(Vec::new());
+        self.exchange_answer(header, &apdu, Err(pcsc::Error::Cancelled))
+            .encode(&mut data)
+            .unwrap();
+        self.outQueue.push_back(data.into_inner());
+        self.set_in_flight(None);
+    }
+
+    /// Response to the command of an exchange with `header` and `data`, which ended with `result`
+    fn exchange_answer(
+        &mut self,
+        header: CommonMessageHeader,
+        data: &[u8],
+        result: Result<usize, pcsc::Error>,
+    ) -> Response {
+        match header.bMessageType {
+            ccid_const::PC_to_RDR_Escape => self.escape_response(header, result),
+            _ => self.exchange_response(header, data, result),
+        }
+    }
+
+    /// RDR_to_PC_Escape answering the PC_to_RDR_Escape with `header`, after the escape ended with
+    /// `result`
+    fn escape_response(
+        &self,
+        header: CommonMessageHeader,
+        result: Result<usize, pcsc::Error>,
+    ) -> Response {
+        let mut resp = ccid_proto::Response::new(header);
+        match result {
+            Ok(len) => {
+                resp.append(&self.response_buffer[..len]).unwrap();
+            }
+            Err(e) => {
+                debug!(
+                    "SCardControl with control code 0x{:08X} failed: {}",
+                    self.config.escape_control_code, e
+                );
+                resp.set_status(
+                    SlotStatusRegister::ICCActiveFailure,
+                    match e {
+                        pcsc::Error::Cancelled => SlotErrorRegister::CommandAbort,
+                        pcsc::Error::UnsupportedFeature | pcsc::Error::InvalidParameter => {
+                            SlotErrorRegister::UnsupportedCommand
+                        }
+                        _ => SlotErrorRegister::HardwareError,
+                    },
+                );
+            }
+        }
+        resp
+    }
+
+    /// RDR_to_PC_DataBlock answering the PC_to_RDR_XfrBlock with `header`, after the exchange
+    /// of `apdu` ended with `result`
+    fn exchange_response(
+        &mut self,
+        header: CommonMessageHeader,
+        apdu: &[u8],
+        result: Result<usize, pcsc::Error>,
+    ) -> Response {
+        self.counters.apdus += 1;
+        self.counters.bytes_out += apdu.len() as u64;
+        match result {
+            Ok(len) => self.counters.bytes_in += len as u64,
+            Err(_) => self.counters.errors += 1,
+        }
+        let mut resp = ccid_proto::Response::new(header);
+        match result.map(|len| &self.response_buffer[..len]) {
+            Ok(response)
+                if self
+                    .config
+                    .max_apdu_len
+                    .is_some_and(|max| response.len() > max as usize) =>
+            {
+                debug!(
+                    "Response APDU of {} bytes exceeds maximum APDU length",
+                    response.len()
+                );
+                resp.set_status(self.slot_status(false), SlotErrorRegister::TransferOverrun);
+            }
+            Ok(response) => {
+                debug!(
+                    "APDU: {} -> {}",
+                    hexdump(apdu),
+                    status_word::annotate(response)
+                );
+                let response = response.to_vec();
+                self.chain_response(&mut resp, response, true);
+            }
+            Err(pcsc::Error::InsufficientBuffer) => {
+                debug!("Response APDU exceeds {} bytes", self.response_buffer.len());
+                resp.set_status(self.slot_status(false), SlotErrorRegister::TransferOverrun);
+            }
+            Err(pcsc::Error::Cancelled) => {
+                debug!("SCardTransmit cancelled");
+                resp.set_status(self.slot_status(false), SlotErrorRegister::CommandAbort);
+            }
+            Err(e @ (pcsc::Error::RemovedCard | pcsc::Error::NoSmartcard)) => {
+                debug!("SCardTransmit failed: {}", e);
+                self.card_removed();
+                resp.set_status(
+                    SlotStatusRegister::ICCAbsentFailure,
+                    SlotErrorRegister::ICCMute,
+                );
+            }
+            Err(e) => {
+                debug!("SCardTransmit failed: {}", e);
+                if let ccid_proto::Response::RDR_to_PC_DataBlock {
+                    header,
+                    bChainParameter: _,
+                    abData: _,
+                } = &mut resp
+                {
+                    header.bError = SlotErrorRegister::CommandSlotBusy;
+                    header.bStatus = SlotStatusRegister::ICCActiveFailure;
+                }
+            }
+        }
+        resp
+    }
+
+    fn publish_status(&self) {
+        let powered = self.backend.is_connected();
+        *self.status.snapshot.lock().unwrap() = SlotSnapshot {
+            powered,
+            card_present: self.card_present,
+            atr: self.atr.clone().filter(|_| powered),
+            voltage: powered.then_some(self.voltage),
+            counters: self.counters,
+            in_flight: self.in_flight,
+        };
+    }
+}
+
+impl UsbInterfaceHandler for CCIDInterfaceHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.ccid_descriptor.encode()
+    }
+
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        trace::urb("ccid", ep.address, transfer_buffer_length, &setup, req);
+        if ep.is_ep0() {
+            match setup.request {
+                // GET_STATUS(Interface), no status bits are defined for interfaces
+                request if setup.request_type == 0x81 && request == GetStatus as u8 => {
+                    Ok(vec![0x00, 0x00])
+                }
+                // Class requests to the interface, standard requests like CLEAR_FEATURE share
+                // their numbers
+                ccid_const::ABORT if setup.request_type == 0x21 => {
+                    debug!("CCID Setup ABORT request: {:?}", setup);
+                    self.control_abort((setup.value & 0xFF) as u8, (setup.value >> 8) as u8)?;
+                    Ok(vec![])
+                }
+                // The arrays bNumClockSupported and bNumDataRatesSupported count. Hosts should
+                // not issue them if those are 0.
+                request @ (ccid_const::GET_CLOCK_FREQUENCIES | ccid_const::GET_DATA_RATES)
+                    if setup.request_type == 0xA1 =>
+                {
+                    debug!(
+                        "CCID Setup GET_CLOCK_FREQUENCIES/GET_DATA_RATES request: {:?}",
+                        setup
+                    );
+                    let values = match request {
+                        ccid_const::GET_CLOCK_FREQUENCIES => &self.config.clock_frequencies,
+                        _ => &self.config.data_rates,
+                    };
+                    if values.is_empty() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            format!("Unsupported CCID setup request 0x{:02X}", request),
+                        ));
+                    }
+                    let mut data: Vec<u8> = values
+                        .iter()
+                        .flat_map(|value| value.to_le_bytes())
+                        .collect();
+                    data.truncate(transfer_buffer_length.min(setup.length as u32) as usize);
+                    Ok(data)
+                }
+                _ => {
+                    debug!("Unknown SETUP request: {:?}", setup);
+                    Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Invalid setup request 0x{:02X} of type 0x{:02X}",
+                            setup.request, setup.request_type
+                        ),
+                    ))
+                }
+            }
+        } else {
+            // Endpoint numbers depend on the position of the interface, see `endpoints_at`
+            let bulk = ep.attributes == EndpointAttributes::Bulk as u8;
+            match ep.address | (setup.request_type & 0x80) {
+                address if bulk && address & 0x80 != 0 => {
+                    debug!("CCID Bulk IN request: {:?}", setup);
+                    self.collect_exchange();
+                    // NAK until a command was answered, an empty reply would end the transfer
+                    self.outQueue.pop_front().ok_or(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "No CCID response pending",
+                    ))
+                }
+                _ if bulk => {
+                    if req.len() < 10 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "Invalid transfer buffer length {}, CCID message must be at least 10 bytes long",
+                                req.len()
+                            ),
+                        ));
+                    }
+                    let mut data = io::Cursor::new(req);
+                    let cmd = match ccid_proto::Command::decode(&mut data) {
+                        Ok(cmd) => cmd,
+                        Err(CCIDError::BadCommand { field, offset }) => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!(
+                                    "Command bytes is not valid: truncated before {} at offset {}",
+                                    field, offset
+                                ),
+                            ));
+                        }
+                        Err(CCIDError::CommandError(header)) => {
+                            error!("Failed to decode command: {:?}", header);
+                            let response = match header.bError {
+                                SlotErrorRegister::UnsupportedCommand => self.unsupported(*header),
+                                _ => ccid_proto::Response::new_with_error(header),
+                            };
+                            let mut data = io::Cursor::new(Vec::new());
+                            response.encode(&mut data).unwrap();
                             self.outQueue.push_back(data.into_inner());
                             return Ok(vec![]);
                         }
                     };
                     error!("CCID command: {:02X?}", cmd);
-                    let response;
-                    if self.card.get_mut().is_none()
+                    self.last_activity = Instant::now();
+                    let header = cmd.get_header();
+                    if let ccid_proto::Command::PC_to_RDR_Abort { header, .. } = cmd {
+                        self.abort_exchange(header.bSlot);
+                    } else {
+                        self.collect_exchange();
+                    }
+                    // Before the command itself is taken in flight
+                    let busy = self.slot_busy(header.bSlot);
+                    if !busy {
+                        self.set_in_flight(Some(CommandInFlight {
+                            slot: header.bSlot,
+                            seq: header.bSeq,
+                            message_type: header.bMessageType,
+                            started_at: self.last_activity,
+                        }));
+                    }
+                    if std::mem::take(&mut self.idle_dropped)
+                        && cmd.get_header().bMessageType != ccid_const::PC_to_RDR_IccPowerOff
+                    {
+                        self.resume_from_idle();
+                    }
+                    let mut response;
+                    if let ccid_proto::Command::PC_to_RDR_Abort { header, .. } = cmd {
+                        match self.bulk_abort(header) {
+                            Some(resp) => response = resp,
+                            None => {
+                                // Waits for its ABORT request, no longer processed
+                                if !busy {
+                                    self.set_in_flight(None);
+                                }
+                                return Ok(vec![]);
+                            }
+                        }
+                    } else if let Some(AbortState::Control { slot, seq }) = self.abort
+                        && slot == cmd.get_header().bSlot
+                    {
+                        debug!(
+                            "Fail command {:02X?} while aborting slot {} seq {}",
+                            cmd.get_header(),
+                            slot,
+                            seq
+                        );
+                        response =
+                            ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                                *cmd.get_header(),
+                                self.slot_status(false),
+                                SlotErrorRegister::CommandAbort,
+                            ));
+                    } else if busy {
+                        debug!(
+                            "Fail command {:02X?}, slot {} is busy",
+                            cmd.get_header(),
+                            cmd.get_header().bSlot
+                        );
+                        response =
+                            ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                                *cmd.get_header(),
+                                self.slot_status(false),
+                                SlotErrorRegister::CommandSlotBusy,
+                            ));
+                    } else if !self.backend.is_connected()
                         && cmd.get_header().bMessageType != ccid_const::PC_to_RDR_IccPowerOn
                         && cmd.get_header().bMessageType != ccid_const::PC_to_RDR_IccPowerOff
                         && cmd.get_header().bMessageType != ccid_const::PC_to_RDR_GetSlotStatus
@@ -462,16 +1967,46 @@ impl UsbInterfaceHandler for CCIDInterfaceHandler {
                                 SlotStatusRegister::ICCAbsentFailure,
                                 SlotErrorRegister::InvalidParameter(0x05),
                             ));
+                    } else if self.config.read_only
+                        && matches!(
+                            cmd,
+                            ccid_proto::Command::PC_to_RDR_XfrBlock { .. }
+                                | ccid_proto::Command::PC_to_RDR_Secure { .. }
+                        )
+                    {
+                        info!(
+                            "Blocked command {:02X?} to read-only reader '{}'",
+                            cmd.get_header(),
+                            self.backend.reader_name().to_string_lossy()
+                        );
+                        response =
+                            ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                                *cmd.get_header(),
+                                self.slot_status(false),
+                                SlotErrorRegister::CommandAbort,
+                            ));
+                    } else if self.config.simulate_mute
+                        && matches!(cmd, ccid_proto::Command::PC_to_RDR_XfrBlock { .. })
+                    {
+                        debug!("Simulating mute card for {:02X?}", cmd.get_header());
+                        response =
+                            ccid_proto::Response::new_with_error(ResponseMessageHeader::new(
+                                *cmd.get_header(),
+                                self.slot_status(false),
+                                SlotErrorRegister::ICCMute,
+                            ));
                     } else {
+                        // A chained response is only continued by the very next command
+                        let chained_response = self.chained_response.take();
                         match cmd {
-                            ccid_proto::Command::PC_to_RDR_Abort { header, .. } => {
-                                response = ccid_proto::Response::new(header);
+                            ccid_proto::Command::PC_to_RDR_Abort { .. } => {
+                                unreachable!("PC_to_RDR_Abort is handled before slot checks")
                             }
                             ccid_proto::Command::PC_to_RDR_GetSlotStatus { header, .. } => {
                                 let mut resp = ccid_proto::Response::new(header);
-                                if self.card.get_mut().is_none() {
+                                if !self.backend.is_connected() {
                                     resp.set_status(
-                                        SlotStatusRegister::ICCInactiveSuccess,
+                                        self.slot_status(true),
                                         SlotErrorRegister::UnsupportedCommand,
                                     );
                                 }
@@ -479,28 +2014,28 @@ impl UsbInterfaceHandler for CCIDInterfaceHandler {
                             }
                             ccid_proto::Command::PC_to_RDR_IccPowerOff { header, .. } => {
                                 let mut resp = ccid_proto::Response::new(header);
-                                if let ccid_proto::Response::RDR_to_PC_SlotStatus {
-                                    header,
-                                    bClockStatus,
-                                } = &mut resp
-                                {
-                                    header.bStatus = SlotStatusRegister::ICCInactiveSuccess;
-                                    header.bError = SlotErrorRegister::UnsupportedCommand;
-                                    *bClockStatus = ICCClockStatus::Running;
-                                }
+                                resp.set_status(
+                                    SlotStatusRegister::ICCInactiveSuccess,
+                                    SlotErrorRegister::UnsupportedCommand,
+                                );
+                                self.clock = ICCClockStatus::Running;
                                 self.drop_card();
                                 response = resp;
                             }
-                            ccid_proto::Command::PC_to_RDR_IccPowerOn { header, .. } => {
+                            ccid_proto::Command::PC_to_RDR_IccPowerOn {
+                                header,
+                                bPowerSelect,
+                                ..
+                            } => {
                                 let mut resp = ccid_proto::Response::new(header);
                                 (|| {
-                                    if self.card.get_mut().is_none() {
-                                        let card = match self.context.connect(
-                                            &self.reader_name,
-                                            ShareMode::Exclusive,
+                                    if !self.backend.is_connected() {
+                                        match Self::connect(
+                                            self.backend.as_mut(),
+                                            self.config.shared_fallback,
                                             Protocols::T1,
                                         ) {
-                                            Ok(card) => card,
+                                            Ok(share_mode) => self.share_mode = share_mode,
                                             Err(e) => {
                                                 debug!("Failed to connect card: {:?}", e);
                                                 resp.set_status(
@@ -509,113 +2044,164 @@ impl UsbInterfaceHandler for CCIDInterfaceHandler {
                                                 );
                                                 return;
                                             }
-                                        };
-                                        self.card.set(Some(card));
+                                        }
+                                        self.protocol = ICCProtocol::T1;
                                     }
-                                    let status =
-                                        match self.card.get_mut().as_ref().unwrap().status2_owned()
-                                        {
-                                            Ok(status) => status,
-                                            Err(e) => {
-                                                debug!("Failed to get card status: {:?}", e);
-                                                resp.set_status(
-                                                    SlotStatusRegister::ICCInactiveFailure,
-                                                    SlotErrorRegister::HardwareError,
-                                                );
-                                                return;
-                                            }
-                                        };
-                                    resp.append(status.atr()).unwrap();
+                                    let atr = match self.backend.atr() {
+                                        Ok(atr) => atr,
+                                        Err(e) => {
+                                            debug!("Failed to get card status: {:?}", e);
+                                            resp.set_status(
+                                                SlotStatusRegister::ICCInactiveFailure,
+                                                SlotErrorRegister::HardwareError,
+                                            );
+                                            return;
+                                        }
+                                    };
+                                    resp.append(&atr).unwrap();
+                                    self.parameter =
+                                        Self::parse_parameters(self.backend.reader_name(), &atr);
+                                    if let Some(parameter) = &self.parameter {
+                                        info!(
+                                            "Card of reader '{}' has ATR {}, T=1 parameters: {}",
+                                            self.backend.reader_name().to_string_lossy(),
+                                            hexdump(&atr),
+                                            parameter
+                                        );
+                                    }
+                                    // PCSC does not tell the voltage, a card powered on
+                                    // automatically is taken to run at 5V
+                                    self.voltage = match bPowerSelect {
+                                        ICCVoltage::AUTO => ICCVoltage::V_5_0,
+                                        voltage => voltage,
+                                    };
+                                    debug!(
+                                        "Powered on card of reader '{}' at {:?}, requested {:?}",
+                                        self.backend.reader_name().to_string_lossy(),
+                                        self.voltage,
+                                        bPowerSelect
+                                    );
+                                    self.clock = ICCClockStatus::Running;
+                                    self.atr = Some(atr);
+                                    self.card_present = true;
                                 })();
                                 response = resp;
                             }
-                            ccid_proto::Command::PC_to_RDR_XfrBlock { header, abData, .. } => {
+                            ccid_proto::Command::PC_to_RDR_XfrBlock {
+                                header,
+                                wLevelParameter,
+                                abData,
+                                ..
+                            } => {
                                 let mut resp = ccid_proto::Response::new(header);
-                                if header.dwLength > 0 {
-                                    match self.card.get_mut().as_mut().unwrap().transaction() {
-                                        Ok(tx) => {
-                                            static responseData: SyncUnsafeCell<[u8; 65536]> =
-                                                SyncUnsafeCell::new([0u8; 65536]);
-                                            match tx.transmit(&abData, unsafe {
-                                                &mut *responseData.get()
-                                            }) {
-                                                Ok(apdu) => {
-                                                    resp.append(apdu).unwrap();
-                                                }
-                                                Err(e) => {
-                                                    debug!("SCardTransmit failed: {}", e);
-                                                    if let ccid_proto::Response::RDR_to_PC_DataBlock { header, bChainParameter: _, abData: _ } = &mut resp {
-                                                        header.bError = SlotErrorRegister::CommandSlotBusy;
-                                                        header.bStatus = SlotStatusRegister::ICCActiveFailure;
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            debug!("SCardBeginTransaction failed: {}", e);
-                                            if let ccid_proto::Response::RDR_to_PC_DataBlock {
-                                                header,
-                                                bChainParameter: _,
-                                                abData: _,
-                                            } = &mut resp
-                                            {
-                                                header.bError = SlotErrorRegister::CommandSlotBusy;
-                                                header.bStatus =
-                                                    SlotStatusRegister::ICCActiveFailure;
-                                            }
+                                if wLevelParameter == LEVEL_GET_NEXT_BLOCK && header.dwLength == 0 {
+                                    match chained_response {
+                                        Some(rest) => self.chain_response(&mut resp, rest, false),
+                                        None => {
+                                            debug!("No chained response to continue");
+                                            resp.set_status(
+                                                self.slot_status(false),
+                                                SlotErrorRegister::InvalidParameter(0x08),
+                                            );
                                         }
                                     }
+                                } else if self
+                                    .config
+                                    .max_apdu_len
+                                    .is_some_and(|max| abData.len() > max as usize)
+                                {
+                                    debug!(
+                                        "Command APDU of {} bytes exceeds maximum APDU length",
+                                        abData.len()
+                                    );
+                                    resp.set_status(
+                                        self.slot_status(false),
+                                        SlotErrorRegister::TransferOverrun,
+                                    );
+                                } else if header.dwLength > 0
+                                    && !self.config.apdu_filter.allow(&abData)
+                                {
+                                    info!(
+                                        "Blocked APDU {} to reader '{}'",
+                                        hexdump(&abData[..abData.len().min(4)]),
+                                        self.backend.reader_name().to_string_lossy()
+                                    );
+                                    resp.append(&self.config.blocked_sw.to_be_bytes()).unwrap();
+                                } else if header.dwLength > 0 {
+                                    self.check_applet_switch(&abData);
+                                    // Answered once the worker is done, see `collect_exchange`
+                                    self.start_exchange(header, abData);
+                                    return Ok(vec![]);
                                 }
                                 response = resp;
                             }
                             ccid_proto::Command::PC_to_RDR_GetParameters { header, .. } => {
-                                let mut resp;
-                                if self.parameter.is_some() {
-                                    resp = ccid_proto::Response::new(header);
-                                    match &mut resp {
-                                        Response::RDR_to_PC_Parameters { bProtocolNum, .. } => {
-                                            *bProtocolNum = ICCProtocol::T1;
-                                        }
-                                        other => panic!("Unexpected response type: {:?}", other),
-                                    }
-                                    resp.append(self.parameter.as_ref().unwrap()).unwrap();
+                                response = self.parameters_response(header);
+                            }
+                            ccid_proto::Command::PC_to_RDR_SetParameters {
+                                header,
+                                bProtocolNum,
+                                ..
+                            } => {
+                                response = if bProtocolNum == self.protocol {
+                                    self.parameters_response(header)
                                 } else {
-                                    resp = ccid_proto::Response::new_with_error(
-                                        ResponseMessageHeader::new(
-                                            header,
-                                            SlotStatusRegister::ICCActiveFailure,
-                                            SlotErrorRegister::UnsupportedCommand,
-                                        ),
-                                    );
+                                    self.switch_protocol(header, bProtocolNum)
+                                };
+                            }
+                            ccid_proto::Command::PC_to_RDR_Escape { header, abData, .. } => {
+                                // Answered once the worker is done, see `collect_exchange`
+                                self.start_exchange(header, abData);
+                                return Ok(vec![]);
+                            }
+                            ccid_proto::Command::PC_to_RDR_IccClock {
+                                header,
+                                bClockCommand,
+                                ..
+                            } => {
+                                let clock = match bClockCommand {
+                                    ICCClockCommand::Restart => Some(ICCClockStatus::Running),
+                                    ICCClockCommand::Stop => self.stopped_clock(),
+                                };
+                                let mut resp = ccid_proto::Response::new(header);
+                                match clock {
+                                    Some(clock) => self.clock = clock,
+                                    None => {
+                                        debug!("Stopping the clock is not allowed");
+                                        resp.set_status(
+                                            self.slot_status(false),
+                                            SlotErrorRegister::InvalidParameter(0x07),
+                                        );
+                                    }
                                 }
                                 response = resp;
                             }
-                            ccid_proto::Command::PC_to_RDR_Escape { header, .. }
-                            | ccid_proto::Command::PC_to_RDR_IccClock { header, .. }
-                            | ccid_proto::Command::PC_to_RDR_Mechanical { header, .. }
+                            ccid_proto::Command::PC_to_RDR_Mechanical { header, .. }
                             | ccid_proto::Command::PC_to_RDR_ResetParameters { header, .. }
                             | ccid_proto::Command::PC_to_RDR_Secure { header, .. }
                             | ccid_proto::Command::PC_to_RDR_SetDataRateAndClockFrequency {
                                 header,
                                 ..
                             }
-                            | ccid_proto::Command::PC_to_RDR_SetParameters { header, .. }
                             | ccid_proto::Command::PC_to_RDR_T0APDU { header, .. } => {
-                                response = ccid_proto::Response::new_with_error(
-                                    ResponseMessageHeader::new(
-                                        header,
-                                        SlotStatusRegister::ICCActiveFailure,
-                                        SlotErrorRegister::UnsupportedCommand,
-                                    ),
-                                );
+                                response = self.unsupported(header);
                             }
                         }
                     }
+                    // Whichever command it answers
+                    if let ccid_proto::Response::RDR_to_PC_SlotStatus { bClockStatus, .. } =
+                        &mut response
+                    {
+                        *bClockStatus = self.clock_status();
+                    }
                     let mut data = io::Cursor::new(Vec::new());
                     response.encode(&mut data).unwrap();
                     let data = data.into_inner();
                     self.outQueue.push_back(data.clone());
-                    debug!("CCID response bytes: {:02X?}", data);
+                    if !busy {
+                        self.set_in_flight(None);
+                    }
+                    debug!("CCID response bytes: {}", hexdump(&data));
                     Ok(vec![])
                 }
                 other => {
@@ -626,7 +2212,1778 @@ impl UsbInterfaceHandler for CCIDInterfaceHandler {
         }
     }
 
+    fn urb_ready(&self) -> Option<Arc<Notify>> {
+        Some(self.ready.clone())
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu_filter::{AllowedCommand, Allowlist};
+    use crate::fake::{FakeUsbDevice, MemoryBackend, PIGEON_ATR};
+    use crate::reserved::ReservedInterfaceHandler;
+
+    fn interface() -> UsbInterface {
+        UsbInterface {
+            interface_class: 0x0B,
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            interface_number: 0x02,
+            endpoints: CCIDInterfaceHandler::endpoints(),
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        }
+    }
+
+    fn exchange(handler: &mut CCIDInterfaceHandler, command: &[u8]) -> Vec<u8> {
+        let endpoints = CCIDInterfaceHandler::endpoints();
+        handler
+            .handle_urb(
+                &interface(),
+                endpoints[1],
+                command.len() as u32,
+                SetupPacket::default(),
+                command,
+            )
+            .unwrap();
+        // NAKed while the APDU is being exchanged
+        loop {
+            let response = read_response(handler);
+            if !response.is_empty() || handler.exchange.is_none() {
+                return response;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Next queued response, empty if there is none and the read was NAKed
+    fn read_response(handler: &mut CCIDInterfaceHandler) -> Vec<u8> {
+        let endpoints = CCIDInterfaceHandler::endpoints();
+        let result = handler.handle_urb(
+            &interface(),
+            endpoints[0],
+            0x200,
+            SetupPacket {
+                request_type: 0x80,
+                ..Default::default()
+            },
+            &[],
+        );
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => vec![],
+            result => result.unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_empty_bulk_in() {
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let setup = SetupPacket {
+            request_type: 0x80,
+            ..Default::default()
+        };
+        let endpoints = CCIDInterfaceHandler::endpoints();
+        let err = handler
+            .handle_urb(&interface(), endpoints[0], 0x200, setup, &[])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // PC_to_RDR_GetSlotStatus
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[0], 0x81);
+        assert!(read_response(&mut handler).is_empty());
+    }
+
+    #[test]
+    fn test_escape_control_code() {
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0xAA, 0x55]));
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            escape_control_code: 0x003136B0,
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6B, 0x03, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03,
+            ],
+        );
+        assert_eq!(
+            log.lock().unwrap().controls,
+            vec![(0x003136B0, vec![0x01, 0x02, 0x03])]
+        );
+        assert_eq!(response[0], ccid_const::RDR_to_PC_Escape);
+        assert_eq!(response[6], 0x05);
+        assert_eq!(&response[10..], &[0xAA, 0x55]);
+    }
+
+    #[test]
+    fn test_with_config_fake_device() {
+        let backend = MemoryBackend::new(&PIGEON_ATR);
+        let log = backend.log.clone();
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let desc = handler.get_class_specific_descriptor();
+        assert_eq!(desc.len(), 0x36);
+        assert_eq!(&desc[10..14], &4000u32.to_le_bytes());
+        assert_eq!(&desc[19..23], &10752u32.to_le_bytes());
+
+        // PC_to_RDR_XfrBlock: SELECT
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(
+            log.lock().unwrap().transmitted,
+            vec![vec![0x00, 0xA4, 0x04, 0x00, 0x00]]
+        );
+        assert_eq!(response[0], ccid_const::RDR_to_PC_DataBlock);
+        assert_eq!(&response[10..], &[0x90, 0x00]);
+    }
+
+    #[test]
+    fn test_max_message_length() {
+        let config = CCIDConfig {
+            max_message_length: 0x200,
+            ..Default::default()
+        };
+        let handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap();
+        assert_eq!(handler.ccid_descriptor.max_ccid_message_length, 0x200);
+        assert_eq!(handler.response_buffer.len(), 0x200 - 10);
+
+        let config = CCIDConfig {
+            max_message_length: 0x100,
+            ..Default::default()
+        };
+        assert!(
+            CCIDInterfaceHandler::with_config(
+                &FakeUsbDevice::pigeon(),
+                Box::new(MemoryBackend::new(&PIGEON_ATR)),
+                config,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_next_data_block() {
+        let mut apdu = vec![0x5A; 0x4B0];
+        apdu.extend([0x90, 0x00]);
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(apdu.clone()));
+        let config = CCIDConfig {
+            max_message_length: 0x200,
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        let xfr_block = |seq: u8, level: u16, apdu: &[u8]| {
+            let mut command = vec![0x6F, 0x00, 0x00, 0x00, 0x00, 0x00, seq, 0x00, 0x00, 0x00];
+            command[1..5].copy_from_slice(&(apdu.len() as u32).to_le_bytes());
+            command[8..10].copy_from_slice(&level.to_le_bytes());
+            command.extend_from_slice(apdu);
+            command
+        };
+
+        let response = exchange(
+            &mut handler,
+            &xfr_block(1, 0, &[0x00, 0xCA, 0x00, 0x6E, 0x00]),
+        );
+        assert_eq!((response[7], response[9]), (0x00, 0x01));
+        assert_eq!(&response[10..], &apdu[..0x1F6]);
+        let response = exchange(&mut handler, &xfr_block(2, 0x10, &[]));
+        assert_eq!(response[9], 0x03);
+        assert_eq!(&response[10..], &apdu[0x1F6..0x3EC]);
+        let response = exchange(&mut handler, &xfr_block(3, 0x10, &[]));
+        assert_eq!(response[9], 0x02);
+        assert_eq!(&response[10..], &apdu[0x3EC..]);
+
+        // Nothing left to continue, unlike an empty block without wLevelParameter
+        let response = exchange(&mut handler, &xfr_block(4, 0x10, &[]));
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], 0x08);
+        let response = exchange(&mut handler, &xfr_block(5, 0, &[]));
+        assert_eq!((response[7], response.len()), (0x00, 10));
+    }
+
+    #[test]
+    fn test_max_ifsd() {
+        let backend = MemoryBackend::new(&PIGEON_ATR)
+            .with_response(Ok((0..0x28).collect()))
+            .with_response(Ok(vec![0x90, 0x00]));
+        let config = CCIDConfig {
+            max_ifsd: 0x10,
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        assert_eq!(handler.ccid_descriptor.max_ifsd, 0x10);
+        let get_data = [
+            0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xCA, 0x00, 0x6E,
+            0x00,
+        ];
+
+        // Begins, continues, ends
+        let response = exchange(&mut handler, &get_data);
+        assert_eq!(response[9], 0x01);
+        assert_eq!(&response[10..], &(0x00..0x10).collect::<Vec<u8>>());
+        let next = [0x6F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x10, 0x00];
+        let response = exchange(&mut handler, &next);
+        assert_eq!(response[9], 0x03);
+        assert_eq!(&response[10..], &(0x10..0x20).collect::<Vec<u8>>());
+        let response = exchange(&mut handler, &next);
+        assert_eq!(response[9], 0x02);
+        assert_eq!(&response[10..], &(0x20..0x28).collect::<Vec<u8>>());
+
+        // Short responses are not chained
+        let response = exchange(&mut handler, &get_data);
+        assert_eq!((response[9], &response[10..]), (0x00, &[0x90, 0x00][..]));
+
+        let config = CCIDConfig {
+            max_ifsd: 0,
+            ..Default::default()
+        };
+        let err = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_clock_frequencies_and_data_rates() {
+        let class_request = |handler: &mut CCIDInterfaceHandler, request: u8| {
+            let setup = SetupPacket {
+                request_type: 0xA1,
+                request,
+                value: 0,
+                index: 0x02,
+                length: 0x100,
+            };
+            handler.handle_urb(&interface(), UsbEndpoint::default(), 0x100, setup, &[])
+        };
+
+        // Card managed, the requests are not supported
+        let mut handler = pigeon_handler();
+        assert_eq!(
+            (
+                handler.ccid_descriptor.num_clock_supported,
+                handler.ccid_descriptor.num_data_rates_supported
+            ),
+            (0, 0)
+        );
+        assert!(class_request(&mut handler, 0x02).is_err());
+        assert!(class_request(&mut handler, 0x03).is_err());
+
+        let config = CCIDConfig {
+            clock_frequencies: vec![4000, 8000],
+            data_rates: vec![10752, 21505, 43010],
+            ..Default::default()
+        };
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap();
+        let clocks = class_request(&mut handler, 0x02).unwrap();
+        let rates = class_request(&mut handler, 0x03).unwrap();
+        assert_eq!(
+            handler.ccid_descriptor.num_clock_supported as usize,
+            clocks.len() / 4
+        );
+        assert_eq!(
+            handler.ccid_descriptor.num_data_rates_supported as usize,
+            rates.len() / 4
+        );
+        assert_eq!(
+            clocks,
+            [4000u32.to_le_bytes(), 8000u32.to_le_bytes()].concat()
+        );
+        assert_eq!(&rates[8..], 43010u32.to_le_bytes());
+
+        let config = CCIDConfig {
+            data_rates: vec![9600; 256],
+            ..Default::default()
+        };
+        assert!(
+            CCIDInterfaceHandler::with_config(
+                &FakeUsbDevice::pigeon(),
+                Box::new(MemoryBackend::new(&PIGEON_ATR)),
+                config,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_insufficient_buffer() {
+        let backend = MemoryBackend::new(&PIGEON_ATR)
+            .with_response(Ok(vec![0x61; 0x300]))
+            .with_response(Ok(vec![0x62; 0x400]));
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            max_message_length: 0x200,
+            max_apdu_len: Some(0x300),
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        let get_data = [
+            0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xCA, 0x00, 0x6E,
+            0x00,
+        ];
+
+        // Transmitted again with a buffer of the maximum APDU length, then chained
+        let response = exchange(&mut handler, &get_data);
+        assert_eq!((response[7], response[9]), (0x00, 0x01));
+        assert_eq!(log.lock().unwrap().transmitted.len(), 2);
+        assert_eq!(handler.response_buffer.len(), 0x300);
+        let next = [0x6F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x10, 0x00];
+        let response = exchange(&mut handler, &next);
+        assert_eq!(response[9], 0x02);
+        assert_eq!(response.len(), 10 + 0x300 - 0x1F6);
+
+        // Still too long
+        let response = exchange(&mut handler, &get_data);
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::XFR_OVERRUN);
+        assert_eq!(log.lock().unwrap().transmitted.len(), 3);
+    }
+
+    #[test]
+    fn test_max_apdu_len() {
+        let backend = MemoryBackend::new(&PIGEON_ATR)
+            .with_response(Ok(vec![0x00; 0x200]))
+            .with_response(Ok(vec![0x90, 0x00]));
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            max_apdu_len: Some(0x110),
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        assert_eq!(handler.ccid_descriptor.max_ccid_message_length, 0x11A);
+        let xfr_block = |seq: u8, apdu: &[u8]| {
+            let mut command = vec![0x6F, 0x00, 0x00, 0x00, 0x00, 0x00, seq, 0x00, 0x00, 0x00];
+            command[1..5].copy_from_slice(&(apdu.len() as u32).to_le_bytes());
+            command.extend_from_slice(apdu);
+            command
+        };
+
+        // Command APDU over the limit is not transmitted
+        let response = exchange(&mut handler, &xfr_block(1, &[0x00; 0x111]));
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::XFR_OVERRUN);
+        assert!(log.lock().unwrap().transmitted.is_empty());
+
+        // Response APDU over the limit is dropped
+        let response = exchange(&mut handler, &xfr_block(2, &[0x00, 0xCA, 0x00, 0x6E, 0x00]));
+        assert_eq!(response[8], ccid_const::XFR_OVERRUN);
+        assert_eq!(response.len(), 10);
+
+        let response = exchange(&mut handler, &xfr_block(3, &[0x00; 0x110]));
+        assert_eq!(&response[10..], &[0x90, 0x00]);
+        assert_eq!(log.lock().unwrap().transmitted.len(), 2);
+
+        let config = CCIDConfig {
+            max_apdu_len: Some(0x100),
+            ..Default::default()
+        };
+        assert!(
+            CCIDInterfaceHandler::with_config(
+                &FakeUsbDevice::pigeon(),
+                Box::new(MemoryBackend::new(&PIGEON_ATR)),
+                config,
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_nonstandard_descriptor() {
+        let mut device = FakeUsbDevice::pigeon();
+        let offset = device
+            .configuration
+            .windows(2)
+            .position(|d| d == [0x36, 0x21])
+            .unwrap();
+        // Vendor layout of the right length, the clocks are no clocks
+        device.configuration[offset + 2..offset + 4].copy_from_slice(&[0x00, 0x02]);
+        device.configuration[offset + 10..offset + 14].copy_from_slice(&[0xFF; 4]);
+        let handler = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let relayed = handler.ccid_descriptor;
+        assert_eq!(relayed.bcd_ccid, 0x0110);
+        assert_eq!(
+            (relayed.default_clock, relayed.maximum_clock),
+            (DEFAULT_CLOCK, DEFAULT_CLOCK)
+        );
+        assert_eq!(
+            (relayed.data_rate, relayed.max_data_rate),
+            (DEFAULT_DATA_RATE, DEFAULT_DATA_RATE)
+        );
+
+        // Longer than the standard one
+        let mut device = FakeUsbDevice::pigeon();
+        device.configuration[offset + 10..offset + 14].copy_from_slice(&3580u32.to_le_bytes());
+        device.configuration[offset] = 0x38;
+        device
+            .configuration
+            .splice(offset + 0x36..offset + 0x36, [0x00, 0x00]);
+        let total_length = device.configuration.len() as u16;
+        device.configuration[2..4].copy_from_slice(&total_length.to_le_bytes());
+        let handler = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(handler.ccid_descriptor.default_clock, DEFAULT_CLOCK);
+    }
+
+    #[test]
+    fn test_descriptor_merge() {
+        let mut device = FakeUsbDevice::pigeon();
+        let offset = device
+            .configuration
+            .windows(2)
+            .position(|d| d == [0x36, 0x21])
+            .unwrap();
+        let mut physical =
+            CcidFunctionalDescriptor::decode(&device.configuration[offset..offset + 0x36]).unwrap();
+        physical.default_clock = 3580;
+        physical.maximum_clock = 20000;
+        physical.data_rate = 9600;
+        physical.max_data_rate = 344086;
+        // Not taken over
+        physical.max_slot_index = 1;
+        physical.features = 0x00020000;
+        device.configuration[offset..offset + 0x36].copy_from_slice(&physical.encode());
+
+        let handler = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let desc = handler.get_class_specific_descriptor();
+        assert_eq!(desc.len(), 0x36);
+        let relayed = CcidFunctionalDescriptor::decode(&desc).unwrap();
+        assert_eq!(
+            (relayed.default_clock, relayed.maximum_clock),
+            (3580, 20000)
+        );
+        assert_eq!((relayed.data_rate, relayed.max_data_rate), (9600, 344086));
+        assert_eq!(relayed.max_slot_index, 0);
+        assert_eq!(relayed.protocols, 0x03);
+        assert_eq!(relayed.features, 0x000400FE);
+        assert_eq!(relayed.max_ccid_message_length, DEFAULT_MAX_MESSAGE_LENGTH);
+        assert_eq!(&desc[10..14], &3580u32.to_le_bytes());
+        assert_eq!(&desc[23..27], &344086u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_raw_descriptor() {
+        let mut raw = vec![0u8; 0x36];
+        raw[..6].copy_from_slice(&[0x36, 0x21, 0x00, 0x01, 0x00, 0x03]);
+        raw[44..48].copy_from_slice(&0x10Fu32.to_le_bytes());
+        let config = CCIDConfig {
+            raw_descriptor: Some(raw.clone()),
+            ..Default::default()
+        };
+        let device = FakeUsbDevice::pigeon();
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap();
+        assert_eq!(handler.get_class_specific_descriptor(), raw);
+        handler.refresh(&device).unwrap();
+        assert_eq!(handler.get_class_specific_descriptor(), raw);
+
+        for invalid in [&raw[..0x35], &[0x36, 0x24].repeat(0x1B)] {
+            let config = CCIDConfig {
+                raw_descriptor: Some(invalid.to_vec()),
+                ..Default::default()
+            };
+            assert!(
+                CCIDInterfaceHandler::with_config(
+                    &device,
+                    Box::new(MemoryBackend::new(&PIGEON_ATR)),
+                    config,
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_query_atr() {
+        let device = FakeUsbDevice::pigeon();
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        // Published by the handler, the handle follows its state
+        let status = handler.status();
+        assert_eq!(status.slot_count(), 1);
+
+        // PC_to_RDR_IccPowerOff
+        exchange(
+            &mut handler,
+            &[0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert!(!status.is_powered(0));
+        assert_eq!(status.current_atr(0), None);
+
+        // PC_to_RDR_IccPowerOn
+        let response = exchange(
+            &mut handler,
+            &[0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(&response[10..], &PIGEON_ATR);
+        assert!(status.is_powered(0));
+        assert!(!status.is_powered(1));
+        assert_eq!(status.current_atr(0), Some(PIGEON_ATR.to_vec()));
+        assert_eq!(status.current_atr(1), None);
+    }
+
+    fn control_abort(handler: &mut CCIDInterfaceHandler, slot: u8, seq: u8) -> io::Result<Vec<u8>> {
+        handler.handle_urb(
+            &interface(),
+            UsbEndpoint::default(),
+            0,
+            SetupPacket {
+                request_type: 0x21,
+                request: 0x01,
+                value: ((seq as u16) << 8) | slot as u16,
+                index: 0x02,
+                length: 0,
+            },
+            &[],
+        )
+    }
+
+    #[test]
+    fn test_setup_requests() {
+        let config = CCIDConfig {
+            clock_frequencies: vec![4000],
+            data_rates: vec![10752],
+            ..Default::default()
+        };
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap();
+        let mut request = |request_type: u8, request: u8| {
+            let setup = SetupPacket {
+                request_type,
+                request,
+                value: 0,
+                index: 0x02,
+                length: 4,
+            };
+            handler.handle_urb(&interface(), UsbEndpoint::default(), 4, setup, &[])
+        };
+
+        assert_eq!(request(0x21, ccid_const::ABORT).unwrap(), []);
+        assert_eq!(
+            request(0xA1, ccid_const::GET_CLOCK_FREQUENCIES).unwrap(),
+            4000u32.to_le_bytes()
+        );
+        assert_eq!(
+            request(0xA1, ccid_const::GET_DATA_RATES).unwrap(),
+            10752u32.to_le_bytes()
+        );
+        for (request_type, number) in [
+            (0xA1, 0x04),
+            (0x21, 0x7F),
+            // CLEAR_FEATURE(Interface) is no ABORT
+            (0x01, ccid_const::ABORT),
+            (0x21, ccid_const::GET_DATA_RATES),
+        ] {
+            let err = request(request_type, number).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_get_status() {
+        let mut handler = pigeon_handler();
+        let status = handler
+            .handle_urb(
+                &interface(),
+                UsbEndpoint::default(),
+                2,
+                SetupPacket {
+                    request_type: 0x81,
+                    request: GetStatus as u8,
+                    value: 0,
+                    index: 0x02,
+                    length: 2,
+                },
+                &[],
+            )
+            .unwrap();
+        assert_eq!(status, [0x00, 0x00]);
+    }
+
+    fn pigeon_handler() -> CCIDInterfaceHandler {
+        CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap()
+    }
+
+    const ABORT_SEQ_7: [u8; 10] = [0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn test_abort_control_then_bulk() {
+        let mut handler = pigeon_handler();
+        control_abort(&mut handler, 0, 7).unwrap();
+        // Commands on the aborted slot fail with CMD_ABORTED until PC_to_RDR_Abort
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+
+        let response = exchange(&mut handler, &ABORT_SEQ_7);
+        assert_eq!(response[0], ccid_const::RDR_to_PC_SlotStatus);
+        assert_eq!(response[6], 0x07);
+        assert_eq!(response[7] & 0xC0, 0x00);
+
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7] & 0xC0, 0x00);
+    }
+
+    #[test]
+    fn test_abort_bulk_then_control() {
+        let mut handler = pigeon_handler();
+        let response = exchange(&mut handler, &ABORT_SEQ_7);
+        assert!(response.is_empty());
+        control_abort(&mut handler, 0, 7).unwrap();
+        let response = read_response(&mut handler);
+        assert_eq!(response[0], ccid_const::RDR_to_PC_SlotStatus);
+        assert_eq!(response[6], 0x07);
+        assert_eq!(response[7] & 0xC0, 0x00);
+    }
+
+    #[test]
+    fn test_abort_seq_mismatch() {
+        let mut handler = pigeon_handler();
+        control_abort(&mut handler, 0, 6).unwrap();
+        let response = exchange(&mut handler, &ABORT_SEQ_7);
+        assert_eq!(response[6], 0x07);
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], 0x06);
+        assert!(control_abort(&mut handler, 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_abort_bulk_then_control_mismatch() {
+        let mut handler = pigeon_handler();
+        let response = exchange(&mut handler, &ABORT_SEQ_7);
+        assert!(response.is_empty());
+        let err = control_abort(&mut handler, 0, 6).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(handler.outQueue.is_empty());
+        // PC_to_RDR_Abort still waits for its ABORT request
+        control_abort(&mut handler, 0, 7).unwrap();
+        let response = read_response(&mut handler);
+        assert_eq!(response[6], 0x07);
+        assert_eq!(response[7] & 0xC0, 0x00);
+    }
+
+    #[test]
+    fn test_card_removed_during_transmit() {
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR).with_response(Err(pcsc::Error::RemovedCard))),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        // PC_to_RDR_XfrBlock
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(response[7], 0x42);
+        assert_eq!(response[8], ccid_const::ICC_MUTE);
+        assert!(!handler.status().snapshot().card_present);
+
+        // PC_to_RDR_GetSlotStatus reports absent without polling the reader
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7], 0x02);
+        assert_eq!(handler.status().current_atr(0), None);
+
+        // PC_to_RDR_IccPowerOn after the card is inserted again
+        let response = exchange(
+            &mut handler,
+            &[0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7], 0x00);
+        assert!(handler.status().snapshot().card_present);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0x90, 0x00]));
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        // PC_to_RDR_GetSlotStatus
+        exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        let now = Instant::now();
+        assert!(!handler.check_idle(now + Duration::from_secs(29)));
+        assert!(handler.status().is_powered(0));
+        assert!(handler.check_idle(now + Duration::from_secs(30)));
+        assert!(!handler.status().is_powered(0));
+        assert!(!handler.check_idle(now + Duration::from_secs(60)));
+
+        // PC_to_RDR_XfrBlock powers the card on again
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(response[7], 0x00);
+        assert_eq!(&response[10..], &[0x90, 0x00]);
+        assert_eq!(log.lock().unwrap().transmitted.len(), 1);
+        assert_eq!(handler.status().current_atr(0), Some(PIGEON_ATR.to_vec()));
+        assert!(handler.status().snapshot().card_present);
+    }
+
+    #[test]
+    fn test_cancel_transmit() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.blocking = true;
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let endpoints = CCIDInterfaceHandler::endpoints();
+        // PC_to_RDR_XfrBlock to a card which never answers
+        let command = [
+            0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+            0x00,
+        ];
+        handler
+            .handle_urb(
+                &interface(),
+                endpoints[1],
+                command.len() as u32,
+                SetupPacket::default(),
+                &command,
+            )
+            .unwrap();
+        assert!(read_response(&mut handler).is_empty());
+
+        // ABORT request for slot 0 seq 2 cancels the transmit
+        let setup = SetupPacket {
+            request_type: 0x21,
+            request: ccid_const::ABORT,
+            value: 0x0200,
+            index: 0x02,
+            length: 0,
+        };
+        handler
+            .handle_urb(&interface(), UsbEndpoint::default(), 0, setup, &[])
+            .unwrap();
+        let response = read_response(&mut handler);
+        assert_eq!(response[6], 0x01);
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+        assert!(read_response(&mut handler).is_empty());
+
+        // PC_to_RDR_Abort completes the abort
+        let response = exchange(
+            &mut handler,
+            &[0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[0], 0x81);
+        assert_eq!(response[6], 0x02);
+        assert_eq!(response[7] & 0xC0, 0x00);
+        // Nothing else is queued for the aborted command
+        assert!(read_response(&mut handler).is_empty());
+        assert_eq!(handler.status().snapshot().in_flight, None);
+    }
+
+    #[test]
+    fn test_abort_slow_card() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR)
+            .with_response(Ok(vec![0x6A, 0x82]))
+            .with_response(Ok(vec![0x90, 0x00]));
+        backend.transmit_delay = Duration::from_millis(300);
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let endpoints = CCIDInterfaceHandler::endpoints();
+        let xfr_block = |seq| {
+            [
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, seq, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ]
+        };
+        let command = xfr_block(0x01);
+        handler
+            .handle_urb(
+                &interface(),
+                endpoints[1],
+                command.len() as u32,
+                SetupPacket::default(),
+                &command,
+            )
+            .unwrap();
+
+        // The ABORT request is answered without waiting for the card
+        let start = Instant::now();
+        let setup = SetupPacket {
+            request_type: 0x21,
+            request: ccid_const::ABORT,
+            value: 0x0200,
+            index: 0x02,
+            length: 0,
+        };
+        handler
+            .handle_urb(&interface(), UsbEndpoint::default(), 0, setup, &[])
+            .unwrap();
+        let response = read_response(&mut handler);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!(response[6], 0x01);
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+        let response = exchange(
+            &mut handler,
+            &[0x72, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[6], 0x02);
+        assert_eq!(response[7] & 0xC0, 0x00);
+
+        // The slot is busy until the card finished the abandoned exchange
+        let response = exchange(&mut handler, &xfr_block(0x03));
+        assert_eq!(response[8], ccid_const::CMD_SLOT_BUSY);
+        std::thread::sleep(Duration::from_millis(400));
+        // Whose response is thrown away
+        let response = exchange(&mut handler, &xfr_block(0x04));
+        assert_eq!(response[6], 0x04);
+        assert_eq!(response[10..], [0x90, 0x00]);
+        assert!(read_response(&mut handler).is_empty());
+
+        // Powering off does not wait for the card either, it is powered off once handed back
+        let command = xfr_block(0x05);
+        handler
+            .handle_urb(
+                &interface(),
+                endpoints[1],
+                command.len() as u32,
+                SetupPacket::default(),
+                &command,
+            )
+            .unwrap();
+        let start = Instant::now();
+        handler.drop_card();
+        assert!(start.elapsed() < Duration::from_millis(200));
+        let response = read_response(&mut handler);
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(read_response(&mut handler).is_empty());
+        assert!(!handler.backend.is_connected());
+    }
+
+    #[test]
+    fn test_command_delay() {
+        let config = CCIDConfig {
+            transfer: TransferConfig {
+                command_delay: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap();
+        let start = Instant::now();
+        // Nothing exchanged yet
+        let transmitter = handler.lend_card();
+        assert_eq!(transmitter.command_delay(start), Duration::ZERO);
+        handler.return_card(transmitter);
+
+        // PC_to_RDR_XfrBlock
+        let xfr = |seq| {
+            [
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, seq, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ]
+        };
+        let response = exchange(&mut handler, &xfr(0x01));
+        assert_eq!(&response[10..], [0x90, 0x00]);
+        let last = handler.last_exchange.unwrap();
+        let transmitter = handler.lend_card();
+        assert_eq!(transmitter.command_delay(last), Duration::from_millis(100));
+        assert_eq!(
+            transmitter.command_delay(last + Duration::from_millis(30)),
+            Duration::from_millis(70)
+        );
+        assert_eq!(
+            transmitter.command_delay(last + Duration::from_millis(200)),
+            Duration::ZERO
+        );
+        handler.return_card(transmitter);
+
+        // The next exchange waits for the rest of the delay
+        exchange(&mut handler, &xfr(0x02));
+        assert!(handler.last_exchange.unwrap() >= last + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_transmit_timeout() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.blocking = true;
+        let config = CCIDConfig {
+            transfer: TransferConfig {
+                transmit_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            config.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            handler.config.transfer.transmit_timeout,
+            Some(Duration::from_millis(50))
+        );
+        // PC_to_RDR_XfrBlock to a card which never answers
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+
+        // A card which can not be cancelled is answered for all the same
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.transmit_delay = Duration::from_millis(500);
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        let start = Instant::now();
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert!(start.elapsed() < Duration::from_millis(300));
+        assert_eq!(response[6], 0x02);
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.blocking = true;
+        let config = CCIDConfig {
+            transfer: TransferConfig {
+                transmit_timeout: Some(Duration::from_millis(500)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        let status = handler.status();
+        assert_eq!(status.snapshot().in_flight, None);
+
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            // PC_to_RDR_XfrBlock blocked in the transmit until it times out
+            let exchanged = scope.spawn(|| {
+                exchange(
+                    &mut handler,
+                    &[
+                        0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00, 0x00, 0xA4,
+                        0x04, 0x00, 0x00,
+                    ],
+                )
+            });
+            let command = loop {
+                if let Some(command) = status.snapshot().in_flight {
+                    break command;
+                }
+                assert!(!exchanged.is_finished());
+                std::thread::sleep(Duration::from_millis(5));
+            };
+            assert_eq!(
+                (command.slot, command.seq, command.message_type),
+                (0x00, 0x07, ccid_const::PC_to_RDR_XfrBlock)
+            );
+            assert!(command.started_at >= start);
+            let response = exchanged.join().unwrap();
+            assert_eq!(response[8], ccid_const::CMD_ABORTED);
+        });
+        assert_eq!(status.snapshot().in_flight, None);
+    }
+
+    #[test]
+    fn test_busy_slot() {
+        let mut handler = pigeon_handler();
+        assert_eq!(handler.get_class_specific_descriptor()[53], 0x01);
+        let endpoints = CCIDInterfaceHandler::endpoints();
+        // PC_to_RDR_GetSlotStatus twice, without reading the first response
+        for seq in [0x01, 0x02] {
+            let command = [0x65, 0x00, 0x00, 0x00, 0x00, 0x00, seq, 0x00, 0x00, 0x00];
+            handler
+                .handle_urb(
+                    &interface(),
+                    endpoints[1],
+                    command.len() as u32,
+                    SetupPacket::default(),
+                    &command,
+                )
+                .unwrap();
+        }
+        let response = read_response(&mut handler);
+        assert_eq!(response[6], 0x01);
+        assert_eq!(response[7] & 0xC0, 0x00);
+        let response = read_response(&mut handler);
+        assert_eq!(response[6], 0x02);
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::CMD_SLOT_BUSY);
+
+        // Free again once the responses were read
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7] & 0xC0, 0x00);
+
+        // Busy while a command is in flight, which stays in flight
+        let command = CommandInFlight {
+            slot: 0x00,
+            seq: 0x04,
+            message_type: ccid_const::PC_to_RDR_XfrBlock,
+            started_at: Instant::now(),
+        };
+        handler.set_in_flight(Some(command));
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::CMD_SLOT_BUSY);
+        assert_eq!(handler.status().snapshot().in_flight, Some(command));
+        handler.set_in_flight(None);
+
+        for max_busy_slots in [0, 2] {
+            let config = CCIDConfig {
+                max_busy_slots,
+                ..Default::default()
+            };
+            assert!(
+                CCIDInterfaceHandler::with_config(
+                    &FakeUsbDevice::pigeon(),
+                    Box::new(MemoryBackend::new(&PIGEON_ATR)),
+                    config,
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_apdu_filter() {
+        let backend =
+            MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0x61, 0x00, 0x90, 0x00]));
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            apdu_filter: Arc::new(Allowlist {
+                commands: vec![AllowedCommand {
+                    cla: None,
+                    ins: 0xCA,
+                }],
+            }),
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+
+        // CHANGE REFERENCE DATA is answered without reaching the card
+        let mut change_pin = vec![0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        change_pin.extend([0x00, 0x24, 0x00, 0x81, 0x00]);
+        let response = exchange(&mut handler, &change_pin);
+        assert_eq!(response[7] & 0xC0, 0x00);
+        assert_eq!(&response[10..], &[0x69, 0x82]);
+        assert!(log.lock().unwrap().transmitted.is_empty());
+        assert_eq!(handler.status().snapshot().counters.apdus, 0);
+
+        // GET DATA is relayed
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0xCA, 0x00, 0x6E,
+                0x00,
+            ],
+        );
+        assert_eq!(&response[10..], &[0x61, 0x00, 0x90, 0x00]);
+        assert_eq!(log.lock().unwrap().transmitted.len(), 1);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0x90, 0x00]));
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        // PC_to_RDR_IccPowerOn
+        let response = exchange(
+            &mut handler,
+            &[0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7] & 0xC0, 0x00);
+        assert_eq!(&response[10..], &PIGEON_ATR);
+
+        // PC_to_RDR_XfrBlock
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], ccid_const::CMD_ABORTED);
+        assert!(log.lock().unwrap().transmitted.is_empty());
+        assert!(handler.status().is_powered(0));
+    }
+
+    #[test]
+    fn test_shared_fallback() {
+        let in_use = || {
+            let mut backend = MemoryBackend::new(&PIGEON_ATR);
+            backend.in_use = true;
+            backend
+        };
+        let err = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(in_use()),
+            CCIDConfig::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(
+            err.to_string().contains("connecting to it failed"),
+            "{}",
+            err
+        );
+
+        let backend = in_use();
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            shared_fallback: true,
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        assert_eq!(
+            log.lock().unwrap().share_modes,
+            [ShareMode::Exclusive, ShareMode::Shared]
+        );
+        // PC_to_RDR_XfrBlock
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(response[7], 0x00);
+        assert_eq!(&response[10..], &[0x90, 0x00]);
+
+        // Exclusive access is tried again on every power on
+        // PC_to_RDR_IccPowerOff, PC_to_RDR_IccPowerOn
+        exchange(
+            &mut handler,
+            &[0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        let response = exchange(
+            &mut handler,
+            &[0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(&response[10..], &PIGEON_ATR);
+        assert_eq!(
+            log.lock().unwrap().share_modes[2..],
+            [ShareMode::Exclusive, ShareMode::Shared]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_response() {
+        // PC_to_RDR_Mechanical and an unknown message type
+        let mechanical = [0x71, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00];
+        let unknown = [0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00];
+
+        let mut handler = pigeon_handler();
+        let response = exchange(&mut handler, &mechanical);
+        assert_eq!(
+            response,
+            [0x71, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x40, 0x00, 0x00]
+        );
+        let response = exchange(&mut handler, &unknown);
+        assert_eq!(
+            response,
+            [0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x40, 0x00, 0x00]
+        );
+
+        let config = CCIDConfig {
+            unsupported_response: UnsupportedResponse::SlotStatus(SlotErrorRegister::HardwareError),
+            ..Default::default()
+        };
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            config,
+        )
+        .unwrap();
+        let response = exchange(&mut handler, &mechanical);
+        assert_eq!(
+            response,
+            [0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x40, 0xFB, 0x00]
+        );
+        let response = exchange(&mut handler, &unknown);
+        assert_eq!(
+            response,
+            [0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x40, 0xFB, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_power_on_voltage() {
+        let mut handler = pigeon_handler();
+        assert_eq!(handler.status().voltage(0), Some(ICCVoltage::V_5_0));
+        let power_off = |seq| [0x63, 0x00, 0x00, 0x00, 0x00, 0x00, seq, 0x00, 0x00, 0x00];
+        let power_on =
+            |seq, voltage| [0x62, 0x00, 0x00, 0x00, 0x00, 0x00, seq, voltage, 0x00, 0x00];
+
+        exchange(&mut handler, &power_off(0x01));
+        assert_eq!(handler.status().voltage(0), None);
+        // AUTO
+        let response = exchange(&mut handler, &power_on(0x02, 0x00));
+        assert_eq!(&response[10..], &PIGEON_ATR);
+        assert_eq!(handler.status().voltage(0), Some(ICCVoltage::V_5_0));
+
+        exchange(&mut handler, &power_off(0x03));
+        exchange(&mut handler, &power_on(0x04, 0x02));
+        assert_eq!(handler.status().voltage(0), Some(ICCVoltage::V_3_0));
+        assert_eq!(handler.status().voltage(1), None);
+    }
+
+    #[test]
+    fn test_reset_on_applet_switch() {
+        const SELECT_PIV: [u8; 16] = [
+            0x6F, 0x06, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+            0x01, 0x01,
+        ];
+        const SELECT_OPENPGP: [u8; 16] = [
+            0x6F, 0x06, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+            0x01, 0x02,
+        ];
+        const GET_DATA: [u8; 14] = [
+            0x6F, 0x04, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0xCA, 0x00, 0x6E,
+        ];
+        let connects = |reset_on_applet_switch: bool| {
+            let backend = MemoryBackend::new(&PIGEON_ATR);
+            let log = backend.log.clone();
+            // No WebUSB handler drops the card
+            let mut handler = CCIDInterfaceHandler::with_config(
+                &FakeUsbDevice::pigeon(),
+                Box::new(backend),
+                CCIDConfig {
+                    reset_on_applet_switch,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let mut connects = vec![];
+            for command in [
+                &SELECT_PIV[..],
+                &GET_DATA,
+                &SELECT_PIV,
+                &SELECT_OPENPGP,
+                &GET_DATA,
+            ] {
+                let response = exchange(&mut handler, command);
+                assert_eq!(&response[10..], [0x90, 0x00]);
+                connects.push(log.lock().unwrap().protocols.len());
+            }
+            connects
+        };
+
+        // Only switching to OpenPGP resets the card, selecting PIV again does not
+        assert_eq!(connects(true), [1, 1, 1, 2, 2]);
+        assert_eq!(connects(false), [1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_simulate_mute() {
+        let backend = MemoryBackend::new(&PIGEON_ATR);
+        let log = backend.log.clone();
+        let config = CCIDConfig {
+            simulate_mute: true,
+            ..Default::default()
+        };
+        let mut handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(backend), config)
+                .unwrap();
+        // PC_to_RDR_XfrBlock
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+                0x00,
+            ],
+        );
+        assert_eq!(response[0], ccid_const::RDR_to_PC_DataBlock);
+        assert_eq!(response[7], 0x40);
+        assert_eq!(response[8], ccid_const::ICC_MUTE);
+        assert!(log.lock().unwrap().transmitted.is_empty());
+
+        // PC_to_RDR_GetSlotStatus, present and active
+        let response = exchange(
+            &mut handler,
+            &[0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7], 0x00);
+        assert!(handler.status().is_powered(0));
+    }
+
+    #[test]
+    fn test_connect_timeout() {
+        let slow = || {
+            let mut backend = MemoryBackend::new(&PIGEON_ATR);
+            backend.connect_delay = Duration::from_millis(500);
+            backend
+        };
+        let config = CCIDConfig {
+            connect_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let err = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(slow()),
+            config.clone(),
+        )
+        .err()
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(
+            err.to_string()
+                .starts_with("Connecting reader 'Memory Reader 0' did not finish"),
+            "{}",
+            err
+        );
+
+        // Within the timeout the card is connected as usual
+        let config = CCIDConfig {
+            connect_timeout: Some(Duration::from_secs(5)),
+            ..config
+        };
+        let handler =
+            CCIDInterfaceHandler::with_config(&FakeUsbDevice::pigeon(), Box::new(slow()), config)
+                .unwrap();
+        assert!(handler.status().is_powered(0));
+    }
+
+    #[test]
+    fn test_describe_parameters() {
+        let parameter =
+            CCIDInterfaceHandler::parse_parameters(c"Memory Reader 0", &PIGEON_ATR).unwrap();
+        assert_eq!(
+            parameter.to_string(),
+            "Fi/Di 0x11, direct convention, LRC, extra guard time 0 etu, BWI 6, CWI 5, IFSC 254, NAD 0x00"
+        );
+    }
+
+    #[test]
+    fn test_short_atr() {
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(MemoryBackend::new(&[0x3B])),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(handler.status().current_atr(0), None);
+
+        // PC_to_RDR_GetParameters
+        let response = exchange(
+            &mut handler,
+            &[0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7], 0x40);
+        assert_eq!(response[8], 0x00);
+
+        // PC_to_RDR_IccPowerOn
+        let response = exchange(
+            &mut handler,
+            &[0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(&response[10..], &[0x3B]);
+        assert_eq!(handler.status().current_atr(0), Some(vec![0x3B]));
+    }
+
+    #[test]
+    fn test_clock_stop_policy() {
+        let mut handler = pigeon_handler();
+        // PC_to_RDR_GetParameters: bClockStop of the generated block
+        let response = exchange(
+            &mut handler,
+            &[0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[10 + 4], 0x00);
+
+        const CLOCK_STOP: [u8; 10] = [0x6E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00, 0x00];
+        const CLOCK_RESTART: [u8; 10] =
+            [0x6E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00];
+        let response = exchange(&mut handler, &CLOCK_STOP);
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(response[8], 0x07);
+        assert_eq!(response[9], 0x00);
+
+        // Stop with clock signal in state H
+        handler.parameter.as_mut().unwrap().clock_stop = 0x02;
+        let response = exchange(&mut handler, &CLOCK_STOP);
+        assert_eq!(response[7] & 0xC0, 0x00);
+        assert_eq!(response[9], 0x02);
+        let response = exchange(&mut handler, &CLOCK_RESTART);
+        assert_eq!(response[7] & 0xC0, 0x00);
+        assert_eq!(response[9], 0x00);
+    }
+
+    #[test]
+    fn test_slot_status_clock() {
+        let mut handler = pigeon_handler();
+        const GET_SLOT_STATUS: [u8; 10] =
+            [0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(exchange(&mut handler, &GET_SLOT_STATUS)[9], 0x00);
+
+        // PC_to_RDR_IccClock stopping the clock in state L
+        handler.parameter.as_mut().unwrap().clock_stop = 0x01;
+        let response = exchange(
+            &mut handler,
+            &[0x6E, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0x00, 0x00],
+        );
+        assert_eq!(response[9], 0x01);
+        assert_eq!(exchange(&mut handler, &GET_SLOT_STATUS)[9], 0x01);
+
+        // PC_to_RDR_IccPowerOff, no clock without power
+        let response = exchange(
+            &mut handler,
+            &[0x63, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[9], 0x03);
+        assert_eq!(exchange(&mut handler, &GET_SLOT_STATUS)[9], 0x03);
+    }
+
+    #[test]
+    fn test_get_parameters_live_protocol() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        // Connected for T=1, the reader settled on T=0
+        backend.reported_protocol = Some(Protocol::T0);
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(handler.protocol, ICCProtocol::T1);
+        let response = exchange(
+            &mut handler,
+            &[0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[0], 0x82);
+        assert_eq!(response[9], 0x00);
+        assert_eq!(&response[10..], &[0x11, 0x00, 0x00, 0x0A, 0x00]);
+        assert_eq!(handler.protocol, ICCProtocol::T0);
+    }
+
+    #[test]
+    fn test_set_parameters_protocol() {
+        let backend = MemoryBackend::new(&PIGEON_ATR);
+        let log = backend.log.clone();
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        // PC_to_RDR_SetParameters to T=0, answered with the block of T=0
+        let response = exchange(
+            &mut handler,
+            &[
+                0x61, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x0A,
+                0x00,
+            ],
+        );
+        assert_eq!(response[7] & 0xC0, 0x00);
+        assert_eq!(response[9], 0x00);
+        assert_eq!(&response[10..], &[0x11, 0x00, 0x00, 0x0A, 0x00]);
+        assert_eq!(
+            log.lock().unwrap().protocols,
+            vec![Protocols::T1, Protocols::T0]
+        );
+
+        // PC_to_RDR_GetParameters
+        let response = exchange(
+            &mut handler,
+            &[0x6C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[9], 0x00);
+        assert_eq!(response[10..].len(), 5);
+
+        // PC_to_RDR_SetParameters back to T=1
+        let mut set_t1 = vec![0x61, 0x07, 0x00, 0x00, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00];
+        set_t1.extend([0x11, 0x10, 0x00, 0x00, 0x00, 0xFE, 0x00]);
+        let response = exchange(&mut handler, &set_t1);
+        assert_eq!(response[9], 0x01);
+        assert_eq!(&response[10..], &[0x11, 0x10, 0x00, 0x65, 0x00, 0xFE, 0x00]);
+
+        // A card without T=0 is left powered off
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.protocols = Protocols::T1;
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let response = exchange(
+            &mut handler,
+            &[0x61, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x00, 0x00],
+        );
+        assert_eq!(response[7] & 0xC3, 0x41);
+        assert_eq!(response[8], ccid_const::ICC_PROTOCOL_NOT_SUPPORTED);
+        assert!(!handler.status().is_powered(0));
+    }
+
+    #[test]
+    fn test_no_readers() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.readers.clear();
+        let err = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(
+            err.to_string().starts_with("No PCSC readers found"),
+            "{}",
+            err
+        );
+
+        // Other readers only
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.readers = vec![c"Other Reader 0".to_owned()];
+        let err = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("'Other Reader 0'"), "{}", err);
+
+        // Present, but the card can not be connected
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.protocols = Protocols::T0;
+        let err = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("is present but"), "{}", err);
+    }
+
+    #[test]
+    fn test_t0_procedure_bytes() {
+        let backend = MemoryBackend::new(&PIGEON_ATR)
+            .with_response(Ok(vec![0x61, 0x04]))
+            .with_response(Ok(vec![0x01, 0x02, 0x61, 0x02]))
+            .with_response(Ok(vec![0x03, 0x04, 0x90, 0x00]))
+            .with_response(Ok(vec![0x6C, 0x03]))
+            .with_response(Ok(vec![0xAA, 0xBB, 0xCC, 0x90, 0x00]))
+            .with_response(Ok(vec![0x61, 0x04]));
+        let log = backend.log.clone();
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        // PC_to_RDR_SetParameters to T=0
+        let response = exchange(
+            &mut handler,
+            &[
+                0x61, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x0A,
+                0x00,
+            ],
+        );
+        assert_eq!(response[9], 0x00);
+
+        // Case 4 without Le, the response is fetched with GET RESPONSE
+        let mut case4 = vec![0x6F, 0x07, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00];
+        case4.extend([0x00, 0xA4, 0x04, 0x00, 0x02, 0xAA, 0xBB]);
+        let response = exchange(&mut handler, &case4);
+        assert_eq!(&response[10..], &[0x01, 0x02, 0x03, 0x04, 0x90, 0x00]);
+
+        // Case 2 with a wrong Le is transmitted again with the one of the card
+        let response = exchange(
+            &mut handler,
+            &[
+                0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0xCA, 0x00, 0x6E,
+                0x00,
+            ],
+        );
+        assert_eq!(&response[10..], &[0xAA, 0xBB, 0xCC, 0x90, 0x00]);
+        assert_eq!(
+            log.lock().unwrap().transmitted,
+            vec![
+                vec![0x00, 0xA4, 0x04, 0x00, 0x02, 0xAA, 0xBB],
+                vec![0x00, 0xC0, 0x00, 0x00, 0x04],
+                vec![0x00, 0xC0, 0x00, 0x00, 0x02],
+                vec![0x00, 0xCA, 0x00, 0x6E, 0x00],
+                vec![0x00, 0xCA, 0x00, 0x6E, 0x03],
+            ]
+        );
+        assert_eq!(handler.status().snapshot().counters.apdus, 2);
+
+        // PC_to_RDR_SetParameters back to T=1, which passes 61xx on to the host
+        let mut set_t1 = vec![0x61, 0x07, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x00, 0x00];
+        set_t1.extend([0x11, 0x10, 0x00, 0x00, 0x00, 0xFE, 0x00]);
+        assert_eq!(exchange(&mut handler, &set_t1)[9], 0x01);
+        case4[6] = 0x05;
+        let response = exchange(&mut handler, &case4);
+        assert_eq!(&response[10..], &[0x61, 0x04]);
+    }
+
+    #[test]
+    fn test_t0_get_response_without_data() {
+        let backend = MemoryBackend::new(&PIGEON_ATR)
+            .with_response(Ok(vec![0x61, 0x04]))
+            .with_response(Ok(vec![0x61, 0x04]))
+            .with_response(Ok(vec![0x61, 0x04]));
+        let log = backend.log.clone();
+        let mut handler = CCIDInterfaceHandler::with_config(
+            &FakeUsbDevice::pigeon(),
+            Box::new(backend),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let response = exchange(
+            &mut handler,
+            &[
+                0x61, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x11, 0x00, 0x00, 0x0A,
+                0x00,
+            ],
+        );
+        assert_eq!(response[9], 0x00);
+
+        // GET RESPONSE answered with 61xx alone fails instead of being sent forever
+        let mut case4 = vec![0x6F, 0x07, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00];
+        case4.extend([0x00, 0xA4, 0x04, 0x00, 0x02, 0xAA, 0xBB]);
+        let response = exchange(&mut handler, &case4);
+        assert_eq!(response[7] & 0xC0, 0x40);
+        assert_eq!(log.lock().unwrap().transmitted.len(), 2);
+    }
+}
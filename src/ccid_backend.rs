@@ -0,0 +1,364 @@
+use log::debug;
+use pcsc::{Disposition, Protocol, Protocols, Scope, ShareMode};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::Arc;
+
+/// Start of the names of the PCSC readers of keys, followed by their index
+pub const KEY_READER_NAME: &str = "canokeys.org OpenPGP PIV OATH";
+
+/// Interrupts a blocked call of a [`CCIDBackend`] from another thread, see
+/// [`CCIDBackend::canceller`]
+pub type Canceller = Arc<dyn Fn() -> Result<(), pcsc::Error> + Send + Sync>;
+
+/// Card access used by [`crate::ccid::CCIDInterfaceHandler`]
+///
+/// All operations report the raw PCSC error so the handler can map it to a CCID slot error.
+pub trait CCIDBackend: Send {
+    fn reader_name(&self) -> &CStr;
+
+    fn is_connected(&self) -> bool;
+
+    /// Readers the backend can connect to, by default only its own
+    fn list_readers(&self) -> Result<Vec<CString>, pcsc::Error> {
+        Ok(vec![self.reader_name().to_owned()])
+    }
+
+    fn connect(&mut self, share_mode: ShareMode, protocols: Protocols) -> Result<(), pcsc::Error>;
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<(), pcsc::Error>;
+
+    /// Reset the card and connect it again with `protocols`, connecting a card not connected yet
+    fn reconnect(
+        &mut self,
+        share_mode: ShareMode,
+        protocols: Protocols,
+    ) -> Result<(), pcsc::Error> {
+        self.disconnect(Disposition::ResetCard)?;
+        self.connect(share_mode, protocols)
+    }
+
+    fn atr(&mut self) -> Result<Vec<u8>, pcsc::Error>;
+
+    /// Protocol the reader negotiated with the connected card, `None` if it does not tell
+    fn protocol(&mut self) -> Result<Option<Protocol>, pcsc::Error> {
+        Ok(None)
+    }
+
+    fn transmit<'b>(&mut self, apdu: &[u8], buffer: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error>;
+
+    fn control<'b>(
+        &mut self,
+        control_code: u32,
+        data: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], pcsc::Error>;
+
+    /// Handle making a blocked `transmit` or `control` fail with `pcsc::Error::Cancelled`,
+    /// `None` if calls can not be interrupted
+    fn canceller(&self) -> Option<Canceller> {
+        None
+    }
+}
+
+pub struct PcscBackend {
+    context: pcsc::Context,
+    reader_name: CString,
+    card: Option<pcsc::Card>,
+}
+
+impl PcscBackend {
+    pub fn new(reader_name: &CStr) -> io::Result<PcscBackend> {
+        let context = pcsc::Context::establish(Scope::User).map_err(|e| {
+            io::Error::other(format!(
+                "Failed to create PCSC context, status = '0x{:08X}'",
+                e as u32
+            ))
+        })?;
+        Ok(Self {
+            context,
+            reader_name: reader_name.to_owned(),
+            card: None,
+        })
+    }
+
+    fn card(&mut self) -> Result<&mut pcsc::Card, pcsc::Error> {
+        self.card.as_mut().ok_or(pcsc::Error::InvalidHandle)
+    }
+}
+
+impl CCIDBackend for PcscBackend {
+    fn reader_name(&self) -> &CStr {
+        &self.reader_name
+    }
+
+    fn is_connected(&self) -> bool {
+        self.card.is_some()
+    }
+
+    fn list_readers(&self) -> Result<Vec<CString>, pcsc::Error> {
+        self.context.list_readers_owned()
+    }
+
+    fn connect(&mut self, share_mode: ShareMode, protocols: Protocols) -> Result<(), pcsc::Error> {
+        let card = self
+            .context
+            .connect(&self.reader_name, share_mode, protocols)?;
+        debug!("Connected reader '{}'", self.reader_name.to_string_lossy());
+        self.card = Some(card);
+        Ok(())
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<(), pcsc::Error> {
+        match self.card.take() {
+            Some(card) => card.disconnect(disposition).map_err(|(_, e)| e),
+            None => Ok(()),
+        }
+    }
+
+    fn reconnect(
+        &mut self,
+        share_mode: ShareMode,
+        protocols: Protocols,
+    ) -> Result<(), pcsc::Error> {
+        match self.card.as_mut() {
+            Some(card) => card.reconnect(share_mode, protocols, Disposition::ResetCard),
+            None => self.connect(share_mode, protocols),
+        }
+    }
+
+    fn atr(&mut self) -> Result<Vec<u8>, pcsc::Error> {
+        Ok(self.card()?.status2_owned()?.atr().to_vec())
+    }
+
+    fn protocol(&mut self) -> Result<Option<Protocol>, pcsc::Error> {
+        Ok(self.card()?.status2_owned()?.protocol2())
+    }
+
+    fn transmit<'b>(&mut self, apdu: &[u8], buffer: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error> {
+        let tx = self.card()?.transaction().inspect_err(|e| {
+            debug!("SCardBeginTransaction failed: {}", e);
+        })?;
+        tx.transmit(apdu, buffer)
+    }
+
+    fn control<'b>(
+        &mut self,
+        control_code: u32,
+        data: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], pcsc::Error> {
+        self.card()?.control(control_code.into(), data, buffer)
+    }
+
+    // No canceller, SCardCancel only interrupts SCardGetStatusChange and not SCardTransmit or
+    // SCardControl
+}
+
+/// Names of the PCSC readers currently known
+pub fn list_readers() -> io::Result<Vec<CString>> {
+    pcsc::Context::establish(Scope::User)
+        .and_then(|context| context.list_readers_owned())
+        .map_err(|e| {
+            io::Error::other(format!(
+                "Failed to list PCSC readers, status = '0x{:08X}'",
+                e as u32
+            ))
+        })
+}
+
+/// PCSC readers of keys, each with the serial number of its USB device if the reader driver
+/// tells it
+pub fn key_readers() -> io::Result<Vec<(CString, Option<String>)>> {
+    let error = |e: pcsc::Error| {
+        io::Error::other(format!(
+            "Failed to list PCSC readers, status = '0x{:08X}'",
+            e as u32
+        ))
+    };
+    let context = pcsc::Context::establish(Scope::User).map_err(error)?;
+    let readers = context.list_readers_owned().map_err(error)?;
+    Ok(readers
+        .into_iter()
+        .filter(|reader| reader.to_string_lossy().starts_with(KEY_READER_NAME))
+        .map(|reader| {
+            let serial = reader_serial(&context, &reader);
+            (reader, serial)
+        })
+        .collect())
+}
+
+/// Serial number of the USB device of `reader`, as libccid reports it
+fn reader_serial(context: &pcsc::Context, reader: &CStr) -> Option<String> {
+    // Direct connections work without a card
+    let serial = context
+        .connect(reader, ShareMode::Direct, Protocols::UNDEFINED)
+        .and_then(|card| card.get_attribute_owned(pcsc::Attribute::VendorIfdSerialNo))
+        .map_err(|e| {
+            debug!(
+                "Failed to get serial number of reader '{}': {}",
+                reader.to_string_lossy(),
+                e
+            )
+        })
+        .ok()?;
+    let serial = String::from_utf8_lossy(&serial)
+        .trim_end_matches('\0')
+        .to_owned();
+    (!serial.is_empty()).then_some(serial)
+}
+
+/// Reader among `readers` of the key whose USB device has the serial number `serial`
+///
+/// Fails if the key has no serial number or not exactly one reader has it.
+pub fn paired_reader(
+    readers: &[(CString, Option<String>)],
+    serial: Option<&str>,
+) -> io::Result<CString> {
+    let Some(serial) = serial else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Key has no serial number, its PCSC reader can not be told apart from the ones of the other keys",
+        ));
+    };
+    let matches: Vec<_> = readers
+        .iter()
+        .filter(|(_, reader_serial)| reader_serial.as_deref() == Some(serial))
+        .map(|(reader, _)| reader)
+        .collect();
+    match matches.as_slice() {
+        [reader] => Ok((*reader).clone()),
+        [] => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No PCSC reader of the key with serial number {} found",
+                serial
+            ),
+        )),
+        matches => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "PCSC readers {} all belong to the key with serial number {}",
+                matches
+                    .iter()
+                    .map(|reader| format!("'{}'", reader.to_string_lossy()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                serial
+            ),
+        )),
+    }
+}
+
+/// Reader among `readers` whose name contains `pattern`
+///
+/// When several readers match, `index` picks one of them in list order, without it the
+/// selection is ambiguous and fails listing the matches.
+pub fn select_reader(
+    readers: &[CString],
+    pattern: &str,
+    index: Option<usize>,
+) -> io::Result<CString> {
+    let matches: Vec<_> = readers
+        .iter()
+        .filter(|reader| reader.to_string_lossy().contains(pattern))
+        .collect();
+    let names = || {
+        matches
+            .iter()
+            .map(|reader| format!("'{}'", reader.to_string_lossy()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    match (matches.as_slice(), index) {
+        ([], _) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No PCSC reader matches '{}'", pattern),
+        )),
+        ([reader], None) => Ok((*reader).clone()),
+        (_, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "PCSC readers {} all match '{}', pick one with --reader-index",
+                names(),
+                pattern
+            ),
+        )),
+        (matches, Some(index)) => {
+            matches
+                .get(index)
+                .map(|reader| (*reader).clone())
+                .ok_or(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Reader index {} is out of range, PCSC readers matching '{}': {}",
+                        index,
+                        pattern,
+                        names()
+                    ),
+                ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paired_reader() {
+        let readers = [
+            (
+                c"canokeys.org OpenPGP PIV OATH 0".to_owned(),
+                Some("A0001".to_string()),
+            ),
+            (c"canokeys.org OpenPGP PIV OATH 1".to_owned(), None),
+            (
+                c"canokeys.org OpenPGP PIV OATH 2".to_owned(),
+                Some("B0002".to_string()),
+            ),
+            (
+                c"canokeys.org OpenPGP PIV OATH 3".to_owned(),
+                Some("C0003".to_string()),
+            ),
+            (
+                c"canokeys.org OpenPGP PIV OATH 4".to_owned(),
+                Some("C0003".to_string()),
+            ),
+        ];
+        assert_eq!(
+            paired_reader(&readers, Some("B0002")).unwrap(),
+            readers[2].0
+        );
+        let kind = |serial| paired_reader(&readers, serial).unwrap_err().kind();
+        assert_eq!(kind(None), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(Some("D0004")), io::ErrorKind::NotFound);
+        // Ambiguous
+        assert_eq!(kind(Some("C0003")), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_select_reader() {
+        let readers = [
+            c"canokeys.org OpenPGP PIV OATH 0".to_owned(),
+            c"Generic Smart Card Reader 00 00".to_owned(),
+            c"canokeys.org OpenPGP PIV OATH 1".to_owned(),
+        ];
+        let select = |pattern, index| select_reader(&readers, pattern, index);
+
+        assert_eq!(select("Generic", None).unwrap(), readers[1]);
+        let err = select("canokeys.org", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(
+            err.to_string()
+                .contains("OATH 0', 'canokeys.org OpenPGP PIV OATH 1'")
+        );
+        assert_eq!(select("canokeys.org", Some(0)).unwrap(), readers[0]);
+        assert_eq!(select("canokeys.org", Some(1)).unwrap(), readers[2]);
+        assert!(select("canokeys.org", Some(2)).is_err());
+        assert_eq!(
+            select("Yubico", None).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,3 +1,9 @@
+// Class-specific requests on the control pipe
+
+pub const ABORT: u8 = 0x01;
+pub const GET_CLOCK_FREQUENCIES: u8 = 0x02;
+pub const GET_DATA_RATES: u8 = 0x03;
+
 // Command messages
 
 pub const PC_to_RDR_IccPowerOn: u8 = 0x62;
@@ -0,0 +1,186 @@
+//! CCID class descriptor (Smart Card Device Class Descriptor), CCID 5.1
+use crate::hexdump::hexdump;
+use std::io;
+
+/// `bLength` of the descriptor
+pub const LENGTH: usize = 0x36;
+/// `bDescriptorType` of the descriptor
+pub const DESCRIPTOR_TYPE: u8 = 0x21;
+
+/// Fields of the CCID class descriptor, named after the specification without the type prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CcidFunctionalDescriptor {
+    pub bcd_ccid: u16,
+    pub max_slot_index: u8,
+    pub voltage_support: u8,
+    pub protocols: u32,
+    /// In kHz
+    pub default_clock: u32,
+    pub maximum_clock: u32,
+    pub num_clock_supported: u8,
+    /// In bps
+    pub data_rate: u32,
+    pub max_data_rate: u32,
+    pub num_data_rates_supported: u8,
+    pub max_ifsd: u32,
+    pub synch_protocols: u32,
+    pub mechanical: u32,
+    pub features: u32,
+    pub max_ccid_message_length: u32,
+    pub class_get_response: u8,
+    pub class_envelope: u8,
+    pub lcd_layout: u16,
+    pub pin_support: u8,
+    pub max_ccid_busy_slots: u8,
+}
+
+impl CcidFunctionalDescriptor {
+    /// Parse the descriptor, failing unless it has the length and type of a CCID one
+    pub fn decode(desc: &[u8]) -> io::Result<CcidFunctionalDescriptor> {
+        if desc.len() != LENGTH || desc[0] as usize != LENGTH || desc[1] != DESCRIPTOR_TYPE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid CCID class descriptor {}", hexdump(desc)),
+            ));
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([desc[offset], desc[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(desc[offset..offset + 4].try_into().unwrap());
+        Ok(Self {
+            bcd_ccid: u16_at(2),
+            max_slot_index: desc[4],
+            voltage_support: desc[5],
+            protocols: u32_at(6),
+            default_clock: u32_at(10),
+            maximum_clock: u32_at(14),
+            num_clock_supported: desc[18],
+            data_rate: u32_at(19),
+            max_data_rate: u32_at(23),
+            num_data_rates_supported: desc[27],
+            max_ifsd: u32_at(28),
+            synch_protocols: u32_at(32),
+            mechanical: u32_at(36),
+            features: u32_at(40),
+            max_ccid_message_length: u32_at(44),
+            class_get_response: desc[48],
+            class_envelope: desc[49],
+            lcd_layout: u16_at(50),
+            pin_support: desc[52],
+            max_ccid_busy_slots: desc[53],
+        })
+    }
+
+    /// Check the fields relayed from a device descriptor are plausible, a descriptor of the
+    /// right length may still have a different layout
+    pub fn validate(&self) -> io::Result<()> {
+        let problem = if self.bcd_ccid >> 8 != 0x01 {
+            format!("unknown bcdCCID 0x{:04X}", self.bcd_ccid)
+        } else if self.default_clock == 0 || self.default_clock > self.maximum_clock {
+            format!(
+                "dwDefaultClock {} kHz is not within dwMaximumClock {} kHz",
+                self.default_clock, self.maximum_clock
+            )
+        } else if self.data_rate == 0 || self.data_rate > self.max_data_rate {
+            format!(
+                "dwDataRate {} bps is not within dwMaxDataRate {} bps",
+                self.data_rate, self.max_data_rate
+            )
+        } else {
+            return Ok(());
+        };
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Implausible CCID class descriptor, {}", problem),
+        ))
+    }
+
+    /// The descriptor as sent to the host, `LENGTH` bytes
+    pub fn encode(&self) -> Vec<u8> {
+        let mut desc = Vec::with_capacity(LENGTH);
+        desc.extend_from_slice(&[LENGTH as u8, DESCRIPTOR_TYPE]);
+        desc.extend_from_slice(&self.bcd_ccid.to_le_bytes());
+        desc.extend_from_slice(&[self.max_slot_index, self.voltage_support]);
+        desc.extend_from_slice(&self.protocols.to_le_bytes());
+        desc.extend_from_slice(&self.default_clock.to_le_bytes());
+        desc.extend_from_slice(&self.maximum_clock.to_le_bytes());
+        desc.push(self.num_clock_supported);
+        desc.extend_from_slice(&self.data_rate.to_le_bytes());
+        desc.extend_from_slice(&self.max_data_rate.to_le_bytes());
+        desc.push(self.num_data_rates_supported);
+        desc.extend_from_slice(&self.max_ifsd.to_le_bytes());
+        desc.extend_from_slice(&self.synch_protocols.to_le_bytes());
+        desc.extend_from_slice(&self.mechanical.to_le_bytes());
+        desc.extend_from_slice(&self.features.to_le_bytes());
+        desc.extend_from_slice(&self.max_ccid_message_length.to_le_bytes());
+        desc.extend_from_slice(&[self.class_get_response, self.class_envelope]);
+        desc.extend_from_slice(&self.lcd_layout.to_le_bytes());
+        desc.extend_from_slice(&[self.pin_support, self.max_ccid_busy_slots]);
+        desc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let desc: Vec<u8> = [LENGTH as u8, DESCRIPTOR_TYPE]
+            .into_iter()
+            .chain(2..LENGTH as u8)
+            .collect();
+        let decoded = CcidFunctionalDescriptor::decode(&desc).unwrap();
+        assert_eq!(decoded.bcd_ccid, 0x0302);
+        assert_eq!(decoded.default_clock, 0x0D0C0B0A);
+        assert_eq!(decoded.num_clock_supported, 18);
+        assert_eq!(decoded.data_rate, 0x16151413);
+        assert_eq!(decoded.num_data_rates_supported, 27);
+        assert_eq!(decoded.max_ccid_message_length, 0x2F2E2D2C);
+        assert_eq!(decoded.max_ccid_busy_slots, 53);
+        assert_eq!(decoded.encode(), desc);
+
+        let encoded = CcidFunctionalDescriptor::default().encode();
+        assert_eq!(encoded.len(), LENGTH);
+        assert_eq!(
+            CcidFunctionalDescriptor::decode(&encoded).unwrap(),
+            CcidFunctionalDescriptor::default()
+        );
+
+        assert!(CcidFunctionalDescriptor::decode(&desc[..LENGTH - 1]).is_err());
+        let mut wrong_type = desc.clone();
+        wrong_type[1] = 0x24;
+        assert!(CcidFunctionalDescriptor::decode(&wrong_type).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = CcidFunctionalDescriptor {
+            bcd_ccid: 0x0110,
+            default_clock: 4000,
+            maximum_clock: 4000,
+            data_rate: 10752,
+            max_data_rate: 10752,
+            ..Default::default()
+        };
+        assert!(valid.validate().is_ok());
+        for invalid in [
+            CcidFunctionalDescriptor {
+                bcd_ccid: 0x0302,
+                ..valid
+            },
+            CcidFunctionalDescriptor {
+                default_clock: 0,
+                ..valid
+            },
+            CcidFunctionalDescriptor {
+                data_rate: 20000,
+                ..valid
+            },
+        ] {
+            assert_eq!(
+                invalid.validate().unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+}
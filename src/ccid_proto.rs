@@ -12,11 +12,19 @@ pub struct CommonMessageHeader {
 }
 
 pub enum CCIDError {
-    BadCommand,
+    /// The message ended before `field`, which starts `offset` bytes into it
+    BadCommand {
+        field: &'static str,
+        offset: usize,
+    },
     CommandError(ResponseMessageHeader),
 }
 
 impl CCIDError {
+    pub fn bad_command(field: &'static str, offset: usize) -> CCIDError {
+        CCIDError::BadCommand { field, offset }
+    }
+
     pub fn command_error(
         command: CommonMessageHeader,
         status: SlotStatusRegister,
@@ -33,12 +41,18 @@ impl CCIDError {
 impl Decode for CommonMessageHeader {
     type Error = CCIDError;
     fn decode<T: byteorder::ReadBytesExt>(input: &mut T) -> Result<Self, Self::Error> {
-        let bMessageType = input.read_u8().map_err(|_| CCIDError::BadCommand)?;
+        let bMessageType = input
+            .read_u8()
+            .map_err(|_| CCIDError::bad_command("bMessageType", 0))?;
         let dwLength = input
             .read_u32::<LittleEndian>()
-            .map_err(|_| CCIDError::BadCommand)?;
-        let bSlot = input.read_u8().map_err(|_| CCIDError::BadCommand)?;
-        let bSeq = input.read_u8().map_err(|_| CCIDError::BadCommand)?;
+            .map_err(|_| CCIDError::bad_command("dwLength", 1))?;
+        let bSlot = input
+            .read_u8()
+            .map_err(|_| CCIDError::bad_command("bSlot", 5))?;
+        let bSeq = input
+            .read_u8()
+            .map_err(|_| CCIDError::bad_command("bSeq", 6))?;
         Ok(Self {
             bMessageType,
             dwLength,
@@ -63,7 +77,113 @@ impl Encode for CommonMessageHeader {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// abProtocolDataStructure of T=1, as per CCID 6.1.7
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct T1Parameters {
+    pub bmFindex: u8, // bmFindexDindex
+    pub bmTCCKST1: u8,
+    pub bGuardTime: u8,
+    pub bwi_cwi: u8, // bmWaitingIntegersT1
+    pub clock_stop: u8,
+    pub ifsc: u8,
+    pub nad: u8,
+}
+
+impl Decode for T1Parameters {
+    type Error = CCIDError;
+    fn decode<T: byteorder::ReadBytesExt>(input: &mut T) -> Result<Self, Self::Error> {
+        let mut block = [0u8; 7];
+        input
+            .read_exact(&mut block)
+            // Following the header of PC_to_RDR_SetParameters
+            .map_err(|_| CCIDError::bad_command("abProtocolDataStructure", 10))?;
+        let [
+            bmFindex,
+            bmTCCKST1,
+            bGuardTime,
+            bwi_cwi,
+            clock_stop,
+            ifsc,
+            nad,
+        ] = block;
+        Ok(Self {
+            bmFindex,
+            bmTCCKST1,
+            bGuardTime,
+            bwi_cwi,
+            clock_stop,
+            ifsc,
+            nad,
+        })
+    }
+}
+
+impl Encode for T1Parameters {
+    type Error = ();
+    fn encode<T: byteorder::WriteBytesExt>(&self, out: &mut T) -> Result<(), Self::Error> {
+        out.write_all(&[
+            self.bmFindex,
+            self.bmTCCKST1,
+            self.bGuardTime,
+            self.bwi_cwi,
+            self.clock_stop,
+            self.ifsc,
+            self.nad,
+        ])
+        .expect("T1Parameters: Failed to write parameter block");
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for T1Parameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Fi/Di 0x{:02X}, {} convention, {}, extra guard time {} etu, BWI {}, CWI {}, IFSC {}, NAD 0x{:02X}",
+            self.bmFindex,
+            match self.bmTCCKST1 & 0x02 {
+                0 => "direct",
+                _ => "inverse",
+            },
+            match self.bmTCCKST1 & 0x01 {
+                0 => "LRC",
+                _ => "CRC",
+            },
+            self.bGuardTime,
+            self.bwi_cwi >> 4,
+            self.bwi_cwi & 0x0F,
+            self.ifsc,
+            self.nad
+        )
+    }
+}
+
+/// abProtocolDataStructure of T=0, as per CCID 6.1.7
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct T0Parameters {
+    pub bmFindex: u8, // bmFindexDindex
+    pub bmTCCKST0: u8,
+    pub bGuardTime: u8,
+    pub bWaitingInteger: u8,
+    pub clock_stop: u8,
+}
+
+impl Encode for T0Parameters {
+    type Error = ();
+    fn encode<T: byteorder::WriteBytesExt>(&self, out: &mut T) -> Result<(), Self::Error> {
+        out.write_all(&[
+            self.bmFindex,
+            self.bmTCCKST0,
+            self.bGuardTime,
+            self.bWaitingInteger,
+            self.clock_stop,
+        ])
+        .expect("T0Parameters: Failed to write parameter block");
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum ICCVoltage {
     AUTO,
     V_5_0, // 5V
@@ -211,7 +331,7 @@ impl TryFrom<u8> for ICCStatus {
             0x00 => Ok(ICCStatus::Active),
             0x01 => Ok(ICCStatus::Inactive),
             0x02 => Ok(ICCStatus::Absent),
-            _ => panic!("invalid ICC status value {}", value),
+            _ => Err(()),
         }
     }
 }
@@ -247,7 +367,7 @@ impl TryFrom<u8> for CommandStatus {
             0x00 => Ok(CommandStatus::Success),
             0x01 => Ok(CommandStatus::Failure),
             0x02 => Ok(CommandStatus::TimeExtensionRequested),
-            _ => panic!("invalid command status value {}", value),
+            _ => Err(()),
         }
     }
 }
@@ -271,11 +391,33 @@ pub enum SlotStatusRegister {
 #[allow(dead_code)]
 impl SlotStatusRegister {
     pub fn ICCStatus(self) -> ICCStatus {
-        ICCStatus::try_from(Into::<u8>::into(self) & 0x03).unwrap()
+        match self {
+            SlotStatusRegister::ICCActiveSuccess
+            | SlotStatusRegister::ICCActiveFailure
+            | SlotStatusRegister::ICCActiveTimeExtensionRequested => ICCStatus::Active,
+            SlotStatusRegister::ICCInactiveSuccess
+            | SlotStatusRegister::ICCInactiveFailure
+            | SlotStatusRegister::ICCInactiveTimeExtensionRequested => ICCStatus::Inactive,
+            SlotStatusRegister::ICCAbsentSuccess
+            | SlotStatusRegister::ICCAbsentFailure
+            | SlotStatusRegister::ICCAbsentTimeExtensionRequested => ICCStatus::Absent,
+        }
     }
 
     pub fn CommandStatus(self) -> CommandStatus {
-        CommandStatus::try_from((Into::<u8>::into(self) & 0xC0) >> 6).unwrap()
+        match self {
+            SlotStatusRegister::ICCActiveSuccess
+            | SlotStatusRegister::ICCInactiveSuccess
+            | SlotStatusRegister::ICCAbsentSuccess => CommandStatus::Success,
+            SlotStatusRegister::ICCActiveFailure
+            | SlotStatusRegister::ICCInactiveFailure
+            | SlotStatusRegister::ICCAbsentFailure => CommandStatus::Failure,
+            SlotStatusRegister::ICCActiveTimeExtensionRequested
+            | SlotStatusRegister::ICCInactiveTimeExtensionRequested
+            | SlotStatusRegister::ICCAbsentTimeExtensionRequested => {
+                CommandStatus::TimeExtensionRequested
+            }
+        }
     }
 }
 
@@ -390,7 +532,7 @@ impl TryFrom<u8> for SlotStatusRegister {
 //     }
 // }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ICCProtocol {
     T0,
     T1,
@@ -1324,7 +1466,7 @@ impl Response {
                     abData: Vec::new(),
                 }
             }
-            ccid_const::RDR_to_PC_Escape => {
+            ccid_const::PC_to_RDR_Escape => {
                 header.bMessageType = ccid_const::RDR_to_PC_Escape;
                 Self::RDR_to_PC_Escape {
                     header,
@@ -1333,6 +1475,8 @@ impl Response {
             }
             ccid_const::PC_to_RDR_SetDataRateAndClockFrequency => {
                 header.bMessageType = ccid_const::RDR_to_PC_DataRateAndClockFrequency;
+                // dwClockFrequency and dwDataRate
+                header.dwLength = 8;
                 Self::RDR_to_PC_DataRateAndClockFrequency {
                     header,
                     dwDataRate: 0x0,
@@ -1360,6 +1504,21 @@ impl Response {
         Self::new_with_status(header.inner, header.bStatus, header.bError)
     }
 
+    /// `RDR_to_PC_SlotStatus` answering `command`, whichever command it is
+    pub fn new_slot_status(
+        command: CommonMessageHeader,
+        status: SlotStatusRegister,
+        error: SlotErrorRegister,
+    ) -> Self {
+        let mut header = ResponseMessageHeader::new(command, status, error);
+        header.inner.bMessageType = ccid_const::RDR_to_PC_SlotStatus;
+        header.inner.dwLength = 0x0;
+        Self::RDR_to_PC_SlotStatus {
+            header,
+            bClockStatus: ICCClockStatus::Running,
+        }
+    }
+
     pub fn set_status(&mut self, status: SlotStatusRegister, error: SlotErrorRegister) {
         match self {
             Self::RDR_to_PC_SlotStatus { header, .. }
@@ -1439,6 +1598,8 @@ impl Encode for Response {
             }
             Self::RDR_to_PC_Escape { header, abData } => {
                 header.encode(out)?;
+                out.write_u8(0x00)
+                    .expect("RDR_to_PC_Escape: Failed to write bRFU");
                 out.write_all(abData)
                     .expect("RDR_to_PC_Escape: Failed to write abData");
             }
@@ -1447,12 +1608,15 @@ impl Encode for Response {
                 dwDataRate,
                 dwClockFrequency,
             } => {
+                // bRFU, dwClockFrequency at offset 10, then dwDataRate, as per CCID 6.2.8
                 header.encode(out)?;
-                out.write_u32::<LittleEndian>(*dwDataRate)
-                    .expect("RDR_to_PC_DataRateAndClockFrequency: Failed to write dwDataRate");
+                out.write_u8(0x00)
+                    .expect("RDR_to_PC_DataRateAndClockFrequency: Failed to write bRFU");
                 out.write_u32::<LittleEndian>(*dwClockFrequency).expect(
                     "RDR_to_PC_DataRateAndClockFrequency: Failed to write dwClockFrequency",
                 );
+                out.write_u32::<LittleEndian>(*dwDataRate)
+                    .expect("RDR_to_PC_DataRateAndClockFrequency: Failed to write dwDataRate");
             }
             Self::RDR_to_PC_UnsupportedCommand { header } => {
                 header.encode(out)?;
@@ -1465,4 +1629,127 @@ impl Encode for Response {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_slot_status_decomposition() {
+        use CommandStatus::*;
+        use ICCStatus::*;
+        let variants = [
+            (SlotStatusRegister::ICCActiveSuccess, Active, Success),
+            (SlotStatusRegister::ICCActiveFailure, Active, Failure),
+            (
+                SlotStatusRegister::ICCActiveTimeExtensionRequested,
+                Active,
+                TimeExtensionRequested,
+            ),
+            (SlotStatusRegister::ICCInactiveSuccess, Inactive, Success),
+            (SlotStatusRegister::ICCInactiveFailure, Inactive, Failure),
+            (
+                SlotStatusRegister::ICCInactiveTimeExtensionRequested,
+                Inactive,
+                TimeExtensionRequested,
+            ),
+            (SlotStatusRegister::ICCAbsentSuccess, Absent, Success),
+            (SlotStatusRegister::ICCAbsentFailure, Absent, Failure),
+            (
+                SlotStatusRegister::ICCAbsentTimeExtensionRequested,
+                Absent,
+                TimeExtensionRequested,
+            ),
+        ];
+        for (status, icc, command) in variants {
+            assert_eq!(status.ICCStatus(), icc, "{:?}", status);
+            assert_eq!(status.CommandStatus(), command, "{:?}", status);
+            // Agrees with the bStatus encoding
+            let value: u8 = status.into();
+            assert_eq!(ICCStatus::try_from(value & 0x03), Ok(icc));
+            assert_eq!(CommandStatus::try_from(value >> 6), Ok(command));
+        }
+        assert_eq!(ICCStatus::try_from(0x03), Err(()));
+        assert_eq!(CommandStatus::try_from(0x03), Err(()));
+    }
+
+    #[test]
+    fn test_t1_parameters() {
+        let block = [0x11, 0x10, 0xFF, 0x45, 0x00, 0xFE, 0x00];
+        let parameters = T1Parameters::decode(&mut io::Cursor::new(&block))
+            .ok()
+            .unwrap();
+        assert_eq!(parameters.bmFindex, 0x11);
+        assert_eq!(parameters.bwi_cwi, 0x45);
+        assert_eq!(parameters.ifsc, 0xFE);
+        let mut out = Vec::new();
+        parameters.encode(&mut out).unwrap();
+        assert_eq!(out, block);
+
+        assert!(matches!(
+            T1Parameters::decode(&mut io::Cursor::new(&block[..6])),
+            Err(CCIDError::BadCommand {
+                field: "abProtocolDataStructure",
+                offset: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn test_truncated_command() {
+        // PC_to_RDR_XfrBlock cut off within its header
+        let xfr_block = [0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        for (len, expected_field, expected_offset) in [
+            (0, "bMessageType", 0),
+            (3, "dwLength", 1),
+            (5, "bSlot", 5),
+            (6, "bSeq", 6),
+        ] {
+            match Command::decode(&mut io::Cursor::new(&xfr_block[..len])) {
+                Err(CCIDError::BadCommand { field, offset }) => {
+                    assert_eq!((field, offset), (expected_field, expected_offset));
+                }
+                _ => panic!("{} bytes decoded without BadCommand", len),
+            }
+        }
+    }
+
+    #[test]
+    fn test_data_rate_and_clock_frequency_layout() {
+        // PC_to_RDR_SetDataRateAndClockFrequency, 4000 kHz and 10752 bps
+        let command = [
+            0x73, 0x08, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0xA0, 0x0F, 0x00, 0x00,
+            0x00, 0x2A, 0x00, 0x00,
+        ];
+        let decoded = Command::decode(&mut io::Cursor::new(&command))
+            .ok()
+            .unwrap();
+        let Command::PC_to_RDR_SetDataRateAndClockFrequency {
+            header,
+            dwClockFrequency,
+            dwDataRate,
+            ..
+        } = decoded
+        else {
+            panic!("Decoded {:?}", decoded);
+        };
+        assert_eq!((dwClockFrequency, dwDataRate), (4000, 10752));
+
+        // The response echoes both in the same order
+        let mut response = Response::new(header);
+        if let Response::RDR_to_PC_DataRateAndClockFrequency {
+            dwClockFrequency: clock,
+            dwDataRate: rate,
+            ..
+        } = &mut response
+        {
+            *clock = dwClockFrequency;
+            *rate = dwDataRate;
+        }
+        let mut out = Vec::new();
+        response.encode(&mut out).unwrap();
+        assert_eq!(out[0], ccid_const::RDR_to_PC_DataRateAndClockFrequency);
+        assert_eq!(&out[1..5], &[0x08, 0x00, 0x00, 0x00]);
+        assert_eq!(out[9], 0x00);
+        assert_eq!(&out[10..], &command[10..]);
+    }
+}
@@ -0,0 +1,601 @@
+use crate::apdu_filter::AllowedCommand;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Parser)]
+#[command(version, about = "USB/IP relay for Canokey Pigeon")]
+pub struct Args {
+    /// Control code passed to SCardControl for PC_to_RDR_Escape, in hex (e.g. 0x42000001)
+    #[arg(
+        long,
+        alias = "pcsc-control-code",
+        value_name = "HEX",
+        value_parser = parse_control_code,
+        default_value = "0x42000001"
+    )]
+    pub escape_control_code: u32,
+
+    /// Whether the FIDO/U2F interface must come up, may be replaced by a reserved interface
+    /// when it fails to initialize, or is always reserved
+    #[arg(long, value_enum, default_value_t = InterfaceMode::Optional)]
+    pub fido: InterfaceMode,
+
+    /// Whether the WebUSB interface must come up, is left out when it cannot be found, or is
+    /// always left out
+    #[arg(long, value_enum, default_value_t = InterfaceMode::Optional)]
+    pub webusb: InterfaceMode,
+
+    /// Maximum number of USB/IP clients served at the same time, others are refused
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_clients: u32,
+
+    /// Also accept USB/IP clients over WebSocket on this address, for browser based clients
+    #[arg(long, value_name = "ADDR")]
+    pub ws: Option<SocketAddr>,
+
+    /// Origin of web pages allowed to attach over WebSocket, e.g. https://example.org,
+    /// repeatable. Clients outside browsers send no origin and are always allowed.
+    #[arg(long, value_name = "ORIGIN", requires = "ws")]
+    pub ws_origin: Vec<String>,
+
+    /// Serve APDU counters on this address, as Prometheus metrics on /metrics and JSON on
+    /// /status
+    #[arg(long, value_name = "ADDR")]
+    pub status_listen: Option<SocketAddr>,
+
+    /// Configuration string shown by some hosts, the configuration has no name when omitted
+    #[arg(long, value_name = "NAME", value_parser = parse_config_name)]
+    pub config_name: Option<String>,
+
+    /// CCID class descriptor announced instead of the computed one, in hex (54 bytes)
+    #[arg(long, value_name = "HEX", value_parser = parse_hex_bytes)]
+    // Fully qualified so that clap takes the whole value instead of one byte per occurrence
+    pub ccid_descriptor: Option<::std::vec::Vec<u8>>,
+
+    /// Longest command or response APDU relayed, in bytes, longer ones are rejected with a
+    /// transfer overrun error
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(261..=65544))]
+    pub max_apdu_len: Option<u32>,
+
+    /// Detach kernel drivers bound to the interfaces of the key the relay claims, attaching them
+    /// again on exit
+    #[arg(long)]
+    pub detach_drivers: bool,
+
+    /// Slots announced as usable at the same time, commands beyond fail with a slot busy error.
+    /// Each CCID interface relays a single slot, so this can not exceed 1
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..))]
+    pub max_busy_slots: u8,
+
+    /// Longest block of a response APDU sent at once, longer responses are chained
+    #[arg(long, value_name = "BYTES", default_value_t = crate::ccid::DEFAULT_MAX_IFSD, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_ifsd: u32,
+
+    /// Clock frequencies announced as supported and returned by GET_CLOCK_FREQUENCIES, in kHz,
+    /// comma separated. None, the card manages the clock, by default.
+    #[arg(long, value_name = "KHZ", value_delimiter = ',')]
+    pub clock_frequencies: Vec<u32>,
+
+    /// Data rates announced as supported and returned by GET_DATA_RATES, in bps, comma
+    /// separated. None, the card manages the data rate, by default.
+    #[arg(long, value_name = "BPS", value_delimiter = ',')]
+    pub data_rates: Vec<u32>,
+
+    /// Present the card without passing APDUs to it, they fail with an aborted command error
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Answer APDUs with a mute card error while reporting the card present, for testing how
+    /// hosts handle a dead card
+    #[arg(long)]
+    pub simulate_mute: bool,
+
+    /// Reset the card when the host selects another applet, standing in for the resets of
+    /// WebUSB requests with `--webusb disabled`
+    #[arg(long)]
+    pub reset_on_applet_switch: bool,
+
+    /// Connect the card shared when another process, e.g. gpg-agent, keeps it from being
+    /// connected exclusively, instead of failing
+    #[arg(long)]
+    pub shared_fallback: bool,
+
+    /// Only relay command APDUs with this class and instruction, in hex with `*` for any class
+    /// (e.g. `*:2A`), repeatable. Others are answered with --blocked-sw.
+    #[arg(long, value_name = "CLA:INS", value_parser = crate::apdu_filter::parse_allowed_command)]
+    pub allow_apdu: Vec<AllowedCommand>,
+
+    /// Status word answering APDUs blocked by --allow-apdu, in hex
+    #[arg(long, value_name = "HEX", default_value = "6982", value_parser = parse_status_word)]
+    pub blocked_sw: u16,
+
+    /// Answer unsupported CCID commands with a failed RDR_to_PC_SlotStatus carrying this
+    /// bError, in hex, instead of the dedicated response with bError 0
+    #[arg(long, value_name = "HEX", value_parser = parse_error_code)]
+    pub unsupported_error: Option<u8>,
+
+    /// Abort startup if connecting the card of a reader takes longer than this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub reader_timeout: u64,
+
+    /// Power the card down after this many seconds without CCID commands, it is powered on
+    /// again by the next command
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_timeout: Option<u64>,
+
+    /// Reach the reader of each device through the agent at this address instead of PCSC, in
+    /// device order, repeatable
+    #[arg(long, value_name = "HOST:PORT")]
+    pub remote_reader: Vec<String>,
+
+    /// Use the PCSC reader whose name contains this for the first device, instead of
+    /// `canokeys.org OpenPGP PIV OATH 0`
+    #[arg(long, value_name = "PATTERN")]
+    pub reader: Option<String>,
+
+    /// Which of several readers matching --reader to use, counting from 0 in PCSC order
+    #[arg(long, value_name = "N", requires = "reader")]
+    pub reader_index: Option<usize>,
+
+    /// Relay this PCSC reader as a further CCID interface of the first device, e.g. the
+    /// contactless reader of a dual-interface key, repeatable
+    #[arg(long, value_name = "NAME")]
+    pub extra_reader: Vec<String>,
+
+    /// Relay this HID interface of every key besides FIDO/U2F, by its interface number,
+    /// e.g. a management interface of a composite key, repeatable
+    #[arg(long, value_name = "N")]
+    pub extra_hid_interface: Vec<u8>,
+
+    /// Serve the reader of the first device to a relay on another host at this address,
+    /// instead of relaying devices. Relays are not authenticated, only listen on an address
+    /// reachable by trusted hosts.
+    #[arg(long, value_name = "ADDR")]
+    pub serve_reader: Option<SocketAddr>,
+
+    /// Present the device at Full speed with USB 1.1 descriptors, for clients mishandling High
+    /// speed devices. WebUSB is then not advertised to browsers.
+    #[arg(long)]
+    pub full_speed: bool,
+
+    /// Forward SET_IDLE of the FIDO/U2F interface to the HID device instead of only
+    /// acknowledging it, where the HID library supports it
+    #[arg(long)]
+    pub forward_set_idle: bool,
+
+    /// Keep the cards of a device powered when its client detaches, so that e.g. verified PINs
+    /// survive a reattach. They are powered off after this many seconds without one.
+    #[arg(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "300", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keep_card_powered: Option<u64>,
+
+    /// Abort startup if creating the handlers of a device, which reads its descriptors, takes
+    /// longer than this many seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub probe_timeout: u64,
+
+    /// Timeout of control transfers relayed to the WebUSB interface, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 5000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub control_timeout: u64,
+
+    /// Timeout of HID reads answering FIDO/U2F interrupt IN transfers, in milliseconds
+    #[arg(long, value_name = "MS", default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interrupt_read_timeout: u64,
+
+    /// Wait this long between APDU exchanges, in milliseconds, for readers misbehaving on
+    /// back-to-back commands
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub command_delay: u64,
+
+    /// Times a failed HID write of the FIDO/U2F interface is issued again, unless the key was
+    /// disconnected
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub hid_write_retries: u32,
+
+    /// Answer APDU exchanges and escapes taking longer than this with an aborted command, in
+    /// milliseconds. The card finishes the command meanwhile, its slot is busy until then.
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub transmit_timeout: Option<u64>,
+
+    /// Serial number string of the relayed device, further devices get their index appended
+    #[arg(long, value_name = "SERIAL", value_parser = parse_serial_string, conflicts_with_all = ["random_serial", "mirror_serial"])]
+    pub serial_string: Option<String>,
+
+    /// Give every relayed device a serial number generated at launch
+    #[arg(long, conflicts_with = "mirror_serial")]
+    pub random_serial: bool,
+
+    /// Present the serial number of the relayed key, if it has one
+    #[arg(long)]
+    pub mirror_serial: bool,
+
+    /// Present the USB version and device release number of the relayed key instead of 2.10
+    /// and 1.00, for drivers matching on them
+    #[arg(long)]
+    pub mirror_version: bool,
+
+    /// File to log to, stderr is used instead if it cannot be created
+    #[arg(long, value_name = "PATH", default_value = "smredir.log")]
+    pub log_file: PathBuf,
+
+    /// Log every URB handed to the relayed device and its interfaces
+    #[arg(long)]
+    pub trace_urbs: bool,
+
+    /// Lock this file and write the PID to it once the devices are created, refusing to start
+    /// while another instance holds it
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
+    /// Detach from the terminal and run in the background, Unix only. Needs a PID file to be
+    /// stopped with SIGTERM later.
+    #[arg(long, requires = "pid_file", conflicts_with = "foreground")]
+    pub daemon: bool,
+
+    /// Stay attached to the terminal, the default
+    #[arg(long)]
+    pub foreground: bool,
+
+    /// Start the USB/IP server again after it panicked, instead of exiting
+    #[arg(long)]
+    pub restart_on_panic: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Action taken instead of serving the relayed devices, options go before it
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Print the descriptors the relayed devices present to a client with the given options,
+    /// then exit
+    Dump,
+    /// Power on the card of every relayed device, SELECT the OpenPGP application and power it
+    /// off, then exit, failing if any step does
+    Selftest,
+    /// Print the card insertions and removals PCSC reports for the reader of the first device,
+    /// until Ctrl-C
+    Monitor,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum InterfaceMode {
+    Required,
+    Optional,
+    Disabled,
+}
+
+fn parse_control_code(value: &str) -> Result<u32, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    let code = u32::from_str_radix(digits, 16)
+        .map_err(|e| format!("'{}' is not a 32-bit hexadecimal value: {}", value, e))?;
+    if code == 0 {
+        return Err("control code must not be zero".to_string());
+    }
+    Ok(code)
+}
+
+fn parse_status_word(value: &str) -> Result<u16, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u16::from_str_radix(digits, 16)
+        .map_err(|e| format!("'{}' is not a 16-bit hexadecimal value: {}", value, e))
+}
+
+fn parse_error_code(value: &str) -> Result<u8, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u8::from_str_radix(digits, 16)
+        .map_err(|e| format!("'{}' is not a hexadecimal byte: {}", value, e))
+}
+
+fn parse_hex_bytes(value: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = value.chars().filter(|c| !c.is_whitespace()).collect();
+    if let Some(c) = digits.iter().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(format!("'{}' is not a hexadecimal digit", c));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!(
+            "'{}' has an odd number of hexadecimal digits",
+            value
+        ));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16)
+                .map_err(|e| format!("'{}' is not a hexadecimal byte: {}", byte, e))
+        })
+        .collect()
+}
+
+fn parse_config_name(value: &str) -> Result<String, String> {
+    parse_string_descriptor("configuration name", value)
+}
+
+fn parse_serial_string(value: &str) -> Result<String, String> {
+    parse_string_descriptor("serial number", value)
+}
+
+fn parse_string_descriptor(what: &str, value: &str) -> Result<String, String> {
+    // bLength of a string descriptor is a byte, 2 of them taken by the header
+    const MAX_UTF16_UNITS: usize = (u8::MAX as usize - 2) / 2;
+    if value.is_empty() {
+        return Err(format!("{} must not be empty", what));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(format!("{} must not contain control characters", what));
+    }
+    let units = value.encode_utf16().count();
+    if units > MAX_UTF16_UNITS {
+        return Err(format!(
+            "{} is {} UTF-16 code units long, at most {} are allowed",
+            what, units, MAX_UTF16_UNITS
+        ));
+    }
+    Ok(value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccid::DEFAULT_ESCAPE_CONTROL_CODE;
+
+    #[test]
+    fn test_parse_control_code() {
+        assert_eq!(parse_control_code("0x42000001"), Ok(0x42000001));
+        assert_eq!(parse_control_code("3136B0"), Ok(0x003136B0));
+        assert!(parse_control_code("0").is_err());
+        assert!(parse_control_code("0x").is_err());
+        assert!(parse_control_code("0x100000000").is_err());
+        assert!(parse_control_code("SCARD_CTL_CODE(1)").is_err());
+    }
+
+    #[test]
+    fn test_default_control_code() {
+        let args = Args::parse_from(["smredir"]);
+        assert_eq!(args.escape_control_code, DEFAULT_ESCAPE_CONTROL_CODE);
+        let args = Args::parse_from(["smredir", "--pcsc-control-code", "0x003136B0"]);
+        assert_eq!(args.escape_control_code, 0x003136B0);
+    }
+
+    #[test]
+    fn test_max_clients() {
+        assert_eq!(Args::parse_from(["smredir"]).max_clients, 1);
+        let args = Args::parse_from(["smredir", "--max-clients", "3"]);
+        assert_eq!(args.max_clients, 3);
+        assert!(Args::try_parse_from(["smredir", "--max-clients", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_config_name() {
+        assert_eq!(Args::parse_from(["smredir"]).config_name, None);
+        let args = Args::parse_from(["smredir", "--config-name", "Canokey Relay"]);
+        assert_eq!(args.config_name.as_deref(), Some("Canokey Relay"));
+        assert!(parse_config_name("").is_err());
+        assert!(parse_config_name("a\nb").is_err());
+        assert!(parse_config_name(&"a".repeat(126)).is_ok());
+        assert!(parse_config_name(&"a".repeat(127)).is_err());
+    }
+
+    #[test]
+    fn test_serial_string() {
+        let args = Args::parse_from(["smredir"]);
+        assert_eq!(args.serial_string, None);
+        assert!(!args.random_serial && !args.mirror_serial);
+        let args = Args::parse_from(["smredir", "--serial-string", "RELAY0001"]);
+        assert_eq!(args.serial_string.as_deref(), Some("RELAY0001"));
+        assert!(Args::try_parse_from(["smredir", "--serial-string", ""]).is_err());
+        assert!(Args::try_parse_from(["smredir", "--serial-string", &"0".repeat(127)]).is_err());
+        assert!(
+            Args::try_parse_from(["smredir", "--serial-string", "A", "--random-serial"]).is_err()
+        );
+        assert!(Args::try_parse_from(["smredir", "--random-serial", "--mirror-serial"]).is_err());
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        assert_eq!(Args::parse_from(["smredir"]).idle_timeout, None);
+        let args = Args::parse_from(["smredir", "--idle-timeout", "300"]);
+        assert_eq!(args.idle_timeout, Some(300));
+        assert!(Args::try_parse_from(["smredir", "--idle-timeout", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_remote_reader() {
+        let args = Args::parse_from(["smredir"]);
+        assert!(args.remote_reader.is_empty());
+        assert_eq!(args.serve_reader, None);
+        let args = Args::parse_from([
+            "smredir",
+            "--remote-reader",
+            "reader-host:35963",
+            "--remote-reader",
+            "192.168.1.2:35963",
+        ]);
+        assert_eq!(
+            args.remote_reader,
+            vec!["reader-host:35963", "192.168.1.2:35963"]
+        );
+        assert!(Args::try_parse_from(["smredir", "--serve-reader", "reader-host"]).is_err());
+    }
+
+    #[test]
+    fn test_dump_command() {
+        assert_eq!(Args::parse_from(["smredir"]).command, None);
+        let args = Args::parse_from(["smredir", "--fido", "disabled", "dump"]);
+        assert_eq!(args.command, Some(Command::Dump));
+        assert_eq!(args.fido, InterfaceMode::Disabled);
+        let args = Args::parse_from(["smredir", "selftest"]);
+        assert_eq!(args.command, Some(Command::Selftest));
+        let args = Args::parse_from(["smredir", "--reader", "Generic", "monitor"]);
+        assert_eq!(args.command, Some(Command::Monitor));
+    }
+
+    #[test]
+    fn test_reader_index() {
+        let args = Args::parse_from(["smredir", "--reader", "canokeys", "--reader-index", "1"]);
+        assert_eq!(args.reader.as_deref(), Some("canokeys"));
+        assert_eq!(args.reader_index, Some(1));
+        assert!(Args::try_parse_from(["smredir", "--reader-index", "1"]).is_err());
+    }
+
+    #[test]
+    fn test_keep_card_powered() {
+        assert_eq!(Args::parse_from(["smredir"]).keep_card_powered, None);
+        let args = Args::parse_from(["smredir", "--keep-card-powered"]);
+        assert_eq!(args.keep_card_powered, Some(300));
+        let args = Args::parse_from(["smredir", "--keep-card-powered", "60"]);
+        assert_eq!(args.keep_card_powered, Some(60));
+    }
+
+    #[test]
+    fn test_pid_file() {
+        assert_eq!(Args::parse_from(["smredir"]).pid_file, None);
+        let args = Args::parse_from(["smredir", "--pid-file", "/run/smredir.pid"]);
+        assert_eq!(args.pid_file, Some(PathBuf::from("/run/smredir.pid")));
+    }
+
+    #[test]
+    fn test_clock_frequencies_and_data_rates() {
+        let args = Args::parse_from(["smredir"]);
+        assert!(args.clock_frequencies.is_empty() && args.data_rates.is_empty());
+        let args = Args::parse_from([
+            "smredir",
+            "--clock-frequencies",
+            "4000,8000",
+            "--data-rates",
+            "10752",
+        ]);
+        assert_eq!(args.clock_frequencies, [4000, 8000]);
+        assert_eq!(args.data_rates, [10752]);
+        assert!(Args::try_parse_from(["smredir", "--data-rates", "fast"]).is_err());
+    }
+
+    #[test]
+    fn test_allow_apdu() {
+        let args = Args::parse_from(["smredir"]);
+        assert!(args.allow_apdu.is_empty());
+        assert_eq!(args.blocked_sw, 0x6982);
+        let args = Args::parse_from([
+            "smredir",
+            "--allow-apdu",
+            "00:A4",
+            "--allow-apdu",
+            "*:2A",
+            "--blocked-sw",
+            "0x6D00",
+        ]);
+        assert_eq!(
+            args.allow_apdu,
+            vec![
+                AllowedCommand {
+                    cla: Some(0x00),
+                    ins: 0xA4
+                },
+                AllowedCommand {
+                    cla: None,
+                    ins: 0x2A
+                },
+            ]
+        );
+        assert_eq!(args.blocked_sw, 0x6D00);
+        assert!(Args::try_parse_from(["smredir", "--blocked-sw", "10000"]).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_error() {
+        assert_eq!(Args::parse_from(["smredir"]).unsupported_error, None);
+        let args = Args::parse_from(["smredir", "--unsupported-error", "0xFB"]);
+        assert_eq!(args.unsupported_error, Some(0xFB));
+        assert!(Args::try_parse_from(["smredir", "--unsupported-error", "100"]).is_err());
+    }
+
+    #[test]
+    fn test_daemon() {
+        let args = Args::parse_from(["smredir"]);
+        assert!(!args.daemon && !args.foreground);
+        let args = Args::parse_from(["smredir", "--daemon", "--pid-file", "smredir.pid"]);
+        assert!(args.daemon);
+        // Could not be stopped without a PID file
+        assert!(Args::try_parse_from(["smredir", "--daemon"]).is_err());
+        assert!(
+            Args::try_parse_from([
+                "smredir",
+                "--daemon",
+                "--foreground",
+                "--pid-file",
+                "smredir.pid"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_transfer_timeouts() {
+        let args = Args::parse_from(["smredir"]);
+        assert_eq!(
+            (
+                args.control_timeout,
+                args.interrupt_read_timeout,
+                args.transmit_timeout
+            ),
+            (5000, 4, None)
+        );
+        let args = Args::parse_from(["smredir", "--transmit-timeout", "30000"]);
+        assert_eq!(args.transmit_timeout, Some(30000));
+        assert!(Args::try_parse_from(["smredir", "--control-timeout", "0"]).is_err());
+        assert_eq!(Args::parse_from(["smredir"]).probe_timeout, 10);
+        assert_eq!(Args::parse_from(["smredir"]).reader_timeout, 5);
+        let args = Args::parse_from(["smredir", "--probe-timeout", "30"]);
+        assert_eq!(args.probe_timeout, 30);
+        assert!(Args::try_parse_from(["smredir", "--probe-timeout", "0"]).is_err());
+        assert_eq!(Args::parse_from(["smredir"]).hid_write_retries, 1);
+        let args = Args::parse_from(["smredir", "--hid-write-retries", "0"]);
+        assert_eq!(args.hid_write_retries, 0);
+        assert_eq!(Args::parse_from(["smredir"]).command_delay, 0);
+        let args = Args::parse_from(["smredir", "--command-delay", "20"]);
+        assert_eq!(args.command_delay, 20);
+    }
+
+    #[test]
+    fn test_ws_origin() {
+        let args = Args::parse_from([
+            "smredir",
+            "--ws",
+            "127.0.0.1:3241",
+            "--ws-origin",
+            "https://usbip.example",
+        ]);
+        assert_eq!(args.ws_origin, ["https://usbip.example"]);
+        assert!(Args::try_parse_from(["smredir", "--ws-origin", "https://usbip.example"]).is_err());
+    }
+
+    #[test]
+    fn test_extra_reader() {
+        assert!(Args::parse_from(["smredir"]).extra_reader.is_empty());
+        let args = Args::parse_from(["smredir", "--extra-reader", "Canokey Contactless 00 00"]);
+        assert_eq!(args.extra_reader, ["Canokey Contactless 00 00"]);
+    }
+
+    #[test]
+    fn test_parse_hex_bytes() {
+        assert_eq!(
+            parse_hex_bytes("3621 0001"),
+            Ok(vec![0x36, 0x21, 0x00, 0x01])
+        );
+        assert!(parse_hex_bytes("362").is_err());
+        assert!(parse_hex_bytes("36+1").is_err());
+        assert_eq!(Args::parse_from(["smredir"]).ccid_descriptor, None);
+        let args = Args::parse_from(["smredir", "--ccid-descriptor", "36210001"]);
+        assert_eq!(args.ccid_descriptor, Some(vec![0x36, 0x21, 0x00, 0x01]));
+    }
+}
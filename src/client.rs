@@ -0,0 +1,261 @@
+//! USB/IP client over an in-process connection, drives the server without the kernel
+//!
+//! Only the subset of the protocol the relay is exercised with is implemented:
+//!
+//! - `OP_REQ_DEVLIST`, replied with the bus IDs of the exported devices
+//! - `OP_REQ_IMPORT` of a device by bus ID
+//! - `USBIP_CMD_SUBMIT` of control, bulk and interrupt transfers, isochronous transfers are not
+//!   supported
+//! - `USBIP_CMD_UNLINK` of a submitted transfer
+//!
+//! Replies are read one at a time in the order the server sends them, which may differ from the
+//! order of the requests while a bulk IN is kept pending.
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use usbip::usbip_protocol::{
+    USBIP_CMD_SUBMIT, USBIP_CMD_UNLINK, USBIP_RET_SUBMIT, UsbIpCommand, UsbIpHeaderBasic,
+};
+use usbip::{ConnectionEvent, UsbIpServer};
+
+/// Reply to a submitted or unlinked transfer
+#[derive(Debug, Clone, PartialEq)]
+pub struct UrbReply {
+    pub seqnum: u32,
+    pub status: i32,
+    /// Data of an IN transfer, empty otherwise
+    pub data: Vec<u8>,
+}
+
+pub struct MemoryClient {
+    socket: DuplexStream,
+    connection: JoinHandle<io::Result<()>>,
+    seqnum: u32,
+    // Direction of each submitted transfer, tells whether its reply carries data
+    directions: HashMap<u32, u32>,
+}
+
+impl MemoryClient {
+    pub fn connect(server: Arc<UsbIpServer>) -> MemoryClient {
+        Self::connect_with_events(server, |_| {})
+    }
+
+    /// Connect to `server`, passing attach and detach events to `on_event`
+    pub fn connect_with_events(
+        server: Arc<UsbIpServer>,
+        on_event: impl FnMut(ConnectionEvent) + Send + 'static,
+    ) -> MemoryClient {
+        let (client, mut socket) = tokio::io::duplex(0x10000);
+        let connection =
+            tokio::spawn(
+                async move { usbip::handle_connection(&mut socket, server, on_event).await },
+            );
+        Self {
+            socket: client,
+            connection,
+            seqnum: 0,
+            directions: HashMap::new(),
+        }
+    }
+
+    /// Bus IDs of the devices available for import
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn devlist(&mut self) -> io::Result<Vec<String>> {
+        let request = UsbIpCommand::OpReqDevlist { status: 0 };
+        self.socket.write_all(&request.to_bytes()).await?;
+        let mut reply = [0u8; 12];
+        self.socket.read_exact(&mut reply).await?;
+        let count = u32::from_be_bytes(reply[8..12].try_into().unwrap());
+        let mut bus_ids = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut device = [0u8; 312];
+            self.socket.read_exact(&mut device).await?;
+            let busid = &device[256..288];
+            let length = busid.iter().position(|&b| b == 0).unwrap_or(busid.len());
+            bus_ids.push(String::from_utf8_lossy(&busid[..length]).into_owned());
+            // Class, subclass and protocol of each interface
+            let mut interfaces = vec![0u8; device[311] as usize * 4];
+            self.socket.read_exact(&mut interfaces).await?;
+        }
+        Ok(bus_ids)
+    }
+
+    pub async fn import(&mut self, bus_id: &str) -> io::Result<()> {
+        let mut busid = [0u8; 32];
+        busid[..bus_id.len()].copy_from_slice(bus_id.as_bytes());
+        let import = UsbIpCommand::OpReqImport { status: 0, busid };
+        self.socket.write_all(&import.to_bytes()).await?;
+        let mut reply = [0u8; 8];
+        self.socket.read_exact(&mut reply).await?;
+        if reply[4..8] != [0; 4] {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Failed to import device {}", bus_id),
+            ));
+        }
+        self.socket.read_exact(&mut [0u8; 312]).await?;
+        Ok(())
+    }
+
+    /// Submit a transfer on `ep`, `data` for OUT transfers or `length` bytes of IN ones, returns
+    /// its seqnum
+    pub async fn submit(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        data: &[u8],
+        length: u32,
+    ) -> io::Result<u32> {
+        let direction = ((ep & 0x80) >> 7) as u32;
+        let seqnum = self.next_seqnum();
+        let submit = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid: 0,
+                direction,
+                ep: (ep & 0x7F) as u32,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: if direction == 0 {
+                data.len() as u32
+            } else {
+                length
+            },
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+            data: data.to_vec(),
+            iso_packet_descriptor: vec![],
+        };
+        self.socket.write_all(&submit.to_bytes()).await?;
+        self.directions.insert(seqnum, direction);
+        Ok(seqnum)
+    }
+
+    /// Unlink the transfer of `unlink_seqnum`, returns the seqnum of the unlink
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn unlink(&mut self, unlink_seqnum: u32) -> io::Result<u32> {
+        let seqnum = self.next_seqnum();
+        let unlink = UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum,
+                devid: 0,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum,
+        };
+        self.socket.write_all(&unlink.to_bytes()).await?;
+        Ok(seqnum)
+    }
+
+    /// Read the next reply of the server
+    pub async fn reply(&mut self) -> io::Result<UrbReply> {
+        let mut header = [0u8; 48];
+        self.socket.read_exact(&mut header).await?;
+        let field =
+            |offset: usize| u32::from_be_bytes(header[offset..offset + 4].try_into().unwrap());
+        let seqnum = field(4);
+        let mut data = vec![];
+        if field(0) == USBIP_RET_SUBMIT as u32 && self.directions.remove(&seqnum) == Some(1) {
+            data.resize(field(24) as usize, 0);
+            self.socket.read_exact(&mut data).await?;
+        }
+        Ok(UrbReply {
+            seqnum,
+            status: field(20) as i32,
+            data,
+        })
+    }
+
+    /// Submit a transfer and wait for its reply
+    pub async fn transfer(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        data: &[u8],
+        length: u32,
+    ) -> io::Result<UrbReply> {
+        let seqnum = self.submit(ep, setup, data, length).await?;
+        let reply = self.reply().await?;
+        if reply.seqnum != seqnum {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected reply to {}, got {}", seqnum, reply.seqnum),
+            ));
+        }
+        Ok(reply)
+    }
+
+    /// Close the connection, which returns an imported device to the available ones
+    pub async fn close(self) -> io::Result<()> {
+        drop(self.socket);
+        self.connection.await.map_err(io::Error::other)?
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        self.seqnum += 1;
+        self.seqnum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::build_relay;
+    use crate::fake::{MemoryBackend, PIGEON_ATR, pigeon_relay};
+
+    #[tokio::test]
+    async fn test_power_on_and_apdu() {
+        let backend = MemoryBackend::new(&PIGEON_ATR);
+        let log = backend.log.clone();
+        let config = pigeon_relay(backend);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            build_relay(0, config).unwrap(),
+        ]));
+        let mut client = MemoryClient::connect(server);
+        assert_eq!(client.devlist().await.unwrap(), vec!["0-0-0".to_string()]);
+        assert!(client.import("0-0-9").await.is_err());
+        client.import("0-0-0").await.unwrap();
+
+        // SET_CONFIGURATION(1)
+        let reply = client
+            .transfer(
+                0x00,
+                [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+                &[],
+                0,
+            )
+            .await
+            .unwrap();
+        assert_eq!(reply.status, 0);
+
+        // PC_to_RDR_IccPowerOn answered with the ATR
+        let power_on = [0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        let reply = client.transfer(0x01, [0; 8], &power_on, 0).await.unwrap();
+        assert_eq!(reply.status, 0);
+        let reply = client.transfer(0x81, [0; 8], &[], 0x200).await.unwrap();
+        assert_eq!(reply.data[0], 0x80);
+        assert_eq!(reply.data[6], 0x01);
+        assert_eq!(&reply.data[10..], &PIGEON_ATR);
+
+        // PC_to_RDR_XfrBlock with SELECT
+        let mut xfr_block = vec![0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00];
+        xfr_block.extend([0x00, 0xA4, 0x04, 0x00, 0x00]);
+        client.transfer(0x01, [0; 8], &xfr_block, 0).await.unwrap();
+        let reply = client.transfer(0x81, [0; 8], &[], 0x200).await.unwrap();
+        assert_eq!(reply.data[6], 0x02);
+        assert_eq!(&reply.data[10..], &[0x90, 0x00]);
+        assert_eq!(
+            log.lock().unwrap().transmitted,
+            vec![vec![0x00, 0xA4, 0x04, 0x00, 0x00]]
+        );
+
+        client.close().await.unwrap();
+    }
+}
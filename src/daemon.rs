@@ -0,0 +1,172 @@
+//! Running in the background with `--daemon`
+//!
+//! Forking a process running a tokio runtime is not safe, so the daemon is a new instance of the
+//! executable started with `--foreground` instead, in its own session and without a terminal.
+//! It logs to the log file and writes the PID file itself once its devices are created, the
+//! instance that started it exits once the PID file names the daemon.
+#[cfg(unix)]
+use crate::pidfile::PidFile;
+#[cfg(unix)]
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+#[cfg(unix)]
+use std::process::Child;
+#[cfg(unix)]
+use std::time::{Duration, Instant};
+
+/// Time given to the daemon to write its PID file, above the default `--probe-timeout`
+#[cfg(unix)]
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Arguments of the daemon, `args` without the executable and `--daemon` replaced by
+/// `--foreground`
+#[cfg(unix)]
+pub fn daemon_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    args.into_iter()
+        .skip(1)
+        .map(|arg| match arg == "--daemon" {
+            true => OsString::from("--foreground"),
+            false => arg,
+        })
+        .collect()
+}
+
+/// Start this executable as a daemon writing `pid_file` and return its PID once it did
+#[cfg(unix)]
+pub fn spawn(pid_file: &Path) -> io::Result<u32> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    // Refused here, while errors still reach the terminal
+    PidFile::check(pid_file)?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(daemon_args(std::env::args_os()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // In a new session without a controlling terminal, Ctrl-C and SIGHUP of the terminal miss it
+    // and it can not acquire the terminal again
+    // SAFETY: setsid is async-signal-safe and touches no memory of the parent
+    unsafe {
+        command.pre_exec(|| match libc::setsid() {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        });
+    }
+    let mut child = command.spawn()?;
+    wait_started(&mut child, pid_file, START_TIMEOUT)?;
+    Ok(child.id())
+}
+
+#[cfg(not(unix))]
+pub fn spawn(_pid_file: &Path) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Daemon mode is only supported on Unix",
+    ))
+}
+
+/// Wait until `pid_file` holds the PID of `child`, failing if it exits first
+#[cfg(unix)]
+fn wait_started(child: &mut Child, pid_file: &Path, timeout: Duration) -> io::Result<()> {
+    let pid = child.id().to_string();
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(io::Error::other(format!(
+                "Daemon exited with {}, see the log file",
+                status
+            )));
+        }
+        if std::fs::read_to_string(pid_file).is_ok_and(|written| written.trim() == pid) {
+            return Ok(());
+        }
+        if start.elapsed() > timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Daemon (PID {}) did not write '{}' in time",
+                    pid,
+                    pid_file.display()
+                ),
+            ));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Wait for Ctrl-C or SIGTERM and return the name of the signal, both shut the relay down
+#[cfg(unix)]
+pub async fn shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = terminate.recv() => "SIGTERM",
+        },
+        Err(e) => {
+            log::warn!("Failed to handle SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            "SIGINT"
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn shutdown_signal() -> &'static str {
+    let _ = tokio::signal::ctrl_c().await;
+    "Ctrl-C"
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_daemon_args() {
+        let args = ["smredir", "--daemon", "--pid-file", "/run/smredir.pid"].map(OsString::from);
+        assert_eq!(
+            daemon_args(args),
+            ["--foreground", "--pid-file", "/run/smredir.pid"].map(OsString::from)
+        );
+    }
+
+    #[test]
+    fn test_wait_started() {
+        let path = std::env::temp_dir().join(format!("smredir-daemon-{}.pid", std::process::id()));
+        // Held by another instance
+        let held = PidFile::acquire(&path).unwrap();
+        let err = PidFile::check(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        drop(held);
+        PidFile::check(&path).unwrap();
+        assert!(!path.exists());
+
+        // Not started while the daemon holds the file but still creates its devices
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        let acquired = PidFile::acquire(&path).unwrap();
+        let err = wait_started(&mut child, &path, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        drop(acquired);
+
+        // Started once the PID file names the daemon
+        std::fs::write(&path, format!("{}\n", child.id())).unwrap();
+        wait_started(&mut child, &path, Duration::from_secs(5)).unwrap();
+        // A stale PID is not enough
+        std::fs::write(&path, "1\n").unwrap();
+        let err = wait_started(&mut child, &path, Duration::ZERO).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Exiting before is reported
+        let mut child = Command::new("false").spawn().unwrap();
+        let err = wait_started(&mut child, &path, Duration::from_secs(5)).unwrap_err();
+        assert!(err.to_string().starts_with("Daemon exited with"), "{}", err);
+    }
+}
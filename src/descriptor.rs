@@ -0,0 +1,226 @@
+use crate::hexdump::hexdump;
+use std::fmt::Write;
+use std::io;
+
+const CONFIGURATION: u8 = 0x02;
+const INTERFACE: u8 = 0x04;
+const ENDPOINT: u8 = 0x05;
+const CLASS_SPECIFIC: u8 = 0x21;
+
+/// Interface descriptor and what follows it until the next interface
+struct InterfaceLayout {
+    number: u8,
+    class: u8,
+    num_endpoints: u8,
+    endpoints: u8,
+    class_specific_len: Option<u8>,
+}
+
+impl InterfaceLayout {
+    fn check(&self) -> io::Result<()> {
+        if self.endpoints != self.num_endpoints {
+            return Err(invalid(format!(
+                "Interface {} announces {} endpoints, found {}",
+                self.number, self.num_endpoints, self.endpoints
+            )));
+        }
+        // HID descriptor, CCID class descriptor
+        let expected = match self.class {
+            0x03 => Some(0x09),
+            0x0B => Some(0x36),
+            _ => None,
+        };
+        if let Some(length) = expected
+            && self.class_specific_len != expected
+        {
+            return Err(invalid(format!(
+                "Interface {} of class 0x{:02X} expects a class specific descriptor of {} bytes, found {:?}",
+                self.number, self.class, length, self.class_specific_len
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Check the layout of an assembled configuration descriptor
+///
+/// Every HID and CCID interface must carry its class specific descriptor between the interface
+/// descriptor and its endpoint descriptors, and the descriptor counts must match the headers.
+pub fn check_configuration(desc: &[u8]) -> io::Result<()> {
+    if desc.len() < 9 || desc[0] != 9 || desc[1] != CONFIGURATION {
+        return Err(invalid(
+            "Invalid configuration descriptor header".to_string(),
+        ));
+    }
+    let total_length = u16::from_le_bytes([desc[2], desc[3]]) as usize;
+    if total_length != desc.len() {
+        return Err(invalid(format!(
+            "Configuration descriptor length mismatch, buffer length: {}, total length: {}",
+            desc.len(),
+            total_length
+        )));
+    }
+    let mut interfaces = Vec::<InterfaceLayout>::new();
+    let mut data = &desc[9..];
+    while !data.is_empty() {
+        let length = data[0] as usize;
+        if length < 2 || length > data.len() {
+            return Err(invalid(format!(
+                "Invalid descriptor length {} at offset {}",
+                length,
+                desc.len() - data.len()
+            )));
+        }
+        let current = interfaces.last_mut();
+        match (data[1], current) {
+            (INTERFACE, _) if length >= 9 => interfaces.push(InterfaceLayout {
+                number: data[2],
+                class: data[5],
+                num_endpoints: data[4],
+                endpoints: 0,
+                class_specific_len: None,
+            }),
+            (ENDPOINT, Some(interface)) => interface.endpoints += 1,
+            (CLASS_SPECIFIC, Some(interface)) => {
+                if interface.endpoints > 0 {
+                    return Err(invalid(format!(
+                        "Class specific descriptor of interface {} follows its endpoints",
+                        interface.number
+                    )));
+                }
+                interface.class_specific_len = Some(length as u8);
+            }
+            (INTERFACE | ENDPOINT | CLASS_SPECIFIC, _) => {
+                return Err(invalid(format!(
+                    "Unexpected descriptor of type 0x{:02X} at offset {}",
+                    data[1],
+                    desc.len() - data.len()
+                )));
+            }
+            _ => {}
+        }
+        data = &data[length..];
+    }
+    if interfaces.len() != desc[4] as usize {
+        return Err(invalid(format!(
+            "Configuration announces {} interfaces, found {}",
+            desc[4],
+            interfaces.len()
+        )));
+    }
+    interfaces.iter().try_for_each(InterfaceLayout::check)
+}
+
+/// Annotated hex dump of a device descriptor and a configuration descriptor, one descriptor per
+/// entry, followed by the result of [`check_configuration`]
+pub fn dump(device: &[u8], configuration: &[u8]) -> String {
+    let mut out = String::new();
+    if device.len() >= 18 {
+        writeln!(
+            out,
+            "Device: bcdUSB {:04X}, class 0x{:02X}, VID {:04X}, PID {:04X}, bcdDevice {:04X}, {} configurations",
+            u16::from_le_bytes([device[2], device[3]]),
+            device[4],
+            u16::from_le_bytes([device[8], device[9]]),
+            u16::from_le_bytes([device[10], device[11]]),
+            u16::from_le_bytes([device[12], device[13]]),
+            device[17]
+        )
+        .unwrap();
+    } else {
+        out.push_str("Device: invalid device descriptor\n");
+    }
+    writeln!(out, "  {}", hexdump(device)).unwrap();
+
+    let mut data = configuration;
+    while data.len() >= 2 && data[0] >= 2 && data[0] as usize <= data.len() {
+        let (desc, rest) = data.split_at(data[0] as usize);
+        let (indent, annotation) = match desc[1] {
+            CONFIGURATION if desc.len() >= 9 => (
+                "",
+                format!(
+                    "Configuration {}: {} interfaces, attributes 0x{:02X}, max power {} mA",
+                    desc[5],
+                    desc[4],
+                    desc[7],
+                    desc[8] as u32 * 2
+                ),
+            ),
+            INTERFACE if desc.len() >= 9 => (
+                "  ",
+                format!(
+                    "Interface {}: alternate {}, class 0x{:02X}/0x{:02X}/0x{:02X}, {} endpoints",
+                    desc[2], desc[3], desc[5], desc[6], desc[7], desc[4]
+                ),
+            ),
+            ENDPOINT if desc.len() >= 7 => (
+                "    ",
+                format!(
+                    "Endpoint 0x{:02X}: attributes 0x{:02X}, max packet size {}, interval {}",
+                    desc[2],
+                    desc[3],
+                    u16::from_le_bytes([desc[4], desc[5]]),
+                    desc[6]
+                ),
+            ),
+            CLASS_SPECIFIC => (
+                "    ",
+                format!("Class specific 0x{:02X}: {} bytes", desc[1], desc.len()),
+            ),
+            other => (
+                "    ",
+                format!("Descriptor 0x{:02X}: {} bytes", other, desc.len()),
+            ),
+        };
+        writeln!(
+            out,
+            "{}{}\n{}  {}",
+            indent,
+            annotation,
+            indent,
+            hexdump(desc)
+        )
+        .unwrap();
+        data = rest;
+    }
+    match check_configuration(configuration) {
+        Ok(()) => out.push_str("Configuration descriptor is valid\n"),
+        Err(e) => writeln!(out, "Invalid configuration descriptor: {}", e).unwrap(),
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeUsbDevice;
+
+    #[test]
+    fn test_check_configuration() {
+        let configuration = FakeUsbDevice::pigeon().configuration;
+        check_configuration(&configuration).unwrap();
+
+        // Interrupt IN endpoint of the HID interface moved before the HID descriptor
+        let mut swapped = configuration.clone();
+        let hid = 18;
+        let endpoint: Vec<u8> = swapped.drain(hid + 9..hid + 16).collect();
+        swapped.splice(hid..hid, endpoint);
+        assert!(check_configuration(&swapped).is_err());
+
+        // CCID interface without its class descriptor
+        let mut missing = configuration.clone();
+        let ccid = missing.len() - 14 - 0x36;
+        missing.drain(ccid..ccid + 0x36);
+        let total_length = missing.len() as u16;
+        missing[2..4].copy_from_slice(&total_length.to_le_bytes());
+        assert!(check_configuration(&missing).is_err());
+
+        let mut truncated = configuration;
+        truncated.pop();
+        assert!(check_configuration(&truncated).is_err());
+    }
+}
@@ -1,15 +1,41 @@
-use log::{debug, error};
+use crate::ccid::{CCIDConfig, CCIDInterfaceHandler};
+use crate::ccid_backend::CCIDBackend;
+use crate::cli::InterfaceMode;
+use crate::fido::FIDOInterfaceHandler;
+use crate::hexdump::hexdump;
+use crate::hid_backend::HidApiBackend;
+use crate::panic_guard::CatchPanic;
+use crate::reserved::{ReservedInterfaceHandler, optional_interface};
+use crate::trace;
+use crate::transfer::TransferConfig;
+use crate::usb_backend::UsbBackend;
+use crate::webusb::WebUSBInterfaceHandler;
+use futures_core::Stream;
+use log::{debug, error, info};
+use nusb::hotplug::HotplugEvent;
 use nusb::transfer;
 use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
 use std::any::Any;
 use std::cell::OnceCell;
 use std::fmt::{Debug, Formatter};
+use std::future::poll_fn;
+use std::hash::{BuildHasher, Hasher, RandomState};
 use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex};
-use usbip::{DescriptorType, SetupPacket, StandardRequest, UsbDeviceHandler, UsbInterfaceHandler};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use usbip::{
+    DescriptorType, SetupPacket, StandardRequest, UsbDevice, UsbDeviceHandler, UsbInterfaceHandler,
+    UsbSpeed,
+};
+
+/// Interface number and handler of a vendor specific interface
+pub type VendorHandler = (u8, Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>);
 
 pub struct CanokeyVirtDeviceHandler {
-    vendor_handlers: Vec<Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+    vendor_handlers: Vec<VendorHandler>,
     bos_descriptors: OnceCell<Vec<u8>>,
 }
 
@@ -113,13 +139,102 @@ impl<'a> ControlSetup<'a> {
     }
 }
 
+/// Re-resolve the interfaces behind `handlers` against `device`, e.g. after it re-enumerated
+///
+/// Every handler is refreshed even if an earlier one fails; the first error is returned.
+pub fn refresh_interfaces(
+    device: &Arc<dyn UsbBackend>,
+    hidapi: &dyn HidApiBackend,
+    handlers: &[Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>],
+) -> io::Result<()> {
+    let mut result = Ok(());
+    for handler in handlers {
+        let mut handler = handler.lock().unwrap();
+        let handler = handler.as_any();
+        let refreshed = if let Some(ccid) = handler.downcast_mut::<CCIDInterfaceHandler>() {
+            ccid.refresh(device.as_ref())
+        } else if let Some(fido) = handler.downcast_mut::<FIDOInterfaceHandler>() {
+            fido.refresh(device.as_ref(), hidapi)
+        } else if let Some(webusb) = handler.downcast_mut::<WebUSBInterfaceHandler>() {
+            webusb.refresh(device.clone())
+        } else {
+            Ok(())
+        };
+        if let Err(e) = refreshed {
+            error!("Failed to refresh interface handler: {}", e);
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result
+}
+
+/// Wait between a key being plugged in and its interfaces being resolved again
+const REPLUG_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Refresh the interfaces of each relay in `relays` when the physical device with its serial
+/// number is plugged in again, opened with `open`
+///
+/// Runs until watching for hotplug events fails.
+pub async fn refresh_on_replug(
+    relays: Vec<(String, UsbDevice)>,
+    open: impl Fn(&nusb::DeviceInfo) -> io::Result<Arc<dyn UsbBackend>> + Send + Sync + 'static,
+) -> io::Result<()> {
+    let mut watch = nusb::watch_devices()
+        .map_err(|e| io::Error::other(format!("Failed to watch USB devices: {}", e)))?;
+    let open = Arc::new(open);
+    while let Some(event) = poll_fn(|cx| Pin::new(&mut watch).poll_next(cx)).await {
+        let HotplugEvent::Connected(info) = event else {
+            continue;
+        };
+        let Some((serial, relay)) = relays.iter().find(|(serial, relay)| {
+            info.vendor_id() == relay.vendor_id
+                && info.product_id() == relay.product_id
+                && info.serial_number() == Some(serial.as_str())
+        }) else {
+            continue;
+        };
+        info!("Device {} was plugged in again", serial);
+        // The HID device of a key shows up after its USB device
+        tokio::time::sleep(REPLUG_SETTLE_TIME).await;
+        let relay = relay.clone();
+        let open = open.clone();
+        let refreshed = tokio::task::spawn_blocking(move || {
+            let device = open(&info)?;
+            let hidapi = hidapi::HidApi::new().map_err(|e| {
+                io::Error::other(format!("Failed to initialize HID API library: {}", e))
+            })?;
+            let handlers: Vec<_> = relay
+                .interfaces
+                .iter()
+                .map(|interface| interface.handler.clone())
+                .collect();
+            refresh_interfaces(&device, &hidapi, &handlers)?;
+            Ok::<_, io::Error>(relay.bus_id)
+        })
+        .await
+        .unwrap();
+        match refreshed {
+            Ok(bus_id) => info!("Refreshed interfaces of device {}", bus_id),
+            Err(e) => error!("Failed to refresh device {}: {}", serial, e),
+        }
+    }
+    Ok(())
+}
+
 impl CanokeyVirtDeviceHandler {
-    pub fn new(handlers: &[Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>]) -> Self {
+    pub fn new(handlers: &[VendorHandler]) -> Self {
         Self {
             vendor_handlers: handlers.to_vec(),
             bos_descriptors: OnceCell::new(),
         }
     }
+
+    /// Drop the cached BOS descriptor, assembled again on the next request
+    pub fn reset(&mut self) {
+        self.bos_descriptors = OnceCell::new();
+    }
 }
 
 impl UsbDeviceHandler for CanokeyVirtDeviceHandler {
@@ -129,9 +244,23 @@ impl UsbDeviceHandler for CanokeyVirtDeviceHandler {
         setup: SetupPacket,
         req: &[u8],
     ) -> io::Result<Vec<u8>> {
+        trace::urb("device", 0x00, transfer_buffer_length, &setup, req);
         let control = ControlSetup::new(&setup, Some(req))?;
         if control.control_type() == ControlType::Vendor {
-            for handler in self.vendor_handlers.iter_mut() {
+            // A request naming a vendor interface in the low byte of wIndex belongs to it alone
+            let target = self
+                .vendor_handlers
+                .iter()
+                .find(|(number, _)| *number == control.index() as u8);
+            if let Some((_, handler)) = target {
+                return handler.lock().unwrap().handle_device_urb(
+                    transfer_buffer_length,
+                    setup,
+                    req,
+                );
+            }
+            // Others such as WebUSB GET_URL have no interface, wIndex is the request there
+            for (_, handler) in self.vendor_handlers.iter_mut() {
                 match handler
                     .lock()
                     .unwrap()
@@ -149,7 +278,17 @@ impl UsbDeviceHandler for CanokeyVirtDeviceHandler {
         }
         const GET_STATUS: u8 = StandardRequest::GetStatus as u8;
         const GET_DESCRIPTOR: u8 = StandardRequest::GetDescriptor as u8;
+        const SET_ADDRESS: u8 = StandardRequest::SetAddress as u8;
         match control {
+            // The client's host controller addresses the device, there is nothing to change
+            ControlSetup::Out(control)
+                if control.control_type == ControlType::Standard
+                    && control.recipient == Recipient::Device
+                    && control.request == SET_ADDRESS =>
+            {
+                debug!("Acknowledging SET_ADDRESS {}", control.value);
+                Ok(vec![])
+            }
             ControlSetup::In(control)
                 if control.control_type == ControlType::Standard
                     && control.request == GET_STATUS =>
@@ -170,7 +309,7 @@ impl UsbDeviceHandler for CanokeyVirtDeviceHandler {
                             0x00, // bNumDeviceCaps
                         ];
                         let mut capability_descriptors = Vec::new();
-                        for handler in self.vendor_handlers.iter() {
+                        for (_, handler) in self.vendor_handlers.iter() {
                             capability_descriptors.extend(handler.lock().unwrap().get_device_capability_descriptors());
                         }
                         let total_length = capability_descriptors.iter().fold(5usize, |v, d| { v + d.len() });
@@ -188,12 +327,13 @@ impl UsbDeviceHandler for CanokeyVirtDeviceHandler {
                         bos_descriptors.extend(total_length.to_le_bytes());
                         bos_descriptors.push(capability_descriptors.len() as u8);
                         capability_descriptors.into_iter().for_each(|v| bos_descriptors.extend(v));
-                        debug!("On init Device capability descriptors {:02X?}", bos_descriptors);
+                        debug!("On init Device capability descriptors {}", hexdump(&bos_descriptors));
                         bos_descriptors
                     }).clone())
             }
+            // Unsupported requests STALL like those of the interfaces
             _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
+                io::ErrorKind::Unsupported,
                 format!("Unknown setup request for device: {:02X?}", setup),
             )),
         }
@@ -203,3 +343,1076 @@ impl UsbDeviceHandler for CanokeyVirtDeviceHandler {
         self
     }
 }
+
+/// Assemble the relayed device from its interface handlers, numbered in order
+///
+/// Without FIDO/U2F handler interface 0 is kept as a reserved interface, while a missing WebUSB
+/// interface is left out and the CCID interfaces take its number. The FIDO/U2F interface keeps
+/// the number it has on the physical device as far as the numbers stay contiguous, the others
+/// fill the remaining ones. Every CCID interface after the first uses the next odd endpoint
+/// number, as 2 belongs to FIDO/U2F. Further HID interfaces come last, with the next even
+/// endpoint numbers from 4. `index` makes the bus id of the device unique on the server.
+pub fn relay_device(
+    index: u32,
+    fido: Option<FIDOInterfaceHandler>,
+    hid: Vec<FIDOInterfaceHandler>,
+    webusb: Option<Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+    ccid: Vec<Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+) -> UsbDevice {
+    let fido_number = fido
+        .as_ref()
+        .map_or(0, FIDOInterfaceHandler::interface_number)
+        .min((ccid.len() + webusb.iter().len()) as u8);
+    let mut numbers = (0..).filter(|number| *number != fido_number);
+    let (fido_class, fido_endpoints, fido_handler) = match fido {
+        Some(handler) => (0x03, FIDOInterfaceHandler::endpoints(), shared(handler)),
+        None => (0xFF, vec![], shared(ReservedInterfaceHandler::new())),
+    };
+    let mut interfaces = vec![(
+        fido_number,
+        (fido_class, 0x00, 0x00),
+        "FIDO/U2F".to_string(),
+        fido_endpoints,
+        fido_handler,
+    )];
+    let mut vendor_handlers = Vec::new();
+    if let Some(webusb) = webusb {
+        let number = numbers.next().unwrap();
+        vendor_handlers.push((number, webusb.clone()));
+        interfaces.push((
+            number,
+            (0xFF, 0xFF, 0xFF),
+            "WebUSB".to_string(),
+            vec![],
+            webusb,
+        ));
+    }
+    for (i, ccid) in ccid.into_iter().enumerate() {
+        let name = match i {
+            0 => "OpenPGP PIV OATH".to_string(),
+            i => format!("OpenPGP PIV OATH {}", i + 1),
+        };
+        interfaces.push((
+            numbers.next().unwrap(),
+            (0x0B, 0x00, 0x00),
+            name,
+            CCIDInterfaceHandler::endpoints_at(0x01 + 2 * i as u8),
+            ccid,
+        ));
+    }
+    for (i, hid) in hid.into_iter().enumerate() {
+        let name = match i {
+            0 => hid.name().to_string(),
+            i => format!("{} {}", hid.name(), i + 1),
+        };
+        interfaces.push((
+            numbers.next().unwrap(),
+            (0x03, 0x00, 0x00),
+            name,
+            FIDOInterfaceHandler::endpoints_at(0x04 + 2 * i as u8),
+            shared(hid),
+        ));
+    }
+    interfaces.sort_by_key(|(number, ..)| *number);
+
+    let device_handler: Box<dyn UsbDeviceHandler + Send> =
+        Box::new(CanokeyVirtDeviceHandler::new(&vendor_handlers));
+    let device_handler: Box<dyn UsbDeviceHandler + Send> =
+        Box::new(CatchPanic::new(device_handler));
+    let device_handler = Arc::new(Mutex::new(device_handler));
+    let mut device = UsbDevice::new(index).with_device_handler(device_handler);
+    for (number, (class, subclass, protocol), name, endpoints, handler) in interfaces {
+        device = device.with_interface_and_number(
+            class,
+            subclass,
+            protocol,
+            number,
+            Some(&name),
+            endpoints,
+            handler,
+        );
+    }
+    device.bus_id = format!("0-0-{}", index);
+    device.path = format!("/sys/bus/0/0/{}", index);
+    device
+}
+
+/// Further HID interfaces take the even endpoint numbers from 4 to 14
+const MAX_EXTRA_HID_INTERFACES: usize = 6;
+
+/// Box `handler` to be shared by a `UsbDevice` and other handlers, failing the URBs it panics on
+fn shared(
+    handler: impl UsbInterfaceHandler + Send + 'static,
+) -> Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> {
+    let handler = Box::new(handler) as Box<dyn UsbInterfaceHandler + Send>;
+    Arc::new(Mutex::new(Box::new(CatchPanic::new(handler))))
+}
+
+/// Physical device and the backends its virtual device is relayed with
+pub struct RelayConfig<'a> {
+    pub device: Arc<dyn UsbBackend>,
+    pub ccid_backend: Box<dyn CCIDBackend>,
+    /// Readers of further CCID interfaces, e.g. the contactless one of a dual-interface key
+    pub extra_ccid_backends: Vec<Box<dyn CCIDBackend>>,
+    pub ccid_config: CCIDConfig,
+    /// `None` if the HID API library is unavailable
+    pub hidapi: Option<&'a dyn HidApiBackend>,
+    pub fido: InterfaceMode,
+    pub webusb: InterfaceMode,
+    pub config_name: Option<String>,
+    /// Present the device at Full speed instead of High speed
+    pub full_speed: bool,
+    /// Forward SET_IDLE of the FIDO/U2F interface to the HID device
+    pub forward_set_idle: bool,
+    /// Present bcdUSB and bcdDevice of the physical device instead of 2.10 and 1.00
+    pub mirror_version: bool,
+    /// Numbers of further HID interfaces of the physical device to relay besides FIDO/U2F
+    pub extra_hid_interfaces: Vec<u8>,
+    /// Timeouts of all handlers, replacing the one of `ccid_config`
+    pub transfer: TransferConfig,
+    /// Serial number string, `AAAABBBBCC` followed by the index when `None`
+    pub serial_number: Option<String>,
+}
+
+/// Create the handlers of a physical device and the virtual device relaying it as `index`
+///
+/// Every virtual device owns its CCID backend, so cards are never shared between them.
+pub fn build_relay(index: u32, config: RelayConfig) -> io::Result<UsbDevice> {
+    let device = config.device;
+    let ccid_config = CCIDConfig {
+        transfer: config.transfer,
+        ..config.ccid_config
+    };
+    let ccid = std::iter::once(config.ccid_backend)
+        .chain(config.extra_ccid_backends)
+        .map(|backend| {
+            let handler =
+                CCIDInterfaceHandler::with_config(device.as_ref(), backend, ccid_config.clone())?;
+            Ok(shared(handler))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let webusb = optional_interface("WebUSB", config.webusb, || {
+        WebUSBInterfaceHandler::new(device.clone(), 1, ccid.clone(), config.transfer)
+    })?
+    .map(shared);
+    let fido = optional_interface("FIDO/U2F", config.fido, || match config.hidapi {
+        Some(hidapi) => FIDOInterfaceHandler::new(device.as_ref(), hidapi, config.transfer).map(
+            |mut handler| {
+                handler.set_forward_set_idle(config.forward_set_idle);
+                handler
+            },
+        ),
+        None => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "HID API library is unavailable",
+        )),
+    })?;
+    if config.extra_hid_interfaces.len() > MAX_EXTRA_HID_INTERFACES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "At most {} further HID interfaces can be relayed, endpoint numbers run out",
+                MAX_EXTRA_HID_INTERFACES
+            ),
+        ));
+    }
+    let hid = config
+        .extra_hid_interfaces
+        .iter()
+        .map(|&number| {
+            let hidapi = config.hidapi.ok_or(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "HID API library is unavailable",
+            ))?;
+            FIDOInterfaceHandler::with_interface(device.as_ref(), hidapi, number, config.transfer)
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut v = relay_device(index, fido, hid, webusb, ccid);
+    v.speed = UsbSpeed::High as u32;
+    v.vendor_id = 0x20A0;
+    v.product_id = 0x42D4;
+    v.set_product_name("Canokey Relay Card");
+    v.set_manufacturer_name("canokeys.org");
+    match (config.serial_number, index) {
+        (Some(serial_number), _) => v.set_serial_number(&serial_number),
+        (None, 0) => v.set_serial_number("AAAABBBBCC"),
+        (None, index) => v.set_serial_number(&format!("AAAABBBBCC{}", index)),
+    };
+    set_configuration_name(&mut v, config.config_name.as_deref());
+    v.usb_version.major = 0x2;
+    v.usb_version.minor = 0x10;
+    v.usb_version.patch = 0x0;
+    v.device_bcd.major = 0x1;
+    v.device_bcd.minor = 0x0;
+    v.device_bcd.patch = 0x0;
+    if config.mirror_version {
+        mirror_version(&mut v, device.as_ref());
+    }
+    if config.full_speed {
+        set_full_speed(&mut v);
+    }
+    check_interface_numbers(&v)?;
+    Ok(v)
+}
+
+/// Run `what` on its own thread, failing with `TimedOut` if the device did not let it finish
+/// within `timeout`
+///
+/// The thread of an unresponsive device is left behind, startup is aborted anyway.
+pub fn probe<T: Send + 'static>(
+    what: &str,
+    timeout: Duration,
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || sender.send(f()));
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "{} did not finish within {}s, the device does not respond. Try replugging it.",
+                what,
+                timeout.as_secs_f32()
+            ),
+        )),
+        Err(RecvTimeoutError::Disconnected) => Err(io::Error::other(format!("{} panicked", what))),
+    }
+}
+
+/// Serial number unique to this launch, 16 hexadecimal digits
+pub fn random_serial_number() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // RandomState is seeded randomly per process, the counter tells calls apart
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    format!("{:016X}", hasher.finish())
+}
+
+/// Check that the interface numbers of `device` are unique and contiguous from 0, which hosts
+/// expect of bInterfaceNumber
+pub fn check_interface_numbers(device: &UsbDevice) -> io::Result<()> {
+    let mut numbers: Vec<_> = device
+        .interfaces
+        .iter()
+        .map(|interface| interface.interface_number)
+        .collect();
+    numbers.sort_unstable();
+    for (expected, number) in numbers.iter().enumerate() {
+        if *number != expected as u8 {
+            let problem = match numbers.iter().filter(|n| *n == number).count() {
+                1 => format!("interface {} is missing", expected),
+                _ => format!("interface number {} is used more than once", number),
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid interface numbers {:?} of device {}: {}",
+                    numbers, device.bus_id, problem
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Present bcdUSB and bcdDevice of `physical` as those of `device`
+pub fn mirror_version(device: &mut UsbDevice, physical: &dyn UsbBackend) {
+    let descriptor = physical.device_descriptor();
+    // BCD, the major version in the high byte
+    let [minor, major] = descriptor.usb_version().to_le_bytes();
+    device.usb_version.major = major;
+    device.usb_version.minor = minor;
+    let [minor, major] = descriptor.device_version().to_le_bytes();
+    device.device_bcd.major = major;
+    device.device_bcd.minor = minor;
+    debug!(
+        "Mirroring bcdUSB {:04X} and bcdDevice {:04X}",
+        descriptor.usb_version(),
+        descriptor.device_version()
+    );
+}
+
+/// Present `device` as a USB 1.1 Full speed device
+///
+/// Packet sizes are capped to the Full speed maximums and interrupt intervals converted from
+/// microframe exponents to frames. Hosts do not read the BOS descriptor of USB 1.1 devices, so
+/// WebUSB is not advertised to browsers anymore.
+pub fn set_full_speed(device: &mut UsbDevice) {
+    device.speed = UsbSpeed::Full as u32;
+    device.usb_version.major = 0x1;
+    device.usb_version.minor = 0x10;
+    device.usb_version.patch = 0x0;
+    for interface in &mut device.interfaces {
+        for ep in &mut interface.endpoints {
+            match ep.attributes & 0x03 {
+                // Isochronous
+                0x01 => ep.max_packet_size = ep.max_packet_size.min(1023),
+                // Bulk
+                0x02 => ep.max_packet_size = ep.max_packet_size.min(64),
+                // Interrupt, bInterval is 2^(n-1) microframes at High speed, n frames at Full speed
+                0x03 => {
+                    ep.max_packet_size = ep.max_packet_size.min(64);
+                    let microframes = 1u32 << (ep.interval.clamp(1, 16) - 1);
+                    ep.interval = (microframes / 8).clamp(1, 255) as u8;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Human readable layout of `device`, one line per interface, for the startup log
+///
+/// Interface numbers used more than once are flagged.
+pub fn device_summary(device: &UsbDevice) -> String {
+    let mut summary = format!(
+        "Device {} {:04X}:{:04X} with {} interface(s)",
+        device.bus_id,
+        device.vendor_id,
+        device.product_id,
+        device.interfaces.len()
+    );
+    for (i, interface) in device.interfaces.iter().enumerate() {
+        let endpoints = interface
+            .endpoints
+            .iter()
+            .map(|ep| {
+                let kind = match ep.attributes & 0x03 {
+                    0 => "Control",
+                    1 => "Isochronous",
+                    2 => "Bulk",
+                    _ => "Interrupt",
+                };
+                format!("0x{:02X} {}", ep.address, kind)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut handler = interface.handler.lock().unwrap();
+        let handler = handler.as_any();
+        let handler = if handler.is::<CCIDInterfaceHandler>() {
+            "CCID"
+        } else if let Some(hid) = handler.downcast_ref::<FIDOInterfaceHandler>() {
+            hid.name()
+        } else if handler.is::<WebUSBInterfaceHandler>() {
+            "WebUSB"
+        } else if handler.is::<ReservedInterfaceHandler>() {
+            "reserved"
+        } else {
+            "unknown"
+        };
+        summary += &format!(
+            "\n  Interface {}: class {:02X}/{:02X}/{:02X}, endpoints [{}], handler {}",
+            interface.interface_number,
+            interface.interface_class,
+            interface.interface_subclass,
+            interface.interface_protocol,
+            endpoints,
+            handler
+        );
+        if device.interfaces[..i]
+            .iter()
+            .any(|other| other.interface_number == interface.interface_number)
+        {
+            summary += " (duplicate interface number)";
+        }
+    }
+    summary
+}
+
+/// Make the handlers of `devices` usable again after a panic while serving them
+///
+/// Poisoned locks are cleared and cards are powered off, as the host lost track of them.
+pub fn reset_after_panic(devices: &[UsbDevice]) {
+    for device in devices {
+        if let Some(handler) = &device.device_handler {
+            handler.clear_poison();
+        }
+        for interface in &device.interfaces {
+            interface.handler.clear_poison();
+            let mut handler = interface.handler.lock().unwrap();
+            if let Some(ccid) = handler.as_any().downcast_mut::<CCIDInterfaceHandler>() {
+                ccid.drop_card();
+            }
+        }
+    }
+}
+
+/// Clear the state a client left in the handlers of `device`, so the next one starts fresh
+///
+/// Cards are not powered off, see [`drop_cards`].
+pub fn reset_handlers(device: &UsbDevice) {
+    // Poisoned handlers are left to `reset_after_panic`
+    if let Some(Ok(mut handler)) = device.device_handler.as_ref().map(|handler| handler.lock())
+        && let Some(handler) = handler.as_any().downcast_mut::<CanokeyVirtDeviceHandler>()
+    {
+        handler.reset();
+    }
+    for interface in &device.interfaces {
+        let Ok(mut handler) = interface.handler.lock() else {
+            continue;
+        };
+        let handler = handler.as_any();
+        if let Some(ccid) = handler.downcast_mut::<CCIDInterfaceHandler>() {
+            ccid.reset();
+        } else if let Some(fido) = handler.downcast_mut::<FIDOInterfaceHandler>() {
+            fido.reset();
+        }
+    }
+}
+
+/// Power off the cards of all CCID interfaces of `device`
+pub fn drop_cards(device: &UsbDevice) {
+    for interface in &device.interfaces {
+        // Left to `reset_after_panic`
+        let Ok(mut handler) = interface.handler.lock() else {
+            continue;
+        };
+        if let Some(ccid) = handler.as_any().downcast_mut::<CCIDInterfaceHandler>() {
+            ccid.drop_card();
+        }
+    }
+}
+
+/// Power down the cards of `devices` which stayed idle longer than their idle timeout
+pub fn power_down_idle_cards(devices: &[UsbDevice], now: Instant) {
+    for device in devices {
+        for interface in &device.interfaces {
+            // Left to `reset_after_panic`
+            let Ok(mut handler) = interface.handler.lock() else {
+                continue;
+            };
+            if let Some(ccid) = handler.as_any().downcast_mut::<CCIDInterfaceHandler>()
+                && ccid.check_idle(now)
+            {
+                info!(
+                    "Powered down idle card of device {} interface {}",
+                    device.bus_id, interface.interface_number
+                );
+            }
+        }
+    }
+}
+
+/// Give the configuration a name string, or none at all
+pub fn set_configuration_name(device: &mut UsbDevice, name: Option<&str>) {
+    match name {
+        Some(name) => device.set_configuration_name(name),
+        None => device.unset_configuration_name(),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{
+        FakeHidApi, FakeHidDevice, FakeUsbDevice, MemoryBackend, PIGEON_ATR, pigeon_relay,
+    };
+
+    type Handler = Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>;
+
+    fn handlers(device: &Arc<dyn UsbBackend>, hidapi: &FakeHidApi) -> Vec<Handler> {
+        let ccid: Handler = Arc::new(Mutex::new(Box::new(
+            CCIDInterfaceHandler::with_config(
+                device.as_ref(),
+                Box::new(MemoryBackend::new(&PIGEON_ATR)),
+                CCIDConfig::default(),
+            )
+            .unwrap(),
+        )));
+        let webusb: Handler = Arc::new(Mutex::new(Box::new(
+            WebUSBInterfaceHandler::new(
+                device.clone(),
+                1,
+                vec![ccid.clone()],
+                TransferConfig::default(),
+            )
+            .unwrap(),
+        )));
+        let fido: Handler = Arc::new(Mutex::new(Box::new(
+            FIDOInterfaceHandler::new(device.as_ref(), hidapi, TransferConfig::default()).unwrap(),
+        )));
+        vec![fido, webusb, ccid]
+    }
+
+    #[test]
+    fn test_probe() {
+        let build = |device: FakeUsbDevice| {
+            move || {
+                let config = RelayConfig {
+                    device: Arc::new(device),
+                    ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+                };
+                build_relay(0, config)
+            }
+        };
+        let device = probe(
+            "Creating device 0",
+            Duration::from_secs(5),
+            build(FakeUsbDevice::pigeon()),
+        )
+        .unwrap();
+        assert_eq!(device.bus_id, "0-0-0");
+
+        let mut unresponsive = FakeUsbDevice::pigeon();
+        unresponsive.unresponsive = true;
+        let err = probe(
+            "Creating device 0",
+            Duration::from_millis(100),
+            build(unresponsive),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(
+            err.to_string()
+                .starts_with("Creating device 0 did not finish within 0.1s")
+        );
+
+        let err = probe(
+            "Creating device 0",
+            Duration::from_secs(5),
+            || -> io::Result<()> { panic!("Broken device") },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Creating device 0 panicked");
+    }
+
+    #[test]
+    fn test_refresh_interfaces() {
+        let fake = FakeUsbDevice::pigeon();
+        let log = fake.interface.log.clone();
+        let device: Arc<dyn UsbBackend> = Arc::new(fake);
+        let hidapi = FakeHidApi::pigeon();
+        let handlers = handlers(&device, &hidapi);
+
+        refresh_interfaces(&device, &hidapi, &handlers).unwrap();
+        assert_eq!(log.lock().unwrap().claimed, vec![1, 1]);
+        assert_eq!(log.lock().unwrap().released, vec![1]);
+
+        let reenumerated = FakeUsbDevice::pigeon();
+        let new_log = reenumerated.interface.log.clone();
+        let reenumerated: Arc<dyn UsbBackend> = Arc::new(reenumerated);
+        refresh_interfaces(&reenumerated, &hidapi, &handlers).unwrap();
+        assert_eq!(log.lock().unwrap().released, vec![1, 1]);
+        assert_eq!(new_log.lock().unwrap().claimed, vec![1]);
+    }
+
+    #[test]
+    fn test_refresh_without_ccid_interface() {
+        let device: Arc<dyn UsbBackend> = Arc::new(FakeUsbDevice::pigeon());
+        let hidapi = FakeHidApi::pigeon();
+        let handlers = handlers(&device, &hidapi);
+        let ccid_descriptor = handlers[2].lock().unwrap().get_class_specific_descriptor();
+
+        let reenumerated: Arc<dyn UsbBackend> = Arc::new(FakeUsbDevice::pigeon().without_ccid());
+        let err = refresh_interfaces(&reenumerated, &hidapi, &handlers).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            handlers[2].lock().unwrap().get_class_specific_descriptor(),
+            ccid_descriptor
+        );
+    }
+
+    #[test]
+    fn test_unsupported_device_request() {
+        let device: Arc<dyn UsbBackend> = Arc::new(FakeUsbDevice::pigeon());
+        let hidapi = FakeHidApi::pigeon();
+        let handlers = handlers(&device, &hidapi);
+        let mut handler = CanokeyVirtDeviceHandler::new(&[(1, handlers[1].clone())]);
+
+        // SET_FEATURE(DEVICE_REMOTE_WAKEUP) is not supported by the relay
+        let setup = SetupPacket {
+            request_type: 0x00,
+            request: 0x03,
+            value: 0x0001,
+            index: 0,
+            length: 0,
+        };
+        let err = handler.handle_urb(0, setup, &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        // SET_ADDRESS is acknowledged, the address does not matter to the relay
+        let setup = SetupPacket {
+            request_type: 0x00,
+            request: 0x05,
+            value: 0x0007,
+            index: 0,
+            length: 0,
+        };
+        assert_eq!(handler.handle_urb(0, setup, &[]).unwrap(), []);
+
+        let setup = SetupPacket {
+            request_type: 0x80,
+            request: 0x00,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        assert_eq!(handler.handle_urb(2, setup, &[]).unwrap(), vec![0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_vendor_request_routing() {
+        let first = FakeUsbDevice::pigeon();
+        let first_log = first.interface.log.clone();
+        let second = FakeUsbDevice::pigeon();
+        second
+            .interface
+            .responses
+            .lock()
+            .unwrap()
+            .push_back(vec![0x01, 0x02]);
+        let second_log = second.interface.log.clone();
+        let webusb = |device: FakeUsbDevice| -> Handler {
+            let device: Arc<dyn UsbBackend> = Arc::new(device);
+            Arc::new(Mutex::new(Box::new(
+                WebUSBInterfaceHandler::new(device, 1, vec![], TransferConfig::default()).unwrap(),
+            )))
+        };
+        let mut handler = CanokeyVirtDeviceHandler::new(&[(1, webusb(first)), (2, webusb(second))]);
+
+        let setup = SetupPacket {
+            request_type: 0xC0,
+            request: 0x01,
+            value: 0,
+            index: 0x0002,
+            length: 2,
+        };
+        assert_eq!(handler.handle_urb(2, setup, &[]).unwrap(), vec![0x01, 0x02]);
+        assert!(first_log.lock().unwrap().control_in.is_empty());
+        let control_in = &second_log.lock().unwrap().control_in;
+        assert_eq!(control_in.len(), 1);
+        assert_eq!(control_in[0].index, 0x0002);
+    }
+
+    #[test]
+    fn test_random_serial_number() {
+        let serials: std::collections::HashSet<_> =
+            (0..1000).map(|_| random_serial_number()).collect();
+        assert_eq!(serials.len(), 1000);
+        assert!(
+            serials
+                .iter()
+                .all(|serial| serial.len() == 16 && serial.chars().all(|c| c.is_ascii_hexdigit()))
+        );
+    }
+
+    #[test]
+    fn test_set_configuration_name() {
+        let mut device = UsbDevice::new(0);
+        set_configuration_name(&mut device, None);
+        assert_eq!(device.unset_configuration_name(), None);
+
+        set_configuration_name(&mut device, Some("Relay"));
+        assert_eq!(device.unset_configuration_name(), Some("Relay".to_string()));
+    }
+
+    #[test]
+    fn test_relay_device_without_webusb() {
+        let device: Arc<dyn UsbBackend> = Arc::new(FakeUsbDevice::pigeon());
+        let hidapi = FakeHidApi::pigeon();
+        let handlers = handlers(&device, &hidapi);
+
+        let relayed = relay_device(
+            0,
+            None,
+            vec![],
+            Some(handlers[1].clone()),
+            vec![handlers[2].clone()],
+        );
+        let interfaces: Vec<_> = relayed
+            .interfaces
+            .iter()
+            .map(|i| (i.interface_number, i.interface_class))
+            .collect();
+        assert_eq!(interfaces, vec![(0, 0xFF), (1, 0xFF), (2, 0x0B)]);
+
+        let relayed = relay_device(0, None, vec![], None, vec![handlers[2].clone()]);
+        let interfaces: Vec<_> = relayed
+            .interfaces
+            .iter()
+            .map(|i| (i.interface_number, i.interface_class))
+            .collect();
+        assert_eq!(interfaces, vec![(0, 0xFF), (1, 0x0B)]);
+    }
+
+    #[test]
+    fn test_relay_device_fido_number() {
+        // Key with the FIDO/U2F interface at 1 and WebUSB at 0
+        let mut fake = FakeUsbDevice::pigeon();
+        let webusb = fake
+            .configuration
+            .windows(9)
+            .position(|d| d == [0x09, 0x04, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x00])
+            .unwrap();
+        fake.configuration[webusb + 2] = 0x00;
+        fake.configuration[9 + 2] = 0x01;
+        let device: Arc<dyn UsbBackend> = Arc::new(fake);
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi.devices[0].interface_number = 1;
+        let fido =
+            FIDOInterfaceHandler::new(device.as_ref(), &hidapi, TransferConfig::default()).unwrap();
+        assert_eq!(fido.interface_number(), 1);
+        let handlers = handlers(&device, &hidapi);
+
+        let relayed = relay_device(
+            0,
+            Some(fido),
+            vec![],
+            Some(handlers[1].clone()),
+            vec![handlers[2].clone()],
+        );
+        let interfaces: Vec<_> = relayed
+            .interfaces
+            .iter()
+            .map(|i| (i.interface_number, i.interface_class))
+            .collect();
+        assert_eq!(interfaces, vec![(0, 0xFF), (1, 0x03), (2, 0x0B)]);
+        check_interface_numbers(&relayed).unwrap();
+
+        // Kept contiguous without WebUSB
+        let fido =
+            FIDOInterfaceHandler::new(device.as_ref(), &hidapi, TransferConfig::default()).unwrap();
+        let relayed = relay_device(0, Some(fido), vec![], None, vec![]);
+        assert_eq!(relayed.interfaces[0].interface_number, 0);
+    }
+
+    #[test]
+    fn test_build_two_relays() {
+        let hidapi = FakeHidApi::pigeon();
+        let mut logs = Vec::new();
+        let mut relays = Vec::new();
+        for index in 0..2 {
+            let backend = MemoryBackend::new(&PIGEON_ATR);
+            logs.push(backend.log.clone());
+            let config = RelayConfig {
+                hidapi: Some(&hidapi),
+                fido: if index == 0 {
+                    InterfaceMode::Required
+                } else {
+                    InterfaceMode::Disabled
+                },
+                webusb: InterfaceMode::Required,
+                ..pigeon_relay(backend)
+            };
+            relays.push(build_relay(index, config).unwrap());
+        }
+        assert_eq!(relays[0].bus_id, "0-0-0");
+        assert_eq!(relays[1].bus_id, "0-0-1");
+        assert_eq!(relays[0].interfaces[0].interface_class, 0x03);
+        assert_eq!(relays[1].interfaces[0].interface_class, 0xFF);
+
+        // PC_to_RDR_XfrBlock to the second device only reaches its own card
+        let ccid = &relays[1].interfaces[2];
+        let command = [
+            0x6F, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        ccid.handler
+            .lock()
+            .unwrap()
+            .handle_urb(
+                ccid,
+                ccid.endpoints[1],
+                command.len() as u32,
+                SetupPacket::default(),
+                &command,
+            )
+            .unwrap();
+        assert!(logs[0].lock().unwrap().transmitted.is_empty());
+        assert_eq!(logs[1].lock().unwrap().transmitted, vec![vec![0x00]]);
+    }
+
+    #[test]
+    fn test_interface_numbers() {
+        let reserved = || -> Handler {
+            Arc::new(Mutex::new(
+                Box::new(ReservedInterfaceHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
+            ))
+        };
+        let device = |numbers: &[u8]| {
+            numbers.iter().fold(UsbDevice::new(0), |device, number| {
+                device.with_interface_and_number(0xFF, 0, 0, *number, None, vec![], reserved())
+            })
+        };
+        assert!(check_interface_numbers(&device(&[0, 1, 2])).is_ok());
+        assert!(check_interface_numbers(&device(&[1, 0])).is_ok());
+
+        let err = check_interface_numbers(&device(&[0, 1, 1])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("used more than once"), "{}", err);
+        let err = check_interface_numbers(&device(&[0, 2])).unwrap_err();
+        assert!(
+            err.to_string().contains("interface 1 is missing"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_full_speed() {
+        let hidapi = FakeHidApi::pigeon();
+        let config = RelayConfig {
+            hidapi: Some(&hidapi),
+            fido: InterfaceMode::Required,
+            webusb: InterfaceMode::Required,
+            full_speed: true,
+            ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+        };
+        let relay = build_relay(0, config).unwrap();
+        assert_eq!(relay.speed, UsbSpeed::Full as u32);
+        assert_eq!(
+            (relay.usb_version.major, relay.usb_version.minor),
+            (1, 0x10)
+        );
+        let endpoints: Vec<_> = relay
+            .interfaces
+            .iter()
+            .flat_map(|i| &i.endpoints)
+            .map(|ep| (ep.address, ep.max_packet_size, ep.interval))
+            .collect();
+        // FIDO interrupt endpoints every 4 ms, CCID bulk endpoints
+        assert_eq!(
+            endpoints,
+            vec![(0x82, 64, 4), (0x02, 64, 4), (0x81, 64, 0), (0x01, 64, 0)]
+        );
+    }
+
+    #[test]
+    fn test_mirror_version() {
+        let mut fake = FakeUsbDevice::pigeon();
+        // bcdUSB 2.00, bcdDevice 3.21
+        fake.device_descriptor[2..4].copy_from_slice(&[0x00, 0x02]);
+        fake.device_descriptor[12..14].copy_from_slice(&[0x21, 0x03]);
+        let device: Arc<dyn UsbBackend> = Arc::new(fake);
+        let relay = |mirror_version| {
+            let config = RelayConfig {
+                device: device.clone(),
+                mirror_version,
+                ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+            };
+            build_relay(0, config).unwrap()
+        };
+        let versions = |relay: UsbDevice| {
+            (
+                (relay.usb_version.major, relay.usb_version.minor),
+                (relay.device_bcd.major, relay.device_bcd.minor),
+            )
+        };
+        assert_eq!(versions(relay(false)), ((0x02, 0x10), (0x01, 0x00)));
+        assert_eq!(versions(relay(true)), ((0x02, 0x00), (0x03, 0x21)));
+    }
+
+    #[test]
+    fn test_dual_ccid() {
+        let config = RelayConfig {
+            extra_ccid_backends: vec![Box::new(MemoryBackend::new(&PIGEON_ATR))],
+            webusb: InterfaceMode::Required,
+            ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+        };
+        let relay = build_relay(0, config).unwrap();
+        let interfaces: Vec<_> = relay
+            .interfaces
+            .iter()
+            .map(|i| {
+                let endpoints: Vec<_> = i.endpoints.iter().map(|ep| ep.address).collect();
+                (i.interface_number, i.interface_class, endpoints)
+            })
+            .collect();
+        assert_eq!(
+            interfaces,
+            vec![
+                (0, 0xFF, vec![]),
+                (1, 0xFF, vec![]),
+                (2, 0x0B, vec![0x81, 0x01]),
+                (3, 0x0B, vec![0x83, 0x03]),
+            ]
+        );
+        let ccid: Vec<_> = relay.interfaces[2..]
+            .iter()
+            .map(|i| i.handler.clone())
+            .collect();
+        assert!(!Arc::ptr_eq(&ccid[0], &ccid[1]));
+        let powered = || {
+            ccid.iter()
+                .map(|handler| {
+                    let mut handler = handler.lock().unwrap();
+                    let ccid = handler.as_any().downcast_mut::<CCIDInterfaceHandler>();
+                    ccid.unwrap().status().is_powered(0)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(powered(), vec![true, true]);
+
+        // The second interface answers on its own endpoints
+        let second = &relay.interfaces[3];
+        let mut handler = second.handler.lock().unwrap();
+        let get_slot_status = [0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        let out = handler.handle_urb(
+            second,
+            second.endpoints[1],
+            10,
+            SetupPacket::default(),
+            &get_slot_status,
+        );
+        assert_eq!(out.unwrap(), vec![]);
+        let setup = SetupPacket {
+            request_type: 0x80,
+            ..Default::default()
+        };
+        let response = handler.handle_urb(second, second.endpoints[0], 0x200, setup, &[]);
+        assert_eq!(
+            response.unwrap()[..7],
+            [0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01]
+        );
+        drop(handler);
+
+        // A vendor request on the WebUSB interface powers off both cards
+        let setup = SetupPacket {
+            request_type: 0x41,
+            request: 0x00,
+            value: 0x00,
+            index: 0x01,
+            length: 0,
+        };
+        let webusb = &relay.interfaces[1];
+        webusb
+            .handler
+            .lock()
+            .unwrap()
+            .handle_urb(webusb, usbip::UsbEndpoint::default(), 0, setup, &[])
+            .unwrap();
+        assert_eq!(powered(), vec![false, false]);
+    }
+
+    #[test]
+    fn test_extra_hid_interface() {
+        let fido = FakeHidDevice {
+            report_descriptor: vec![0x06, 0xD0, 0xF1],
+            ..Default::default()
+        };
+        let management = FakeHidDevice {
+            report_descriptor: vec![0x06, 0x00, 0xFF],
+            ..Default::default()
+        };
+        let mut hidapi = FakeHidApi::pigeon().with_hid_interface(3, management);
+        hidapi.device = fido;
+        let config = RelayConfig {
+            device: Arc::new(FakeUsbDevice::pigeon().with_hid_interface(3)),
+            hidapi: Some(&hidapi),
+            fido: InterfaceMode::Required,
+            extra_hid_interfaces: vec![3],
+            ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+        };
+        let relay = build_relay(0, config).unwrap();
+        let interfaces: Vec<_> = relay
+            .interfaces
+            .iter()
+            .map(|i| {
+                let endpoints: Vec<_> = i.endpoints.iter().map(|ep| ep.address).collect();
+                (i.interface_number, i.interface_class, endpoints)
+            })
+            .collect();
+        assert_eq!(
+            interfaces,
+            vec![
+                (0, 0x03, vec![0x82, 0x02]),
+                (1, 0x0B, vec![0x81, 0x01]),
+                (2, 0x03, vec![0x84, 0x04]),
+            ]
+        );
+        assert_eq!(
+            hidapi.device.log.lock().unwrap().opened,
+            vec![c"fake-hid-0".to_owned(), c"fake-hid-3".to_owned()]
+        );
+
+        // Each interface caches the report descriptor of its own HID device
+        let report_descriptor = |interface: &usbip::UsbInterface| {
+            let setup = SetupPacket {
+                request_type: 0x81,
+                request: 0x06,
+                value: 0x2200,
+                index: interface.interface_number as u16,
+                length: 0xFF,
+            };
+            let mut handler = interface.handler.lock().unwrap();
+            handler
+                .handle_urb(interface, usbip::UsbEndpoint::default(), 0xFF, setup, &[])
+                .unwrap()
+        };
+        for _ in 0..2 {
+            assert_eq!(report_descriptor(&relay.interfaces[0]), [0x06, 0xD0, 0xF1]);
+            assert_eq!(report_descriptor(&relay.interfaces[2]), [0x06, 0x00, 0xFF]);
+        }
+
+        // The interface must exist on the physical device
+        let config = RelayConfig {
+            hidapi: Some(&hidapi),
+            fido: InterfaceMode::Required,
+            extra_hid_interfaces: vec![3],
+            ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+        };
+        let err = build_relay(0, config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_device_summary() {
+        let device: Arc<dyn UsbBackend> = Arc::new(FakeUsbDevice::pigeon());
+        let hidapi = FakeHidApi::pigeon();
+        let fido =
+            FIDOInterfaceHandler::new(device.as_ref(), &hidapi, TransferConfig::default()).unwrap();
+        let handlers = handlers(&device, &hidapi);
+        let relayed = relay_device(
+            0,
+            Some(fido),
+            vec![],
+            Some(handlers[1].clone()),
+            vec![handlers[2].clone()],
+        );
+        assert_eq!(
+            device_summary(&relayed),
+            "Device 0-0-0 0000:0000 with 3 interface(s)\n  \
+             Interface 0: class 03/00/00, endpoints [0x82 Interrupt, 0x02 Interrupt], handler FIDO/U2F\n  \
+             Interface 1: class FF/FF/FF, endpoints [], handler WebUSB\n  \
+             Interface 2: class 0B/00/00, endpoints [0x81 Bulk, 0x01 Bulk], handler CCID"
+        );
+    }
+
+    #[test]
+    fn test_reset_after_panic() {
+        let device: Arc<dyn UsbBackend> = Arc::new(FakeUsbDevice::pigeon());
+        let hidapi = FakeHidApi::pigeon();
+        let handlers = handlers(&device, &hidapi);
+        let relayed = relay_device(
+            0,
+            None,
+            vec![],
+            Some(handlers[1].clone()),
+            vec![handlers[2].clone()],
+        );
+
+        let ccid = handlers[2].clone();
+        std::thread::spawn(move || {
+            let _guard = ccid.lock().unwrap();
+            panic!("injected panic");
+        })
+        .join()
+        .unwrap_err();
+        assert!(handlers[2].is_poisoned());
+
+        reset_after_panic(&[relayed]);
+        let mut ccid = handlers[2].lock().unwrap();
+        let ccid = ccid
+            .as_any()
+            .downcast_mut::<CCIDInterfaceHandler>()
+            .unwrap();
+        assert!(!ccid.status().is_powered(0));
+    }
+}
@@ -0,0 +1,620 @@
+//! In-memory stand-ins for the hardware the handlers relay to
+//!
+//! Handlers only reach hardware through these trait boundaries, which are the injection points:
+//!
+//! - [`UsbBackend`] / [`UsbInterfaceBackend`] for `nusb` descriptor access and control transfers,
+//!   used by `CCIDInterfaceHandler::with_config`, `WebUSBInterfaceHandler::new` and
+//!   `FIDOInterfaceHandler::new`
+//! - [`CCIDBackend`] for the PCSC reader behind `CCIDInterfaceHandler`
+//! - [`HidApiBackend`] / [`HidBackend`] for the `hidapi` device behind `FIDOInterfaceHandler`
+//!
+//! Built for tests, or with the `fake-backend` feature.
+#![cfg_attr(not(test), allow(dead_code))]
+
+use crate::ccid::CCIDConfig;
+use crate::ccid_backend::{CCIDBackend, Canceller};
+use crate::cli::InterfaceMode;
+use crate::device::RelayConfig;
+use crate::hid_backend::{HidApiBackend, HidBackend, HidDeviceInfo};
+use crate::transfer::TransferConfig;
+use crate::usb_backend::{UsbBackend, UsbInterfaceBackend};
+use nusb::descriptors::DeviceDescriptor;
+use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
+use pcsc::{Disposition, Protocol, Protocols, ShareMode};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+pub const PIGEON_VENDOR_ID: u16 = 0x20A0;
+pub const PIGEON_PRODUCT_ID: u16 = 0x42D4;
+
+pub const PIGEON_ATR: [u8; 17] = [
+    0x3B, 0xF7, 0x11, 0x00, 0x00, 0x81, 0x31, 0xFE, 0x65, 0x43, 0x61, 0x6E, 0x6F, 0x6B, 0x65, 0x79,
+    0x99,
+];
+
+pub const PIGEON_HID_DESCRIPTOR: [u8; 9] = [0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x22, 0x00];
+
+/// Control transfer recorded by [`FakeUsbInterface`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FakeControl {
+    pub control_type: ControlType,
+    pub recipient: Recipient,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// wLength, the length of `data` for OUT transfers
+    pub length: u16,
+    pub data: Vec<u8>,
+}
+
+/// Records of the calls issued to a fake, shared with the test body
+#[derive(Debug, Default)]
+pub struct FakeLog {
+    pub claimed: Vec<u8>,
+    /// Descriptor type and wLength of each GET_DESCRIPTOR
+    pub descriptors: Vec<(u8, u16)>,
+    pub released: Vec<u8>,
+    pub control_in: Vec<FakeControl>,
+    pub control_out: Vec<FakeControl>,
+    pub transmitted: Vec<Vec<u8>>,
+    pub controls: Vec<(u32, Vec<u8>)>,
+    /// Protocols of each connect of the card
+    pub protocols: Vec<Protocols>,
+    /// Share modes of each connect of the card
+    pub share_modes: Vec<ShareMode>,
+    pub written: Vec<Vec<u8>>,
+    /// Feature reports sent to the HID device, with their report ID
+    pub features: Vec<Vec<u8>>,
+    /// Idle duration and report ID of each SET_IDLE
+    pub idle: Vec<(u8, u8)>,
+    /// Paths of the opened HID devices
+    pub opened: Vec<CString>,
+}
+
+/// USB device with the descriptor layout of a Canokey Pigeon
+pub struct FakeUsbDevice {
+    pub device_descriptor: Vec<u8>,
+    pub configuration: Vec<u8>,
+    pub bos: Option<Vec<u8>>,
+    /// Errors failing the next GET_DESCRIPTOR requests, in order
+    pub descriptor_errors: Mutex<VecDeque<io::ErrorKind>>,
+    /// Interfaces a kernel driver is bound to, which can not be claimed
+    pub kernel_drivers: Mutex<Vec<u8>>,
+    /// Never answer, like a device in a bad state
+    pub unresponsive: bool,
+    /// Returned as string descriptor `iSerialNumber`, like the one of the HID device
+    pub serial_number: Option<String>,
+    pub interface: FakeUsbInterface,
+}
+
+impl FakeUsbDevice {
+    pub fn pigeon() -> FakeUsbDevice {
+        let [vid_lo, vid_hi] = PIGEON_VENDOR_ID.to_le_bytes();
+        let [pid_lo, pid_hi] = PIGEON_PRODUCT_ID.to_le_bytes();
+        let device_descriptor = vec![
+            0x12, 0x01, 0x10, 0x02, 0x00, 0x00, 0x00, 0x40, vid_lo, vid_hi, pid_lo, pid_hi, 0x00,
+            0x01, 0x01, 0x02, 0x03, 0x01,
+        ];
+
+        let mut configuration = vec![0x09, 0x02, 0x00, 0x00, 0x03, 0x01, 0x00, 0x80, 0x32];
+        // Interface 0: FIDO/U2F
+        configuration.extend_from_slice(&[0x09, 0x04, 0x00, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00]);
+        configuration.extend_from_slice(&PIGEON_HID_DESCRIPTOR);
+        configuration.extend_from_slice(&[0x07, 0x05, 0x82, 0x03, 0x40, 0x00, 0x05]);
+        configuration.extend_from_slice(&[0x07, 0x05, 0x02, 0x03, 0x40, 0x00, 0x05]);
+        // Interface 1: WebUSB
+        configuration.extend_from_slice(&[0x09, 0x04, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0x00]);
+        // Interface 2: CCID
+        configuration.extend_from_slice(&[0x09, 0x04, 0x02, 0x00, 0x02, 0x0B, 0x00, 0x00, 0x00]);
+        let mut ccid = vec![0u8; 0x36];
+        ccid[0] = 0x36;
+        ccid[1] = 0x21;
+        ccid[2..4].copy_from_slice(&[0x10, 0x01]);
+        ccid[10..14].copy_from_slice(&4000u32.to_le_bytes()); // dwDefaultClock
+        ccid[14..18].copy_from_slice(&4000u32.to_le_bytes()); // dwMaximumClock
+        ccid[19..23].copy_from_slice(&10752u32.to_le_bytes()); // dwDataRate
+        ccid[23..27].copy_from_slice(&10752u32.to_le_bytes()); // dwMaxDataRate
+        configuration.extend_from_slice(&ccid);
+        configuration.extend_from_slice(&[0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00]);
+        configuration.extend_from_slice(&[0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00]);
+        let total_length = configuration.len() as u16;
+        configuration[2..4].copy_from_slice(&total_length.to_le_bytes());
+
+        let bos = vec![
+            0x05, 0x0F, 0x0C, 0x00, 0x01, // BOS
+            0x07, 0x10, 0x02, 0x06, 0x00, 0x00, 0x00, // USB 2.0 Extension
+        ];
+
+        Self {
+            device_descriptor,
+            configuration,
+            bos: Some(bos),
+            descriptor_errors: Mutex::new(VecDeque::new()),
+            kernel_drivers: Mutex::new(vec![]),
+            unresponsive: false,
+            serial_number: Some("FAKE0001".to_string()),
+            interface: FakeUsbInterface::default(),
+        }
+    }
+
+    /// Drop the trailing CCID interface from the configuration descriptor
+    pub fn without_ccid(mut self) -> FakeUsbDevice {
+        let ccid = self
+            .configuration
+            .windows(6)
+            .position(|d| d[0] == 0x09 && d[1] == 0x04 && d[5] == 0x0B)
+            .expect("Fake configuration has no CCID interface");
+        self.configuration.truncate(ccid);
+        let total_length = self.configuration.len() as u16;
+        self.configuration[2..4].copy_from_slice(&total_length.to_le_bytes());
+        self.configuration[4] -= 1; // bNumInterfaces
+        self
+    }
+
+    /// Append a further HID interface `number` with interrupt endpoints `0x83`/`0x03`
+    pub fn with_hid_interface(mut self, number: u8) -> FakeUsbDevice {
+        let configuration = &mut self.configuration;
+        configuration.extend_from_slice(&[0x09, 0x04, number, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00]);
+        configuration.extend_from_slice(&PIGEON_HID_DESCRIPTOR);
+        configuration.extend_from_slice(&[0x07, 0x05, 0x83, 0x03, 0x40, 0x00, 0x05]);
+        configuration.extend_from_slice(&[0x07, 0x05, 0x03, 0x03, 0x40, 0x00, 0x05]);
+        let total_length = configuration.len() as u16;
+        configuration[2..4].copy_from_slice(&total_length.to_le_bytes());
+        configuration[4] += 1; // bNumInterfaces
+        self
+    }
+}
+
+impl UsbBackend for FakeUsbDevice {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        DeviceDescriptor::new(&self.device_descriptor).expect("Invalid fake device descriptor")
+    }
+
+    fn active_configuration(&self) -> io::Result<Vec<u8>> {
+        if self.unresponsive {
+            loop {
+                std::thread::park();
+            }
+        }
+        Ok(self.configuration.clone())
+    }
+
+    fn get_descriptor(
+        &self,
+        desc_type: u8,
+        _desc_index: u8,
+        _language_id: u16,
+        length: u16,
+        _timeout: Duration,
+    ) -> io::Result<Vec<u8>> {
+        self.interface
+            .log
+            .lock()
+            .unwrap()
+            .descriptors
+            .push((desc_type, length));
+        if let Some(kind) = self.descriptor_errors.lock().unwrap().pop_front() {
+            return Err(io::Error::new(kind, "Fake GET_DESCRIPTOR failure"));
+        }
+        let mut desc = match desc_type {
+            0x01 => self.device_descriptor.clone(),
+            0x02 => self.configuration.clone(),
+            0x0F => self.bos.clone().ok_or(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Fake device has no BOS descriptor",
+            ))?,
+            0x03 => {
+                let serial_number = self.serial_number.as_ref().ok_or(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "Fake device has no serial number",
+                ))?;
+                let mut desc = vec![0x00, 0x03];
+                desc.extend(serial_number.encode_utf16().flat_map(u16::to_le_bytes));
+                desc[0] = desc.len() as u8;
+                desc
+            }
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    format!("Fake device has no descriptor of type 0x{:02X}", other),
+                ));
+            }
+        };
+        desc.truncate(length as usize);
+        Ok(desc)
+    }
+
+    fn claim_interface(&self, interface: u8) -> io::Result<Box<dyn UsbInterfaceBackend>> {
+        let mut log = self.interface.log.lock().unwrap();
+        if log.claimed.len() > log.released.len()
+            || self.kernel_drivers.lock().unwrap().contains(&interface)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!("Fake interface {} is already claimed", interface),
+            ));
+        }
+        log.claimed.push(interface);
+        Ok(Box::new(FakeUsbInterface {
+            number: Some(interface),
+            responses: self.interface.responses.clone(),
+            log: self.interface.log.clone(),
+        }))
+    }
+
+    fn detach_kernel_driver(&self, interface: u8) -> io::Result<()> {
+        let mut drivers = self.kernel_drivers.lock().unwrap();
+        let index = drivers
+            .iter()
+            .position(|&bound| bound == interface)
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No kernel driver bound to fake interface {}", interface),
+            ))?;
+        drivers.remove(index);
+        Ok(())
+    }
+
+    fn attach_kernel_driver(&self, interface: u8) -> io::Result<()> {
+        self.kernel_drivers.lock().unwrap().push(interface);
+        Ok(())
+    }
+}
+
+/// Relay of a Pigeon with the card of `backend` and neither FIDO/U2F nor WebUSB, for tests to
+/// override the fields they are about
+pub fn pigeon_relay(backend: MemoryBackend) -> RelayConfig<'static> {
+    RelayConfig {
+        device: Arc::new(FakeUsbDevice::pigeon()),
+        ccid_backend: Box::new(backend),
+        extra_ccid_backends: vec![],
+        ccid_config: CCIDConfig::default(),
+        hidapi: None,
+        fido: InterfaceMode::Disabled,
+        webusb: InterfaceMode::Disabled,
+        config_name: None,
+        full_speed: false,
+        forward_set_idle: false,
+        mirror_version: false,
+        extra_hid_interfaces: vec![],
+        serial_number: None,
+        transfer: TransferConfig::default(),
+    }
+}
+
+/// Claimed interface which replies to IN transfers with scripted responses
+#[derive(Debug, Clone, Default)]
+pub struct FakeUsbInterface {
+    /// Interface number once handed out by [`FakeUsbDevice::claim_interface`]
+    pub number: Option<u8>,
+    pub responses: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    pub log: Arc<Mutex<FakeLog>>,
+}
+
+impl Drop for FakeUsbInterface {
+    fn drop(&mut self) {
+        if let Some(number) = self.number {
+            self.log.lock().unwrap().released.push(number);
+        }
+    }
+}
+
+impl UsbInterfaceBackend for FakeUsbInterface {
+    fn control_in(&self, control: ControlIn, _timeout: Duration) -> io::Result<Vec<u8>> {
+        self.log.lock().unwrap().control_in.push(FakeControl {
+            control_type: control.control_type,
+            recipient: control.recipient,
+            request: control.request,
+            value: control.value,
+            index: control.index,
+            length: control.length,
+            data: vec![],
+        });
+        let mut data = self
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_default();
+        data.truncate(control.length as usize);
+        Ok(data)
+    }
+
+    fn control_out(&self, control: ControlOut, _timeout: Duration) -> io::Result<()> {
+        self.log.lock().unwrap().control_out.push(FakeControl {
+            control_type: control.control_type,
+            recipient: control.recipient,
+            request: control.request,
+            value: control.value,
+            index: control.index,
+            length: control.data.len() as u16,
+            data: control.data.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+/// In-memory card which replies with scripted responses
+///
+/// A blocking transmit waits until its canceller is called, like a card that never answers.
+pub struct MemoryBackend {
+    pub reader_name: CString,
+    pub atr: Vec<u8>,
+    pub connected: bool,
+    /// Protocols the card supports, connecting with others fails
+    pub protocols: Protocols,
+    /// Readers reported by PCSC, only this one by default
+    pub readers: Vec<CString>,
+    pub responses: VecDeque<Result<Vec<u8>, pcsc::Error>>,
+    pub log: Arc<Mutex<FakeLog>>,
+    pub blocking: bool,
+    /// Transmitting takes this long, like a card generating a key, cancelling does not end it
+    pub transmit_delay: Duration,
+    /// Another process has the card connected shared, exclusive connects fail
+    pub in_use: bool,
+    /// Connecting blocks this long, like a slow or wedged reader
+    pub connect_delay: Duration,
+    /// Protocol of the last connect, T=1 if the card supports it
+    pub active_protocol: Option<Protocol>,
+    /// Reported instead of `active_protocol`, like a reader negotiating on its own
+    pub reported_protocol: Option<Protocol>,
+    cancelled: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl MemoryBackend {
+    pub fn new(atr: &[u8]) -> MemoryBackend {
+        Self {
+            reader_name: c"Memory Reader 0".to_owned(),
+            atr: atr.to_vec(),
+            connected: false,
+            protocols: Protocols::T0 | Protocols::T1,
+            readers: vec![c"Memory Reader 0".to_owned()],
+            responses: VecDeque::new(),
+            log: Arc::new(Mutex::new(FakeLog::default())),
+            blocking: false,
+            transmit_delay: Duration::ZERO,
+            in_use: false,
+            connect_delay: Duration::ZERO,
+            active_protocol: None,
+            reported_protocol: None,
+            cancelled: Arc::new((Mutex::new(false), Condvar::new())),
+        }
+    }
+
+    pub fn with_response(mut self, response: Result<Vec<u8>, pcsc::Error>) -> Self {
+        self.responses.push_back(response);
+        self
+    }
+}
+
+impl CCIDBackend for MemoryBackend {
+    fn reader_name(&self) -> &CStr {
+        &self.reader_name
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn list_readers(&self) -> Result<Vec<CString>, pcsc::Error> {
+        Ok(self.readers.clone())
+    }
+
+    fn connect(&mut self, share_mode: ShareMode, protocols: Protocols) -> Result<(), pcsc::Error> {
+        if !self.readers.contains(&self.reader_name) {
+            return Err(pcsc::Error::UnknownReader);
+        }
+        std::thread::sleep(self.connect_delay);
+        let mut log = self.log.lock().unwrap();
+        log.share_modes.push(share_mode);
+        if self.in_use && share_mode == ShareMode::Exclusive {
+            return Err(pcsc::Error::SharingViolation);
+        }
+        log.protocols.push(protocols);
+        drop(log);
+        if !self.protocols.intersects(protocols) {
+            return Err(pcsc::Error::ProtoMismatch);
+        }
+        self.connected = true;
+        self.active_protocol = match self.protocols & protocols {
+            available if available.contains(Protocols::T1) => Some(Protocol::T1),
+            _ => Some(Protocol::T0),
+        };
+        Ok(())
+    }
+
+    fn disconnect(&mut self, _: Disposition) -> Result<(), pcsc::Error> {
+        self.connected = false;
+        Ok(())
+    }
+
+    fn atr(&mut self) -> Result<Vec<u8>, pcsc::Error> {
+        if !self.connected {
+            return Err(pcsc::Error::InvalidHandle);
+        }
+        Ok(self.atr.clone())
+    }
+
+    fn protocol(&mut self) -> Result<Option<Protocol>, pcsc::Error> {
+        if !self.connected {
+            return Err(pcsc::Error::InvalidHandle);
+        }
+        Ok(self.reported_protocol.or(self.active_protocol))
+    }
+
+    fn transmit<'b>(&mut self, apdu: &[u8], buffer: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error> {
+        self.log.lock().unwrap().transmitted.push(apdu.to_vec());
+        std::thread::sleep(self.transmit_delay);
+        if self.blocking {
+            let (cancelled, cancel) = &*self.cancelled;
+            let mut cancelled = cancel
+                .wait_while(cancelled.lock().unwrap(), |cancelled| !*cancelled)
+                .unwrap();
+            *cancelled = false;
+            return Err(pcsc::Error::Cancelled);
+        }
+        let response = self.responses.pop_front().unwrap_or(Ok(vec![0x90, 0x00]))?;
+        if response.len() > buffer.len() {
+            // The card answers the same when the APDU is transmitted again
+            self.responses.push_front(Ok(response));
+            return Err(pcsc::Error::InsufficientBuffer);
+        }
+        buffer[..response.len()].copy_from_slice(&response);
+        Ok(&buffer[..response.len()])
+    }
+
+    fn control<'b>(
+        &mut self,
+        control_code: u32,
+        data: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], pcsc::Error> {
+        self.log
+            .lock()
+            .unwrap()
+            .controls
+            .push((control_code, data.to_vec()));
+        let response = self.responses.pop_front().unwrap_or(Ok(vec![]))?;
+        if response.len() > buffer.len() {
+            return Err(pcsc::Error::InsufficientBuffer);
+        }
+        buffer[..response.len()].copy_from_slice(&response);
+        Ok(&buffer[..response.len()])
+    }
+    fn canceller(&self) -> Option<Canceller> {
+        let cancelled = self.cancelled.clone();
+        Some(Arc::new(move || {
+            let (flag, cancel) = &*cancelled;
+            *flag.lock().unwrap() = true;
+            cancel.notify_all();
+            Ok(())
+        }))
+    }
+}
+
+/// HID enumeration exposing a single FIDO device
+pub struct FakeHidApi {
+    pub devices: Vec<HidDeviceInfo>,
+    pub device: FakeHidDevice,
+    /// Devices opened instead of `device` for their path
+    pub devices_by_path: HashMap<CString, FakeHidDevice>,
+}
+
+impl FakeHidApi {
+    pub fn pigeon() -> FakeHidApi {
+        Self {
+            devices: vec![HidDeviceInfo {
+                path: c"fake-hid-0".to_owned(),
+                vendor_id: PIGEON_VENDOR_ID,
+                product_id: PIGEON_PRODUCT_ID,
+                usage_page: Some(0xF1D0),
+                interface_number: 0,
+                serial_number: Some("FAKE0001".to_string()),
+            }],
+            device: FakeHidDevice::default(),
+            devices_by_path: HashMap::new(),
+        }
+    }
+
+    /// Expose a further HID interface `number` of the key, opened as `device`
+    pub fn with_hid_interface(mut self, number: u8, device: FakeHidDevice) -> FakeHidApi {
+        let path = CString::new(format!("fake-hid-{}", number)).unwrap();
+        self.devices.push(HidDeviceInfo {
+            path: path.clone(),
+            usage_page: Some(0xFF00),
+            interface_number: number as i32,
+            ..self.devices[0].clone()
+        });
+        self.devices_by_path.insert(path, device);
+        self
+    }
+}
+
+impl HidApiBackend for FakeHidApi {
+    fn device_list(&self) -> Vec<HidDeviceInfo> {
+        self.devices.clone()
+    }
+
+    fn open(&self, info: &HidDeviceInfo) -> hidapi::HidResult<Box<dyn HidBackend>> {
+        self.device
+            .log
+            .lock()
+            .unwrap()
+            .opened
+            .push(info.path.clone());
+        let device = self.devices_by_path.get(&info.path).unwrap_or(&self.device);
+        Ok(Box::new(device.clone()))
+    }
+}
+
+/// HID device which returns queued input reports and records output reports
+///
+/// Output reports are recorded as sent on the wire: like hidapi, a leading report ID of 0 is
+/// stripped. Feature reports are kept with their report ID, as hidapi passes them.
+#[derive(Debug, Clone, Default)]
+pub struct FakeHidDevice {
+    pub report_descriptor: Vec<u8>,
+    /// Number of next report descriptor reads returning no bytes
+    pub empty_report_descriptors: Arc<Mutex<u32>>,
+    pub reports: Arc<Mutex<VecDeque<Vec<u8>>>>,
+    /// Returned by `get_feature_report`, starting with the report ID
+    pub feature_report: Vec<u8>,
+    /// Messages of the errors the next writes fail with
+    pub write_failures: Arc<Mutex<VecDeque<String>>>,
+    pub log: Arc<Mutex<FakeLog>>,
+}
+
+impl HidBackend for FakeHidDevice {
+    fn get_report_descriptor(&self, buf: &mut [u8]) -> hidapi::HidResult<usize> {
+        let mut empty = self.empty_report_descriptors.lock().unwrap();
+        if *empty > 0 {
+            *empty -= 1;
+            return Ok(0);
+        }
+        let len = self.report_descriptor.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.report_descriptor[..len]);
+        Ok(len)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> hidapi::HidResult<usize> {
+        match self.reports.lock().unwrap().pop_front() {
+            Some(report) => {
+                let len = report.len().min(buf.len());
+                buf[..len].copy_from_slice(&report[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+        if let Some(message) = self.write_failures.lock().unwrap().pop_front() {
+            return Err(hidapi::HidError::HidApiError { message });
+        }
+        let report = match data.first() {
+            Some(0x00) => &data[1..],
+            _ => data,
+        };
+        self.log.lock().unwrap().written.push(report.to_vec());
+        Ok(data.len())
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> hidapi::HidResult<usize> {
+        let len = self.feature_report.len().min(buf.len());
+        buf[..len].copy_from_slice(&self.feature_report[..len]);
+        Ok(len)
+    }
+
+    fn send_feature_report(&self, data: &[u8]) -> hidapi::HidResult<()> {
+        self.log.lock().unwrap().features.push(data.to_vec());
+        Ok(())
+    }
+
+    fn set_idle(&self, duration: u8, report_id: u8) -> hidapi::HidResult<()> {
+        self.log.lock().unwrap().idle.push((duration, report_id));
+        Ok(())
+    }
+}
@@ -1,45 +1,208 @@
 use crate::device::ControlSetup;
+use crate::hexdump::hexdump;
+use crate::hid_backend::{HidApiBackend, HidBackend, HidDeviceInfo};
+use crate::trace;
+use crate::transfer::TransferConfig;
+use crate::usb_backend::{self, UsbBackend, parse_configuration};
 use hidapi::MAX_REPORT_DESCRIPTOR_SIZE;
-use log::debug;
+use log::{debug, warn};
 use nusb::transfer::{ControlType, Recipient};
 use std::any::Any;
+use std::collections::BTreeSet;
 use std::fmt::Debug;
 use std::io;
-use usbip::StandardRequest::GetDescriptor;
+use std::time::Duration;
+use usbip::StandardRequest::{GetDescriptor, GetStatus};
 use usbip::hid::HidDescriptorType;
 use usbip::{EndpointAttributes, SetupPacket, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
 
+const FIDO_USAGE_PAGE: u16 = 0xF1D0;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+const HID_INTERFACE_CLASS: u8 = 0x03;
+
 #[derive(Debug)]
 pub struct FIDOInterfaceHandler {
     class_desc: Vec<u8>,
-    device: hidapi::HidDevice,
+    device: Box<dyn HidBackend>,
+    // HID device opened last, reopened by serial number (or path) on refresh
+    identity: HidDeviceInfo,
+    // Physical HID interface relayed, the FIDO/U2F one if `None`
+    bound_interface: Option<u8>,
     report_desc: Option<Vec<u8>>,
+    // Forward SET_IDLE to the HID device instead of acknowledging it
+    forward_set_idle: bool,
+    transfer: TransferConfig,
 }
 
 impl FIDOInterfaceHandler {
-    pub fn new(device: nusb::Device) -> io::Result<FIDOInterfaceHandler> {
+    pub fn new(
+        device: &dyn UsbBackend,
+        hidapi: &dyn HidApiBackend,
+        transfer: TransferConfig,
+    ) -> io::Result<FIDOInterfaceHandler> {
+        Self::open_bound(device, hidapi, None, transfer)
+    }
+
+    /// Relay the HID interface `interface_number` of `device` rather than the FIDO/U2F one, e.g.
+    /// a management interface of a composite key
+    pub fn with_interface(
+        device: &dyn UsbBackend,
+        hidapi: &dyn HidApiBackend,
+        interface_number: u8,
+        transfer: TransferConfig,
+    ) -> io::Result<FIDOInterfaceHandler> {
+        Self::open_bound(device, hidapi, Some(interface_number), transfer)
+    }
+
+    fn open_bound(
+        device: &dyn UsbBackend,
+        hidapi: &dyn HidApiBackend,
+        bound_interface: Option<u8>,
+        transfer: TransferConfig,
+    ) -> io::Result<FIDOInterfaceHandler> {
+        let (class_desc, device, identity) = Self::open(device, hidapi, None, bound_interface)?;
+        Ok(Self {
+            class_desc,
+            device,
+            identity,
+            bound_interface,
+            report_desc: None,
+            forward_set_idle: false,
+            transfer,
+        })
+    }
+
+    /// Forward SET_IDLE requests to the HID device rather than only acknowledging them
+    ///
+    /// A failed forward is logged and the request still acknowledged, hidapi itself has no way
+    /// of issuing it.
+    pub fn set_forward_set_idle(&mut self, forward: bool) {
+        self.forward_set_idle = forward;
+    }
+
+    /// Re-resolve the FIDO interface on `device` and reopen its HID device, e.g. after a replug
+    ///
+    /// Only the HID device with the serial number of the one opened before is accepted, or with
+    /// its path if it has no serial number. The cached report descriptor is dropped along with
+    /// the old HID handle.
+    pub fn refresh(
+        &mut self,
+        device: &dyn UsbBackend,
+        hidapi: &dyn HidApiBackend,
+    ) -> io::Result<()> {
+        let (class_desc, device, identity) =
+            Self::open(device, hidapi, Some(&self.identity), self.bound_interface)?;
+        self.class_desc = class_desc;
+        self.device = device;
+        self.identity = identity;
+        self.report_desc = None;
+        Ok(())
+    }
+
+    /// Drop the cached report descriptor, read again on the next request
+    pub fn reset(&mut self) {
+        self.report_desc = None;
+    }
+
+    /// Number of the relayed HID interface on the physical device
+    pub fn interface_number(&self) -> u8 {
+        self.identity.interface_number as u8
+    }
+
+    /// What the relayed HID interface is, for logs and interface strings
+    pub fn name(&self) -> &'static str {
+        match self.bound_interface {
+            Some(_) => "HID",
+            None => "FIDO/U2F",
+        }
+    }
+
+    fn open(
+        device: &dyn UsbBackend,
+        hidapi: &dyn HidApiBackend,
+        identity: Option<&HidDeviceInfo>,
+        bound_interface: Option<u8>,
+    ) -> io::Result<(Vec<u8>, Box<dyn HidBackend>, HidDeviceInfo)> {
         let desc = device.device_descriptor();
-        let hidapi = hidapi::HidApi::new().map_err(|e| {
-            io::Error::other(format!("Failed to initialize HID API library: {}", e))
-        })?;
+        let configuration = device.active_configuration()?;
+        let configuration = parse_configuration(&configuration)?;
+        let is_hid_interface = |number: i32| {
+            configuration.interfaces().any(|intf| {
+                intf.interface_number() as i32 == number
+                    && intf
+                        .alt_settings()
+                        .any(|setting| setting.class() == HID_INTERFACE_CLASS)
+            })
+        };
 
-        let dev_info = hidapi
+        let mut candidates: Vec<_> = hidapi
             .device_list()
-            .find(|dev| {
-                dev.vendor_id() == desc.vendor_id()
-                    && desc.product_id() == desc.product_id()
-                    && dev.usage_page() == 0xF1D0
+            .into_iter()
+            .filter(|dev| {
+                dev.vendor_id == desc.vendor_id()
+                    && dev.product_id == desc.product_id()
+                    && identity.is_none_or(|identity| match &identity.serial_number {
+                        Some(serial_number) => dev.serial_number.as_ref() == Some(serial_number),
+                        None => dev.path == identity.path,
+                    })
             })
-            .ok_or(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!(
-                    "No FIDO device with PID = 0x{:04X}, VID = {:04X} found",
-                    desc.vendor_id(),
-                    desc.product_id()
-                ),
-            ))?;
-        let descs = device.active_configuration()?.interfaces().find(|intf| {
-            intf.interface_number() == dev_info.interface_number() as u8
+            .collect();
+        // HID devices of several keys are paired with the USB device by the serial number
+        // they share
+        let serials: BTreeSet<_> = candidates
+            .iter()
+            .filter_map(|dev| dev.serial_number.as_deref())
+            .collect();
+        if identity.is_none() && serials.len() > 1 {
+            let Some(serial_number) = usb_backend::serial_number(device) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "HID devices of several keys with PID = 0x{:04X}, VID = {:04X} found, the USB device has no serial number to pair them by",
+                        desc.vendor_id(),
+                        desc.product_id()
+                    ),
+                ));
+            };
+            debug!("Pairing HID devices by serial number {}", serial_number);
+            candidates.retain(|dev| dev.serial_number.as_ref() == Some(&serial_number));
+        }
+        let dev_info = match bound_interface {
+            Some(number) => candidates
+                .iter()
+                .find(|dev| dev.interface_number == number as i32)
+                .filter(|dev| is_hid_interface(dev.interface_number))
+                .cloned()
+                .ok_or(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "No HID interface {} of device with PID = 0x{:04X}, VID = {:04X} found",
+                        number,
+                        desc.vendor_id(),
+                        desc.product_id()
+                    ),
+                ))?,
+            // Without a reported usage page, any HID interface of the key is taken as FIDO/U2F
+            None => candidates
+                .iter()
+                .find(|dev| dev.usage_page == Some(FIDO_USAGE_PAGE))
+                .or_else(|| {
+                    candidates.iter().find(|dev| {
+                        dev.usage_page.is_none() && is_hid_interface(dev.interface_number)
+                    })
+                })
+                .cloned()
+                .ok_or(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "No FIDO device with PID = 0x{:04X}, VID = {:04X} found",
+                        desc.vendor_id(),
+                        desc.product_id()
+                    ),
+                ))?,
+        };
+        let descs = configuration.interfaces().find(|intf| {
+            intf.interface_number() == dev_info.interface_number as u8
         }).ok_or(io::Error::new(io::ErrorKind::NotFound, format!("Failed to get interface descriptors of FIDO device with PID = 0x{:04X}, VID = {:04X}", desc.vendor_id(), desc.product_id())))?;
         let mut class_desc = None;
         for setting in descs.alt_settings() {
@@ -55,9 +218,9 @@ impl FIDOInterfaceHandler {
             None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("No HID class descriptor of FIDO device with PID = 0x{:04X}, VID = {:04X} found", desc.vendor_id(), desc.product_id())))
         }.to_vec();
 
-        debug!("FIDO class desc: {:02X?}", class_desc);
+        debug!("FIDO class desc: {}", hexdump(&class_desc));
 
-        let device = dev_info.open_device(&hidapi).map_err(|e| {
+        let device = hidapi.open(&dev_info).map_err(|e| {
             io::Error::other(format!(
                 "Failed to open FIDO device with PID = 0x{:04X}, VID = {:04X}: {}",
                 desc.vendor_id(),
@@ -65,23 +228,151 @@ impl FIDOInterfaceHandler {
                 e
             ))
         })?;
-        Ok(Self {
-            class_desc,
-            device,
-            report_desc: None,
-        })
+        Ok((class_desc, device, dev_info))
+    }
+
+    /// Write `data` to the HID device, retrying transient failures as configured
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+        let mut retries = self.transfer.hid_write_retries;
+        loop {
+            match self.device.write(data) {
+                Err(e) if retries > 0 && !is_disconnect(&e) => {
+                    debug!("Failed to write to HID device, retrying: {}", e);
+                    retries -= 1;
+                    std::thread::sleep(WRITE_RETRY_DELAY);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// HID read timeout in milliseconds, as hidapi takes it
+    fn read_timeout(&self) -> i32 {
+        self.transfer.interrupt_read_timeout.as_millis() as i32
+    }
+
+    fn report_descriptor(&mut self) -> io::Result<&[u8]> {
+        if self.report_desc.is_none() {
+            let mut buffer = vec![0u8; MAX_REPORT_DESCRIPTOR_SIZE];
+            let size = self
+                .device
+                .get_report_descriptor(&mut buffer)
+                .map_err(|e| {
+                    io::Error::other(format!(
+                        "Failed to get HID report descriptor from device: {}",
+                        e
+                    ))
+                })?;
+            // Not cached, the next request reads it again
+            if size == 0 {
+                warn!("Device returned an empty HID report descriptor");
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Empty HID report descriptor",
+                ));
+            }
+            buffer.truncate(size);
+            self.report_desc = Some(buffer);
+        }
+        Ok(self.report_desc.as_deref().unwrap())
+    }
+
+    /// Whether the reports of the device start with a report ID, assumed not if the report
+    /// descriptor can not be read
+    fn numbered_reports(&mut self) -> bool {
+        match self.report_descriptor() {
+            Ok(desc) => uses_report_ids(desc),
+            Err(e) => {
+                debug!("FIDO: Assume unnumbered reports: {}", e);
+                false
+            }
+        }
+    }
+
+    /// GET_REPORT and SET_REPORT, dispatched on the report type in the high byte of wValue
+    ///
+    /// Input reports are read like interrupt IN transfers, output reports written like
+    /// interrupt OUT transfers, and feature reports go through the feature report calls.
+    /// Other combinations fail, which stalls the request.
+    fn handle_report(
+        &mut self,
+        control: &ControlSetup,
+        transfer_buffer_length: u32,
+    ) -> io::Result<Vec<u8>> {
+        let report_id = control.value() as u8;
+        let report_type = ReportType::from_value(control.value());
+        debug!(
+            "FIDO: Report request 0x{:02X}, type {:?}, ID {}",
+            control.request(),
+            report_type,
+            report_id
+        );
+        match (control, report_type) {
+            (ControlSetup::In(_), Some(ReportType::Input)) => {
+                let mut report = vec![0u8; transfer_buffer_length as usize];
+                let size = self
+                    .device
+                    .read_timeout(&mut report, self.read_timeout())
+                    .map_err(|e| io::Error::other(format!("Failed to read input report: {}", e)))?;
+                report.truncate(size);
+                Ok(report)
+            }
+            (ControlSetup::In(_), Some(ReportType::Feature)) => {
+                let mut report = vec![0u8; transfer_buffer_length as usize + 1];
+                report[0] = report_id;
+                let size = self.device.get_feature_report(&mut report).map_err(|e| {
+                    io::Error::other(format!("Failed to get feature report: {}", e))
+                })?;
+                report.truncate(size);
+                // The report ID is only sent on the wire for numbered reports
+                if report_id == 0 && !report.is_empty() {
+                    report.remove(0);
+                }
+                report.truncate(transfer_buffer_length as usize);
+                Ok(report)
+            }
+            (ControlSetup::Out(out), Some(ReportType::Output)) => {
+                self.write(&hidapi_report(report_id, out.data))
+                    .map_err(|e| {
+                        io::Error::other(format!("Failed to write output report: {}", e))
+                    })?;
+                Ok(vec![])
+            }
+            (ControlSetup::Out(out), Some(ReportType::Feature)) => {
+                self.device
+                    .send_feature_report(&hidapi_report(report_id, out.data))
+                    .map_err(|e| {
+                        io::Error::other(format!("Failed to send feature report: {}", e))
+                    })?;
+                Ok(vec![])
+            }
+            (control, _) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Unsupported HID report request 0x{:02X} with wValue 0x{:04X}",
+                    control.request(),
+                    control.value()
+                ),
+            )),
+        }
     }
 
     pub fn endpoints() -> Vec<UsbEndpoint> {
+        Self::endpoints_at(0x02)
+    }
+
+    /// Interrupt IN and OUT endpoints with endpoint number `number`, for relaying several HID
+    /// interfaces
+    pub fn endpoints_at(number: u8) -> Vec<UsbEndpoint> {
         vec![
             UsbEndpoint {
-                address: 0x82,
+                address: 0x80 | number,
                 attributes: EndpointAttributes::Interrupt as u8,
                 max_packet_size: 64,
                 interval: 6,
             },
             UsbEndpoint {
-                address: 0x02,
+                address: number,
                 attributes: EndpointAttributes::Interrupt as u8,
                 max_packet_size: 64,
                 interval: 6,
@@ -90,6 +381,87 @@ impl FIDOInterfaceHandler {
     }
 }
 
+// HID class requests, HID 1.11 7.2
+const GET_REPORT: u8 = 0x01;
+const SET_REPORT: u8 = 0x09;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ReportType {
+    Input,
+    Output,
+    Feature,
+}
+
+impl ReportType {
+    fn from_value(value: u16) -> Option<ReportType> {
+        match value >> 8 {
+            1 => Some(ReportType::Input),
+            2 => Some(ReportType::Output),
+            3 => Some(ReportType::Feature),
+            _ => None,
+        }
+    }
+}
+
+/// Report as hidapi takes it, which always starts with the report ID, 0 for unnumbered reports
+fn hidapi_report(report_id: u8, report: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(report.len() + 1);
+    if report_id == 0 {
+        data.push(0);
+    }
+    data.extend_from_slice(report);
+    data
+}
+
+/// CTAPHID input report read by hidapi as sent on the wire, the inverse of interrupt OUT adding
+/// the report ID
+///
+/// Only for the FIDO/U2F interface, reports of other HID interfaces are relayed as read. The
+/// report ID 0 of unnumbered reports is not on the wire, but some platforms put it in
+/// front of a full `report_size` report. CTAPHID reports always have `report_size` bytes, a
+/// shorter one is padded with zeros. Nothing read stays empty.
+fn wire_report(mut report: Vec<u8>, numbered: bool, report_size: usize) -> Vec<u8> {
+    if report.is_empty() || numbered {
+        return report;
+    }
+    if report.len() == report_size + 1 && report[0] == 0 {
+        report.remove(0);
+    }
+    if report.len() < report_size {
+        report.resize(report_size, 0);
+    }
+    report
+}
+
+/// Whether a failed HID call means the device is gone, hidapi only tells it by the message
+fn is_disconnect(e: &hidapi::HidError) -> bool {
+    match e {
+        hidapi::HidError::HidApiError { message } => {
+            ["No such device", "disconnected", "not connected"]
+                .iter()
+                .any(|gone| message.contains(gone))
+        }
+        hidapi::HidError::IoError { error } => error.kind() == io::ErrorKind::NotConnected,
+        _ => false,
+    }
+}
+
+/// Whether a HID report descriptor declares any Report ID item
+fn uses_report_ids(report_desc: &[u8]) -> bool {
+    let mut data = report_desc;
+    while let Some(&prefix) = data.first() {
+        let len = match prefix {
+            0xFE => 3 + *data.get(1).unwrap_or(&0) as usize, // Long item
+            _ => 1 + [0, 1, 2, 4][(prefix & 0x03) as usize],
+        };
+        if prefix & 0xFC == 0x84 {
+            return true;
+        }
+        data = &data[len.min(data.len())..];
+    }
+    false
+}
+
 impl UsbInterfaceHandler for FIDOInterfaceHandler {
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         debug!("FIDO: get_class_specific_descriptor");
@@ -103,9 +475,13 @@ impl UsbInterfaceHandler for FIDOInterfaceHandler {
         setup: SetupPacket,
         req: &[u8],
     ) -> std::io::Result<Vec<u8>> {
+        trace::urb("fido", ep.address, transfer_buffer_length, &setup, req);
         debug!(
-            "FIDO: handle_urb: ep: {:0X?}, transfer_buffer_length: {:0X?}, setup: {:02X?}, req: {:02X?}",
-            ep, transfer_buffer_length, setup, req
+            "FIDO: handle_urb: ep: {:0X?}, transfer_buffer_length: {:0X?}, setup: {:02X?}, req: {}",
+            ep,
+            transfer_buffer_length,
+            setup,
+            hexdump(req)
         );
         if ep.is_ep0() {
             let control = ControlSetup::new(&setup, Some(req))?;
@@ -115,25 +491,18 @@ impl UsbInterfaceHandler for FIDOInterfaceHandler {
                         && control.recipient == Recipient::Interface
                         && control.request == GetDescriptor as u8 =>
                 {
+                    // GET_DESCRIPTOR has no offset, hosts reading a descriptor in parts read
+                    // it again from the start with a larger wLength
+                    let length = (control.length as u32).min(transfer_buffer_length) as usize;
                     match (control.value >> 8) as u8 {
+                        v if v == HidDescriptorType::Hid as u8 => {
+                            let mut out = self.class_desc.clone();
+                            out.truncate(length);
+                            Ok(out)
+                        }
                         v if v == HidDescriptorType::Report as u8 => {
-                            if self.report_desc.is_none() {
-                                let mut buffer = vec![0u8; MAX_REPORT_DESCRIPTOR_SIZE];
-                                let size = self.device.get_report_descriptor(&mut buffer).map_err(
-                                    |e| {
-                                        io::Error::other(format!(
-                                            "Failed to get HID report descriptor from device: {}",
-                                            e
-                                        ))
-                                    },
-                                )?;
-                                buffer.truncate(size);
-                                self.report_desc = Some(buffer);
-                            }
-                            let mut out = self.report_desc.clone().unwrap();
-                            if out.len() > transfer_buffer_length as usize {
-                                out.truncate(transfer_buffer_length as usize);
-                            }
+                            let mut out = self.report_descriptor()?.to_vec();
+                            out.truncate(length);
                             Ok(out)
                         }
                         v => Err(io::Error::other(format!(
@@ -142,12 +511,33 @@ impl UsbInterfaceHandler for FIDOInterfaceHandler {
                         ))),
                     }
                 }
+                ControlSetup::In(control)
+                    if control.control_type == ControlType::Standard
+                        && control.recipient == Recipient::Interface
+                        && control.request == GetStatus as u8 =>
+                {
+                    // No status bits are defined for interfaces
+                    Ok(vec![0x00, 0x00])
+                }
+                ControlSetup::In(_) | ControlSetup::Out(_)
+                    if control.control_type() == ControlType::Class
+                        && control.recipient() == Recipient::Interface
+                        && matches!(control.request(), GET_REPORT | SET_REPORT) =>
+                {
+                    self.handle_report(&control, transfer_buffer_length)
+                }
                 ControlSetup::Out(control)
                     if control.control_type == ControlType::Class
                         && control.recipient == Recipient::Interface
                         && control.request == 0x0Au8 =>
                 {
                     debug!("FIDO: Received SetIdle HID request: {:0X?}", control);
+                    if self.forward_set_idle {
+                        let [report_id, duration] = control.value.to_le_bytes();
+                        if let Err(e) = self.device.set_idle(duration, report_id) {
+                            warn!("FIDO: Failed to forward SetIdle to HID device: {}", e);
+                        }
+                    }
                     Ok(vec![])
                 }
                 other => Err(io::Error::other(format!(
@@ -156,14 +546,25 @@ impl UsbInterfaceHandler for FIDOInterfaceHandler {
                 ))),
             }
         } else {
+            // Endpoint numbers depend on the position of the interface, see `endpoints_at`
+            let interrupt = ep.attributes == EndpointAttributes::Interrupt as u8;
             match ep.address {
-                0x82 => {
-                    // interrupt IN
-                    let mut report = vec![0u8; transfer_buffer_length as usize];
-                    match self.device.read_timeout(&mut report, 4) {
+                address if interrupt && address & 0x80 != 0 => {
+                    // interrupt IN, with room for a report ID hidapi may put in front
+                    let mut report = vec![0u8; transfer_buffer_length as usize + 1];
+                    match self.device.read_timeout(&mut report, self.read_timeout()) {
                         Ok(v) => {
                             debug!("FIDO Interrupt IN: Read {:0X?} bytes from device", v);
                             report.truncate(v);
+                            let mut report = match self.bound_interface {
+                                None => wire_report(
+                                    report,
+                                    self.numbered_reports(),
+                                    ep.max_packet_size as usize,
+                                ),
+                                Some(_) => report,
+                            };
+                            report.truncate(transfer_buffer_length as usize);
                             Ok(report)
                         }
                         Err(e) => {
@@ -175,10 +576,14 @@ impl UsbInterfaceHandler for FIDOInterfaceHandler {
                         }
                     }
                 }
-                0x02 => {
+                _ if interrupt => {
+                    // hidapi expects the report ID as first byte, which is already part of the
+                    // report for devices using numbered reports, and 0 for the others.
                     let mut req = req.to_vec();
-                    req.insert(0, 0x0);
-                    match self.device.write(&req) {
+                    if !self.numbered_reports() {
+                        req.insert(0, 0x0);
+                    }
+                    match self.write(&req) {
                         Ok(v) => {
                             debug!("FIDO Interrupt OUT: Write {:0X?} bytes to device", v);
                             Ok(Vec::new())
@@ -204,7 +609,536 @@ impl UsbInterfaceHandler for FIDOInterfaceHandler {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::fake::{FakeHidApi, FakeHidDevice, FakeUsbDevice, PIGEON_HID_DESCRIPTOR};
+    use crate::reserved::ReservedInterfaceHandler;
     use hidapi::MAX_REPORT_DESCRIPTOR_SIZE;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const EP0: UsbEndpoint = UsbEndpoint {
+        address: 0x80,
+        attributes: EndpointAttributes::Control as u8,
+        max_packet_size: 64,
+        interval: 0,
+    };
+
+    const EP_OUT: UsbEndpoint = UsbEndpoint {
+        address: 0x02,
+        attributes: EndpointAttributes::Interrupt as u8,
+        max_packet_size: 64,
+        interval: 5,
+    };
+
+    fn interface() -> UsbInterface {
+        UsbInterface {
+            interface_class: 0x03,
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            interface_number: 0x00,
+            endpoints: FIDOInterfaceHandler::endpoints(),
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        }
+    }
+
+    fn get_descriptor(descriptor_type: HidDescriptorType, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: 0x81,
+            request: GetDescriptor as u8,
+            value: (descriptor_type as u16) << 8,
+            index: 0,
+            length,
+        }
+    }
+
+    #[test]
+    fn test_get_hid_descriptor() {
+        let mut handler = FIDOInterfaceHandler::new(
+            &FakeUsbDevice::pigeon(),
+            &FakeHidApi::pigeon(),
+            TransferConfig::default(),
+        )
+        .unwrap();
+        let desc = handler
+            .handle_urb(
+                &interface(),
+                EP0,
+                0xFF,
+                get_descriptor(HidDescriptorType::Hid, 0xFF),
+                &[],
+            )
+            .unwrap();
+        assert_eq!(desc, PIGEON_HID_DESCRIPTOR);
+        let desc = handler
+            .handle_urb(
+                &interface(),
+                EP0,
+                4,
+                get_descriptor(HidDescriptorType::Hid, 4),
+                &[],
+            )
+            .unwrap();
+        assert_eq!(desc, PIGEON_HID_DESCRIPTOR[..4]);
+    }
+
+    #[test]
+    fn test_get_status() {
+        let mut handler = FIDOInterfaceHandler::new(
+            &FakeUsbDevice::pigeon(),
+            &FakeHidApi::pigeon(),
+            TransferConfig::default(),
+        )
+        .unwrap();
+        let setup = SetupPacket {
+            request_type: 0x81,
+            request: GetStatus as u8,
+            value: 0,
+            index: 0,
+            length: 2,
+        };
+        let status = handler
+            .handle_urb(&interface(), EP0, 2, setup, &[])
+            .unwrap();
+        assert_eq!(status, [0x00, 0x00]);
+    }
+
+    #[test]
+    fn test_report_descriptor_in_parts() {
+        let mut hidapi = FakeHidApi::pigeon();
+        let report_descriptor: Vec<u8> = (0..0x22).collect();
+        hidapi.device.report_descriptor = report_descriptor.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+        // wLength limits the reply even if the transfer buffer is larger
+        let first = handler
+            .handle_urb(
+                &interface(),
+                EP0,
+                0xFF,
+                get_descriptor(HidDescriptorType::Report, 0x11),
+                &[],
+            )
+            .unwrap();
+        assert_eq!(first, report_descriptor[..0x11]);
+        let whole = handler
+            .handle_urb(
+                &interface(),
+                EP0,
+                0x22,
+                get_descriptor(HidDescriptorType::Report, 0x22),
+                &[],
+            )
+            .unwrap();
+        assert_eq!([&first[..], &whole[0x11..]].concat(), report_descriptor);
+    }
+
+    #[test]
+    fn test_empty_report_descriptor() {
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi.device.report_descriptor = vec![0x06, 0xD0, 0xF1, 0x09, 0x01];
+        *hidapi.device.empty_report_descriptors.lock().unwrap() = 1;
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+        let setup = get_descriptor(HidDescriptorType::Report, 0xFF);
+        assert!(
+            handler
+                .handle_urb(&interface(), EP0, 0xFF, setup, &[])
+                .is_err()
+        );
+        // Read again instead of the empty one being cached
+        let desc = handler
+            .handle_urb(&interface(), EP0, 0xFF, setup, &[])
+            .unwrap();
+        assert_eq!(desc, hidapi.device.report_descriptor);
+    }
+
+    #[test]
+    fn test_fake_hid_reports() {
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi.device.report_descriptor = vec![0x06, 0xD0, 0xF1, 0x09, 0x01];
+        hidapi
+            .device
+            .reports
+            .lock()
+            .unwrap()
+            .push_back(vec![0xFF; 64]);
+        let log = hidapi.device.log.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+        let desc = handler
+            .handle_urb(
+                &interface(),
+                EP0,
+                0xFF,
+                get_descriptor(HidDescriptorType::Report, 0xFF),
+                &[],
+            )
+            .unwrap();
+        assert_eq!(desc, hidapi.device.report_descriptor);
+
+        let endpoints = FIDOInterfaceHandler::endpoints();
+        handler
+            .handle_urb(
+                &interface(),
+                endpoints[1],
+                64,
+                SetupPacket::default(),
+                &[0x01; 64],
+            )
+            .unwrap();
+        assert_eq!(log.lock().unwrap().written, vec![vec![0x01; 64]]);
+        let report = handler
+            .handle_urb(&interface(), endpoints[0], 64, SetupPacket::default(), &[])
+            .unwrap();
+        assert_eq!(report, vec![0xFF; 64]);
+    }
+
+    #[test]
+    fn test_report_ids() {
+        // Usage Page (FIDO), Usage (CTAPHID), Collection (Application), ..., End Collection
+        let unnumbered = [0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01, 0x09, 0x20, 0xC0];
+        assert!(!uses_report_ids(&unnumbered));
+        // Long item whose data contains 0x85 must be skipped as a whole
+        assert!(!uses_report_ids(&[0xFE, 0x02, 0x10, 0x85, 0x01]));
+        assert!(uses_report_ids(&[
+            0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x02, 0xC0
+        ]));
+    }
+
+    fn write_report(report_descriptor: &[u8], report: &[u8]) -> Vec<Vec<u8>> {
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi.device.report_descriptor = report_descriptor.to_vec();
+        let log = hidapi.device.log.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+        let endpoints = FIDOInterfaceHandler::endpoints();
+        handler
+            .handle_urb(
+                &interface(),
+                endpoints[1],
+                report.len() as u32,
+                SetupPacket::default(),
+                report,
+            )
+            .unwrap();
+        log.lock().unwrap().written.clone()
+    }
+
+    #[test]
+    fn test_write_without_report_id() {
+        let report = [0xFF, 0xFF, 0xFF, 0xFF, 0x86, 0x00, 0x08];
+        let written = write_report(&[0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01, 0xC0], &report);
+        assert_eq!(written, vec![report.to_vec()]);
+    }
+
+    #[test]
+    fn test_write_with_report_id() {
+        let report = [0x02, 0xFF, 0xFF, 0xFF, 0xFF, 0x86, 0x00, 0x08];
+        let written = write_report(
+            &[0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x02, 0xC0],
+            &report,
+        );
+        assert_eq!(written, vec![report.to_vec()]);
+    }
+
+    #[test]
+    fn test_report_id_round_trip() {
+        // The report read back is the one written, on the wire and towards hidapi
+        let round_trip = |report_descriptor: &[u8], report: &[u8], read: Vec<u8>| {
+            let mut hidapi = FakeHidApi::pigeon();
+            hidapi.device.report_descriptor = report_descriptor.to_vec();
+            hidapi.device.reports.lock().unwrap().push_back(read);
+            let log = hidapi.device.log.clone();
+            let mut handler = FIDOInterfaceHandler::new(
+                &FakeUsbDevice::pigeon(),
+                &hidapi,
+                TransferConfig::default(),
+            )
+            .unwrap();
+            let endpoints = FIDOInterfaceHandler::endpoints();
+            handler
+                .handle_urb(
+                    &interface(),
+                    endpoints[1],
+                    64,
+                    SetupPacket::default(),
+                    report,
+                )
+                .unwrap();
+            let read = handler
+                .handle_urb(&interface(), endpoints[0], 64, SetupPacket::default(), &[])
+                .unwrap();
+            assert_eq!(read, report);
+            log.lock().unwrap().written.clone()
+        };
+        let unnumbered = [0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01, 0xC0];
+        let report: Vec<u8> = (0..64).collect();
+        // Read without and with the report ID 0 hidapi was given
+        assert_eq!(
+            round_trip(&unnumbered, &report, report.clone()),
+            vec![report.clone()]
+        );
+        let read = [&[0x00], &report[..]].concat();
+        assert_eq!(round_trip(&unnumbered, &report, read), vec![report.clone()]);
+        // Short reports are padded
+        let mut short = vec![0xFF, 0xFF, 0xFF, 0xFF, 0x86, 0x00, 0x08];
+        short.resize(64, 0);
+        round_trip(&unnumbered, &short, short[..7].to_vec());
+
+        // Numbered reports keep their report ID both ways
+        let numbered = [0x06, 0xD0, 0xF1, 0x09, 0x01, 0xA1, 0x01, 0x85, 0x02, 0xC0];
+        let report = [&[0x02], &report[..63]].concat();
+        assert_eq!(
+            round_trip(&numbered, &report, report.clone()),
+            vec![report.clone()]
+        );
+
+        assert_eq!(wire_report(vec![], false, 64), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_bound_interface_reports_as_read() {
+        // Neither stripped nor padded like CTAPHID reports
+        let reports = [vec![0x00, 0x01, 0x02], [&[0x00], &[0xAA; 64][..]].concat()];
+        let device = FakeHidDevice::default();
+        device
+            .reports
+            .lock()
+            .unwrap()
+            .extend(reports.iter().cloned());
+        let hidapi = FakeHidApi::pigeon().with_hid_interface(3, device);
+        let mut handler = FIDOInterfaceHandler::with_interface(
+            &FakeUsbDevice::pigeon().with_hid_interface(3),
+            &hidapi,
+            3,
+            TransferConfig::default(),
+        )
+        .unwrap();
+        let endpoints = FIDOInterfaceHandler::endpoints();
+        for report in &reports {
+            let read = handler
+                .handle_urb(&interface(), endpoints[0], 65, SetupPacket::default(), &[])
+                .unwrap();
+            assert_eq!(&read, report);
+        }
+    }
+
+    #[test]
+    fn test_report_requests() {
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi
+            .device
+            .reports
+            .lock()
+            .unwrap()
+            .push_back(vec![0xAA; 4]);
+        hidapi.device.feature_report = vec![0x00, 0xBB, 0xBB];
+        let log = hidapi.device.log.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+
+        // bRequest, report type, data and the expected response
+        type Case = (u8, u16, &'static [u8], Option<&'static [u8]>);
+        let matrix: [Case; 8] = [
+            (GET_REPORT, 1, &[], Some(&[0xAA; 4])),
+            (GET_REPORT, 2, &[], None),
+            (GET_REPORT, 3, &[], Some(&[0xBB, 0xBB])),
+            (SET_REPORT, 1, &[0x01], None),
+            (SET_REPORT, 2, &[0x02, 0x02], Some(&[])),
+            (SET_REPORT, 3, &[0x03], Some(&[])),
+            (GET_REPORT, 0, &[], None),
+            (SET_REPORT, 4, &[0x04], None),
+        ];
+        for (request, report_type, data, expected) in matrix {
+            let setup = SetupPacket {
+                request_type: if request == GET_REPORT { 0xA1 } else { 0x21 },
+                request,
+                value: report_type << 8,
+                index: 0,
+                length: 0x40,
+            };
+            let result = handler.handle_urb(&interface(), EP0, 0x40, setup, data);
+            assert_eq!(
+                result.ok().as_deref(),
+                expected,
+                "request 0x{:02X} report type {}",
+                request,
+                report_type
+            );
+        }
+        let log = log.lock().unwrap();
+        assert_eq!(log.written, vec![vec![0x02, 0x02]]);
+        assert_eq!(log.features, vec![vec![0x00, 0x03]]);
+    }
+
+    #[test]
+    fn test_transfer_config() {
+        let transfer = TransferConfig {
+            control_timeout: Duration::from_secs(1),
+            interrupt_read_timeout: Duration::from_millis(20),
+            transmit_timeout: Some(Duration::from_secs(30)),
+            command_delay: Duration::ZERO,
+            hid_write_retries: 3,
+        };
+        let handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &FakeHidApi::pigeon(), transfer)
+                .unwrap();
+        assert_eq!(handler.transfer, transfer);
+        assert_eq!(handler.read_timeout(), 20);
+    }
+
+    #[test]
+    fn test_write_retry() {
+        let hidapi = FakeHidApi::pigeon();
+        let log = hidapi.device.log.clone();
+        let failures = hidapi.device.write_failures.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+        let report = [0x01; 0x40];
+
+        failures
+            .lock()
+            .unwrap()
+            .push_back("hid_write: Resource temporarily unavailable".to_string());
+        let result =
+            handler.handle_urb(&interface(), EP_OUT, 0x40, SetupPacket::default(), &report);
+        assert_eq!(result.unwrap(), vec![]);
+        assert_eq!(log.lock().unwrap().written, vec![report.to_vec()]);
+
+        // Out of retries
+        failures
+            .lock()
+            .unwrap()
+            .extend(["busy".to_string(), "busy".to_string()]);
+        let result =
+            handler.handle_urb(&interface(), EP_OUT, 0x40, SetupPacket::default(), &report);
+        assert!(result.is_err());
+        failures.lock().unwrap().clear();
+
+        // A disconnect is not retried
+        failures
+            .lock()
+            .unwrap()
+            .extend(["hid_write: No such device".to_string(), String::new()]);
+        let result =
+            handler.handle_urb(&interface(), EP_OUT, 0x40, SetupPacket::default(), &report);
+        assert!(result.is_err());
+        assert_eq!(failures.lock().unwrap().len(), 1);
+        assert_eq!(log.lock().unwrap().written.len(), 1);
+    }
+
+    #[test]
+    fn test_set_idle() {
+        let hidapi = FakeHidApi::pigeon();
+        let log = hidapi.device.log.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+        let setup = SetupPacket {
+            request_type: 0x21,
+            request: 0x0A,
+            value: 0x7D00,
+            index: 0,
+            length: 0,
+        };
+
+        let result = handler.handle_urb(&interface(), EP0, 0, setup, &[]);
+        assert_eq!(result.unwrap(), vec![]);
+        assert!(log.lock().unwrap().idle.is_empty());
+
+        handler.set_forward_set_idle(true);
+        let result = handler.handle_urb(&interface(), EP0, 0, setup, &[]);
+        assert_eq!(result.unwrap(), vec![]);
+        assert_eq!(log.lock().unwrap().idle, vec![(0x7D, 0x00)]);
+    }
+
+    #[test]
+    fn test_refresh_same_serial() {
+        let mut hidapi = FakeHidApi::pigeon();
+        let log = hidapi.device.log.clone();
+        let mut handler =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap();
+
+        // Replugged under a new path, next to another key with the same VID/PID
+        let mut other = hidapi.devices[0].clone();
+        other.path = c"fake-hid-1".to_owned();
+        other.serial_number = Some("FAKE0002".to_string());
+        hidapi.devices[0].path = c"fake-hid-2".to_owned();
+        hidapi.devices.insert(0, other);
+        handler.refresh(&FakeUsbDevice::pigeon(), &hidapi).unwrap();
+        assert_eq!(
+            log.lock().unwrap().opened,
+            vec![c"fake-hid-0".to_owned(), c"fake-hid-2".to_owned()]
+        );
+
+        // Only the other key is left
+        hidapi.devices.remove(1);
+        let err = handler
+            .refresh(&FakeUsbDevice::pigeon(), &hidapi)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(log.lock().unwrap().opened.len(), 2);
+    }
+
+    #[test]
+    fn test_paired_by_serial() {
+        let mut hidapi = FakeHidApi::pigeon();
+        let log = hidapi.device.log.clone();
+        // Another key listed first
+        let mut other = hidapi.devices[0].clone();
+        other.path = c"fake-hid-1".to_owned();
+        other.serial_number = Some("FAKE0002".to_string());
+        hidapi.devices.insert(0, other);
+        FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+            .unwrap();
+        assert_eq!(log.lock().unwrap().opened, vec![c"fake-hid-0".to_owned()]);
+
+        let mut device = FakeUsbDevice::pigeon();
+        device.serial_number = Some("FAKE0002".to_string());
+        FIDOInterfaceHandler::new(&device, &hidapi, TransferConfig::default()).unwrap();
+        assert_eq!(log.lock().unwrap().opened[1], c"fake-hid-1".to_owned());
+
+        // Nothing to pair them by
+        device.serial_number = None;
+        let err =
+            FIDOInterfaceHandler::new(&device, &hidapi, TransferConfig::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(log.lock().unwrap().opened.len(), 2);
+    }
+
+    #[test]
+    fn test_without_usage_page() {
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi.devices[0].usage_page = None;
+        let log = hidapi.device.log.clone();
+        FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+            .unwrap();
+        assert_eq!(log.lock().unwrap().opened, vec![c"fake-hid-0".to_owned()]);
+
+        // Interface 2 is CCID, not HID
+        hidapi.devices[0].interface_number = 2;
+        let err =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // A reported usage page other than FIDO is not taken
+        hidapi.devices[0].interface_number = 0;
+        hidapi.devices[0].usage_page = Some(0x0001);
+        let err =
+            FIDOInterfaceHandler::new(&FakeUsbDevice::pigeon(), &hidapi, TransferConfig::default())
+                .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
 
     #[test]
     fn test_hid() {
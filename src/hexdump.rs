@@ -0,0 +1,22 @@
+use std::fmt::Write;
+
+/// Format `bytes` as contiguous uppercase hex, e.g. `00A40400`, for logging
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02X}", b).unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hexdump() {
+        assert_eq!(hexdump(&[]), "");
+        assert_eq!(hexdump(&[0xDE, 0xAD, 0xBE, 0xEF]), "DEADBEEF");
+        assert_eq!(hexdump(&[0x00, 0x0A, 0x90, 0x00]), "000A9000");
+    }
+}
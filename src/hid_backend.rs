@@ -0,0 +1,87 @@
+use std::ffi::CString;
+use std::fmt::Debug;
+
+/// HID report access used by [`crate::fido::FIDOInterfaceHandler`]
+pub trait HidBackend: Debug + Send {
+    fn get_report_descriptor(&self, buf: &mut [u8]) -> hidapi::HidResult<usize>;
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> hidapi::HidResult<usize>;
+
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize>;
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> hidapi::HidResult<usize>;
+
+    fn send_feature_report(&self, data: &[u8]) -> hidapi::HidResult<()>;
+
+    /// SET_IDLE with the idle `duration` in units of 4 ms for `report_id`, 0 for all reports
+    fn set_idle(&self, duration: u8, report_id: u8) -> hidapi::HidResult<()>;
+}
+
+#[derive(Debug, Clone)]
+pub struct HidDeviceInfo {
+    pub path: CString,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// `None` where the platform does not report it, e.g. with the libusb backend of hidapi
+    pub usage_page: Option<u16>,
+    pub interface_number: i32,
+    pub serial_number: Option<String>,
+}
+
+/// HID device enumeration
+///
+/// Implemented for [`hidapi::HidApi`], and by [`crate::fake::FakeHidApi`] in tests.
+pub trait HidApiBackend {
+    fn device_list(&self) -> Vec<HidDeviceInfo>;
+
+    fn open(&self, info: &HidDeviceInfo) -> hidapi::HidResult<Box<dyn HidBackend>>;
+}
+
+impl HidBackend for hidapi::HidDevice {
+    fn get_report_descriptor(&self, buf: &mut [u8]) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::get_report_descriptor(self, buf)
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout: i32) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::read_timeout(self, buf, timeout)
+    }
+
+    fn write(&self, data: &[u8]) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::write(self, data)
+    }
+
+    fn get_feature_report(&self, buf: &mut [u8]) -> hidapi::HidResult<usize> {
+        hidapi::HidDevice::get_feature_report(self, buf)
+    }
+
+    fn send_feature_report(&self, data: &[u8]) -> hidapi::HidResult<()> {
+        hidapi::HidDevice::send_feature_report(self, data)
+    }
+
+    // hidapi has no call issuing HID class requests
+    fn set_idle(&self, _duration: u8, _report_id: u8) -> hidapi::HidResult<()> {
+        Err(hidapi::HidError::HidApiError {
+            message: "SET_IDLE is not supported by hidapi".to_string(),
+        })
+    }
+}
+
+impl HidApiBackend for hidapi::HidApi {
+    fn device_list(&self) -> Vec<HidDeviceInfo> {
+        hidapi::HidApi::device_list(self)
+            .map(|dev| HidDeviceInfo {
+                path: dev.path().to_owned(),
+                vendor_id: dev.vendor_id(),
+                product_id: dev.product_id(),
+                // hidapi reports an unknown usage page as 0
+                usage_page: Some(dev.usage_page()).filter(|page| *page != 0),
+                interface_number: dev.interface_number(),
+                serial_number: dev.serial_number().map(str::to_owned),
+            })
+            .collect()
+    }
+
+    fn open(&self, info: &HidDeviceInfo) -> hidapi::HidResult<Box<dyn HidBackend>> {
+        Ok(Box::new(self.open_path(&info.path)?))
+    }
+}
@@ -0,0 +1,69 @@
+use crate::trace;
+use env_logger::{Builder, Target};
+use log::{LevelFilter, warn};
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Log to the file at `path`, or to stderr if it cannot be created
+fn log_target(path: &Path) -> (Target, Option<io::Error>) {
+    match File::create(path) {
+        Ok(file) => (Target::Pipe(Box::new(file)), None),
+        Err(e) => (Target::Stderr, Some(e)),
+    }
+}
+
+/// Set up logging to the file at `path`, falling back to stderr with a warning, URBs are only
+/// logged if `trace_urbs` is set
+pub fn init(path: &Path, trace_urbs: bool) {
+    let (target, error) = log_target(path);
+    Builder::new()
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "{}:{} {} [{}] - {}",
+                record.file().unwrap_or("unknown"),
+                record.line().unwrap_or(0),
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
+                record.level(),
+                record.args()
+            )
+        })
+        .target(target)
+        .filter(None, LevelFilter::Trace)
+        .filter(
+            Some(trace::TARGET),
+            if trace_urbs {
+                LevelFilter::Trace
+            } else {
+                LevelFilter::Off
+            },
+        )
+        .init();
+    if let Some(e) = error {
+        warn!(
+            "Can't create log file '{}', logging to stderr: {}",
+            path.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_target() {
+        let path = std::env::temp_dir().join(format!("smredir-{}.log", std::process::id()));
+        let (target, error) = log_target(&path);
+        assert!(matches!(target, Target::Pipe(_)));
+        assert!(error.is_none());
+        std::fs::remove_file(&path).unwrap();
+
+        let (target, error) = log_target(&path.join("smredir.log"));
+        assert!(matches!(target, Target::Stderr));
+        assert!(error.is_some());
+    }
+}
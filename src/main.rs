@@ -1,4 +1,3 @@
-#![feature(sync_unsafe_cell)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 #![allow(non_upper_case_globals)]
@@ -9,118 +8,398 @@
 extern crate core;
 use nusb::MaybeFuture;
 
-use crate::device::CanokeyVirtDeviceHandler;
-use crate::fido::FIDOInterfaceHandler;
-use crate::webusb::WebUSBInterfaceHandler;
-use env_logger::Builder;
-use log::LevelFilter;
-use std::fs::File;
-use std::io::Write;
+use crate::apdu_filter::{AllowAll, Allowlist, ApduFilter};
+use crate::ccid::{CCIDConfig, UnsupportedResponse};
+use crate::ccid_backend::{CCIDBackend, PcscBackend};
+use crate::cli::{Args, Command};
+use crate::device::RelayConfig;
+use crate::hid_backend::HidApiBackend;
+use crate::remote::RemoteBackend;
+use crate::reserved::optional_interface;
+use crate::transfer::TransferConfig;
+use crate::usb_backend::{DetachingBackend, UsbBackend};
+use clap::Parser;
+use log::{error, info};
+use std::ffi::CString;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::{Arc, Mutex};
-use usbip::{UsbDevice, UsbDeviceHandler, UsbInterfaceHandler, UsbIpServer, UsbSpeed};
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time::{Duration, Instant};
+use usbip::UsbIpServer;
 
+mod apdu_filter;
 mod ccid;
+mod ccid_backend;
 mod ccid_const;
+mod ccid_descriptor;
 mod ccid_proto;
+mod cli;
+mod client;
+mod daemon;
+mod descriptor;
 mod device;
+#[cfg(any(test, feature = "fake-backend"))]
+mod fake;
 mod fido;
+mod hexdump;
+mod hid_backend;
+mod logging;
+mod metrics;
+mod monitor;
+mod panic_guard;
+mod pidfile;
+mod remote;
 mod reserved;
+mod selftest;
+mod server;
+mod status_word;
+mod trace;
+mod transfer;
+mod usb_backend;
+mod websocket;
 mod webusb;
 
+fn reader_name(index: usize) -> CString {
+    CString::new(format!("{} {}", ccid_backend::KEY_READER_NAME, index))
+        .expect("Invalid reader name")
+}
+
+/// Open the Canokey `device`, detaching kernel drivers from its claimed interfaces if asked to
+fn open_device(device: &nusb::DeviceInfo, detach_drivers: bool) -> io::Result<Arc<dyn UsbBackend>> {
+    let opened: Arc<dyn UsbBackend> = Arc::new(device.open().wait()?);
+    Ok(match detach_drivers {
+        true => Arc::new(DetachingBackend::new(opened)),
+        false => opened,
+    })
+}
+
 #[tokio::main]
 async fn main() {
-    //env_logger::init();
-    let target = Box::new(File::create("smredir.log").expect("Can't create log file"));
-
-    Builder::new()
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{}:{} {} [{}] - {}",
-                record.file().unwrap_or("unknown"),
-                record.line().unwrap_or(0),
-                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%.3f"),
-                record.level(),
-                record.args()
-            )
-        })
-        .target(env_logger::Target::Pipe(target))
-        .filter(None, LevelFilter::Trace)
-        .init();
-    let usb_device = nusb::list_devices()
+    let args = Args::parse();
+    if args.daemon {
+        // Logging and the PID file are left to the daemon
+        match daemon::spawn(
+            args.pid_file
+                .as_deref()
+                .expect("--daemon requires --pid-file"),
+        ) {
+            Ok(pid) => println!("Started daemon with PID {}", pid),
+            Err(e) => {
+                eprintln!("Failed to start daemon: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    logging::init(&args.log_file, args.trace_urbs);
+    // Taken before the devices are created, released on return, after cards and readers of this
+    // instance are dropped
+    let mut pid_file = match args.pid_file.as_deref().map(pidfile::PidFile::acquire) {
+        Some(Err(e)) => {
+            eprintln!("Refusing to start: {}", e);
+            std::process::exit(1);
+        }
+        Some(Ok(pid_file)) => Some(pid_file),
+        None => None,
+    };
+    // The PID tells `--daemon` the instance started, so it is only written once it is running
+    let mut started = || {
+        if let Some(pid_file) = &mut pid_file {
+            pid_file.write_pid().expect("Failed to write PID file");
+        }
+    };
+    if let Some(addr) = args.serve_reader {
+        let backend = PcscBackend::new(&reader_name(0)).expect("Failed to create reader backend");
+        started();
+        tokio::task::spawn_blocking(move || remote::run_agent(addr, backend))
+            .await
+            .unwrap()
+            .expect("Failed to serve reader");
+        return;
+    }
+    if args.command == Some(Command::Monitor) {
+        let reader = match args.reader.as_deref() {
+            Some(pattern) => ccid_backend::list_readers()
+                .and_then(|readers| {
+                    ccid_backend::select_reader(&readers, pattern, args.reader_index)
+                })
+                .expect("Failed to select reader"),
+            None => reader_name(0),
+        };
+        let context =
+            pcsc::Context::establish(pcsc::Scope::User).expect("Failed to create PCSC context");
+        let canceller = context.clone();
+        let monitor = tokio::task::spawn_blocking(move || {
+            monitor::run(&context, &reader, |line| println!("{}", line))
+        });
+        tokio::select! {
+            result = monitor => result.unwrap().expect("Failed to monitor reader"),
+            _ = daemon::shutdown_signal() => {
+                let _ = canceller.cancel();
+            }
+        }
+        return;
+    }
+    let usb_devices: Vec<(Arc<dyn UsbBackend>, Option<String>)> = nusb::list_devices()
         .wait()
         .expect("list_devices failed")
-        .find(|device| device.vendor_id() == 0x20A0 && device.product_id() == 0x42D4)
-        .expect("Failed to find Canokey pigeon device")
-        .open()
-        .wait()
-        .expect("Failed to open Canokey pigeon device");
-    let ccid_handler = Arc::new(Mutex::new(Box::new(
-        ccid::CCIDInterfaceHandler::new(c"canokeys.org OpenPGP PIV OATH 0", &usb_device).unwrap(),
+        .filter(|device| device.vendor_id() == 0x20A0 && device.product_id() == 0x42D4)
+        .map(|device| {
+            let opened = open_device(&device, args.detach_drivers)
+                .expect("Failed to open Canokey pigeon device");
+            (opened, device.serial_number().map(str::to_owned))
+        })
+        .collect();
+    if usb_devices.is_empty() {
+        panic!("Failed to find Canokey pigeon device");
+    }
+    let device_serials: Vec<_> = usb_devices
+        .iter()
+        .map(|(_, serial)| serial.clone())
+        .collect();
+    let hidapi = optional_interface("FIDO/U2F", args.fido, || {
+        hidapi::HidApi::new()
+            .map_err(|e| io::Error::other(format!("Failed to initialize HID API library: {}", e)))
+    })
+    .expect("Failed to create FIDO InterfaceHandler");
+    let apdu_filter: Arc<dyn ApduFilter> = match args.allow_apdu.is_empty() {
+        true => Arc::new(AllowAll),
+        false => Arc::new(Allowlist {
+            commands: args.allow_apdu.clone(),
+        }),
+    };
+    let probe_args = args.clone();
+    let probe = move || {
+        let args = probe_args;
+        // Readers are only numbered in enumeration order, several keys are paired with their
+        // readers by serial number
+        let paired = usb_devices.len() > 1;
+        let key_readers = match paired && args.remote_reader.len() < usb_devices.len() {
+            true => ccid_backend::key_readers()?,
+            false => vec![],
+        };
+        usb_devices
+            .into_iter()
+            .enumerate()
+            .map(|(index, (device, device_serial))| {
+                let ccid_backend: Box<dyn CCIDBackend> =
+                    match (args.remote_reader.get(index), index) {
+                        (Some(addr), _) => Box::new(RemoteBackend::new(addr)?),
+                        (None, 0) if args.reader.is_some() => {
+                            let name = ccid_backend::select_reader(
+                                &ccid_backend::list_readers()?,
+                                args.reader.as_deref().unwrap(),
+                                args.reader_index,
+                            )?;
+                            Box::new(PcscBackend::new(&name)?)
+                        }
+                        (None, _) if paired => Box::new(PcscBackend::new(
+                            &ccid_backend::paired_reader(&key_readers, device_serial.as_deref())?,
+                        )?),
+                        (None, _) => Box::new(PcscBackend::new(&reader_name(index))?),
+                    };
+                let serial_number = match &args.serial_string {
+                    Some(serial) if index == 0 => Some(serial.clone()),
+                    Some(serial) => Some(format!("{}{}", serial, index)),
+                    None if args.random_serial => Some(device::random_serial_number()),
+                    None if args.mirror_serial => device_serial,
+                    None => None,
+                };
+                let extra_ccid_backends = match index {
+                    0 => args
+                        .extra_reader
+                        .iter()
+                        .map(|name| {
+                            let name = CString::new(name.as_str())
+                                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                            Ok(Box::new(PcscBackend::new(&name)?) as Box<dyn CCIDBackend>)
+                        })
+                        .collect::<io::Result<Vec<_>>>()?,
+                    _ => vec![],
+                };
+                let config = RelayConfig {
+                    device,
+                    ccid_backend,
+                    extra_ccid_backends,
+                    ccid_config: CCIDConfig {
+                        escape_control_code: args.escape_control_code,
+                        raw_descriptor: args.ccid_descriptor.clone(),
+                        connect_timeout: Some(Duration::from_secs(args.reader_timeout)),
+                        idle_timeout: args.idle_timeout.map(Duration::from_secs),
+                        max_apdu_len: args.max_apdu_len,
+                        read_only: args.read_only,
+                        simulate_mute: args.simulate_mute,
+                        reset_on_applet_switch: args.reset_on_applet_switch,
+                        shared_fallback: args.shared_fallback,
+                        max_busy_slots: args.max_busy_slots,
+                        max_ifsd: args.max_ifsd,
+                        clock_frequencies: args.clock_frequencies.clone(),
+                        data_rates: args.data_rates.clone(),
+                        unsupported_response: match args.unsupported_error {
+                            Some(error) => UnsupportedResponse::SlotStatus(error.into()),
+                            None => UnsupportedResponse::Dedicated,
+                        },
+                        apdu_filter: apdu_filter.clone(),
+                        blocked_sw: args.blocked_sw,
+                        ..Default::default()
+                    },
+                    hidapi: hidapi.as_ref().map(|hidapi| hidapi as &dyn HidApiBackend),
+                    fido: args.fido,
+                    webusb: args.webusb,
+                    config_name: args.config_name.clone(),
+                    full_speed: args.full_speed,
+                    forward_set_idle: args.forward_set_idle,
+                    mirror_version: args.mirror_version,
+                    extra_hid_interfaces: args.extra_hid_interface.clone(),
+                    serial_number,
+                    transfer: TransferConfig {
+                        control_timeout: Duration::from_millis(args.control_timeout),
+                        interrupt_read_timeout: Duration::from_millis(args.interrupt_read_timeout),
+                        transmit_timeout: args.transmit_timeout.map(Duration::from_millis),
+                        command_delay: Duration::from_millis(args.command_delay),
+                        hid_write_retries: args.hid_write_retries,
+                    },
+                };
+                info!(
+                    "Relaying reader '{}' as device {}",
+                    config.ccid_backend.reader_name().to_string_lossy(),
+                    index
+                );
+                let relay = device::build_relay(index as u32, config)?;
+                info!("{}", device::device_summary(&relay));
+                Ok(relay)
+            })
+            .collect::<io::Result<Vec<_>>>()
+    };
+    // Reading the descriptors of a device in a bad state may never return
+    let devices = device::probe(
+        "Creating the relayed devices",
+        Duration::from_secs(args.probe_timeout),
+        probe,
     )
-        as Box<dyn usbip::UsbInterfaceHandler + Send>));
-    let webusb_handler = Arc::new(Mutex::new(Box::new(
-        WebUSBInterfaceHandler::new(usb_device.clone(), 1, ccid_handler.clone())
-            .expect("Failed to create WebUSB InterfaceHandler"),
-    ) as Box<dyn UsbInterfaceHandler + Send>));
+    .expect("Failed to create relayed device");
+    started();
 
-    let device_handler =
-        Arc::new(Mutex::new(
-            Box::new(CanokeyVirtDeviceHandler::new(&[webusb_handler.clone()]))
-                as Box<dyn UsbDeviceHandler + Send>,
-        ));
-    let fido_handler = Arc::new(Mutex::new(Box::new(
-        FIDOInterfaceHandler::new(usb_device.clone())
-            .expect("Failed to create FIDO InterfaceHandler"),
-    ) as Box<dyn UsbInterfaceHandler + Send>));
-    let mut v = UsbDevice::new(0)
-        .with_device_handler(device_handler)
-        .with_interface_and_number(
-            0x03,
-            0x00,
-            0x00,
-            0x00,
-            Some("FIDO/U2F"),
-            FIDOInterfaceHandler::endpoints(),
-            fido_handler,
-        )
-        .with_interface_and_number(
-            0xFF,
-            0xFF,
-            0xFF,
-            0x1,
-            Some("WebUSB"),
-            vec![],
-            webusb_handler,
-        )
-        .with_interface_and_number(
-            0x0B,
-            0x00,
-            0x00,
-            0x02,
-            Some("OpenPGP PIV OATH"),
-            ccid::CCIDInterfaceHandler::endpoints(),
-            ccid_handler,
-        );
-    v.speed = UsbSpeed::High as u32;
-    v.vendor_id = 0x20A0;
-    v.product_id = 0x42D4;
-    v.set_product_name("Canokey Relay Card").unwrap();
-    v.set_manufacturer_name("canokeys.org").unwrap();
-    v.set_serial_number("AAAABBBBCC").unwrap();
-    v.unset_configuration_name().unwrap();
-    v.usb_version.major = 0x2;
-    v.usb_version.minor = 0x10;
-    v.usb_version.patch = 0x0;
-    v.device_bcd.major = 0x1;
-    v.device_bcd.minor = 0x0;
-    v.device_bcd.patch = 0x0;
+    if args.command == Some(Command::Selftest) {
+        for device in &devices {
+            match selftest::run(device) {
+                Ok(report) => print!("{}:\n{}", device.bus_id, report),
+                Err(e) => {
+                    eprintln!("Self-test of {} failed: {}", device.bus_id, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        return;
+    }
+    let relayed = devices.clone();
+    let server = Arc::new(UsbIpServer::new_simulated(devices));
+    if args.command == Some(Command::Dump) {
+        for device in &relayed {
+            let device_desc = server::fetch_descriptor(server.clone(), &device.bus_id, 0x01)
+                .await
+                .expect("Failed to fetch device descriptor");
+            let configuration = server::fetch_configuration(server.clone(), &device.bus_id)
+                .await
+                .expect("Failed to fetch configuration descriptor");
+            println!(
+                "{}:\n{}",
+                device.bus_id,
+                descriptor::dump(&device_desc, &configuration)
+            );
+        }
+        return;
+    }
+    if cfg!(debug_assertions) {
+        for device in &relayed {
+            let checked = server::fetch_configuration(server.clone(), &device.bus_id)
+                .await
+                .and_then(|desc| descriptor::check_configuration(&desc));
+            match checked {
+                Ok(()) => info!("Configuration descriptor of {} is valid", device.bus_id),
+                Err(e) => error!(
+                    "Invalid configuration descriptor of {}: {}",
+                    device.bus_id, e
+                ),
+            }
+        }
+    }
 
-    let server = Arc::new(UsbIpServer::new_simulated(vec![v]));
+    if args.idle_timeout.is_some() {
+        let relayed = relayed.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                device::power_down_idle_cards(&relayed, Instant::now());
+            }
+        });
+    }
+
+    // Keys without a serial number can not be recognized when plugged in again
+    let replugged: Vec<_> = device_serials
+        .into_iter()
+        .zip(relayed.iter().cloned())
+        .filter_map(|(serial, relay)| Some((serial?, relay)))
+        .collect();
+    if !replugged.is_empty() {
+        let detach_drivers = args.detach_drivers;
+        tokio::spawn(async move {
+            let refreshed = device::refresh_on_replug(replugged, move |device| {
+                open_device(device, detach_drivers)
+            });
+            if let Err(e) = refreshed.await {
+                error!("Stopped refreshing replugged devices: {}", e);
+            }
+        });
+    }
+
+    let clients = Arc::new(AtomicUsize::new(0));
+    if let Some(addr) = args.status_listen {
+        let readers = metrics::readers(&relayed);
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr, readers, clients).await {
+                error!("Status server failed: {}", e);
+            }
+        });
+    }
 
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
-    let _ = tokio::spawn(usbip::server(addr, server)).await;
+    let served = server::supervise(
+        || {
+            server::serve(
+                addr,
+                args.ws,
+                args.ws_origin.clone(),
+                server.clone(),
+                args.max_clients as usize,
+                server::DetachCleanup::new(
+                    relayed.clone(),
+                    args.keep_card_powered.map(Duration::from_secs),
+                ),
+                clients.clone(),
+            )
+        },
+        args.restart_on_panic,
+        || {
+            device::reset_after_panic(&relayed);
+            // Connections of the panicked server were aborted without releasing their devices
+            let server = server.clone();
+            let relayed = relayed.clone();
+            async move {
+                for device in &relayed {
+                    server.release_device(&device.bus_id).await;
+                }
+            }
+        },
+    );
+    // Returning on Ctrl-C or SIGTERM removes the PID file
+    tokio::select! {
+        result = served => result.expect("Failed to start USB/IP server"),
+        signal = daemon::shutdown_signal() => info!("Received {}, exiting", signal),
+    }
 
     // loop {
     //     // sleep 1s
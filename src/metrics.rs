@@ -0,0 +1,382 @@
+//! Status server with the APDU counters of the relayed readers, enabled with `--status-listen`
+//!
+//! - `GET /metrics` in the Prometheus text exposition format
+//! - `GET /status` as JSON
+//!
+//! Each CCID interface is labelled with the bus ID of its device and its interface number.
+use crate::ccid::{ApduCounters, CCIDInterfaceHandler, CommandInFlight, SlotStatus};
+use crate::ccid_proto::ICCVoltage;
+use log::{debug, info, warn};
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use usbip::UsbDevice;
+
+/// Longest request read, only its request line is looked at
+const MAX_REQUEST_LENGTH: usize = 0x2000;
+
+/// State of a CCID interface at the time of the request
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReaderSample {
+    pub bus_id: String,
+    pub interface: u8,
+    pub card_present: bool,
+    pub counters: ApduCounters,
+    pub slots: Vec<SlotSample>,
+    pub in_flight: Option<CommandInFlight>,
+}
+
+/// Power state of a slot of a CCID interface at the time of the request
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlotSample {
+    pub slot: u8,
+    pub powered: bool,
+    pub atr: Option<Vec<u8>>,
+    pub voltage: Option<ICCVoltage>,
+}
+
+/// CCID interface sampled by the status server
+#[derive(Debug, Clone)]
+pub struct Reader {
+    pub bus_id: String,
+    pub interface: u8,
+    pub status: SlotStatus,
+}
+
+/// The CCID interfaces of `devices`, taking the status handle of each handler once
+///
+/// Sampling them later does not wait for a command in progress.
+pub fn readers(devices: &[UsbDevice]) -> Vec<Reader> {
+    let mut readers = Vec::new();
+    for device in devices {
+        for interface in &device.interfaces {
+            let mut handler = interface.handler.lock().unwrap();
+            if let Some(ccid) = handler.as_any().downcast_mut::<CCIDInterfaceHandler>() {
+                readers.push(Reader {
+                    bus_id: device.bus_id.clone(),
+                    interface: interface.interface_number,
+                    status: ccid.status(),
+                });
+            }
+        }
+    }
+    readers
+}
+
+/// Sample `readers`, as published by their handlers
+pub fn sample(readers: &[Reader]) -> Vec<ReaderSample> {
+    readers
+        .iter()
+        .map(|reader| {
+            let status = &reader.status;
+            let snapshot = status.snapshot();
+            ReaderSample {
+                bus_id: reader.bus_id.clone(),
+                interface: reader.interface,
+                card_present: snapshot.card_present,
+                counters: snapshot.counters,
+                slots: (0..status.slot_count())
+                    .map(|slot| SlotSample {
+                        slot,
+                        powered: status.is_powered(slot),
+                        atr: status.current_atr(slot),
+                        voltage: status.voltage(slot),
+                    })
+                    .collect(),
+                in_flight: snapshot.in_flight,
+            }
+        })
+        .collect()
+}
+
+/// Name, type, help and value of a metric of each reader
+type ReaderMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&ReaderSample) -> u64,
+);
+
+const READER_METRICS: [ReaderMetric; 6] = [
+    (
+        "smredir_apdu_total",
+        "counter",
+        "APDUs transmitted to the card",
+        |s| s.counters.apdus,
+    ),
+    (
+        "smredir_apdu_errors_total",
+        "counter",
+        "APDU exchanges failed by the reader",
+        |s| s.counters.errors,
+    ),
+    (
+        "smredir_bytes_in_total",
+        "counter",
+        "Bytes of response APDUs sent to the host",
+        |s| s.counters.bytes_in,
+    ),
+    (
+        "smredir_bytes_out_total",
+        "counter",
+        "Bytes of command APDUs received from the host",
+        |s| s.counters.bytes_out,
+    ),
+    (
+        "smredir_card_present",
+        "gauge",
+        "Whether a card is in the reader",
+        |s| s.card_present as u64,
+    ),
+    (
+        "smredir_card_powered",
+        "gauge",
+        "Whether the card is powered on",
+        |s| s.slots.iter().any(|slot| slot.powered) as u64,
+    ),
+];
+
+/// Prometheus text exposition of `samples` and the number of connected clients
+pub fn prometheus(samples: &[ReaderSample], clients: usize) -> String {
+    let mut text = String::new();
+    for (name, kind, help, value) in READER_METRICS {
+        writeln!(text, "# HELP {} {}", name, help).unwrap();
+        writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+        for sample in samples {
+            writeln!(
+                text,
+                "{}{{device=\"{}\",interface=\"{}\"}} {}",
+                name,
+                sample.bus_id,
+                sample.interface,
+                value(sample)
+            )
+            .unwrap();
+        }
+    }
+    writeln!(text, "# HELP smredir_clients Connected USB/IP clients").unwrap();
+    writeln!(text, "# TYPE smredir_clients gauge").unwrap();
+    writeln!(text, "smredir_clients {}", clients).unwrap();
+    text
+}
+
+/// JSON object with the same values as [`prometheus`], the ATR and voltage of each slot and the
+/// command in flight
+pub fn json(samples: &[ReaderSample], clients: usize) -> String {
+    let readers: Vec<String> = samples
+        .iter()
+        .map(|s| {
+            format!(
+                "{{\"device\":\"{}\",\"interface\":{},\"card_present\":{},\"apdus\":{},\"apdu_errors\":{},\"bytes_in\":{},\"bytes_out\":{},\"slots\":[{}],\"in_flight\":{}}}",
+                s.bus_id,
+                s.interface,
+                s.card_present,
+                s.counters.apdus,
+                s.counters.errors,
+                s.counters.bytes_in,
+                s.counters.bytes_out,
+                s.slots.iter().map(slot_json).collect::<Vec<_>>().join(","),
+                s.in_flight.map_or("null".to_string(), |command| in_flight_json(&command))
+            )
+        })
+        .collect();
+    format!(
+        "{{\"clients\":{},\"readers\":[{}]}}",
+        clients,
+        readers.join(",")
+    )
+}
+
+fn slot_json(slot: &SlotSample) -> String {
+    let atr = match &slot.atr {
+        Some(atr) => format!(
+            "\"{}\"",
+            atr.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+        ),
+        None => "null".to_string(),
+    };
+    let voltage = match slot.voltage {
+        Some(ICCVoltage::V_5_0) => "5.0",
+        Some(ICCVoltage::V_3_0) => "3.0",
+        Some(ICCVoltage::V_1_8) => "1.8",
+        Some(ICCVoltage::AUTO) | None => "null",
+    };
+    format!(
+        "{{\"slot\":{},\"powered\":{},\"atr\":{},\"voltage\":{}}}",
+        slot.slot, slot.powered, atr, voltage
+    )
+}
+
+fn in_flight_json(command: &CommandInFlight) -> String {
+    format!(
+        "{{\"slot\":{},\"seq\":{},\"message_type\":{},\"elapsed_ms\":{}}}",
+        command.slot,
+        command.seq,
+        command.message_type,
+        command.started_at.elapsed().as_millis()
+    )
+}
+
+/// Serve `/metrics` and `/status` of `readers` on `addr`, `clients` counts the USB/IP clients
+pub async fn serve(
+    addr: SocketAddr,
+    readers: Vec<Reader>,
+    clients: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Status server listening on {}", addr);
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept status connection: {}", e);
+                continue;
+            }
+        };
+        let readers = readers.clone();
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(socket, &readers, &clients).await {
+                debug!("Status request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn respond(
+    mut socket: TcpStream,
+    readers: &[Reader],
+    clients: &AtomicUsize,
+) -> io::Result<()> {
+    let mut request = Vec::new();
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let mut buf = [0u8; 0x400];
+        let read = socket.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_LENGTH {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let request_line = request.lines().next().unwrap_or_default();
+    let clients = clients.load(Ordering::Relaxed);
+    let (status, content_type, body) = match request_line.split(' ').take(2).collect::<Vec<_>>()[..]
+    {
+        ["GET", "/metrics"] => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            prometheus(&sample(readers), clients),
+        ),
+        ["GET", "/status"] => (
+            "200 OK",
+            "application/json",
+            json(&sample(readers), clients),
+        ),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::build_relay;
+    use crate::fake::{MemoryBackend, PIGEON_ATR, pigeon_relay};
+    use crate::selftest;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_prometheus() {
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0x90, 0x00]));
+        let config = pigeon_relay(backend);
+        let device = build_relay(0, config).unwrap();
+        // One SELECT answered with 9000
+        selftest::run(&device).unwrap();
+        let readers = readers(std::slice::from_ref(&device));
+        // Sampled without waiting for a command in progress
+        let ccid = device
+            .interfaces
+            .iter()
+            .find(|interface| interface.interface_class == 0x0B)
+            .unwrap();
+        let locked = ccid.handler.lock().unwrap();
+        let samples = sample(&readers);
+        drop(locked);
+        assert_eq!(samples.len(), 1);
+
+        let text = prometheus(&samples, 1);
+        let mut names = HashSet::new();
+        for line in text.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                assert!(comment.starts_with("HELP ") || comment.starts_with("TYPE "));
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            value.parse::<f64>().unwrap();
+            let name = match series.split_once('{') {
+                Some((name, labels)) => {
+                    assert!(labels.ends_with('}'), "{}", line);
+                    name
+                }
+                None => series,
+            };
+            assert!(
+                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{}",
+                line
+            );
+            names.insert(name);
+        }
+        for name in [
+            "smredir_apdu_total",
+            "smredir_apdu_errors_total",
+            "smredir_bytes_in_total",
+            "smredir_bytes_out_total",
+            "smredir_card_present",
+            "smredir_card_powered",
+            "smredir_clients",
+        ] {
+            assert!(names.contains(name), "{} missing from\n{}", name, text);
+        }
+        let interface = samples[0].interface;
+        assert!(text.contains(&format!(
+            "smredir_apdu_total{{device=\"0-0-0\",interface=\"{}\"}} 1\n",
+            interface
+        )));
+        assert!(text.contains("smredir_bytes_in_total{device=\"0-0-0\","));
+        assert!(text.contains("smredir_clients 1\n"));
+
+        assert_eq!(
+            json(&samples, 1),
+            format!(
+                "{{\"clients\":1,\"readers\":[{{\"device\":\"0-0-0\",\"interface\":{},\"card_present\":true,\"apdus\":1,\"apdu_errors\":0,\"bytes_in\":2,\"bytes_out\":11,\"slots\":[{{\"slot\":0,\"powered\":false,\"atr\":null,\"voltage\":null}}],\"in_flight\":null}}]}}",
+                interface
+            )
+        );
+
+        let mut busy = samples[0].clone();
+        busy.in_flight = Some(CommandInFlight {
+            slot: 0,
+            seq: 7,
+            message_type: 0x6F,
+            started_at: std::time::Instant::now(),
+        });
+        assert!(
+            json(&[busy], 1).contains(
+                "\"in_flight\":{\"slot\":0,\"seq\":7,\"message_type\":111,\"elapsed_ms\":"
+            )
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Print the slot status changes PCSC reports for a reader, for debugging card hotplug
+//!
+//! This is what a relayed device bases `RDR_to_PC_NotifySlotChange` and the slot status on, so
+//! a reader not reporting insertion and removal here will not be relayed correctly either.
+use crate::hexdump::hexdump;
+use pcsc::{Context, ReaderState, State};
+use std::ffi::CStr;
+use std::io;
+
+/// Describe the change of a reader from `old` to `new`, `None` if nothing worth printing
+/// changed
+///
+/// The first state, changed from `UNAWARE`, is described as well.
+pub fn describe_change(old: State, new: State, atr: &[u8]) -> Option<String> {
+    if new.intersects(State::UNKNOWN | State::IGNORE) {
+        return Some("Reader is gone".to_string());
+    }
+    if new.contains(State::UNAVAILABLE) && !old.contains(State::UNAVAILABLE) {
+        return Some("Reader is unavailable".to_string());
+    }
+    let first = old == State::UNAWARE;
+    if new.contains(State::PRESENT) && (first || !old.contains(State::PRESENT)) {
+        let event = match first {
+            true => "Card present",
+            false => "Card inserted",
+        };
+        return Some(match new.contains(State::MUTE) {
+            true => format!("{}, but mute", event),
+            false => format!("{}, ATR {}", event, hexdump(atr)),
+        });
+    }
+    if new.contains(State::EMPTY) && (first || !old.contains(State::EMPTY)) {
+        return Some(match first {
+            true => "No card".to_string(),
+            false => "Card removed".to_string(),
+        });
+    }
+    None
+}
+
+/// Print the status changes of `reader` until its monitoring is cancelled through `context`
+/// or the reader is gone
+pub fn run(context: &Context, reader: &CStr, mut print: impl FnMut(&str)) -> io::Result<()> {
+    let mut states = [ReaderState::new(reader.to_owned(), State::UNAWARE)];
+    loop {
+        match context.get_status_change(None, &mut states) {
+            Ok(()) => {}
+            Err(pcsc::Error::Cancelled) => return Ok(()),
+            Err(e) => {
+                return Err(io::Error::other(format!(
+                    "Failed to wait for status changes of reader '{}': {}",
+                    reader.to_string_lossy(),
+                    e
+                )));
+            }
+        }
+        let state = &mut states[0];
+        let event = state.event_state();
+        if let Some(change) = describe_change(state.current_state(), event, state.atr()) {
+            print(&format!("{}: {}", reader.to_string_lossy(), change));
+        }
+        if event.intersects(State::UNKNOWN | State::IGNORE) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Reader '{}' is gone", reader.to_string_lossy()),
+            ));
+        }
+        state.sync_current_state();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_change() {
+        let atr = [0x3B, 0xF7, 0x11, 0x00];
+        let present = State::CHANGED | State::PRESENT;
+        let empty = State::CHANGED | State::EMPTY;
+        assert_eq!(
+            describe_change(State::UNAWARE, present, &atr).as_deref(),
+            Some("Card present, ATR 3BF71100")
+        );
+        assert_eq!(
+            describe_change(State::UNAWARE, empty, &[]).as_deref(),
+            Some("No card")
+        );
+        assert_eq!(
+            describe_change(State::EMPTY, present, &atr).as_deref(),
+            Some("Card inserted, ATR 3BF71100")
+        );
+        assert_eq!(
+            describe_change(State::EMPTY, present | State::MUTE, &[]).as_deref(),
+            Some("Card inserted, but mute")
+        );
+        assert_eq!(
+            describe_change(State::PRESENT, empty, &[]).as_deref(),
+            Some("Card removed")
+        );
+        // Only the card being opened by someone
+        assert_eq!(
+            describe_change(State::PRESENT, present | State::INUSE, &atr),
+            None
+        );
+        assert_eq!(
+            describe_change(State::PRESENT, State::CHANGED | State::UNKNOWN, &[]).as_deref(),
+            Some("Reader is gone")
+        );
+    }
+}
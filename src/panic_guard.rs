@@ -0,0 +1,181 @@
+//! Containing panics of handlers to the URB they were handling
+//!
+//! A panic unwinding out of a handler would end the connection of the client, or the whole
+//! USB/IP server. [`CatchPanic`] turns it into a failed URB instead. The handler keeps whatever
+//! state it panicked in, its lock is not poisoned.
+use log::error;
+use std::any::Any;
+use std::io;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use usbip::{SetupPacket, UsbDeviceHandler, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
+
+/// Handler failing the URBs `inner` panics on, transparent to `as_any` downcasts
+#[derive(Debug)]
+pub struct CatchPanic<H: ?Sized> {
+    inner: Box<H>,
+}
+
+impl<H: ?Sized> CatchPanic<H> {
+    pub fn new(inner: Box<H>) -> CatchPanic<H> {
+        Self { inner }
+    }
+}
+
+/// Message of a caught panic, as passed to `panic!`
+pub fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+fn catch<T>(what: &str, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic_message(&panic);
+        error!("{} panicked, failing the URB: {}", what, message);
+        Err(io::Error::other(format!("{} panicked: {}", what, message)))
+    })
+}
+
+impl UsbInterfaceHandler for CatchPanic<dyn UsbInterfaceHandler + Send> {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        self.inner.get_class_specific_descriptor()
+    }
+
+    fn handle_urb(
+        &mut self,
+        interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let inner = &mut self.inner;
+        catch("Interface handler", || {
+            inner.handle_urb(interface, ep, transfer_buffer_length, setup, req)
+        })
+    }
+
+    fn handle_device_urb(
+        &mut self,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let inner = &mut self.inner;
+        catch("Interface handler", || {
+            inner.handle_device_urb(transfer_buffer_length, setup, req)
+        })
+    }
+
+    fn get_device_capability_descriptors(&self) -> Vec<Vec<u8>> {
+        self.inner.get_device_capability_descriptors()
+    }
+
+    fn urb_ready(&self) -> Option<Arc<Notify>> {
+        self.inner.urb_ready()
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self.inner.as_any()
+    }
+}
+
+impl UsbDeviceHandler for CatchPanic<dyn UsbDeviceHandler + Send> {
+    fn handle_urb(
+        &mut self,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let inner = &mut self.inner;
+        catch("Device handler", || {
+            inner.handle_urb(transfer_buffer_length, setup, req)
+        })
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self.inner.as_any()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reserved::ReservedInterfaceHandler;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Default)]
+    struct Panicking {
+        urbs: usize,
+    }
+
+    impl UsbInterfaceHandler for Panicking {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            req: &[u8],
+        ) -> io::Result<Vec<u8>> {
+            self.urbs += 1;
+            Ok(vec![req[0]])
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>> =
+            Arc::new(Mutex::new(Box::new(CatchPanic::new(
+                Box::new(Panicking::default()) as Box<dyn UsbInterfaceHandler + Send>,
+            ))));
+        let interface = UsbInterface {
+            interface_class: 0xFF,
+            interface_subclass: 0x00,
+            interface_protocol: 0x00,
+            interface_number: 0x00,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        };
+        let urb = |req: &[u8]| {
+            handler.lock().unwrap().handle_urb(
+                &interface,
+                UsbEndpoint::default(),
+                1,
+                SetupPacket::default(),
+                req,
+            )
+        };
+
+        // Indexing the empty request panics
+        let err = urb(&[]).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Interface handler panicked: index out of bounds"),
+            "{}",
+            err
+        );
+        // The handler is still usable and reachable
+        assert_eq!(urb(&[0x42]).unwrap(), [0x42]);
+        assert!(!handler.is_poisoned());
+        let mut handler = handler.lock().unwrap();
+        let panicking = handler.as_any().downcast_mut::<Panicking>().unwrap();
+        assert_eq!(panicking.urbs, 2);
+    }
+}
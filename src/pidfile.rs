@@ -0,0 +1,114 @@
+use log::warn;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// PID file locked for as long as this instance runs, removed when dropped
+///
+/// The PID is only written by [`PidFile::write_pid`] once the instance started, until then the
+/// file is locked but empty. The lock is advisory and released by the OS when the process exits, so a file left behind by
+/// a crashed instance does not keep the next one from starting.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    // Holds the lock
+    file: File,
+}
+
+impl PidFile {
+    /// Lock the file at `path` and empty it, failing if another instance holds it
+    pub fn acquire(path: &Path) -> io::Result<PidFile> {
+        // Not truncated before it is locked, the PID of the running instance is kept
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        lock(&mut file, path)?;
+        file.set_len(0)?;
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+        })
+    }
+
+    /// Write the PID of this process into the file
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())
+    }
+
+    /// Fail like [`PidFile::acquire`] would if another instance holds the file at `path`, without
+    /// creating or writing it
+    pub fn check(path: &Path) -> io::Result<()> {
+        match File::open(path) {
+            // Unlocked again when closed
+            Ok(mut file) => lock(&mut file, path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn lock(file: &mut File, path: &Path) -> io::Result<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                format!(
+                    "Another instance (PID {}) is running, it holds '{}'",
+                    pid.trim(),
+                    path.display()
+                ),
+            ))
+        }
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Failed to remove PID file '{}': {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join(format!("smredir-{}.pid", std::process::id()));
+        let mut pid_file = PidFile::acquire(&path).unwrap();
+        // Empty until the instance started
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        pid_file.write_pid().unwrap();
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(pid, format!("{}\n", std::process::id()));
+
+        let err = PidFile::acquire(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(err.to_string().contains(pid.trim()), "{}", err);
+        // The refused instance leaves the file as it was
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pid);
+
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // A file left behind without a lock is taken over
+        std::fs::write(&path, "1\n").unwrap();
+        let mut pid_file = PidFile::acquire(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        pid_file.write_pid().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), pid);
+        drop(pid_file);
+    }
+}
@@ -0,0 +1,545 @@
+//! CCID backend tunnelled to a reader on another host
+//!
+//! The relay talks to a companion agent, another `smredir --serve-reader`, over TCP. Every
+//! message is a frame of a big endian `u32` body length followed by the body:
+//!
+//! - Request, relay to agent: an opcode byte and its arguments, big endian
+//!   - `0x01` connect: `u32` share mode, `u32` protocol mask
+//!   - `0x02` disconnect: `u32` disposition
+//!   - `0x03` ATR
+//!   - `0x04` transmit: `u32` response buffer size, APDU
+//!   - `0x05` control: `u32` response buffer size, `u32` control code, data
+//! - Response, agent to relay: `u32` PCSC status, 0 on success, then the response data
+//!
+//! The agent answers each request before the next one is read, and resets the card when the
+//! relay disconnects or the connection fails. Relays are neither authenticated nor encrypted,
+//! the agent must only listen on an address reachable by trusted hosts.
+
+use crate::ccid_backend::CCIDBackend;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::{debug, error, info};
+use pcsc::{Disposition, Protocols, ShareMode};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Larger than any short or extended APDU exchange
+const MAX_FRAME_LENGTH: u32 = 0x20000;
+
+/// How long the relay waits for the agent, above on-card key generation
+const AGENT_TIMEOUT: Duration = Duration::from_secs(120);
+
+const CONNECT: u8 = 0x01;
+const DISCONNECT: u8 = 0x02;
+const ATR: u8 = 0x03;
+const TRANSMIT: u8 = 0x04;
+const CONTROL: u8 = 0x05;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Connect {
+        share_mode: ShareMode,
+        protocols: Protocols,
+    },
+    Disconnect(Disposition),
+    Atr,
+    Transmit {
+        max_response: u32,
+        apdu: Vec<u8>,
+    },
+    Control {
+        max_response: u32,
+        control_code: u32,
+        data: Vec<u8>,
+    },
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Request::Connect {
+                share_mode,
+                protocols,
+            } => {
+                out.push(CONNECT);
+                out.write_u32::<BigEndian>(*share_mode as u32).unwrap();
+                out.write_u32::<BigEndian>(protocols.bits() as u32).unwrap();
+            }
+            Request::Disconnect(disposition) => {
+                out.push(DISCONNECT);
+                out.write_u32::<BigEndian>(*disposition as u32).unwrap();
+            }
+            Request::Atr => out.push(ATR),
+            Request::Transmit { max_response, apdu } => {
+                out.push(TRANSMIT);
+                out.write_u32::<BigEndian>(*max_response).unwrap();
+                out.extend_from_slice(apdu);
+            }
+            Request::Control {
+                max_response,
+                control_code,
+                data,
+            } => {
+                out.push(CONTROL);
+                out.write_u32::<BigEndian>(*max_response).unwrap();
+                out.write_u32::<BigEndian>(*control_code).unwrap();
+                out.extend_from_slice(data);
+            }
+        }
+        out
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Request> {
+        let mut input = io::Cursor::new(body);
+        let opcode = input.read_u8()?;
+        let request = match opcode {
+            CONNECT => {
+                let share_mode = match input.read_u32::<BigEndian>()? {
+                    v if v == ShareMode::Exclusive as u32 => ShareMode::Exclusive,
+                    v if v == ShareMode::Shared as u32 => ShareMode::Shared,
+                    v if v == ShareMode::Direct as u32 => ShareMode::Direct,
+                    v => return Err(invalid(format!("Invalid share mode {}", v))),
+                };
+                let protocols = input.read_u32::<BigEndian>()?;
+                let protocols = Protocols::from_bits(protocols as _)
+                    .ok_or_else(|| invalid(format!("Invalid protocols 0x{:X}", protocols)))?;
+                Request::Connect {
+                    share_mode,
+                    protocols,
+                }
+            }
+            DISCONNECT => Request::Disconnect(match input.read_u32::<BigEndian>()? {
+                v if v == Disposition::LeaveCard as u32 => Disposition::LeaveCard,
+                v if v == Disposition::ResetCard as u32 => Disposition::ResetCard,
+                v if v == Disposition::UnpowerCard as u32 => Disposition::UnpowerCard,
+                v if v == Disposition::EjectCard as u32 => Disposition::EjectCard,
+                v => return Err(invalid(format!("Invalid disposition {}", v))),
+            }),
+            ATR => Request::Atr,
+            TRANSMIT => Request::Transmit {
+                max_response: input.read_u32::<BigEndian>()?,
+                apdu: remaining(input),
+            },
+            CONTROL => Request::Control {
+                max_response: input.read_u32::<BigEndian>()?,
+                control_code: input.read_u32::<BigEndian>()?,
+                data: remaining(input),
+            },
+            other => return Err(invalid(format!("Unknown request opcode 0x{:02X}", other))),
+        };
+        Ok(request)
+    }
+}
+
+fn remaining(input: io::Cursor<&[u8]>) -> Vec<u8> {
+    let position = input.position() as usize;
+    input.into_inner()[position..].to_vec()
+}
+
+pub fn encode_response(response: &Result<Vec<u8>, pcsc::Error>) -> Vec<u8> {
+    let mut out = Vec::new();
+    match response {
+        Ok(data) => {
+            out.write_u32::<BigEndian>(0).unwrap();
+            out.extend_from_slice(data);
+        }
+        Err(e) => out.write_u32::<BigEndian>(*e as u32).unwrap(),
+    }
+    out
+}
+
+pub fn decode_response(body: &[u8]) -> io::Result<Result<Vec<u8>, pcsc::Error>> {
+    let mut input = io::Cursor::new(body);
+    match input.read_u32::<BigEndian>()? {
+        0 => Ok(Ok(remaining(input))),
+        status => Ok(Err(pcsc_error(status))),
+    }
+}
+
+/// PCSC error with the status `status`, the ones the CCID handler tells apart
+fn pcsc_error(status: u32) -> pcsc::Error {
+    use pcsc::Error::*;
+    [
+        InternalError,
+        Cancelled,
+        InvalidHandle,
+        InvalidParameter,
+        NoMemory,
+        InsufficientBuffer,
+        UnknownReader,
+        Timeout,
+        SharingViolation,
+        NoSmartcard,
+        ProtoMismatch,
+        NotReady,
+        NotTransacted,
+        ReaderUnavailable,
+        NoService,
+        ServiceStopped,
+        CommError,
+        UnsupportedFeature,
+        UnsupportedCard,
+        UnresponsiveCard,
+        UnpoweredCard,
+        ResetCard,
+        RemovedCard,
+    ]
+    .into_iter()
+    .find(|e| *e as u32 == status)
+    .unwrap_or(UnknownError)
+}
+
+pub fn write_frame(output: &mut impl Write, body: &[u8]) -> io::Result<()> {
+    output.write_u32::<BigEndian>(body.len() as u32)?;
+    output.write_all(body)?;
+    output.flush()
+}
+
+pub fn read_frame(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let length = input.read_u32::<BigEndian>()?;
+    if length > MAX_FRAME_LENGTH {
+        return Err(invalid(format!("Frame of {} bytes is too long", length)));
+    }
+    let mut body = vec![0u8; length as usize];
+    input.read_exact(&mut body)?;
+    Ok(body)
+}
+
+/// Reader behind an agent started with `--serve-reader`
+pub struct RemoteBackend {
+    addr: String,
+    // `None` once the connection to the agent broke, opened again by the next request
+    stream: Option<TcpStream>,
+    reader_name: CString,
+    connected: bool,
+}
+
+impl RemoteBackend {
+    pub fn new(addr: &str) -> io::Result<RemoteBackend> {
+        let stream = Self::open_stream(addr)?;
+        Ok(Self {
+            addr: addr.to_owned(),
+            stream: Some(stream),
+            reader_name: CString::new(format!("remote reader {}", addr))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            connected: false,
+        })
+    }
+
+    fn open_stream(addr: &str) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect(addr).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to connect to reader agent {}: {}", addr, e),
+            )
+        })?;
+        stream.set_nodelay(true)?;
+        // A hung agent fails the request instead of keeping the card forever
+        stream.set_read_timeout(Some(AGENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(AGENT_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// The agent resets the card when the connection breaks, so the card is taken as
+    /// disconnected until the host powers it on again
+    fn request(&mut self, request: Request) -> Result<Vec<u8>, pcsc::Error> {
+        match self.exchange(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                error!(
+                    "Failed to exchange {:?} with '{}': {}",
+                    request,
+                    self.reader_name.to_string_lossy(),
+                    e
+                );
+                self.stream = None;
+                self.connected = false;
+                Err(pcsc::Error::CommError)
+            }
+        }
+    }
+
+    fn exchange(&mut self, request: &Request) -> io::Result<Result<Vec<u8>, pcsc::Error>> {
+        if self.stream.is_none() {
+            info!("Connecting to reader agent {} again", self.addr);
+            self.stream = Some(Self::open_stream(&self.addr)?);
+        }
+        let stream = self.stream.as_mut().unwrap();
+        write_frame(stream, &request.encode())?;
+        decode_response(&read_frame(stream)?)
+    }
+}
+
+impl CCIDBackend for RemoteBackend {
+    fn reader_name(&self) -> &CStr {
+        &self.reader_name
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn connect(&mut self, share_mode: ShareMode, protocols: Protocols) -> Result<(), pcsc::Error> {
+        self.request(Request::Connect {
+            share_mode,
+            protocols,
+        })?;
+        self.connected = true;
+        Ok(())
+    }
+
+    fn disconnect(&mut self, disposition: Disposition) -> Result<(), pcsc::Error> {
+        if !self.connected {
+            return Ok(());
+        }
+        self.connected = false;
+        self.request(Request::Disconnect(disposition)).map(|_| ())
+    }
+
+    fn atr(&mut self) -> Result<Vec<u8>, pcsc::Error> {
+        self.request(Request::Atr)
+    }
+
+    fn transmit<'b>(&mut self, apdu: &[u8], buffer: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error> {
+        let response = self.request(Request::Transmit {
+            max_response: buffer.len() as u32,
+            apdu: apdu.to_vec(),
+        })?;
+        copy_response(&response, buffer)
+    }
+
+    fn control<'b>(
+        &mut self,
+        control_code: u32,
+        data: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], pcsc::Error> {
+        let response = self.request(Request::Control {
+            max_response: buffer.len() as u32,
+            control_code,
+            data: data.to_vec(),
+        })?;
+        copy_response(&response, buffer)
+    }
+}
+
+fn copy_response<'b>(response: &[u8], buffer: &'b mut [u8]) -> Result<&'b [u8], pcsc::Error> {
+    let buffer = buffer
+        .get_mut(..response.len())
+        .ok_or(pcsc::Error::InsufficientBuffer)?;
+    buffer.copy_from_slice(response);
+    Ok(buffer)
+}
+
+/// Answer requests from one relay with `backend` until it disconnects, then reset the card
+///
+/// The card is reset however the connection ends, so the next relay does not find it e.g. with
+/// a verified PIN.
+pub fn serve_agent(
+    stream: &mut (impl Read + Write),
+    backend: &mut dyn CCIDBackend,
+) -> io::Result<()> {
+    let result = serve_requests(stream, backend);
+    if let Err(e) = backend.disconnect(Disposition::ResetCard) {
+        debug!("Failed to disconnect card after relay left: {:?}", e);
+    }
+    result
+}
+
+fn serve_requests(
+    stream: &mut (impl Read + Write),
+    backend: &mut dyn CCIDBackend,
+) -> io::Result<()> {
+    loop {
+        let body = match read_frame(stream) {
+            Ok(body) => body,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let request = Request::decode(&body)?;
+        debug!("Agent request: {:02X?}", request);
+        let response = match request {
+            Request::Connect {
+                share_mode,
+                protocols,
+            } => backend.connect(share_mode, protocols).map(|_| vec![]),
+            Request::Disconnect(disposition) => backend.disconnect(disposition).map(|_| vec![]),
+            Request::Atr => backend.atr(),
+            Request::Transmit { max_response, apdu } => {
+                let mut buffer = vec![0u8; max_response.min(MAX_FRAME_LENGTH) as usize];
+                backend.transmit(&apdu, &mut buffer).map(<[u8]>::to_vec)
+            }
+            Request::Control {
+                max_response,
+                control_code,
+                data,
+            } => {
+                let mut buffer = vec![0u8; max_response.min(MAX_FRAME_LENGTH) as usize];
+                backend
+                    .control(control_code, &data, &mut buffer)
+                    .map(<[u8]>::to_vec)
+            }
+        };
+        write_frame(stream, &encode_response(&response))?;
+    }
+}
+
+/// Serve the reader of `backend` to relays connecting to `addr`, one at a time
+pub fn run_agent(addr: SocketAddr, mut backend: impl CCIDBackend) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "Serving reader '{}' on {}",
+        backend.reader_name().to_string_lossy(),
+        addr
+    );
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept relay: {}", e);
+                continue;
+            }
+        };
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(e) => {
+                error!("Failed to get address of relay: {}", e);
+                continue;
+            }
+        };
+        info!("Relay {} connected", peer);
+        let result = serve_agent(&mut stream, &mut backend);
+        info!("Relay {} disconnected: {:?}", peer, result);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{MemoryBackend, PIGEON_ATR};
+    use std::thread;
+
+    #[test]
+    fn test_frame_codec() {
+        let requests = [
+            Request::Connect {
+                share_mode: ShareMode::Exclusive,
+                protocols: Protocols::T1,
+            },
+            Request::Disconnect(Disposition::ResetCard),
+            Request::Atr,
+            Request::Transmit {
+                max_response: 0x102,
+                apdu: vec![0x00, 0xA4, 0x04, 0x00],
+            },
+            Request::Control {
+                max_response: 0x10,
+                control_code: 0x42000001,
+                data: vec![],
+            },
+        ];
+        let mut wire = Vec::new();
+        for request in &requests {
+            write_frame(&mut wire, &request.encode()).unwrap();
+        }
+        let mut input = io::Cursor::new(wire);
+        for request in &requests {
+            assert_eq!(
+                &Request::decode(&read_frame(&mut input).unwrap()).unwrap(),
+                request
+            );
+        }
+        assert!(read_frame(&mut input).is_err());
+
+        for response in [
+            Ok(vec![0x90, 0x00]),
+            Ok(vec![]),
+            Err(pcsc::Error::RemovedCard),
+        ] {
+            assert_eq!(
+                decode_response(&encode_response(&response)).unwrap(),
+                response
+            );
+        }
+        assert!(Request::decode(&[0x06]).is_err());
+        assert!(Request::decode(&[CONNECT, 0x00]).is_err());
+        let mut oversized = io::Cursor::new((MAX_FRAME_LENGTH + 1).to_be_bytes());
+        assert!(read_frame(&mut oversized).is_err());
+    }
+
+    #[test]
+    fn test_remote_backend() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0x90, 0x00]));
+        let log = local.log.clone();
+        let agent = thread::spawn(move || {
+            let mut local = local;
+            let (mut stream, _) = listener.accept().unwrap();
+            serve_agent(&mut stream, &mut local).unwrap();
+            local.is_connected()
+        });
+
+        let mut remote = RemoteBackend::new(&addr.to_string()).unwrap();
+        assert_eq!(remote.atr(), Err(pcsc::Error::InvalidHandle));
+        remote.connect(ShareMode::Exclusive, Protocols::T1).unwrap();
+        assert!(remote.is_connected());
+        assert_eq!(remote.atr().unwrap(), PIGEON_ATR);
+        let mut buffer = [0u8; 0x10];
+        let response = remote.transmit(&[0x00, 0xA4, 0x04, 0x00], &mut buffer);
+        assert_eq!(response.unwrap(), &[0x90, 0x00]);
+        assert_eq!(
+            log.lock().unwrap().transmitted,
+            vec![vec![0x00, 0xA4, 0x04, 0x00]]
+        );
+        drop(remote);
+        // The agent resets the card once the relay is gone
+        assert!(!agent.join().unwrap());
+    }
+
+    #[test]
+    fn test_agent_resets_card_on_error() {
+        let mut local = MemoryBackend::new(&PIGEON_ATR);
+        local.connected = true;
+        // A request with an unknown opcode fails the connection
+        let mut request = Vec::new();
+        write_frame(&mut request, &[0x7F]).unwrap();
+        let mut stream = io::Cursor::new(request);
+        assert!(serve_agent(&mut stream, &mut local).is_err());
+        assert!(!local.is_connected());
+    }
+
+    #[test]
+    fn test_remote_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let agent = thread::spawn(move || {
+            let mut local = MemoryBackend::new(&PIGEON_ATR);
+            // The first connection breaks, as if the agent was restarted
+            drop(listener.accept().unwrap());
+            let (mut stream, _) = listener.accept().unwrap();
+            serve_agent(&mut stream, &mut local).unwrap();
+        });
+
+        let mut remote = RemoteBackend::new(&addr.to_string()).unwrap();
+        assert_eq!(
+            remote.connect(ShareMode::Exclusive, Protocols::T1),
+            Err(pcsc::Error::CommError)
+        );
+        assert!(!remote.is_connected());
+        // The next request connects to the agent again
+        remote.connect(ShareMode::Exclusive, Protocols::T1).unwrap();
+        assert!(remote.is_connected());
+        assert_eq!(remote.atr().unwrap(), PIGEON_ATR);
+        drop(remote);
+        agent.join().unwrap();
+    }
+}
@@ -1,3 +1,6 @@
+use crate::cli::InterfaceMode;
+use crate::trace;
+use log::warn;
 use std::any::Any;
 use std::io;
 use usbip::{SetupPacket, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
@@ -12,6 +15,32 @@ impl ReservedInterfaceHandler {
     }
 }
 
+/// Initialize the handler of an interface according to `mode`
+///
+/// `None` means the interface is not relayed, either because it is disabled or because it is
+/// optional and failed to initialize. The caller serves [`ReservedInterfaceHandler`] instead or
+/// leaves the interface out.
+pub fn optional_interface<H>(
+    name: &str,
+    mode: InterfaceMode,
+    init: impl FnOnce() -> io::Result<H>,
+) -> io::Result<Option<H>> {
+    match mode {
+        InterfaceMode::Disabled => Ok(None),
+        InterfaceMode::Required => init().map(Some),
+        InterfaceMode::Optional => match init() {
+            Ok(handler) => Ok(Some(handler)),
+            Err(e) => {
+                warn!(
+                    "Failed to initialize {} interface, continuing without it: {}",
+                    name, e
+                );
+                Ok(None)
+            }
+        },
+    }
+}
+
 impl UsbInterfaceHandler for ReservedInterfaceHandler {
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         vec![]
@@ -20,11 +49,12 @@ impl UsbInterfaceHandler for ReservedInterfaceHandler {
     fn handle_urb(
         &mut self,
         _interface: &UsbInterface,
-        _ep: UsbEndpoint,
-        _transfer_buffer_length: u32,
-        _setup: SetupPacket,
-        _req: &[u8],
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
     ) -> std::io::Result<Vec<u8>> {
+        trace::urb("reserved", ep.address, transfer_buffer_length, &setup, req);
         Err(io::Error::new(
             io::ErrorKind::Unsupported,
             "Attempt to access reserved USB interface",
@@ -35,3 +65,41 @@ impl UsbInterfaceHandler for ReservedInterfaceHandler {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{FakeHidApi, FakeUsbDevice};
+    use crate::fido::FIDOInterfaceHandler;
+    use crate::transfer::TransferConfig;
+
+    #[test]
+    fn test_optional_interface() {
+        let device = FakeUsbDevice::pigeon();
+        let mut hidapi = FakeHidApi::pigeon();
+        hidapi.devices.clear();
+        let init = || FIDOInterfaceHandler::new(&device, &hidapi, TransferConfig::default());
+
+        assert!(
+            optional_interface("FIDO/U2F", InterfaceMode::Optional, init)
+                .unwrap()
+                .is_none()
+        );
+        assert!(optional_interface("FIDO/U2F", InterfaceMode::Required, init).is_err());
+        assert!(
+            optional_interface("FIDO/U2F", InterfaceMode::Disabled, || -> io::Result<()> {
+                panic!("Disabled interface must not be initialized")
+            })
+            .unwrap()
+            .is_none()
+        );
+        let hidapi = FakeHidApi::pigeon();
+        assert!(
+            optional_interface("FIDO/U2F", InterfaceMode::Optional, || {
+                FIDOInterfaceHandler::new(&device, &hidapi, TransferConfig::default())
+            })
+            .unwrap()
+            .is_some()
+        );
+    }
+}
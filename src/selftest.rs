@@ -0,0 +1,146 @@
+//! Check that a relayed device can talk to its card, without a USB/IP client
+//!
+//! The CCID messages a host would send are passed to the `CCIDInterfaceHandler` of the device
+//! directly, so the reader and the card are exercised the same way as when serving.
+use crate::ccid::CCIDInterfaceHandler;
+use crate::hexdump::hexdump;
+use std::io;
+use std::time::Duration;
+use usbip::{SetupPacket, UsbDevice, UsbInterface, UsbInterfaceHandler};
+
+/// SELECT of the OpenPGP application, which leaves the card as it was
+const SELECT_OPENPGP: [u8; 11] = [
+    0x00, 0xA4, 0x04, 0x00, 0x06, 0xD2, 0x76, 0x00, 0x01, 0x24, 0x01,
+];
+
+/// Power on the card of the first CCID interface of `device`, SELECT an application and power
+/// it off again, returning the ATR and status word as printable lines
+pub fn run(device: &UsbDevice) -> io::Result<String> {
+    let interface = device
+        .interfaces
+        .iter()
+        .find(|interface| interface.interface_class == 0x0B)
+        .ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Device {} has no CCID interface", device.bus_id),
+        ))?;
+    let mut handler = interface.handler.lock().unwrap();
+    let ccid = handler
+        .as_any()
+        .downcast_mut::<CCIDInterfaceHandler>()
+        .ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Device {} has no CCID interface", device.bus_id),
+        ))?;
+
+    let mut report = String::new();
+    // PC_to_RDR_IccPowerOn with automatic voltage selection
+    let atr = exchange(ccid, interface, "IccPowerOn", &[0x62], 1)?;
+    report.push_str(&format!("ATR: {}\n", hexdump(&atr)));
+    let mut xfr_block = vec![0x6F];
+    xfr_block.extend(&SELECT_OPENPGP);
+    let response = exchange(ccid, interface, "XfrBlock", &xfr_block, 2)?;
+    if response.len() < 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("SELECT response {} has no status word", hexdump(&response)),
+        ));
+    }
+    report.push_str(&format!(
+        "SELECT OpenPGP: SW {}\n",
+        hexdump(&response[response.len() - 2..])
+    ));
+    exchange(ccid, interface, "IccPowerOff", &[0x63], 3)?;
+    Ok(report)
+}
+
+/// Send the CCID message of `command`, its type followed by the data, and return the data of
+/// its response
+fn exchange(
+    ccid: &mut CCIDInterfaceHandler,
+    interface: &UsbInterface,
+    name: &str,
+    command: &[u8],
+    seq: u8,
+) -> io::Result<Vec<u8>> {
+    let data = &command[1..];
+    let mut message = vec![command[0]];
+    message.extend((data.len() as u32).to_le_bytes());
+    message.extend([0x00, seq, 0x00, 0x00, 0x00]);
+    message.extend(data);
+    let [bulk_in, bulk_out, ..] = interface.endpoints[..] else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "CCID interface has no bulk endpoints",
+        ));
+    };
+    ccid.handle_urb(
+        interface,
+        bulk_out,
+        message.len() as u32,
+        SetupPacket::default(),
+        &message,
+    )?;
+    // NAKed while the card is still exchanging the APDU
+    let response = loop {
+        match ccid.handle_urb(interface, bulk_in, 0x10000, SetupPacket::default(), &[]) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            result => break result?,
+        }
+    };
+    if response.len() < 10 || response[6] != seq {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Invalid response to PC_to_RDR_{}: {}",
+                name,
+                hexdump(&response)
+            ),
+        ));
+    }
+    // bmCommandStatus of bStatus
+    if response[7] & 0xC0 != 0 {
+        return Err(io::Error::other(format!(
+            "PC_to_RDR_{} failed with bStatus 0x{:02X}, bError 0x{:02X}",
+            name, response[7], response[8]
+        )));
+    }
+    Ok(response[10..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::build_relay;
+    use crate::fake::{MemoryBackend, PIGEON_ATR, pigeon_relay};
+
+    fn relay(backend: MemoryBackend) -> UsbDevice {
+        let config = pigeon_relay(backend);
+        build_relay(0, config).unwrap()
+    }
+
+    #[test]
+    fn test_selftest() {
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Ok(vec![0x90, 0x00]));
+        let log = backend.log.clone();
+        let report = run(&relay(backend)).unwrap();
+        assert_eq!(
+            report,
+            "ATR: 3BF71100008131FE6543616E6F6B657999\nSELECT OpenPGP: SW 9000\n"
+        );
+        assert_eq!(
+            log.lock().unwrap().transmitted,
+            vec![SELECT_OPENPGP.to_vec()]
+        );
+
+        let backend = MemoryBackend::new(&PIGEON_ATR).with_response(Err(pcsc::Error::NoSmartcard));
+        let err = run(&relay(backend)).unwrap_err();
+        assert!(
+            err.to_string().contains("PC_to_RDR_XfrBlock failed"),
+            "{}",
+            err
+        );
+    }
+}
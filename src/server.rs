@@ -0,0 +1,673 @@
+use crate::client::MemoryClient;
+use crate::device;
+use crate::panic_guard::panic_message;
+use crate::websocket;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinSet};
+use usbip::{ConnectionEvent, UsbDevice, UsbIpServer};
+
+/// Resets the handlers of a device attached by a client and powers off its cards once the
+/// client detached
+#[derive(Clone)]
+pub struct DetachCleanup {
+    devices: Vec<UsbDevice>,
+    // Cards are kept powered this long, waiting for the device being attached again
+    keep_powered: Option<Duration>,
+    // Attach count per bus ID, tells a delayed power off whether the device was attached since
+    attached: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl DetachCleanup {
+    pub fn new(devices: Vec<UsbDevice>, keep_powered: Option<Duration>) -> DetachCleanup {
+        Self {
+            devices,
+            keep_powered,
+            attached: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Reset the handlers of the device of an `Attached` event, so nothing is left from the
+    /// previous client. Power off the cards of the device of a `Detached` event, right away or
+    /// once the keep powered timeout passed without it being attached again.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub fn on_event(&self, event: &ConnectionEvent) {
+        let bus_id = match event {
+            ConnectionEvent::Attached { bus_id } => {
+                *self
+                    .attached
+                    .lock()
+                    .unwrap()
+                    .entry(bus_id.to_string())
+                    .or_default() += 1;
+                if let Some(device) = self.devices.iter().find(|device| device.bus_id == *bus_id) {
+                    device::reset_handlers(device);
+                }
+                return;
+            }
+            ConnectionEvent::Detached { bus_id } => bus_id.to_string(),
+        };
+        let Some(device) = self.devices.iter().find(|device| device.bus_id == bus_id) else {
+            return;
+        };
+        let Some(timeout) = self.keep_powered else {
+            device::drop_cards(device);
+            return;
+        };
+        let device = device.clone();
+        let attached = self.attached.clone();
+        let count = attached.lock().unwrap().get(&bus_id).copied();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if attached.lock().unwrap().get(&bus_id).copied() == count {
+                info!(
+                    "Device {} was not attached again within {:?}, powering off its cards",
+                    bus_id, timeout
+                );
+                device::drop_cards(&device);
+            }
+        });
+    }
+}
+
+/// Run the server started by `start` until it returns
+///
+/// A panic of the server is logged and followed by `cleanup`. The server is then started again
+/// if `restart` is set, otherwise an error is returned. Panics while serving a connection only
+/// end that connection, see [`serve`].
+pub async fn supervise<F, C>(
+    mut start: impl FnMut() -> F,
+    restart: bool,
+    mut cleanup: impl FnMut() -> C,
+) -> io::Result<()>
+where
+    F: Future<Output = io::Result<()>> + Send + 'static,
+    C: Future<Output = ()>,
+{
+    loop {
+        let panic = match tokio::spawn(start()).await {
+            Ok(result) => return result,
+            Err(e) if e.is_panic() => e.into_panic(),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+        error!("USB/IP server panicked: {}", panic_message(&panic));
+        cleanup().await;
+        if !restart {
+            return Err(io::Error::other("USB/IP server panicked"));
+        }
+        warn!("Restarting USB/IP server");
+    }
+}
+
+/// Accept USB/IP clients on `addr`, and over WebSocket on `websocket_addr` from pages of
+/// `origins` if set, and log which peer attaches and detaches which device
+///
+/// At most `max_clients` connections are served at a time over both, further ones are closed
+/// right away. WebSocket connections only count once their upgrade request was answered. `cleanup` is told about every attach and detach, `clients` holds the number of
+/// connections being served. A connection whose task panics is logged and closed, its device is
+/// detached and made available again.
+pub async fn serve(
+    addr: SocketAddr,
+    websocket_addr: Option<SocketAddr>,
+    origins: Vec<String>,
+    server: Arc<UsbIpServer>,
+    max_clients: usize,
+    cleanup: DetachCleanup,
+    clients: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Listening on {}", addr);
+    let websocket = match websocket_addr {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("Listening for WebSocket clients on {}", addr);
+            Some(listener)
+        }
+        None => None,
+    };
+    accept_loop(
+        listener,
+        websocket.map(|listener| (listener, origins.into())),
+        server,
+        max_clients,
+        cleanup,
+        clients,
+    )
+    .await
+}
+
+/// Accept a connection on `listener`, never if there is none
+async fn accept<T>(listener: &Option<(TcpListener, T)>) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some((listener, _)) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Byte stream of a USB/IP connection, over TCP or WebSocket
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Bus ID of the device a connection imported
+type AttachedBusId = Arc<Mutex<Option<String>>>;
+
+/// Counts a connection being served until dropped, also when its task panics or is aborted
+struct ClientCount(Arc<AtomicUsize>);
+
+impl ClientCount {
+    fn new(clients: Arc<AtomicUsize>) -> ClientCount {
+        clients.fetch_add(1, Ordering::Relaxed);
+        Self(clients)
+    }
+}
+
+impl Drop for ClientCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    websocket: Option<(TcpListener, Arc<[String]>)>,
+    server: Arc<UsbIpServer>,
+    max_clients: usize,
+    cleanup: DetachCleanup,
+    connected: Arc<AtomicUsize>,
+) -> io::Result<()> {
+    let clients = Arc::new(Semaphore::new(max_clients));
+    let mut connections = JoinSet::new();
+    // Peer and attached device of every connection task, to release the device on a panic
+    let mut attached: HashMap<task::Id, (SocketAddr, AttachedBusId)> = HashMap::new();
+    loop {
+        let (accepted, over_websocket) = tokio::select! {
+            accepted = listener.accept() => (accepted, false),
+            accepted = accept(&websocket) => (accepted, true),
+            Some(result) = connections.join_next_with_id() => {
+                let id = match &result {
+                    Ok((id, _)) => *id,
+                    Err(e) => e.id(),
+                };
+                let Some((peer, bus_id)) = attached.remove(&id) else {
+                    continue;
+                };
+                if let Err(e) = result
+                    && e.is_panic()
+                {
+                    error!(
+                        "Connection from {} panicked: {}",
+                        peer,
+                        panic_message(&e.into_panic())
+                    );
+                    let bus_id = bus_id.lock().unwrap().take();
+                    if let Some(bus_id) = bus_id {
+                        server.release_device(&bus_id).await;
+                        cleanup.on_event(&ConnectionEvent::Detached { bus_id: &bus_id });
+                        info!("{}", event_message(peer, ConnectionEvent::Detached { bus_id: &bus_id }));
+                    }
+                }
+                continue;
+            }
+        };
+        let (socket, peer) = match accepted {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let origins = websocket.as_ref().map(|(_, origins)| origins.clone());
+        let clients = clients.clone();
+        let server = server.clone();
+        let cleanup = cleanup.clone();
+        let connected = connected.clone();
+        let bus_id = Arc::new(Mutex::new(None));
+        let task_bus_id = bus_id.clone();
+        let handle = connections.spawn(async move {
+            let mut stream: Box<dyn Stream> = match (over_websocket, origins) {
+                (true, Some(origins)) => match websocket::accept(socket, &origins).await {
+                    Ok(stream) => Box::new(stream),
+                    Err(e) => {
+                        warn!("Rejected WebSocket connection from {}: {}", peer, e);
+                        return;
+                    }
+                },
+                _ => Box::new(socket),
+            };
+            // Taken once the connection is ready, so a client idling before the WebSocket
+            // upgrade does not lock others out
+            let Ok(permit) = clients.try_acquire_owned() else {
+                warn!(
+                    "Rejected connection from {}: already serving {} client(s)",
+                    peer, max_clients
+                );
+                return;
+            };
+            info!("Accepted connection from {}", peer);
+            let count = ClientCount::new(connected);
+            let on_event = |event: ConnectionEvent<'_>| {
+                *task_bus_id.lock().unwrap() = match event {
+                    ConnectionEvent::Attached { bus_id } => Some(bus_id.to_string()),
+                    ConnectionEvent::Detached { .. } => None,
+                };
+                cleanup.on_event(&event);
+                info!("{}", event_message(peer, event))
+            };
+            let res = usbip::handle_connection(&mut stream, server, on_event).await;
+            info!("Connection from {} closed: {:?}", peer, res);
+            drop(count);
+            drop(permit);
+        });
+        attached.insert(handle.id(), (peer, bus_id));
+    }
+}
+
+/// Fetch the configuration descriptor of `bus_id` the way a USB/IP client would
+///
+/// The device is imported over an in-process connection, so it must not be attached by a client.
+pub async fn fetch_configuration(server: Arc<UsbIpServer>, bus_id: &str) -> io::Result<Vec<u8>> {
+    fetch_descriptor(server, bus_id, 0x02).await
+}
+
+/// Fetch the descriptor of `descriptor_type` of `bus_id`, see [`fetch_configuration`]
+pub async fn fetch_descriptor(
+    server: Arc<UsbIpServer>,
+    bus_id: &str,
+    descriptor_type: u8,
+) -> io::Result<Vec<u8>> {
+    let mut client = MemoryClient::connect(server);
+    client.import(bus_id).await?;
+    // GET_DESCRIPTOR with the largest possible length
+    let setup = [0x80, 0x06, 0x00, descriptor_type, 0x00, 0x00, 0xFF, 0xFF];
+    let reply = client.transfer(0x80, setup, &[], 0xFFFF).await?;
+    if reply.status != 0 {
+        return Err(io::Error::other(format!(
+            "GET_DESCRIPTOR(0x{:02X}) failed with status {}",
+            descriptor_type, reply.status
+        )));
+    }
+    // Closing the connection returns the device to the available ones
+    client.close().await?;
+    Ok(reply.data)
+}
+
+fn event_message(peer: SocketAddr, event: ConnectionEvent) -> String {
+    match event {
+        ConnectionEvent::Attached { bus_id } => {
+            format!("Client {} attached device {}", peer, bus_id)
+        }
+        ConnectionEvent::Detached { bus_id } => {
+            format!("Client {} detached device {}", peer, bus_id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ccid::CCIDInterfaceHandler;
+    use crate::cli::InterfaceMode;
+    use crate::descriptor::{check_configuration, dump};
+    use crate::device::{RelayConfig, build_relay};
+    use crate::fake::{FakeHidApi, MemoryBackend, PIGEON_ATR, pigeon_relay};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::timeout;
+    use usbip::usbip_protocol::{USBIP_CMD_SUBMIT, UsbIpCommand, UsbIpHeaderBasic};
+
+    #[test]
+    fn test_event_message() {
+        let peer: SocketAddr = "192.168.1.20:51234".parse().unwrap();
+        assert_eq!(
+            event_message(peer, ConnectionEvent::Attached { bus_id: "0-0-0" }),
+            "Client 192.168.1.20:51234 attached device 0-0-0"
+        );
+        let peer: SocketAddr = "[fe80::1]:51234".parse().unwrap();
+        assert_eq!(
+            event_message(peer, ConnectionEvent::Detached { bus_id: "0-0-0" }),
+            "Client [fe80::1]:51234 detached device 0-0-0"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_clients() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        let connected = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(accept_loop(
+            listener,
+            None,
+            server,
+            1,
+            DetachCleanup::new(vec![], None),
+            connected.clone(),
+        ));
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        // The second client is closed without a reply
+        let read = timeout(Duration::from_secs(5), second.read(&mut buf)).await;
+        assert_eq!(read.unwrap().unwrap(), 0);
+        // The first one is still being served
+        let read = timeout(Duration::from_millis(100), first.read(&mut buf)).await;
+        assert!(read.is_err());
+        assert_eq!(connected.load(Ordering::Relaxed), 1);
+
+        // Its slot is freed once it disconnects
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut third = TcpStream::connect(addr).await.unwrap();
+        let read = timeout(Duration::from_millis(100), third.read(&mut buf)).await;
+        assert!(read.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_handshake_takes_no_slot() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let websocket = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let websocket_addr = websocket.local_addr().unwrap();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![]));
+        tokio::spawn(accept_loop(
+            listener,
+            Some((websocket, Arc::from([]))),
+            server,
+            1,
+            DetachCleanup::new(vec![], None),
+            Arc::new(AtomicUsize::new(0)),
+        ));
+
+        // A WebSocket client which never sends its upgrade request
+        let _idle = TcpStream::connect(websocket_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // does not keep a USB/IP client from being served
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = timeout(Duration::from_millis(100), client.read(&mut buf)).await;
+        assert!(read.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_submit_without_import() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = pigeon_relay(MemoryBackend::new(&PIGEON_ATR));
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            build_relay(0, config).unwrap(),
+        ]));
+        tokio::spawn(accept_loop(
+            listener,
+            None,
+            server,
+            1,
+            DetachCleanup::new(vec![], None),
+            Arc::new(AtomicUsize::new(0)),
+        ));
+
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let submit = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: 1,
+                ep: 0,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x12,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        socket.write_all(&submit.to_bytes()).await.unwrap();
+        let mut reply = [0u8; 8];
+        socket.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[4..8], [0, 0, 0, 1]);
+
+        // The connection is still served and can import the device
+        let mut busid = [0u8; 32];
+        busid[..5].copy_from_slice(b"0-0-0");
+        let import = UsbIpCommand::OpReqImport { status: 0, busid };
+        socket.write_all(&import.to_bytes()).await.unwrap();
+        socket.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[4..8], [0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_supervise_restart() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let mut cleanups = 0;
+        let result = supervise(
+            || {
+                let starts = starts.clone();
+                async move {
+                    if starts.fetch_add(1, Ordering::SeqCst) == 0 {
+                        panic!("injected panic");
+                    }
+                    Ok(())
+                }
+            },
+            true,
+            || {
+                cleanups += 1;
+                async {}
+            },
+        )
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(starts.load(Ordering::SeqCst), 2);
+        assert_eq!(cleanups, 1);
+
+        let mut cleanups = 0;
+        let result = supervise(
+            || async { panic!("injected panic") },
+            false,
+            || {
+                cleanups += 1;
+                async {}
+            },
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(cleanups, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_configuration() {
+        let hidapi = FakeHidApi::pigeon();
+        let mut devices = Vec::new();
+        for fido in [InterfaceMode::Required, InterfaceMode::Disabled] {
+            let config = RelayConfig {
+                hidapi: Some(&hidapi),
+                fido,
+                webusb: InterfaceMode::Required,
+                ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+            };
+            devices.push(build_relay(devices.len() as u32, config).unwrap());
+        }
+        let server = Arc::new(UsbIpServer::new_simulated(devices));
+
+        for bus_id in ["0-0-0", "0-0-1"] {
+            let desc = fetch_configuration(server.clone(), bus_id).await.unwrap();
+            check_configuration(&desc).unwrap();
+        }
+        // The device is available again after the fetch
+        let desc = fetch_configuration(server.clone(), "0-0-0").await.unwrap();
+        assert_eq!(desc[4], 3);
+        assert!(fetch_configuration(server, "0-0-9").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dump() {
+        let hidapi = FakeHidApi::pigeon();
+        let config = RelayConfig {
+            hidapi: Some(&hidapi),
+            fido: InterfaceMode::Required,
+            webusb: InterfaceMode::Required,
+            ..pigeon_relay(MemoryBackend::new(&PIGEON_ATR))
+        };
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            build_relay(0, config).unwrap(),
+        ]));
+        let device = fetch_descriptor(server.clone(), "0-0-0", 0x01)
+            .await
+            .unwrap();
+        let configuration = fetch_configuration(server, "0-0-0").await.unwrap();
+        let dump = dump(&device, &configuration);
+        assert!(dump.contains("VID 20A0, PID 42D4"), "{}", dump);
+        assert!(
+            dump.contains("Interface 2: alternate 0, class 0x0B"),
+            "{}",
+            dump
+        );
+        assert!(dump.contains("Class specific 0x21: 54 bytes"), "{}", dump);
+        assert!(
+            dump.contains("Configuration descriptor is valid"),
+            "{}",
+            dump
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detach_cleanup() {
+        let relay = || {
+            let mut backend = MemoryBackend::new(&PIGEON_ATR);
+            backend.connected = true;
+            let config = pigeon_relay(backend);
+            build_relay(0, config).unwrap()
+        };
+        let powered = |device: &UsbDevice| {
+            device.interfaces.iter().any(|interface| {
+                interface
+                    .handler
+                    .lock()
+                    .unwrap()
+                    .as_any()
+                    .downcast_mut::<CCIDInterfaceHandler>()
+                    .is_some_and(|ccid| ccid.status().is_powered(0))
+            })
+        };
+        let attached = ConnectionEvent::Attached { bus_id: "0-0-0" };
+        let detached = ConnectionEvent::Detached { bus_id: "0-0-0" };
+
+        let device = relay();
+        DetachCleanup::new(vec![device.clone()], None).on_event(&detached);
+        assert!(!powered(&device));
+
+        // Kept powered across a quick reattach
+        let device = relay();
+        let cleanup = DetachCleanup::new(vec![device.clone()], Some(Duration::from_millis(50)));
+        cleanup.on_event(&attached);
+        cleanup.on_event(&detached);
+        assert!(powered(&device));
+        cleanup.on_event(&attached);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(powered(&device));
+
+        // Powered off once the timeout passed
+        cleanup.on_event(&detached);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!powered(&device));
+    }
+
+    #[tokio::test]
+    async fn test_reattach_resets_handlers() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.connected = true;
+        let config = pigeon_relay(backend);
+        let device = build_relay(0, config).unwrap();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![device.clone()]));
+        // Keeping the card powered, only the reset on attach clears the handler
+        let cleanup = DetachCleanup::new(vec![device], Some(Duration::from_secs(10)));
+        let connect = || {
+            let cleanup = cleanup.clone();
+            MemoryClient::connect_with_events(server.clone(), move |event| cleanup.on_event(&event))
+        };
+        let get_slot_status = [0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00];
+
+        // The first client leaves a response it never read
+        let mut client = connect();
+        client.import("0-0-0").await.unwrap();
+        let seqnum = client
+            .submit(0x01, [0; 8], &get_slot_status, 0)
+            .await
+            .unwrap();
+        assert_eq!(client.reply().await.unwrap().seqnum, seqnum);
+        client.close().await.unwrap();
+
+        // The next one does not get it
+        let mut client = connect();
+        client.import("0-0-0").await.unwrap();
+        client.submit(0x81, [0; 8], &[], 0x200).await.unwrap();
+        let read = timeout(Duration::from_millis(100), client.reply()).await;
+        assert!(read.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bulk_in_nak() {
+        let mut backend = MemoryBackend::new(&PIGEON_ATR);
+        backend.connected = true;
+        backend.transmit_delay = Duration::from_millis(100);
+        let config = pigeon_relay(backend);
+        let server = Arc::new(UsbIpServer::new_simulated(vec![
+            build_relay(0, config).unwrap(),
+        ]));
+        let mut client = MemoryClient::connect(server);
+        client.import("0-0-0").await.unwrap();
+
+        // Bulk IN before any command is kept pending
+        let pending = client.submit(0x81, [0; 8], &[], 0x200).await.unwrap();
+        let read = timeout(Duration::from_millis(100), client.reply()).await;
+        assert!(read.is_err());
+
+        // and completed after PC_to_RDR_GetSlotStatus
+        let get_slot_status = [0x65, 0x00, 0x00, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00];
+        let seqnum = client
+            .submit(0x01, [0; 8], &get_slot_status, 0)
+            .await
+            .unwrap();
+        let reply = client.reply().await.unwrap();
+        assert_eq!((reply.seqnum, reply.status), (seqnum, 0));
+        let reply = client.reply().await.unwrap();
+        assert_eq!((reply.seqnum, reply.status), (pending, 0));
+        assert_eq!(reply.data[0], 0x81);
+        assert_eq!(reply.data[6], 0x07);
+
+        // Completed once a slow APDU exchange ends, without another transfer
+        let xfr_block = [
+            0x6F, 0x05, 0x00, 0x00, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0xA4, 0x04, 0x00,
+            0x00,
+        ];
+        client.submit(0x01, [0; 8], &xfr_block, 0).await.unwrap();
+        assert_eq!(client.reply().await.unwrap().status, 0);
+        let pending = client.submit(0x81, [0; 8], &[], 0x200).await.unwrap();
+        let reply = timeout(Duration::from_secs(5), client.reply())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((reply.seqnum, reply.status), (pending, 0));
+        assert_eq!((reply.data[0], reply.data[6]), (0x80, 0x08));
+
+        // Unlinking a pending bulk IN gives it back
+        let pending = client.submit(0x81, [0; 8], &[], 0x200).await.unwrap();
+        let unlink = client.unlink(pending).await.unwrap();
+        let reply = client.reply().await.unwrap();
+        assert_eq!((reply.seqnum, reply.status), (unlink, -104));
+    }
+}
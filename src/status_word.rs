@@ -0,0 +1,78 @@
+use crate::hexdump::hexdump;
+
+/// Meaning of the ISO 7816-4 status word `sw`, for logging
+pub fn describe(sw: u16) -> String {
+    let [sw1, sw2] = sw.to_be_bytes();
+    let description = match (sw1, sw2) {
+        (0x90, 0x00) => "Success",
+        (0x61, _) => return format!("{} more bytes available", sw2),
+        (0x62, 0x81) => "Part of returned data may be corrupted",
+        (0x62, 0x82) => "End of file reached before reading Le bytes",
+        (0x62, 0x83) => "Selected file invalidated",
+        (0x62, _) => "Warning, state unchanged",
+        (0x63, 0xC0..=0xCF) => {
+            return format!("Verification failed, {} retries left", sw2 & 0x0F);
+        }
+        (0x63, _) => "Warning, state changed",
+        (0x64, _) => "Execution error, state unchanged",
+        (0x65, 0x81) => "Memory failure",
+        (0x65, _) => "Execution error, state changed",
+        (0x67, 0x00) => "Wrong length",
+        (0x68, 0x81) => "Logical channel not supported",
+        (0x68, 0x82) => "Secure messaging not supported",
+        (0x68, _) => "Function in CLA not supported",
+        (0x69, 0x82) => "Security status not satisfied",
+        (0x69, 0x83) => "Authentication method blocked",
+        (0x69, 0x84) => "Reference data not usable",
+        (0x69, 0x85) => "Conditions of use not satisfied",
+        (0x69, 0x86) => "Command not allowed, no current EF",
+        (0x69, 0x87) => "Expected secure messaging data objects missing",
+        (0x69, 0x88) => "Incorrect secure messaging data objects",
+        (0x69, _) => "Command not allowed",
+        (0x6A, 0x80) => "Incorrect parameters in the data field",
+        (0x6A, 0x81) => "Function not supported",
+        (0x6A, 0x82) => "File or application not found",
+        (0x6A, 0x83) => "Record not found",
+        (0x6A, 0x84) => "Not enough memory space in the file",
+        (0x6A, 0x86) => "Incorrect parameters P1-P2",
+        (0x6A, 0x88) => "Referenced data not found",
+        (0x6A, _) => "Wrong parameters",
+        (0x6B, 0x00) => "Wrong parameters P1-P2",
+        (0x6C, _) => return format!("Wrong Le, {} bytes available", sw2),
+        (0x6D, 0x00) => "Instruction not supported",
+        (0x6E, 0x00) => "Class not supported",
+        (0x6F, 0x00) => "No precise diagnosis",
+        _ => "Unknown status",
+    };
+    description.to_string()
+}
+
+/// `response` as hex followed by the meaning of its trailing status word
+pub fn annotate(response: &[u8]) -> String {
+    match response {
+        [.., sw1, sw2] => format!(
+            "{} ({})",
+            hexdump(response),
+            describe(u16::from_be_bytes([*sw1, *sw2]))
+        ),
+        _ => format!("{} (no status word)", hexdump(response)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(describe(0x9000), "Success");
+        assert_eq!(describe(0x6A82), "File or application not found");
+        assert_eq!(describe(0x6982), "Security status not satisfied");
+        assert_eq!(describe(0x63C2), "Verification failed, 2 retries left");
+        assert_eq!(describe(0x6110), "16 more bytes available");
+        assert_eq!(describe(0x6C05), "Wrong Le, 5 bytes available");
+        assert_eq!(describe(0x1234), "Unknown status");
+        assert_eq!(annotate(&[0x01, 0x90, 0x00]), "019000 (Success)");
+        assert_eq!(annotate(&[0x90]), "90 (no status word)");
+    }
+}
@@ -0,0 +1,102 @@
+//! Logging of the URBs handed to the handlers, enabled with `--trace-urbs`
+//!
+//! Lines are logged at trace level with the `urb` target and start with `URB`, one field per
+//! `key=value` pair, so they can be grepped out of the log.
+use crate::hexdump::hexdump;
+use log::{Level, log_enabled, trace};
+use std::fmt::Write;
+use usbip::SetupPacket;
+
+/// Log target of the URB lines, filtered out unless enabled
+pub const TARGET: &str = "urb";
+
+/// Bytes of the transfer data logged, the rest is elided
+const MAX_DATA: usize = 64;
+
+/// Log an URB received by `handler` on the endpoint at `address`
+pub fn urb(
+    handler: &str,
+    address: u8,
+    transfer_buffer_length: u32,
+    setup: &SetupPacket,
+    req: &[u8],
+) {
+    if log_enabled!(target: TARGET, Level::Trace) {
+        trace!(
+            target: TARGET,
+            "{}",
+            format_urb(handler, address, transfer_buffer_length, setup, req)
+        );
+    }
+}
+
+/// Format an URB, the setup packet is only included for endpoint 0
+pub fn format_urb(
+    handler: &str,
+    address: u8,
+    transfer_buffer_length: u32,
+    setup: &SetupPacket,
+    req: &[u8],
+) -> String {
+    let control = address & 0x7F == 0;
+    // Endpoint 0 is bidirectional, bmRequestType tells the direction
+    let direction = if control { setup.request_type } else { address } & 0x80;
+    let mut line = format!(
+        "URB handler={} ep=0x{:02X} dir={} type={}",
+        handler,
+        address,
+        if direction != 0 { "IN" } else { "OUT" },
+        if control { "control" } else { "data" }
+    );
+    if control {
+        write!(
+            line,
+            " bmRequestType=0x{:02X} bRequest=0x{:02X} wValue=0x{:04X} wIndex=0x{:04X} wLength={}",
+            setup.request_type, setup.request, setup.value, setup.index, setup.length
+        )
+        .unwrap();
+    }
+    write!(
+        line,
+        " length={} data[{}]={}",
+        transfer_buffer_length,
+        req.len(),
+        hexdump(&req[..req.len().min(MAX_DATA)])
+    )
+    .unwrap();
+    if req.len() > MAX_DATA {
+        line.push_str("...");
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_urb() {
+        // GET_DESCRIPTOR of the device descriptor
+        let setup = SetupPacket {
+            request_type: 0x80,
+            request: 0x06,
+            value: 0x0100,
+            index: 0x0000,
+            length: 0x12,
+        };
+        assert_eq!(
+            format_urb("device", 0x00, 0x12, &setup, &[]),
+            "URB handler=device ep=0x00 dir=IN type=control bmRequestType=0x80 bRequest=0x06 \
+             wValue=0x0100 wIndex=0x0000 wLength=18 length=18 data[0]="
+        );
+
+        // Bulk OUT with PC_to_RDR_IccPowerOn, long data is cut
+        let power_on = [0x62, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(
+            format_urb("ccid", 0x01, 10, &SetupPacket::default(), &power_on),
+            "URB handler=ccid ep=0x01 dir=OUT type=data length=10 data[10]=62000000000001000000"
+        );
+        let line = format_urb("ccid", 0x01, 100, &SetupPacket::default(), &[0xAB; 100]);
+        assert!(line.ends_with(&format!("data[100]={}...", "AB".repeat(MAX_DATA))));
+    }
+}
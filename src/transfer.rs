@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+/// Timeouts and retries of the transfers the handlers issue to the physical device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferConfig {
+    /// Control transfers relayed to the vendor specific interface
+    pub control_timeout: Duration,
+    /// HID reads answering interrupt IN transfers and input report requests
+    pub interrupt_read_timeout: Duration,
+    /// APDU exchanges and escapes, which are then answered with `CMD_ABORTED`
+    ///
+    /// The exchange is abandoned, not interrupted. Never enforced when `None`.
+    pub transmit_timeout: Option<Duration>,
+    /// Least time between the end of one APDU exchange and the start of the next, for readers
+    /// failing on back-to-back commands
+    pub command_delay: Duration,
+    /// HID writes issued again after a transient failure, a disconnect is never retried
+    pub hid_write_retries: u32,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        Self {
+            control_timeout: Duration::from_secs(5),
+            interrupt_read_timeout: Duration::from_millis(4),
+            transmit_timeout: None,
+            command_delay: Duration::ZERO,
+            hid_write_retries: 1,
+        }
+    }
+}
@@ -0,0 +1,284 @@
+use log::{debug, error, info};
+use nusb::MaybeFuture;
+use nusb::descriptors::{ConfigurationDescriptor, DeviceDescriptor};
+use nusb::transfer::{ControlIn, ControlOut};
+#[cfg(not(target_os = "windows"))]
+use nusb::transfer::{ControlType, Recipient};
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// USB device access used by the interface handlers
+///
+/// Implemented for [`nusb::Device`], and by [`crate::fake::FakeUsbDevice`] in tests.
+pub trait UsbBackend: Send + Sync {
+    fn device_descriptor(&self) -> DeviceDescriptor;
+
+    /// Raw bytes of the active configuration descriptor and all trailing descriptors
+    fn active_configuration(&self) -> io::Result<Vec<u8>>;
+
+    /// GET_DESCRIPTOR on the default control pipe, returning at most `length` bytes
+    fn get_descriptor(
+        &self,
+        desc_type: u8,
+        desc_index: u8,
+        language_id: u16,
+        length: u16,
+        timeout: Duration,
+    ) -> io::Result<Vec<u8>>;
+
+    fn claim_interface(&self, interface: u8) -> io::Result<Box<dyn UsbInterfaceBackend>>;
+
+    /// Unbind the kernel driver from `interface`, failing if none is bound
+    fn detach_kernel_driver(&self, interface: u8) -> io::Result<()>;
+
+    /// Bind the kernel driver to `interface` again
+    fn attach_kernel_driver(&self, interface: u8) -> io::Result<()>;
+}
+
+/// Control transfers on a claimed interface
+pub trait UsbInterfaceBackend: Send {
+    fn control_in(&self, control: ControlIn, timeout: Duration) -> io::Result<Vec<u8>>;
+
+    fn control_out(&self, control: ControlOut, timeout: Duration) -> io::Result<()>;
+}
+
+pub fn parse_configuration(buf: &[u8]) -> io::Result<ConfigurationDescriptor<'_>> {
+    ConfigurationDescriptor::new(buf).ok_or(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Invalid configuration descriptor",
+    ))
+}
+
+/// Serial number string of `device`, `None` if it has none or it could not be read
+pub fn serial_number(device: &dyn UsbBackend) -> Option<String> {
+    let index = device.device_descriptor().serial_number_string_index()?;
+    // English (United States), the language keys have their strings in
+    let desc = device
+        .get_descriptor(0x03, index.get(), 0x0409, 0xFF, Duration::from_secs(1))
+        .map_err(|e| debug!("Failed to read serial number of USB device: {}", e))
+        .ok()?;
+    let length = (*desc.first()? as usize).min(desc.len());
+    let units: Vec<u16> = desc
+        .get(2..length)?
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+impl UsbBackend for nusb::Device {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        nusb::Device::device_descriptor(self)
+    }
+
+    fn active_configuration(&self) -> io::Result<Vec<u8>> {
+        Ok(nusb::Device::active_configuration(self)?
+            .as_bytes()
+            .to_vec())
+    }
+
+    #[cfg(target_os = "windows")]
+    fn get_descriptor(
+        &self,
+        desc_type: u8,
+        desc_index: u8,
+        language_id: u16,
+        length: u16,
+        timeout: Duration,
+    ) -> io::Result<Vec<u8>> {
+        // wLength is chosen by the OS on Windows
+        let mut desc =
+            nusb::Device::get_descriptor(self, desc_type, desc_index, language_id, timeout)
+                .wait()?;
+        desc.truncate(length as usize);
+        Ok(desc)
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    fn get_descriptor(
+        &self,
+        desc_type: u8,
+        desc_index: u8,
+        language_id: u16,
+        length: u16,
+        timeout: Duration,
+    ) -> io::Result<Vec<u8>> {
+        Ok(nusb::Device::control_in(
+            self,
+            ControlIn {
+                control_type: ControlType::Standard,
+                recipient: Recipient::Device,
+                request: 0x06, // GET_DESCRIPTOR
+                value: ((desc_type as u16) << 8) | desc_index as u16,
+                index: language_id,
+                length,
+            },
+            timeout,
+        )
+        .wait()?)
+    }
+
+    fn claim_interface(&self, interface: u8) -> io::Result<Box<dyn UsbInterfaceBackend>> {
+        let interface = nusb::Device::claim_interface(self, interface)
+            .wait()
+            .map_err(|e| io::Error::new(io::ErrorKind::ResourceBusy, e))?;
+        Ok(Box::new(interface))
+    }
+
+    // Both only do something on Linux
+    fn detach_kernel_driver(&self, interface: u8) -> io::Result<()> {
+        Ok(nusb::Device::detach_kernel_driver(self, interface)?)
+    }
+
+    fn attach_kernel_driver(&self, interface: u8) -> io::Result<()> {
+        Ok(nusb::Device::attach_kernel_driver(self, interface)?)
+    }
+}
+
+/// Device whose interfaces are taken over from the kernel drivers bound to them when claimed
+///
+/// A detached driver is bound again once the interface is released.
+pub struct DetachingBackend {
+    device: Arc<dyn UsbBackend>,
+}
+
+impl DetachingBackend {
+    pub fn new(device: Arc<dyn UsbBackend>) -> DetachingBackend {
+        Self { device }
+    }
+}
+
+impl UsbBackend for DetachingBackend {
+    fn device_descriptor(&self) -> DeviceDescriptor {
+        self.device.device_descriptor()
+    }
+
+    fn active_configuration(&self) -> io::Result<Vec<u8>> {
+        self.device.active_configuration()
+    }
+
+    fn get_descriptor(
+        &self,
+        desc_type: u8,
+        desc_index: u8,
+        language_id: u16,
+        length: u16,
+        timeout: Duration,
+    ) -> io::Result<Vec<u8>> {
+        self.device
+            .get_descriptor(desc_type, desc_index, language_id, length, timeout)
+    }
+
+    fn claim_interface(&self, interface: u8) -> io::Result<Box<dyn UsbInterfaceBackend>> {
+        if let Err(e) = self.device.detach_kernel_driver(interface) {
+            debug!(
+                "No kernel driver detached from interface {}: {}",
+                interface, e
+            );
+            return self.device.claim_interface(interface);
+        }
+        info!("Detached kernel driver from interface {}", interface);
+        match self.device.claim_interface(interface) {
+            Ok(claimed) => Ok(Box::new(DetachedInterface {
+                interface: Some(claimed),
+                device: self.device.clone(),
+                number: interface,
+            })),
+            Err(e) => {
+                reattach(self.device.as_ref(), interface);
+                Err(e)
+            }
+        }
+    }
+
+    fn detach_kernel_driver(&self, interface: u8) -> io::Result<()> {
+        self.device.detach_kernel_driver(interface)
+    }
+
+    fn attach_kernel_driver(&self, interface: u8) -> io::Result<()> {
+        self.device.attach_kernel_driver(interface)
+    }
+}
+
+/// Interface claimed by [`DetachingBackend`] after detaching its kernel driver
+struct DetachedInterface {
+    // Released before the driver is attached again
+    interface: Option<Box<dyn UsbInterfaceBackend>>,
+    device: Arc<dyn UsbBackend>,
+    number: u8,
+}
+
+impl UsbInterfaceBackend for DetachedInterface {
+    fn control_in(&self, control: ControlIn, timeout: Duration) -> io::Result<Vec<u8>> {
+        self.interface
+            .as_ref()
+            .unwrap()
+            .control_in(control, timeout)
+    }
+
+    fn control_out(&self, control: ControlOut, timeout: Duration) -> io::Result<()> {
+        self.interface
+            .as_ref()
+            .unwrap()
+            .control_out(control, timeout)
+    }
+}
+
+impl Drop for DetachedInterface {
+    fn drop(&mut self) {
+        self.interface = None;
+        reattach(self.device.as_ref(), self.number);
+    }
+}
+
+fn reattach(device: &dyn UsbBackend, interface: u8) {
+    match device.attach_kernel_driver(interface) {
+        Ok(()) => info!("Attached kernel driver to interface {} again", interface),
+        Err(e) => error!(
+            "Failed to attach kernel driver to interface {}: {}",
+            interface, e
+        ),
+    }
+}
+
+impl UsbInterfaceBackend for nusb::Interface {
+    fn control_in(&self, control: ControlIn, timeout: Duration) -> io::Result<Vec<u8>> {
+        Ok(nusb::Interface::control_in(self, control, timeout).wait()?)
+    }
+
+    fn control_out(&self, control: ControlOut, timeout: Duration) -> io::Result<()> {
+        Ok(nusb::Interface::control_out(self, control, timeout).wait()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeUsbDevice;
+
+    #[test]
+    fn test_detach_kernel_driver() {
+        let device = FakeUsbDevice::pigeon();
+        device.kernel_drivers.lock().unwrap().push(1);
+        let log = device.interface.log.clone();
+        let device: Arc<dyn UsbBackend> = Arc::new(device);
+        assert_eq!(
+            device.claim_interface(1).err().unwrap().kind(),
+            io::ErrorKind::ResourceBusy
+        );
+
+        let detaching = DetachingBackend::new(device.clone());
+        let interface = detaching.claim_interface(1).unwrap();
+        assert!(device.detach_kernel_driver(1).is_err());
+        drop(interface);
+        assert_eq!(log.lock().unwrap().released, vec![1]);
+        // Bound again, after the interface was released
+        device.detach_kernel_driver(1).unwrap();
+
+        // Nothing is attached to an interface which had no driver
+        let interface = detaching.claim_interface(1).unwrap();
+        drop(interface);
+        assert!(device.detach_kernel_driver(1).is_err());
+    }
+}
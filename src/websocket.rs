@@ -0,0 +1,520 @@
+//! USB/IP over WebSocket (RFC 6455), for browser based and sandboxed clients
+//!
+//! A client connects with an HTTP upgrade request to any path, after which the USB/IP byte
+//! stream is carried in binary messages in both directions. Browsers let any web page open
+//! WebSockets to any address, so upgrade requests with an `Origin` other than the allowed ones
+//! are refused. Clients outside browsers send none.
+//!
+//! - Message boundaries carry no meaning, a USB/IP PDU may span several messages and a message
+//!   may hold several PDUs. The server sends each of its writes as one unfragmented message.
+//! - Frames of the client must be masked, text messages and unknown opcodes end the connection.
+//! - Pings are answered with pongs, the server closes with 1000 once the USB/IP connection
+//!   ended, including after a close of the client.
+use log::debug;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::Mutex;
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xA;
+
+/// Appended to `Sec-WebSocket-Key` to derive `Sec-WebSocket-Accept`, RFC 6455 1.3
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Longest upgrade request accepted
+const MAX_REQUEST_LENGTH: usize = 0x2000;
+
+/// How long a client may take to send its upgrade request
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest frame payload accepted, above the largest USB/IP transfer
+const MAX_PAYLOAD_LENGTH: u64 = 0x100000;
+
+/// Buffer between the WebSocket and the USB/IP connection
+const BRIDGE_CAPACITY: usize = 0x10000;
+
+/// Frame as received, unmasked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub masked: bool,
+    pub payload: Vec<u8>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0x00);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            words[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let mut bytes = [0u8; 3];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 0x3F] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// `Sec-WebSocket-Accept` answering `Sec-WebSocket-Key` `key`
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Read a frame, unmasking its payload
+pub async fn read_frame(input: &mut (impl AsyncRead + Unpin)) -> io::Result<Frame> {
+    let mut header = [0u8; 2];
+    input.read_exact(&mut header).await?;
+    if header[0] & 0x70 != 0 {
+        return Err(invalid(format!(
+            "WebSocket frame has reserved bits set: 0x{:02X}",
+            header[0]
+        )));
+    }
+    let length = match header[1] & 0x7F {
+        126 => input.read_u16().await? as u64,
+        127 => input.read_u64().await?,
+        length => length as u64,
+    };
+    if length > MAX_PAYLOAD_LENGTH {
+        return Err(invalid(format!(
+            "WebSocket frame of {} bytes is too long",
+            length
+        )));
+    }
+    let masked = header[1] & 0x80 != 0;
+    let mut mask = [0u8; 4];
+    if masked {
+        input.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; length as usize];
+    input.read_exact(&mut payload).await?;
+    if masked {
+        payload
+            .iter_mut()
+            .zip(mask.iter().cycle())
+            .for_each(|(byte, mask)| *byte ^= mask);
+    }
+    Ok(Frame {
+        opcode: header[0] & 0x0F,
+        masked,
+        payload,
+    })
+}
+
+/// Write `payload` as a single final frame, masked with `mask` as clients do
+pub async fn write_frame(
+    output: &mut (impl AsyncWrite + Unpin),
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0x00 };
+    match payload.len() {
+        length @ 0..=125 => frame.push(mask_bit | length as u8),
+        length @ 126..=0xFFFF => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    output.write_all(&frame).await?;
+    output.flush().await
+}
+
+/// Answer the HTTP upgrade request on `socket`, failing with 400 Bad Request for anything but
+/// a WebSocket version 13 upgrade and with 403 Forbidden if it has an `Origin` not in `origins`
+///
+/// The request is read a byte at a time, so nothing after it is consumed.
+pub async fn handshake(
+    socket: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    origins: &[String],
+) -> io::Result<()> {
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_LENGTH {
+            return Err(invalid("WebSocket upgrade request is too long".to_string()));
+        }
+        request.push(socket.read_u8().await?);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let request_line = lines.next().unwrap_or_default();
+    let header = |name: &str| {
+        request.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_string())
+        })
+    };
+    let key = match (
+        request_line.starts_with("GET "),
+        header("Upgrade"),
+        header("Sec-WebSocket-Version"),
+        header("Sec-WebSocket-Key"),
+    ) {
+        (true, Some(upgrade), Some(version), Some(key))
+            if upgrade.eq_ignore_ascii_case("websocket") && version == "13" =>
+        {
+            key
+        }
+        _ => {
+            socket
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Err(invalid(format!(
+                "Not a WebSocket upgrade request: {}",
+                request_line
+            )));
+        }
+    };
+    if let Some(origin) = header("Origin")
+        && !origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&origin))
+    {
+        socket
+            .write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "WebSocket upgrade request from origin {} not allowed",
+                origin
+            ),
+        ));
+    }
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await
+}
+
+/// Pass the payloads of the messages of the client to `output` until it closes
+async fn forward_messages(
+    input: &mut (impl AsyncRead + Unpin),
+    writer: &Mutex<impl AsyncWrite + Unpin>,
+    output: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<()> {
+    loop {
+        let frame = read_frame(input).await?;
+        if !frame.masked {
+            return Err(invalid("Unmasked WebSocket frame from client".to_string()));
+        }
+        match frame.opcode {
+            CONTINUATION | BINARY => output.write_all(&frame.payload).await?,
+            PING => write_frame(&mut *writer.lock().await, PONG, &frame.payload, None).await?,
+            PONG => {}
+            CLOSE => return Ok(()),
+            TEXT => return Err(invalid("Text message on USB/IP WebSocket".to_string())),
+            opcode => {
+                return Err(invalid(format!("Unknown WebSocket opcode 0x{:X}", opcode)));
+            }
+        }
+    }
+}
+
+/// Accept the WebSocket connection on `socket` from a page of `origins`, returning the USB/IP
+/// byte stream it carries
+///
+/// Fails if the upgrade request does not arrive within [`HANDSHAKE_TIMEOUT`].
+pub async fn accept<S>(mut socket: S, origins: &[String]) -> io::Result<DuplexStream>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut socket, origins))
+        .await
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "No WebSocket upgrade request within {:?}",
+                    HANDSHAKE_TIMEOUT
+                ),
+            )
+        })??;
+    let (stream, bridge) = tokio::io::duplex(BRIDGE_CAPACITY);
+    let (mut socket_read, socket_write) = tokio::io::split(socket);
+    let (mut bridge_read, mut bridge_write) = tokio::io::split(bridge);
+    let socket_write = Arc::new(Mutex::new(socket_write));
+
+    let writer = socket_write.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; BRIDGE_CAPACITY];
+        loop {
+            let read = match bridge_read.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            let mut writer = writer.lock().await;
+            if let Err(e) = write_frame(&mut *writer, BINARY, &buf[..read], None).await {
+                debug!("Failed to write WebSocket message: {}", e);
+                return;
+            }
+        }
+        let mut writer = writer.lock().await;
+        let _ = write_frame(&mut *writer, CLOSE, &1000u16.to_be_bytes(), None).await;
+        let _ = writer.shutdown().await;
+    });
+    tokio::spawn(async move {
+        if let Err(e) = forward_messages(&mut socket_read, &socket_write, &mut bridge_write).await {
+            debug!("WebSocket connection failed: {}", e);
+        }
+        // The USB/IP connection reads the end of the stream and ends
+        let _ = bridge_write.shutdown().await;
+    });
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+    use usbip::UsbIpServer;
+
+    const MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    const UPGRADE: &[u8] = b"GET /usbip HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+        Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+
+    async fn read_head(input: &mut (impl AsyncRead + Unpin)) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(input.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Example of RFC 6455 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+    }
+
+    #[tokio::test]
+    async fn test_frame_lengths() {
+        for length in [0, 125, 126, 0xFFFF, 0x10000] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            for mask in [None, Some(MASK)] {
+                let mut frame = Vec::new();
+                write_frame(&mut frame, BINARY, &payload, mask)
+                    .await
+                    .unwrap();
+                let read = read_frame(&mut frame.as_slice()).await.unwrap();
+                assert_eq!(read.opcode, BINARY);
+                assert_eq!(read.masked, mask.is_some());
+                assert_eq!(read.payload, payload, "{} bytes", length);
+            }
+        }
+        let too_long = [0x82, 0x7F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00];
+        assert!(read_frame(&mut too_long.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let accepted = tokio::spawn(async move { accept(server, &[]).await });
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_head(&mut client).await.starts_with("HTTP/1.1 400"));
+        assert!(accepted.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_origin() {
+        let origins = ["https://usbip.example".to_string()];
+        let upgrade = |origin: &str| {
+            let mut request = UPGRADE[..UPGRADE.len() - 2].to_vec();
+            request.extend(format!("Origin: {}\r\n\r\n", origin).as_bytes());
+            request
+        };
+
+        // Pages of other origins are refused
+        let (mut client, mut server) = tokio::io::duplex(0x1000);
+        client
+            .write_all(&upgrade("https://evil.example"))
+            .await
+            .unwrap();
+        let result = handshake(&mut server, &origins).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(read_head(&mut client).await.starts_with("HTTP/1.1 403"));
+
+        let (mut client, mut server) = tokio::io::duplex(0x1000);
+        client
+            .write_all(&upgrade("https://USBIP.example"))
+            .await
+            .unwrap();
+        handshake(&mut server, &origins).await.unwrap();
+        assert!(read_head(&mut client).await.starts_with("HTTP/1.1 101"));
+    }
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        let accepted = tokio::spawn(async move { accept(server, &[]).await });
+        client.write_all(UPGRADE).await.unwrap();
+        let head = read_head(&mut client).await;
+        assert!(head.starts_with("HTTP/1.1 101"), "{}", head);
+        assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        let mut stream = accepted.await.unwrap().unwrap();
+
+        // The payloads form one byte stream
+        write_frame(&mut client, BINARY, &[0x01, 0x02, 0x03], Some(MASK))
+            .await
+            .unwrap();
+        write_frame(&mut client, BINARY, &[0x04, 0x05], Some(MASK))
+            .await
+            .unwrap();
+        let mut received = [0u8; 5];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, [0x01, 0x02, 0x03, 0x04, 0x05]);
+
+        stream.write_all(&[0x09, 0x08, 0x07]).await.unwrap();
+        let frame = read_frame(&mut client).await.unwrap();
+        assert_eq!(frame.opcode, BINARY);
+        assert!(!frame.masked);
+        assert_eq!(frame.payload, [0x09, 0x08, 0x07]);
+
+        write_frame(&mut client, PING, b"ping", Some(MASK))
+            .await
+            .unwrap();
+        let frame = read_frame(&mut client).await.unwrap();
+        assert_eq!(
+            (frame.opcode, frame.payload.as_slice()),
+            (PONG, &b"ping"[..])
+        );
+
+        // The end of the USB/IP connection closes the WebSocket
+        drop(stream);
+        let frame = read_frame(&mut client).await.unwrap();
+        assert_eq!(frame.opcode, CLOSE);
+        assert_eq!(frame.payload, 1000u16.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_usbip_over_websocket() {
+        let (mut client, server) = tokio::io::duplex(0x1000);
+        tokio::spawn(async move {
+            let mut stream = accept(server, &[]).await?;
+            let usbip_server = Arc::new(UsbIpServer::new_simulated(vec![]));
+            usbip::handle_connection(&mut stream, usbip_server, |_| {}).await
+        });
+        client.write_all(UPGRADE).await.unwrap();
+        read_head(&mut client).await;
+
+        // OP_REQ_DEVLIST, split across two messages
+        let request = [0x01, 0x11, 0x80, 0x05, 0x00, 0x00, 0x00, 0x00];
+        write_frame(&mut client, BINARY, &request[..3], Some(MASK))
+            .await
+            .unwrap();
+        write_frame(&mut client, BINARY, &request[3..], Some(MASK))
+            .await
+            .unwrap();
+        let mut reply = Vec::new();
+        while reply.len() < 12 {
+            let frame = timeout(Duration::from_secs(5), read_frame(&mut client))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.opcode, BINARY);
+            reply.extend(frame.payload);
+        }
+        // OP_REP_DEVLIST without devices
+        assert_eq!(&reply[2..4], [0x00, 0x05]);
+        assert_eq!(&reply[8..12], [0x00; 4]);
+
+        // Closing ends the USB/IP connection, which is confirmed with a close
+        write_frame(&mut client, CLOSE, &1000u16.to_be_bytes(), Some(MASK))
+            .await
+            .unwrap();
+        let frame = timeout(Duration::from_secs(5), read_frame(&mut client))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.opcode, CLOSE);
+    }
+}
@@ -1,24 +1,136 @@
 use crate::ccid::CCIDInterfaceHandler;
 use crate::device::ControlSetup;
-use log::{debug, error};
-use nusb::MaybeFuture;
+use crate::fido::FIDOInterfaceHandler;
+use crate::hexdump::hexdump;
+use crate::trace;
+use crate::transfer::TransferConfig;
+use crate::usb_backend::{UsbBackend, UsbInterfaceBackend, parse_configuration};
+use log::{debug, error, info, warn};
 use nusb::transfer;
 use std::any::Any;
 use std::fmt::{Debug, Formatter};
 use std::io;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use usbip::{
     ClassCode, DescriptorType, SetupPacket, StandardRequest, UsbEndpoint, UsbInterface,
     UsbInterfaceHandler,
 };
 
+// GET_DESCRIPTOR(BOS) attempts before the default BOS descriptor is relayed instead
+const BOS_ATTEMPTS: u32 = 3;
+// Vendor request reading the transfer state of the applet
+const WEBUSB_REQ_STAT: u8 = 0x02;
+// Between WEBUSB_REQ_STAT requests while waiting, the device can not signal the state change
+const STAT_POLL_INTERVAL: Duration = Duration::from_millis(2);
+// Longest a state request of the host is held while the applet processes an APDU
+const STAT_HOLD: Duration = Duration::from_millis(100);
+
+/// Transfer state of the WebUSB applet, as read by `WEBUSB_REQ_STAT`
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
+pub enum TransferStatus {
+    STATE_IDLE,
+    STATE_PROCESS,
+    STATE_SENDING_RESP,
+    STATE_SENT_RESP,
+}
+
+impl From<TransferStatus> for u8 {
+    fn from(value: TransferStatus) -> Self {
+        match value {
+            TransferStatus::STATE_IDLE => 0xff,
+            TransferStatus::STATE_PROCESS => 0x01,
+            TransferStatus::STATE_SENDING_RESP => 0x00,
+            TransferStatus::STATE_SENT_RESP => 0x03,
+        }
+    }
+}
+
+impl TryFrom<u8> for TransferStatus {
+    type Error = io::Error;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0xff => Self::STATE_IDLE,
+            0x01 => Self::STATE_PROCESS,
+            0x00 => Self::STATE_SENDING_RESP,
+            0x03 => Self::STATE_SENT_RESP,
+            other => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown TransferStatus value 0x{:02X}", other),
+                ));
+            }
+        })
+    }
+}
+
+/// Read the transfer state of the vendor interface `index`
+fn current_transfer_state(
+    interface: &dyn UsbInterfaceBackend,
+    index: u16,
+    control_timeout: Duration,
+) -> io::Result<TransferStatus> {
+    let data = interface.control_in(
+        transfer::ControlIn {
+            control_type: transfer::ControlType::Vendor,
+            recipient: transfer::Recipient::Interface,
+            request: WEBUSB_REQ_STAT,
+            value: 0x00,
+            index,
+            length: 0x1,
+        },
+        control_timeout,
+    )?;
+    if data.len() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid TransferStatus value size {}", data.len()),
+        ));
+    }
+    TransferStatus::try_from(data[0])
+}
+
+/// Block until the response to the last APDU sent to the vendor interface `index` can be read,
+/// failing with `TimedOut` after `timeout`
+///
+/// The state is polled with a pause between requests, rather than in a busy loop.
+fn wait_response(
+    interface: &dyn UsbInterfaceBackend,
+    index: u16,
+    timeout: Duration,
+    control_timeout: Duration,
+) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if current_transfer_state(interface, index, control_timeout)?
+            == TransferStatus::STATE_SENDING_RESP
+        {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "No response of the WebUSB interface within {}ms",
+                    timeout.as_millis()
+                ),
+            ));
+        }
+        std::thread::sleep(STAT_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
 pub struct WebUSBInterfaceHandler {
-    device: nusb::Device,
-    interface: nusb::Interface,
+    device: Arc<dyn UsbBackend>,
+    interface: Option<Box<dyn UsbInterfaceBackend>>,
     interface_number: u8,
-    ccid: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+    // Relayed endpoint address to the one of the physical device
+    endpoint_map: Vec<(u8, u8)>,
+    // CCID interfaces of the same device, whose cards are dropped before vendor requests
+    ccid: Vec<Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+    transfer: TransferConfig,
 }
 
 impl Debug for WebUSBInterfaceHandler {
@@ -29,13 +141,39 @@ impl Debug for WebUSBInterfaceHandler {
 
 impl WebUSBInterfaceHandler {
     pub fn new(
-        device: nusb::Device,
+        device: Arc<dyn UsbBackend>,
         interface_number: u8,
-        ccid: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
+        ccid: Vec<Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>>,
+        transfer: TransferConfig,
     ) -> Result<Self, io::Error> {
-        let webusb = device
-            .active_configuration()
-            .map_err(io::Error::from)?
+        let interface = device.claim_interface(Self::vendor_interface_number(device.as_ref())?)?;
+        let endpoint_map = Self::endpoint_map(device.as_ref())?;
+        Ok(Self {
+            device,
+            interface: Some(interface),
+            interface_number,
+            endpoint_map,
+            ccid,
+            transfer,
+        })
+    }
+
+    /// Re-resolve the vendor interface on `device` and claim it again
+    ///
+    /// The previously claimed interface is released before claiming, so refreshing against the
+    /// same device does not fail with the interface being busy.
+    pub fn refresh(&mut self, device: Arc<dyn UsbBackend>) -> io::Result<()> {
+        let number = Self::vendor_interface_number(device.as_ref())?;
+        self.endpoint_map = Self::endpoint_map(device.as_ref())?;
+        self.interface = None;
+        self.device = device;
+        self.interface = Some(self.device.claim_interface(number)?);
+        Ok(())
+    }
+
+    fn vendor_interface_number(device: &dyn UsbBackend) -> io::Result<u8> {
+        let configuration = device.active_configuration()?;
+        let webusb = parse_configuration(&configuration)?
             .interfaces()
             .find(|interface| {
                 interface
@@ -46,17 +184,126 @@ impl WebUSBInterfaceHandler {
                 io::ErrorKind::NotFound,
                 "No vendor specific interface found on USB device".to_string(),
             ))?;
-        let interface = device
-            .claim_interface(webusb.interface_number())
-            .wait()
-            .map_err(|e| io::Error::new(io::ErrorKind::ResourceBusy, e))?;
-        Ok(Self {
-            device,
-            interface,
-            interface_number,
-            ccid,
-        })
+        Ok(webusb.interface_number())
+    }
+
+    /// Pair the endpoints of the relayed FIDO/U2F and CCID interfaces with the endpoints of the
+    /// same direction and transfer type on the physical interface of the same class
+    fn endpoint_map(device: &dyn UsbBackend) -> io::Result<Vec<(u8, u8)>> {
+        let configuration = device.active_configuration()?;
+        let configuration = parse_configuration(&configuration)?;
+        let mut map = Vec::new();
+        for (class, endpoints) in [
+            (ClassCode::HID as u8, FIDOInterfaceHandler::endpoints()),
+            (
+                ClassCode::SmartCard as u8,
+                CCIDInterfaceHandler::endpoints(),
+            ),
+        ] {
+            let physical: Vec<_> = configuration
+                .interface_alt_settings()
+                .filter(|setting| setting.class() == class)
+                .flat_map(|setting| setting.endpoints())
+                .collect();
+            for ep in endpoints {
+                if let Some(physical) = physical.iter().find(|physical| {
+                    physical.address() & 0x80 == ep.address & 0x80
+                        && physical.attributes() & 0x03 == ep.attributes & 0x03
+                }) {
+                    map.push((ep.address, physical.address()));
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Power off the cards of all CCID interfaces, vendor requests may change the applets
+    fn drop_cards(&self) {
+        for ccid in &self.ccid {
+            ccid.lock()
+                .unwrap()
+                .as_any()
+                .downcast_mut::<CCIDInterfaceHandler>()
+                .unwrap()
+                .drop_card();
+        }
     }
+
+    /// Replace the interface number or endpoint address in wIndex by the one of the device
+    fn remap_index(&self, recipient: transfer::Recipient, index: &mut u16) -> io::Result<()> {
+        match recipient {
+            transfer::Recipient::Interface => {
+                *index &= 0xFF00;
+                *index |= self.interface_number as u16;
+            }
+            transfer::Recipient::Endpoint => {
+                let address = *index as u8;
+                let (_, physical) = self
+                    .endpoint_map
+                    .iter()
+                    .find(|(relayed, _)| *relayed == address)
+                    .ok_or(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("No endpoint on USB device for endpoint 0x{:02X}", address),
+                    ))?;
+                *index &= 0xFF00;
+                *index |= *physical as u16;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// GET_DESCRIPTOR(BOS) of `length` bytes, retried while the failure looks transient
+    fn get_bos_descriptor(&self, length: u16) -> io::Result<Vec<u8>> {
+        let mut attempt = 1;
+        loop {
+            match self.device.get_descriptor(
+                DescriptorType::BOS as u8,
+                0,
+                0,
+                length,
+                Duration::from_secs(1),
+            ) {
+                Err(e) if is_transient(&e) && attempt < BOS_ATTEMPTS => {
+                    warn!(
+                        "Failed to get BOS descriptor from USB device (attempt {}/{}), retrying: {}",
+                        attempt, BOS_ATTEMPTS, e
+                    );
+                    attempt += 1;
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Block until the physical device has the response to the last APDU relayed to it ready,
+    /// see [`wait_response`]
+    pub fn wait_response(&self, timeout: Duration) -> io::Result<()> {
+        wait_response(
+            self.interface()?,
+            self.interface_number as u16,
+            timeout,
+            self.transfer.control_timeout,
+        )
+    }
+
+    fn interface(&self) -> io::Result<&dyn UsbInterfaceBackend> {
+        self.interface.as_deref().ok_or(io::Error::new(
+            io::ErrorKind::NotConnected,
+            "Vendor specific interface is not claimed",
+        ))
+    }
+}
+
+/// Whether a failed transfer may succeed when issued again, a STALL means the request is not
+/// supported by the device
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::Other
+    )
 }
 
 fn control_string(control: &ControlSetup) -> String {
@@ -73,13 +320,13 @@ fn control_string(control: &ControlSetup) -> String {
         }
         ControlSetup::Out(control) => {
             format!(
-                "ControlOut: control_type: {:0X?}, recipient: {:0X?}, request: {:0X}, value: {:0X}, index: {:0X}, data: {:02X?}",
+                "ControlOut: control_type: {:0X?}, recipient: {:0X?}, request: {:0X}, value: {:0X}, index: {:0X}, data: {}",
                 control.control_type,
                 control.recipient,
                 control.request,
                 control.value,
                 control.index,
-                control.data
+                hexdump(control.data)
             )
         }
     }
@@ -91,54 +338,59 @@ impl UsbInterfaceHandler for WebUSBInterfaceHandler {
         setup: SetupPacket,
         req: &[u8],
     ) -> io::Result<Vec<u8>> {
+        trace::urb("webusb", 0x00, transfer_buffer_length, &setup, req);
         let control = ControlSetup::new(&setup, Some(req))?;
         match control {
             ControlSetup::In(control) => {
                 let mut data = self
-                    .interface
-                    .control_in(control, Duration::from_secs(5))
-                    .wait()
-                    .map_err(io::Error::from)?;
+                    .interface()?
+                    .control_in(control, self.transfer.control_timeout)?;
                 if data.len() > transfer_buffer_length as usize {
                     data.truncate(transfer_buffer_length as usize);
                 }
                 Ok(data)
             }
             ControlSetup::Out(control) => {
-                self.interface
-                    .control_out(control, Duration::from_secs(5))
-                    .wait()
-                    .map_err(io::Error::from)?;
+                self.interface()?
+                    .control_out(control, self.transfer.control_timeout)?;
                 Ok(vec![])
             }
         }
     }
 
     fn get_device_capability_descriptors(&self) -> Vec<Vec<u8>> {
-        let bos = match self
-            .device
-            .get_descriptor(DescriptorType::BOS as u8, 0, 0, Duration::from_secs(1))
-            .wait()
-        {
-            Ok(bos) => bos,
+        // Fetch the header first for wTotalLength, then the whole BOS descriptor
+        let header = match self.get_bos_descriptor(5) {
+            Ok(header) => header,
+            Err(e) if is_transient(&e) => {
+                error!("Failed to get BOS descriptor header from USB device: {}", e);
+                return Vec::new();
+            }
             Err(e) => {
-                error!("Failed to get BOS descriptor from USB device: {}", e);
+                info!("USB device has no BOS descriptor: {}", e);
                 return Vec::new();
             }
         };
-        if bos.len() < 5 || bos[0] != 0x5 || bos[1] != DescriptorType::BOS as u8 {
+        if header.len() < 5 || header[0] != 0x5 || header[1] != DescriptorType::BOS as u8 {
             error!("Invalid BOS descriptor from USB device");
             return Vec::new();
         }
-        let total_length = bos[2] as u16 | ((bos[3] as u16) << 8);
-        let num_capabilities = bos[4];
+        let total_length = header[2] as u16 | ((header[3] as u16) << 8);
+        let num_capabilities = header[4];
         if num_capabilities == 0 {
             error!(
                 "BOS descriptor returned by device indicates no device capability descriptor present"
             );
             return Vec::new();
         }
-        if total_length as usize != bos.len() {
+        let bos = match self.get_bos_descriptor(total_length) {
+            Ok(bos) => bos,
+            Err(e) => {
+                error!("Failed to get BOS descriptor from USB device: {}", e);
+                return Vec::new();
+            }
+        };
+        if total_length as usize != bos.len() || !bos.starts_with(&header[..5]) {
             error!(
                 "BOS descriptor length mismatch, buffer length: {}, total length: {}",
                 bos.len(),
@@ -173,59 +425,58 @@ impl UsbInterfaceHandler for WebUSBInterfaceHandler {
     fn handle_urb(
         &mut self,
         _interface: &UsbInterface,
-        _ep: UsbEndpoint,
+        ep: UsbEndpoint,
         transfer_buffer_length: u32,
         setup: SetupPacket,
         req: &[u8],
     ) -> std::io::Result<Vec<u8>> {
+        trace::urb("webusb", ep.address, transfer_buffer_length, &setup, req);
         let control = ControlSetup::new(&setup, Some(req))?;
         match control {
             ControlSetup::In(control) if control.request == StandardRequest::GetStatus as u8 => {
                 Ok(vec![0x00, 0x00])
             }
             ControlSetup::In(mut control) => {
-                self.ccid
-                    .lock()
-                    .unwrap()
-                    .as_any()
-                    .downcast_mut::<CCIDInterfaceHandler>()
-                    .unwrap()
-                    .drop_card();
-                if control.recipient == transfer::Recipient::Interface {
-                    control.index &= 0xFF00;
-                    control.index |= self.interface_number as u16;
+                self.drop_cards();
+                self.remap_index(control.recipient, &mut control.index)?;
+                // wLength and the URB buffer should agree, the device must not be asked for
+                // more than fits in either
+                let length = transfer_buffer_length.min(control.length as u32) as u16;
+                if length != control.length {
+                    debug!(
+                        "wLength {} of control IN does not match transfer buffer length {}",
+                        control.length, transfer_buffer_length
+                    );
                 }
+                control.length = length;
                 let mut data = self
-                    .interface
-                    .control_in(control, Duration::from_secs(5))
-                    .wait()
-                    .map_err(io::Error::from)?;
-                if data.len() > transfer_buffer_length as usize {
-                    data.truncate(transfer_buffer_length as usize);
+                    .interface()?
+                    .control_in(control, self.transfer.control_timeout)?;
+                // A state request of the host is answered once the applet is done, instead of
+                // the host polling it again across the connection
+                if control.control_type == transfer::ControlType::Vendor
+                    && control.request == WEBUSB_REQ_STAT
+                    && data == [u8::from(TransferStatus::STATE_PROCESS)]
+                {
+                    match self.wait_response(STAT_HOLD) {
+                        Ok(()) => data = vec![TransferStatus::STATE_SENDING_RESP.into()],
+                        Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                        Err(e) => return Err(e),
+                    }
                 }
+                data.truncate(length as usize);
                 Ok(data)
             }
             ControlSetup::Out(mut control) => {
-                self.ccid
-                    .lock()
-                    .unwrap()
-                    .as_any()
-                    .downcast_mut::<CCIDInterfaceHandler>()
-                    .unwrap()
-                    .drop_card();
-                if control.recipient == transfer::Recipient::Interface {
-                    control.index &= 0xFF00;
-                    control.index |= self.interface_number as u16;
-                }
+                self.drop_cards();
+                self.remap_index(control.recipient, &mut control.index)?;
                 debug!(
-                    "Out transfer control: {:02X?}, req: {:02X?}",
+                    "Out transfer control: {}, req: {}",
                     control_string(&ControlSetup::Out(control)),
-                    req
+                    hexdump(req)
                 );
-                self.interface
-                    .control_out(control, Duration::from_secs(5))
-                    .wait()
-                    .map_err(io::Error::from)?;
+                self.interface()?
+                    .control_out(control, self.transfer.control_timeout)?;
                 Ok(vec![])
             }
         }
@@ -238,51 +489,21 @@ impl UsbInterfaceHandler for WebUSBInterfaceHandler {
 
 #[cfg(test)]
 mod tests {
+    use super::{WebUSBInterfaceHandler, wait_response};
+    use crate::ccid::{CCIDConfig, CCIDInterfaceHandler};
     use crate::device::ControlSetup;
+    use crate::fake::{FakeControl, FakeUsbDevice, MemoryBackend, PIGEON_ATR};
+    use crate::hexdump::hexdump;
+    use crate::reserved::ReservedInterfaceHandler;
+    use crate::transfer::TransferConfig;
     use crate::webusb::control_string;
     use log::{debug, error};
     use nusb::MaybeFuture;
     use nusb::transfer::{ControlIn, ControlOut, ControlType, Recipient};
     use std::io;
-    use std::time::Duration;
-    use usbip::DescriptorType;
-
-    #[derive(Debug, Copy, Clone, PartialOrd, PartialEq)]
-    enum TransferStatus {
-        STATE_IDLE,
-        STATE_PROCESS,
-        STATE_SENDING_RESP,
-        STATE_SENT_RESP,
-    }
-
-    impl Into<u8> for TransferStatus {
-        fn into(self) -> u8 {
-            match self {
-                Self::STATE_IDLE => 0xff,
-                Self::STATE_PROCESS => 0x01,
-                Self::STATE_SENDING_RESP => 0x00,
-                Self::STATE_SENT_RESP => 0x03,
-            }
-        }
-    }
-
-    impl TryFrom<u8> for TransferStatus {
-        type Error = io::Error;
-        fn try_from(value: u8) -> Result<Self, Self::Error> {
-            Ok(match value {
-                0xff => Self::STATE_IDLE,
-                0x01 => Self::STATE_PROCESS,
-                0x00 => Self::STATE_SENDING_RESP,
-                0x03 => Self::STATE_SENT_RESP,
-                other => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        format!("Unknown TransferStatus value 0x{:02X}", other),
-                    ));
-                }
-            })
-        }
-    }
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use usbip::{DescriptorType, SetupPacket, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
 
     fn send_apdu(interface: &nusb::Interface, data: &[u8]) -> io::Result<()> {
         let control = ControlOut {
@@ -294,9 +515,9 @@ mod tests {
             data,
         };
         debug!(
-            "Out transfer OUT control: {:02X?}, data: {:02X?}",
+            "Out transfer OUT control: {}, data: {}",
             control_string(&ControlSetup::Out(control)),
-            data
+            hexdump(data)
         );
         interface
             .control_out(control, Duration::from_secs(5))
@@ -306,14 +527,12 @@ mod tests {
     }
 
     fn received_apdu(interface: &nusb::Interface) -> io::Result<Vec<u8>> {
-        loop {
-            match current_transfer_state(interface)? {
-                TransferStatus::STATE_PROCESS
-                | TransferStatus::STATE_IDLE
-                | TransferStatus::STATE_SENT_RESP => (),
-                TransferStatus::STATE_SENDING_RESP => break,
-            }
-        }
+        wait_response(
+            interface,
+            interface.interface_number() as u16,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+        )?;
         let control = ControlIn {
             control_type: ControlType::Vendor,
             recipient: Recipient::Interface,
@@ -322,41 +541,312 @@ mod tests {
             index: interface.interface_number() as u16,
             length: 4096,
         };
-        let other = control.clone();
         let data = interface
             .control_in(control, Duration::from_secs(5))
             .wait()
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
         debug!(
-            "Out transfer IN control: {:02X?}, data: {:02X?}",
-            control_string(&ControlSetup::In(other)),
-            data
+            "Out transfer IN control: {}, data: {}",
+            control_string(&ControlSetup::In(control)),
+            hexdump(&data)
         );
         Ok(data)
     }
 
-    fn current_transfer_state(interface: &nusb::Interface) -> io::Result<TransferStatus> {
-        let data = interface
-            .control_in(
-                ControlIn {
-                    control_type: ControlType::Vendor,
-                    recipient: Recipient::Interface,
-                    request: 0x02, // WEBUSB_REQ_STAT
-                    value: 0x00,
-                    index: interface.interface_number() as u16,
-                    length: 0x1,
-                },
-                Duration::from_secs(5),
+    #[test]
+    fn test_fake_device_forwarding() {
+        let device = FakeUsbDevice::pigeon();
+        let log = device.interface.log.clone();
+        let ccid = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let ccid = Arc::new(Mutex::new(
+            Box::new(ccid) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let mut handler =
+            WebUSBInterfaceHandler::new(Arc::new(device), 1, vec![ccid], TransferConfig::default())
+                .unwrap();
+        assert_eq!(log.lock().unwrap().claimed, vec![1]);
+        assert_eq!(
+            handler.get_device_capability_descriptors(),
+            vec![vec![0x07, 0x10, 0x02, 0x06, 0x00, 0x00, 0x00]]
+        );
+        // Header first, then wTotalLength
+        assert_eq!(log.lock().unwrap().descriptors, vec![(0x0F, 5), (0x0F, 12)]);
+
+        let interface = UsbInterface {
+            interface_class: 0xFF,
+            interface_subclass: 0xFF,
+            interface_protocol: 0xFF,
+            interface_number: 3,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        };
+        let setup = SetupPacket {
+            request_type: 0x41,
+            request: 0x00,
+            value: 0x00,
+            index: 0x03,
+            length: 4,
+        };
+        handler
+            .handle_urb(
+                &interface,
+                UsbEndpoint::default(),
+                4,
+                setup,
+                &[0x00, 0xA4, 0x04, 0x00],
             )
-            .wait()
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        if data.len() != 1 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Invalid TransferStatus value size {}", data.len()),
-            ));
+            .unwrap();
+        assert_eq!(
+            log.lock().unwrap().control_out,
+            vec![FakeControl {
+                control_type: ControlType::Vendor,
+                recipient: Recipient::Interface,
+                request: 0x00,
+                value: 0x00,
+                index: 0x01,
+                length: 4,
+                data: vec![0x00, 0xA4, 0x04, 0x00],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_wait_response() {
+        let device = FakeUsbDevice::pigeon();
+        let log = device.interface.log.clone();
+        let responses = device.interface.responses.clone();
+        let handler =
+            WebUSBInterfaceHandler::new(Arc::new(device), 1, vec![], TransferConfig::default())
+                .unwrap();
+        let stat_requests = || {
+            log.lock()
+                .unwrap()
+                .control_in
+                .iter()
+                .filter(|control| control.request == 0x02 && control.index == 0x01)
+                .count()
+        };
+
+        // STATE_PROCESS twice, then STATE_SENDING_RESP
+        responses
+            .lock()
+            .unwrap()
+            .extend([vec![0x01], vec![0x01], vec![0x00]]);
+        let start = Instant::now();
+        handler.wait_response(Duration::from_secs(5)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(stat_requests(), 3);
+
+        // A busy loop would run out of states long before the timeout
+        responses.lock().unwrap().extend(vec![vec![0x01]; 1000]);
+        let err = handler
+            .wait_response(Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(stat_requests() < 3 + 100, "{}", stat_requests());
+    }
+
+    #[test]
+    fn test_stat_held_while_processing() {
+        let device = FakeUsbDevice::pigeon();
+        let log = device.interface.log.clone();
+        let responses = device.interface.responses.clone();
+        let mut handler =
+            WebUSBInterfaceHandler::new(Arc::new(device), 1, vec![], TransferConfig::default())
+                .unwrap();
+        let interface = UsbInterface {
+            interface_class: 0xFF,
+            interface_subclass: 0xFF,
+            interface_protocol: 0xFF,
+            interface_number: 3,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        };
+        // WEBUSB_REQ_STAT of the host
+        let mut stat = || {
+            let setup = SetupPacket {
+                request_type: 0xC1,
+                request: 0x02,
+                value: 0x00,
+                index: 0x03,
+                length: 1,
+            };
+            handler.handle_urb(&interface, UsbEndpoint::default(), 1, setup, &[])
+        };
+
+        // STATE_PROCESS, answered once the applet reports STATE_SENDING_RESP
+        responses
+            .lock()
+            .unwrap()
+            .extend([vec![0x01], vec![0x01], vec![0x00]]);
+        assert_eq!(stat().unwrap(), [0x00]);
+        assert_eq!(log.lock().unwrap().control_in.len(), 3);
+
+        // Other states are relayed as they are
+        responses.lock().unwrap().push_back(vec![0xFF]);
+        assert_eq!(stat().unwrap(), [0xFF]);
+        assert_eq!(log.lock().unwrap().control_in.len(), 4);
+
+        // Still processing after the hold, the host polls again
+        responses.lock().unwrap().extend(vec![vec![0x01]; 1000]);
+        assert_eq!(stat().unwrap(), [0x01]);
+    }
+
+    #[test]
+    fn test_bos_retry() {
+        let usb2_extension = vec![0x07, 0x10, 0x02, 0x06, 0x00, 0x00, 0x00];
+        let capabilities = |device: FakeUsbDevice| {
+            let log = device.interface.log.clone();
+            let handler =
+                WebUSBInterfaceHandler::new(Arc::new(device), 1, vec![], TransferConfig::default())
+                    .unwrap();
+            let capabilities = handler.get_device_capability_descriptors();
+            let requests = log.lock().unwrap().descriptors.len();
+            (capabilities, requests)
+        };
+
+        // Transient failure of the header is retried
+        let device = FakeUsbDevice::pigeon();
+        device
+            .descriptor_errors
+            .lock()
+            .unwrap()
+            .push_back(io::ErrorKind::TimedOut);
+        assert_eq!(capabilities(device), (vec![usb2_extension.clone()], 3));
+
+        // Gives up after BOS_ATTEMPTS
+        let device = FakeUsbDevice::pigeon();
+        device
+            .descriptor_errors
+            .lock()
+            .unwrap()
+            .extend([io::ErrorKind::TimedOut; 3]);
+        assert_eq!(capabilities(device), (vec![], 3));
+
+        // A STALLed request is not retried
+        let mut device = FakeUsbDevice::pigeon();
+        device.bos = None;
+        assert_eq!(capabilities(device), (vec![], 1));
+    }
+
+    #[test]
+    fn test_control_in_length() {
+        let device = FakeUsbDevice::pigeon();
+        let log = device.interface.log.clone();
+        let responses = device.interface.responses.clone();
+        let ccid = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let ccid = Arc::new(Mutex::new(
+            Box::new(ccid) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let mut handler =
+            WebUSBInterfaceHandler::new(Arc::new(device), 1, vec![ccid], TransferConfig::default())
+                .unwrap();
+        let interface = UsbInterface {
+            interface_class: 0xFF,
+            interface_subclass: 0xFF,
+            interface_protocol: 0xFF,
+            interface_number: 1,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        };
+        let setup = |length| SetupPacket {
+            request_type: 0xC1,
+            request: 0x01,
+            value: 0x00,
+            index: 0x01,
+            length,
+        };
+        // (wLength, transfer buffer length), the smaller one is forwarded and bounds the data
+        for (length, transfer_buffer_length, expected) in [(0x40, 0x10, 0x10), (0x10, 0x40, 0x10)] {
+            responses.lock().unwrap().push_back(vec![0xAA; 0x40]);
+            let data = handler
+                .handle_urb(
+                    &interface,
+                    UsbEndpoint::default(),
+                    transfer_buffer_length,
+                    setup(length),
+                    &[],
+                )
+                .unwrap();
+            assert_eq!(data.len(), expected);
+            let control_in = log.lock().unwrap().control_in.pop().unwrap();
+            assert_eq!(control_in.length, expected as u16);
         }
-        TransferStatus::try_from(data[0])
+    }
+
+    #[test]
+    fn test_endpoint_recipient_remap() {
+        let mut device = FakeUsbDevice::pigeon();
+        // Bulk IN endpoint of the physical CCID interface is 0x83 instead of 0x81
+        let offset = device
+            .configuration
+            .windows(4)
+            .position(|d| d == [0x07, 0x05, 0x81, 0x02])
+            .unwrap();
+        device.configuration[offset + 2] = 0x83;
+        let log = device.interface.log.clone();
+        let ccid = CCIDInterfaceHandler::with_config(
+            &device,
+            Box::new(MemoryBackend::new(&PIGEON_ATR)),
+            CCIDConfig::default(),
+        )
+        .unwrap();
+        let ccid = Arc::new(Mutex::new(
+            Box::new(ccid) as Box<dyn UsbInterfaceHandler + Send>
+        ));
+        let mut handler =
+            WebUSBInterfaceHandler::new(Arc::new(device), 1, vec![ccid], TransferConfig::default())
+                .unwrap();
+        let interface = UsbInterface {
+            interface_class: 0xFF,
+            interface_subclass: 0xFF,
+            interface_protocol: 0xFF,
+            interface_number: 1,
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(ReservedInterfaceHandler::new()))),
+        };
+        let setup = |index| SetupPacket {
+            request_type: 0x42,
+            request: 0x05,
+            value: 0x00,
+            index,
+            length: 0,
+        };
+        handler
+            .handle_urb(&interface, UsbEndpoint::default(), 0, setup(0x81), &[])
+            .unwrap();
+        handler
+            .handle_urb(&interface, UsbEndpoint::default(), 0, setup(0x02), &[])
+            .unwrap();
+        let control_out = &log.lock().unwrap().control_out;
+        assert_eq!(control_out[0].recipient, Recipient::Endpoint);
+        assert_eq!(control_out[0].index, 0x83);
+        assert_eq!(control_out[1].index, 0x02);
+
+        // No such endpoint on the relayed device
+        assert!(
+            handler
+                .handle_urb(&interface, UsbEndpoint::default(), 0, setup(0x85), &[])
+                .is_err()
+        );
     }
 
     #[test]
@@ -439,7 +929,7 @@ mod tests {
         //error!("APDU result: {:02X?}", result);
         //send_apdu(&interface, &[0x00, 0x41, 0x00, 0x00, 0x02]).unwrap();
         let result = received_apdu(&interface).unwrap();
-        error!("APDU result: {:02X?}", result);
+        error!("APDU result: {}", hexdump(&result));
         // let bos = handle.get_descriptor(DescriptorType::BOS as u8, 0, 0, Duration::from_secs(5)).unwrap();
         // //let device_capabilities = handle.get_descriptor(0x10, 0, 0, Duration::from_secs(5)).unwrap();
         // println!("BOS: {:02X?}", bos);
@@ -161,6 +161,52 @@ impl std::fmt::Debug for NusbUsbHostInterfaceHandler {
     }
 }
 
+/// nusb control type and recipient of `setup`
+fn nusb_control_kind(
+    setup: &SetupPacket,
+) -> (nusb::transfer::ControlType, nusb::transfer::Recipient) {
+    let control_type = match (setup.request_type >> 5) & 0b11 {
+        0 => nusb::transfer::ControlType::Standard,
+        1 => nusb::transfer::ControlType::Class,
+        2 => nusb::transfer::ControlType::Vendor,
+        _ => unimplemented!(),
+    };
+    let recipient = match setup.request_type & 0b11111 {
+        0 => nusb::transfer::Recipient::Device,
+        1 => nusb::transfer::Recipient::Interface,
+        2 => nusb::transfer::Recipient::Endpoint,
+        3 => nusb::transfer::Recipient::Other,
+        _ => unimplemented!(),
+    };
+    (control_type, recipient)
+}
+
+/// nusb control OUT request of `setup`, sending `data`
+fn nusb_control_out<'a>(setup: &SetupPacket, data: &'a [u8]) -> nusb::transfer::ControlOut<'a> {
+    let (control_type, recipient) = nusb_control_kind(setup);
+    nusb::transfer::ControlOut {
+        control_type,
+        recipient,
+        request: setup.request,
+        value: setup.value,
+        index: setup.index,
+        data,
+    }
+}
+
+/// nusb control IN request of `setup`, reading up to `transfer_buffer_length` bytes
+fn nusb_control_in(setup: &SetupPacket, transfer_buffer_length: u32) -> nusb::transfer::ControlIn {
+    let (control_type, recipient) = nusb_control_kind(setup);
+    nusb::transfer::ControlIn {
+        control_type,
+        recipient,
+        request: setup.request,
+        value: setup.value,
+        index: setup.index,
+        length: transfer_buffer_length as u16,
+    }
+}
+
 impl NusbUsbHostInterfaceHandler {
     pub fn new(handle: Arc<Mutex<nusb::Interface>>) -> Self {
         Self { handle }
@@ -184,49 +230,13 @@ impl UsbInterfaceHandler for NusbUsbHostInterfaceHandler {
             // control
             if let Direction::In = ep.direction() {
                 // control in
-                let control = nusb::transfer::ControlIn {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    length: transfer_buffer_length as u16,
-                };
+                let control = nusb_control_in(&setup, transfer_buffer_length);
                 if let Ok(data) = handle.control_in(control, timeout).wait() {
                     return Ok(data);
                 }
             } else {
                 // control out
-                let control = nusb::transfer::ControlOut {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    data: req,
-                };
+                let control = nusb_control_out(&setup, req);
                 handle.control_out(control, timeout).wait().ok();
             }
         } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
@@ -283,53 +293,28 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
         {
             let timeout = std::time::Duration::new(1, 0);
             let handle = self.handle.lock().unwrap();
-            if setup.request_type & 0x80 == 0 {
-                // control out
-                let control = nusb::transfer::ControlOut {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    data: req,
-                };
-                #[cfg(not(target_os = "windows"))]
-                handle.control_out_blocking(control, req, timeout).ok();
-            } else {
-                // control in
-                let control = nusb::transfer::ControlIn {
-                    control_type: match (setup.request_type >> 5) & 0b11 {
-                        0 => nusb::transfer::ControlType::Standard,
-                        1 => nusb::transfer::ControlType::Class,
-                        2 => nusb::transfer::ControlType::Vendor,
-                        _ => unimplemented!(),
-                    },
-                    recipient: match setup.request_type & 0b11111 {
-                        0 => nusb::transfer::Recipient::Device,
-                        1 => nusb::transfer::Recipient::Interface,
-                        2 => nusb::transfer::Recipient::Endpoint,
-                        3 => nusb::transfer::Recipient::Other,
-                        _ => unimplemented!(),
-                    },
-                    request: setup.request,
-                    value: setup.value,
-                    index: setup.index,
-                    length: transfer_buffer_length as u16,
-                };
-                #[cfg(not(target_os = "windows"))]
-                if let Ok(len) = handle.control_in_blocking(control, &mut buffer, timeout) {
-                    return Ok(Vec::from(&buffer[..len]));
+            match (setup.request_type, FromPrimitive::from_u8(setup.request)) {
+                (0b00000000, Some(StandardRequest::SetAddress)) => {
+                    // The host controller of the device already addressed it
+                    debug!("Ignoring SET_ADDRESS to host device");
+                }
+                (0b00000000, Some(StandardRequest::SetConfiguration)) => {
+                    // Through the OS, which keeps track of the active configuration
+                    if let Err(e) = handle.set_configuration(setup.value as u8).wait() {
+                        warn!("Failed to set configuration of host device: {e}");
+                    }
+                }
+                (request_type, _) if request_type & 0x80 == 0 => {
+                    // control out
+                    let control = nusb_control_out(&setup, req);
+                    handle.control_out(control, timeout).wait().ok();
+                }
+                _ => {
+                    // control in, e.g. class or vendor requests to the device
+                    let control = nusb_control_in(&setup, transfer_buffer_length);
+                    if let Ok(data) = handle.control_in(control, timeout).wait() {
+                        return Ok(data);
+                    }
                 }
             }
         }
@@ -344,3 +329,71 @@ impl UsbDeviceHandler for NusbUsbHostDeviceHandler {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nusb_control_out_data() {
+        // Class request to interface 1, e.g. HID SET_REPORT
+        let setup = SetupPacket {
+            request_type: 0x21,
+            request: 0x09,
+            value: 0x0200,
+            index: 0x0001,
+            length: 3,
+        };
+        let req = [0x01, 0x02, 0x03];
+        let control = nusb_control_out(&setup, &req);
+        assert_eq!(control.control_type, nusb::transfer::ControlType::Class);
+        assert_eq!(control.recipient, nusb::transfer::Recipient::Interface);
+        assert_eq!(control.request, 0x09);
+        assert_eq!(control.value, 0x0200);
+        assert_eq!(control.index, 0x0001);
+        assert_eq!(control.data, &req);
+
+        // Standard request to the device without data, e.g. SET_CONFIGURATION
+        let setup = SetupPacket {
+            request_type: 0x00,
+            request: 0x09,
+            value: 0x0001,
+            index: 0x0000,
+            length: 0,
+        };
+        let control = nusb_control_out(&setup, &[]);
+        assert_eq!(control.control_type, nusb::transfer::ControlType::Standard);
+        assert_eq!(control.recipient, nusb::transfer::Recipient::Device);
+        assert!(control.data.is_empty());
+    }
+
+    #[test]
+    fn nusb_control_in_device() {
+        // Vendor request to the device, e.g. reading a firmware version
+        let setup = SetupPacket {
+            request_type: 0xC0,
+            request: 0x30,
+            value: 0x0000,
+            index: 0x0000,
+            length: 64,
+        };
+        let control = nusb_control_in(&setup, 64);
+        assert_eq!(control.control_type, nusb::transfer::ControlType::Vendor);
+        assert_eq!(control.recipient, nusb::transfer::Recipient::Device);
+        assert_eq!(control.request, 0x30);
+        assert_eq!(control.length, 64);
+
+        // Class request to the device reading less than wLength
+        let setup = SetupPacket {
+            request_type: 0xA0,
+            request: 0x00,
+            value: 0x0000,
+            index: 0x0000,
+            length: 4,
+        };
+        let control = nusb_control_in(&setup, 2);
+        assert_eq!(control.control_type, nusb::transfer::ControlType::Class);
+        assert_eq!(control.recipient, nusb::transfer::Recipient::Device);
+        assert_eq!(control.length, 2);
+    }
+}
@@ -25,6 +25,10 @@ pub trait UsbInterfaceHandler: std::fmt::Debug {
     ///
     /// Can be one of: control transfer to ep0 or other types of transfer to its endpoint.
     /// The resulting data should not exceed `transfer_buffer_length`.
+    ///
+    /// An IN transfer without data yet may fail with [std::io::ErrorKind::WouldBlock], like a
+    /// NAK: the URB is then kept pending and handled again after the next OUT transfer, or once
+    /// [UsbInterfaceHandler::urb_ready] is notified.
     fn handle_urb(
         &mut self,
         interface: &UsbInterface,
@@ -50,6 +54,12 @@ pub trait UsbInterfaceHandler: std::fmt::Debug {
         vec![]
     }
 
+    /// Notified when IN transfers kept pending may have data without an OUT transfer, e.g. from
+    /// work finishing in the background, `None` if they never do
+    fn urb_ready(&self) -> Option<Arc<tokio::sync::Notify>> {
+        None
+    }
+
     /// Helper to downcast to actual struct
     ///
     /// Please implement it as:
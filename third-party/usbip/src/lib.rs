@@ -10,8 +10,10 @@ use std::collections::{HashMap, VecDeque};
 use std::io::{ErrorKind, Result};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
 use usbip_protocol::UsbIpCommand;
@@ -37,7 +39,7 @@ pub use interface::*;
 pub use setup::*;
 pub use util::*;
 
-use crate::usbip_protocol::{USBIP_RET_SUBMIT, USBIP_RET_UNLINK, UsbIpResponse};
+use crate::usbip_protocol::{USBIP_RET_SUBMIT, USBIP_RET_UNLINK, UsbIpHeaderBasic, UsbIpResponse};
 
 /// Main struct of a USB/IP server
 #[derive(Default, Debug)]
@@ -398,14 +400,226 @@ impl UsbIpServer {
             ))
         }
     }
+
+    /// Make the device `bus_id` available again if a client imported it
+    ///
+    /// For connections that ended without releasing their device, e.g. by a panic.
+    pub async fn release_device(&self, bus_id: &str) {
+        let mut used_devices = self.used_devices.write().await;
+        if let Some(device) = used_devices.remove(bus_id) {
+            self.available_devices.write().await.push(device);
+        }
+    }
+}
+
+/// Device lifecycle events of a connection, reported by [handle_connection]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent<'a> {
+    /// The client imported the device with this bus id
+    Attached { bus_id: &'a str },
+    /// The device imported by the client was released
+    Detached { bus_id: &'a str },
 }
 
 pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-    mut socket: &mut T,
+    socket: &mut T,
     server: Arc<UsbIpServer>,
 ) -> Result<()> {
+    handle_connection(socket, server, |_| {}).await
+}
+
+// Status of USBIP_RET_UNLINK for an unlinked URB
+const ECONNRESET: i32 = 104;
+
+/// IN transfer a handler had no data for yet, see [UsbInterfaceHandler::handle_urb]
+struct PendingUrb {
+    header: UsbIpHeaderBasic,
+    transfer_buffer_length: u32,
+    setup: [u8; 8],
+}
+
+/// Handle a USBIP_CMD_SUBMIT whose header is already turned into the one of the reply
+///
+/// Returns `None` if the transfer is an IN transfer the handler has no data for yet.
+async fn submit_urb(
+    device: &UsbDevice,
+    header: &UsbIpHeaderBasic,
+    transfer_buffer_length: u32,
+    setup: [u8; 8],
+    data: &[u8],
+) -> Option<UsbIpResponse> {
+    let out = header.direction == 0;
+    let real_ep = if out { header.ep } else { header.ep | 0x80 };
+
+    let res = match device.find_ep(real_ep as u8) {
+        None => {
+            warn!("Endpoint {real_ep:02x?} not found");
+            UsbIpResponse::usbip_ret_submit_fail(header)
+        }
+        Some((ep, intf)) => {
+            trace!("->Endpoint {ep:02x?}");
+            trace!("->Setup {setup:02x?}");
+            trace!("->Request {data:02x?}");
+            let resp = device
+                .handle_urb(
+                    ep,
+                    intf,
+                    transfer_buffer_length,
+                    SetupPacket::parse(&setup),
+                    data,
+                )
+                .await;
+
+            match resp {
+                Ok(resp) => {
+                    if out {
+                        trace!("<-Wrote {}", data.len());
+                    } else {
+                        trace!("<-Resp {resp:02x?}, len={}", resp.len());
+                    }
+                    let mut response =
+                        UsbIpResponse::usbip_ret_submit_success(header, 0, 0, resp, vec![]);
+                    // For OUT (host to device) transfer, actual_length should be bytes consumed
+                    // Set actuaal length to zero result in retransmission of same packet
+                    if out {
+                        match &mut response {
+                            UsbIpResponse::UsbIpRetSubmit { actual_length, .. } => {
+                                *actual_length = data.len() as u32;
+                            }
+                            _ => (),
+                        }
+                    }
+                    // if !out && (ep.attributes & EndpointAttributes::Interrupt as u8) != 0 {
+                    //     match &mut response {
+                    //         UsbIpResponse::UsbIpRetSubmit { actual_length, ..} => {
+                    //             *actual_length = transfer_buffer_length as u32;
+                    //         }
+                    //         _ => ()
+                    //     }
+                    // }
+                    response
+                }
+                Err(err) if !out && err.kind() == ErrorKind::WouldBlock => {
+                    trace!("<-No data yet, keeping URB {} pending", header.seqnum);
+                    return None;
+                }
+                Err(err) => {
+                    warn!("Error handling URB: {err}");
+                    UsbIpResponse::usbip_ret_submit_fail(header)
+                }
+            }
+        }
+    };
+    Some(res)
+}
+
+/// Submit the pending IN transfers again, sending the replies of those having data now
+///
+/// Transfers stay in order per endpoint, the ones after a transfer still blocked are skipped.
+async fn retry_pending<T: AsyncWriteExt + Unpin>(
+    device: &UsbDevice,
+    pending: &mut Vec<PendingUrb>,
+    socket: &mut T,
+) -> Result<()> {
+    let mut blocked = Vec::new();
+    let mut i = 0;
+    while i < pending.len() {
+        let urb = &pending[i];
+        if blocked.contains(&urb.header.ep) {
+            i += 1;
+            continue;
+        }
+        let res = submit_urb(
+            device,
+            &urb.header,
+            urb.transfer_buffer_length,
+            urb.setup,
+            &[],
+        )
+        .await;
+        match res {
+            Some(res) => {
+                res.write_to_socket(socket).await?;
+                trace!("Sent USBIP_RET_SUBMIT of pending URB");
+                pending.remove(i);
+            }
+            None => {
+                blocked.push(urb.header.ep);
+                i += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Notifications of the interface handlers of `device` telling pending URBs may have data
+fn urb_ready(device: &UsbDevice) -> Vec<Arc<tokio::sync::Notify>> {
+    device
+        .interfaces
+        .iter()
+        .filter_map(|interface| interface.handler.lock().ok()?.urb_ready())
+        .collect()
+}
+
+/// Wait until one of `ready` is notified
+async fn any_notified(ready: &[Arc<tokio::sync::Notify>]) {
+    let mut notified: Vec<_> = ready
+        .iter()
+        .map(|ready| Box::pin(ready.notified()))
+        .collect();
+    std::future::poll_fn(|cx| {
+        match notified
+            .iter_mut()
+            .any(|notified| notified.as_mut().poll(cx).is_ready())
+        {
+            true => std::task::Poll::Ready(()),
+            false => std::task::Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// Serve a single USB/IP connection, calling `on_event` when a device is attached or detached
+pub async fn handle_connection<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    mut on_event: impl FnMut(ConnectionEvent) + Send,
+) -> Result<()> {
+    // Peeking at the buffer tells whether a command arrived without reading part of it
+    let mut socket = BufReader::new(socket);
     let mut current_import_device_id: Option<String> = None;
+    let mut pending: Vec<PendingUrb> = Vec::new();
     loop {
+        // Handlers may get the data of pending IN transfers without an OUT transfer, from work
+        // finishing in the background
+        let ready = match (pending.is_empty(), &current_import_device_id) {
+            (false, Some(id)) => match server.used_devices.read().await.get(id) {
+                Some(device) => urb_ready(device),
+                None => vec![],
+            },
+            _ => vec![],
+        };
+        if !ready.is_empty() {
+            let notified = {
+                let mut command = std::pin::pin!(socket.fill_buf());
+                let mut notified = std::pin::pin!(any_notified(&ready));
+                std::future::poll_fn(|cx| match command.as_mut().poll(cx) {
+                    std::task::Poll::Ready(_) => std::task::Poll::Ready(false),
+                    std::task::Poll::Pending => notified.as_mut().poll(cx).map(|()| true),
+                })
+                .await
+            };
+            if notified {
+                let used_devices = server.used_devices.read().await;
+                if let Some(device) = current_import_device_id
+                    .as_ref()
+                    .and_then(|id| used_devices.get(id))
+                {
+                    retry_pending(device, &mut pending, &mut socket).await?;
+                }
+                continue;
+            }
+        }
         let command = UsbIpCommand::read_from_socket(&mut socket).await;
         if let Err(err) = command {
             if let Some(dev_id) = current_import_device_id {
@@ -415,6 +629,7 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
                     Some(dev) => available_devices.push(dev),
                     None => unreachable!(),
                 }
+                on_event(ConnectionEvent::Detached { bus_id: &dev_id });
             }
 
             if err.kind() == ErrorKind::UnexpectedEof {
@@ -437,7 +652,7 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
 
                 // OP_REP_DEVLIST
                 UsbIpResponse::op_rep_devlist(&devices)
-                    .write_to_socket(socket)
+                    .write_to_socket(&mut socket)
                     .await?;
                 trace!("Sent OP_REP_DEVLIST");
             }
@@ -445,6 +660,7 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
                 trace!("Got OP_REQ_IMPORT");
 
                 current_import_device_id = None;
+                pending.clear();
                 current_import_device = None;
                 std::mem::drop(used_devices);
 
@@ -464,11 +680,14 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
                 }
 
                 let res = if let Some(dev) = current_import_device {
+                    on_event(ConnectionEvent::Attached {
+                        bus_id: &dev.bus_id,
+                    });
                     UsbIpResponse::op_rep_import_success(dev)
                 } else {
                     UsbIpResponse::op_rep_import_fail()
                 };
-                res.write_to_socket(socket).await?;
+                res.write_to_socket(&mut socket).await?;
                 trace!("Sent OP_REP_IMPORT");
             }
             UsbIpCommand::UsbIpCmdSubmit {
@@ -479,75 +698,38 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
                 ..
             } => {
                 trace!("Got USBIP_CMD_SUBMIT");
-                let device = current_import_device.unwrap();
-
+                let Some(device) = current_import_device else {
+                    warn!("Got USBIP_CMD_SUBMIT without an imported device");
+                    UsbIpResponse::op_rep_import_fail()
+                        .write_to_socket(&mut socket)
+                        .await?;
+                    continue;
+                };
                 let out = header.direction == 0;
-                let real_ep = if out { header.ep } else { header.ep | 0x80 };
-
                 header.command = USBIP_RET_SUBMIT.into();
 
-                let res = match device.find_ep(real_ep as u8) {
-                    None => {
-                        warn!("Endpoint {real_ep:02x?} not found");
-                        UsbIpResponse::usbip_ret_submit_fail(&header)
-                    }
-                    Some((ep, intf)) => {
-                        trace!("->Endpoint {ep:02x?}");
-                        trace!("->Setup {setup:02x?}");
-                        trace!("->Request {data:02x?}");
-                        let resp = device
-                            .handle_urb(
-                                ep,
-                                intf,
-                                transfer_buffer_length,
-                                SetupPacket::parse(&setup),
-                                &data,
-                            )
-                            .await;
-
-                        match resp {
-                            Ok(resp) => {
-                                if out {
-                                    trace!("<-Wrote {}", data.len());
-                                } else {
-                                    trace!("<-Resp {resp:02x?}, len={}", resp.len());
-                                }
-                                let mut response = UsbIpResponse::usbip_ret_submit_success(
-                                    &header,
-                                    0,
-                                    0,
-                                    resp,
-                                    vec![],
-                                );
-                                // For OUT (host to device) transfer, actual_length should be bytes consumed
-                                // Set actuaal length to zero result in retransmission of same packet
-                                if out {
-                                    match &mut response {
-                                        UsbIpResponse::UsbIpRetSubmit { actual_length, .. } => {
-                                            *actual_length = data.len() as u32;
-                                        }
-                                        _ => (),
-                                    }
-                                }
-                                // if !out && (ep.attributes & EndpointAttributes::Interrupt as u8) != 0 {
-                                //     match &mut response {
-                                //         UsbIpResponse::UsbIpRetSubmit { actual_length, ..} => {
-                                //             *actual_length = transfer_buffer_length as u32;
-                                //         }
-                                //         _ => ()
-                                //     }
-                                // }
-                                response
-                            }
-                            Err(err) => {
-                                warn!("Error handling URB: {err}");
-                                UsbIpResponse::usbip_ret_submit_fail(&header)
-                            }
-                        }
-                    }
+                // Later IN transfers of an endpoint must not overtake the pending ones
+                let res = if !out && pending.iter().any(|urb| urb.header.ep == header.ep) {
+                    None
+                } else {
+                    submit_urb(device, &header, transfer_buffer_length, setup, &data).await
                 };
-                res.write_to_socket(socket).await?;
-                trace!("Sent USBIP_RET_SUBMIT");
+                match res {
+                    Some(res) => {
+                        res.write_to_socket(&mut socket).await?;
+                        trace!("Sent USBIP_RET_SUBMIT");
+                    }
+                    None => pending.push(PendingUrb {
+                        header,
+                        transfer_buffer_length,
+                        setup,
+                    }),
+                }
+
+                // The OUT transfer may have provided the data of pending IN transfers
+                if out {
+                    retry_pending(device, &mut pending, &mut socket).await?;
+                }
             }
             UsbIpCommand::UsbIpCmdUnlink {
                 mut header,
@@ -557,8 +739,18 @@ pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
 
                 header.command = USBIP_RET_UNLINK.into();
 
-                let res = UsbIpResponse::usbip_ret_unlink_success(&header);
-                res.write_to_socket(socket).await?;
+                // A pending URB is given back without USBIP_RET_SUBMIT, all others completed
+                let mut res = UsbIpResponse::usbip_ret_unlink_success(&header);
+                if let Some(i) = pending
+                    .iter()
+                    .position(|urb| urb.header.seqnum == unlink_seqnum)
+                {
+                    pending.remove(i);
+                    if let UsbIpResponse::UsbIpRetUnlink { status, .. } = &mut res {
+                        *status = -ECONNRESET as u32;
+                    }
+                }
+                res.write_to_socket(&mut socket).await?;
                 trace!("Sent USBIP_RET_UNLINK");
             }
         }
@@ -667,6 +859,28 @@ mod tests {
         assert_eq!(mock_socket.output.len(), 0xC + 0x138 + 4);
     }
 
+    #[tokio::test]
+    async fn req_import_events() {
+        setup_test_logger();
+        let server = new_server_with_single_device();
+
+        let req = op_req_import(SINGLE_DEVICE_BUSID);
+        let mut mock_socket = MockSocket::new(req);
+        let mut events = Vec::new();
+        handle_connection(&mut mock_socket, Arc::new(server), |event| {
+            events.push(format!("{event:?}"))
+        })
+        .await
+        .ok();
+        assert_eq!(
+            events,
+            vec![
+                format!("Attached {{ bus_id: \"{SINGLE_DEVICE_BUSID}\" }}"),
+                format!("Detached {{ bus_id: \"{SINGLE_DEVICE_BUSID}\" }}"),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn req_import() {
         setup_test_logger();